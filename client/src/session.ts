@@ -11,21 +11,114 @@ interface DeviceInfo {
     id: string;
     name: string;
     dev_type: string;
+    volume_percent: number | null;
+}
+
+interface ImageInfo {
+    url: string;
+    width: number | null;
+    height: number | null;
 }
 
 interface TrackInfo {
+    kind: "track" | "episode";
     name: string;
     artists: string[];
     id: string;
+    album: string;
+    images: ImageInfo[];
+    duration_ms: number;
+    explicit: boolean;
+    popularity: number;
+    preview_url: string | null;
+}
+
+interface QueuedTrack extends TrackInfo {
+    queued_by: string | null;
+    votes: number;
+}
+
+interface ShowInfo {
+    id: string;
+    name: string;
+    publisher: string;
+    images: ImageInfo[];
+}
+
+interface Page<T> {
+    items: T[];
+    offset: number;
+    limit: number;
+    total: number;
+}
+
+interface AlbumInfo {
+    id: string;
+    name: string;
+    artists: string[];
+    images: ImageInfo[];
+    release_date: string | null;
+}
+
+interface ArtistInfo {
+    id: string;
+    name: string;
+    genres: string[];
+    images: ImageInfo[];
+}
+
+interface PlaylistInfo {
+    id: string;
+    name: string;
+    owner: string | null;
+    images: ImageInfo[];
+    total_tracks: number;
 }
 
 interface SearchResults {
-    tracks: TrackInfo[];
+    tracks: Page<TrackInfo>;
+    albums: Page<AlbumInfo>;
+    artists: Page<ArtistInfo>;
+    playlists: Page<PlaylistInfo>;
+    episodes: Page<TrackInfo>;
+    shows: Page<ShowInfo>;
+}
+
+interface TrackList {
+    id: string;
+    tracks: Page<TrackInfo>;
+}
+
+interface LibraryResult {
+    source: "playlists" | "saved" | "top";
+    playlists: Page<PlaylistInfo>;
+    tracks: Page<TrackInfo>;
+}
+
+interface HistoryEntry extends QueuedTrack {
+    autoplayed: boolean;
+    skipped: boolean;
+    started_at: string;
+    ended_at: string | null;
+}
+
+interface Progress {
+    track_id: string;
+    position_ms: number;
+    duration_ms: number;
+    is_playing: boolean;
+    device: DeviceInfo | null;
+    timestamp_ms: number;
 }
 
 interface StateUpdate {
     track: TrackInfo | null;
-    queue: TrackInfo[];
+    queue: QueuedTrack[];
+    progress: Progress | null;
+    paused: boolean;
+    autoplayed: boolean;
+    autoplay: "off" | "playlist" | "recommendations" | "history";
+    fallback_playlist: string | null;
 }
 
 enum Context {
@@ -34,6 +127,8 @@ enum Context {
     None
 }
 
+const PROTOCOL_VERSION = 1
+
 let context = Context.None
 
 switch(document.querySelector<HTMLParagraphElement>("#context").innerText) {
@@ -52,11 +147,28 @@ const logout = () => {
 }
 
 let votedTracksCache: string[] = [];
+let fallbackPlaylist: string | null = null;
+let autoplaySelect: HTMLSelectElement | null = null;
+let clientId: string | null = null;
+// Last progress heard from the server and when it arrived, moved along locally in between
+let progress: Progress | null = null;
+let progressReceivedAt = 0;
 
 const onMessageCb = (ev: MessageEvent<any>) => {
     let result = JSON.parse(ev.data)
 
     switch (result.type) {
+        case "Welcome": {
+            clientId = result.payload.client_id
+            if (context === Context.Host) {
+                const devicesRequest = { type: "Devices" }
+                doSend(JSON.stringify(devicesRequest))
+            }
+
+            const stateRequest = { type: "State" }
+            doSend(JSON.stringify(stateRequest))
+            break
+        }
         case "Devices": {
             let devices = result.payload as DeviceInfo[]
             populateAndDisplayDevicesNav(devices)
@@ -65,22 +177,107 @@ const onMessageCb = (ev: MessageEvent<any>) => {
         case "SearchResult": {
             let searchResults = result.payload as SearchResults
             searchResultsList.textContent = ""
-            searchResultsList.appendChild(createTrackList(searchResults.tracks, "Add", queueTrack))
+            searchResultsList.appendChild(createTrackList(searchResults.tracks.items, "Add", queueTrack))
+            searchResultsList.appendChild(createCollectionList(searchResults.albums.items, (album: AlbumInfo) => album.artists.join(", ")))
+            searchResultsList.appendChild(createCollectionList(searchResults.playlists.items, (playlist: PlaylistInfo) => `${playlist.owner ?? ""} · ${playlist.total_tracks} tracks`))
+            searchResultsList.appendChild(createTrackList(searchResults.episodes.items, "Add", queueTrack))
+            break
+        }
+        case "TrackList": {
+            let trackList = result.payload as TrackList
+            searchResultsList.textContent = ""
+            searchResultsList.appendChild(createTrackList(trackList.tracks.items, "Add", queueTrack))
+            break
+        }
+        case "LibraryResult": {
+            let library = result.payload as LibraryResult
+            searchResultsList.textContent = ""
+            if (library.source === "playlists") {
+                searchResultsList.appendChild(createCollectionList(library.playlists.items, (playlist: PlaylistInfo) => `${playlist.total_tracks} tracks`, createFallbackButton))
+            } else {
+                const importButton = document.createElement("button")
+                importButton.innerText = "Import all"
+                importButton.classList.add("nav-btn")
+                importButton.addEventListener("click", (ev) => {
+                    ev.preventDefault()
+                    doSend(JSON.stringify({ type: "Import", uris: library.tracks.items.map((track) => track.id) }))
+                    closeSearchNavButton.click()
+                })
+                searchResultsList.appendChild(importButton)
+                searchResultsList.appendChild(createTrackList(library.tracks.items, "Add", queueTrack))
+            }
+            searchNav.style.height = "100%"
+            break
+        }
+        case "History": {
+            let history = result.payload as Page<HistoryEntry>
+            searchResultsList.textContent = ""
+            const sessionId = document.querySelector<HTMLButtonElement>("#session_id").innerText
+            const downloadLink = document.createElement("a")
+            downloadLink.innerText = "Download CSV"
+            downloadLink.href = `/api/sessions/${sessionId}/history?format=csv`
+            downloadLink.classList.add("nav-btn")
+            searchResultsList.appendChild(downloadLink)
+            if (context === Context.Host) {
+                const saveButton = document.createElement("button")
+                saveButton.innerText = "Save as playlist"
+                saveButton.classList.add("nav-btn")
+                saveButton.addEventListener("click", (ev) => {
+                    ev.preventDefault()
+                    doSend(JSON.stringify({ type: "SaveHistory" }))
+                    closeSearchNavButton.click()
+                })
+                searchResultsList.appendChild(saveButton)
+            }
+            searchResultsList.appendChild(createTrackList(history.items, "Add", queueTrack))
+            searchNav.style.height = "100%"
+            break
+        }
+        case "SavedPlaylist": {
+            let playlist = result.payload as PlaylistInfo
+            console.info(`Saved ${playlist.total_tracks} tracks to ${playlist.name}`)
             break
         }
         case "StateUpdate": {
             let stateUpdate = result.payload as StateUpdate
+            fallbackPlaylist = stateUpdate.fallback_playlist
+            if (autoplaySelect) {
+                autoplaySelect.value = stateUpdate.autoplay
+            }
             trackQueue.textContent = ""
 
             if (stateUpdate.track) {
                 let currentTrackContainer = document.createElement("div")
                 currentTrackContainer.id = "current-track-container"
+                const cover = createCoverImage(stateUpdate.track, 128)
+                if (cover) {
+                    currentTrackContainer.appendChild(cover)
+                }
+
                 let paragraph = document.createElement("p")
                 const b = document.createElement("b")
                 b.textContent = stateUpdate.track.name + " - " + stateUpdate.track.artists
                 paragraph.appendChild(b)
+                paragraph.appendChild(document.createElement("br"))
+                let details = describeTrack(stateUpdate.track)
+                if (stateUpdate.autoplayed) {
+                    details += " · Autoplay"
+                }
+                paragraph.appendChild(document.createTextNode(details))
                 currentTrackContainer.appendChild(paragraph)
 
+                let progressBar = document.createElement("progress")
+                progressBar.id = "track-progress"
+                progressBar.max = stateUpdate.track.duration_ms
+                if (context === Context.Host) {
+                    progressBar.addEventListener("click", seekTo)
+                }
+                currentTrackContainer.appendChild(progressBar)
+
+                let progressText = document.createElement("span")
+                progressText.id = "track-progress-text"
+                currentTrackContainer.appendChild(progressText)
+
                 let volumeIcon = document.createElement("i")
                 volumeIcon.classList.add("fa")
                 volumeIcon.classList.add("fa-volume-up")
@@ -88,26 +285,47 @@ const onMessageCb = (ev: MessageEvent<any>) => {
                 volumeIcon.id = "volume-icon"
                 currentTrackContainer.appendChild(volumeIcon)
 
+                if (context === Context.Host) {
+                    currentTrackContainer.appendChild(createPlaybackControls(stateUpdate))
+                }
+
+                let skipButton = document.createElement("button")
+                skipButton.innerText = context === Context.Host ? "Skip" : "Vote skip"
+                skipButton.addEventListener("click", (ev) => {
+                    ev.preventDefault()
+                    const skipRequest = { type: context === Context.Host ? "Skip" : "VoteSkip" }
+                    doSend(JSON.stringify(skipRequest))
+                    skipButton.disabled = true
+                })
+                currentTrackContainer.appendChild(skipButton)
+
                 trackQueue.appendChild(currentTrackContainer)
             }
             
             trackQueue.appendChild(createTrackList(stateUpdate.queue, "Vote", voteTrack))
+
+            if (stateUpdate.progress) {
+                setProgress(stateUpdate.progress)
+            } else if (!stateUpdate.track || progress?.track_id !== stateUpdate.track.id) {
+                progress = null
+            }
+            renderProgress()
             
             const votedTracksRequest = { type: "VotedTracks" }
             doSend(JSON.stringify(votedTracksRequest))
             break
         }
+        case "Progress": {
+            setProgress(result.payload as Progress)
+            renderProgress()
+            break
+        }
         case "Shutdown": {
             logout()
             break
         }
         case "Transfer": {
-            let resultCode = result.payload as string
-
-            if (resultCode === "OK") {
-                devicesNav.style.width = "0"
-            }
-
+            devicesNav.style.width = "0"
             break
         }
         case "VotedTracks": {
@@ -115,19 +333,18 @@ const onMessageCb = (ev: MessageEvent<any>) => {
             disableVotingForTracks(votedTracksCache)
             break
         }
+        case "Error": {
+            console.error(`${result.code}: ${result.message}`)
+            break
+        }
     }
 }
 
 const { doConnect, doSend } = useWebSocket(onMessageCb)
 
 const onOpenCb = () => {
-    if (context === Context.Host) {
-        const devicesRequest = { type: "Devices" }
-        doSend(JSON.stringify(devicesRequest))
-    }
-
-    const stateRequest = { type: "State" }
-    doSend(JSON.stringify(stateRequest))
+    const helloRequest = { type: "Hello", versions: [PROTOCOL_VERSION] }
+    doSend(JSON.stringify(helloRequest))
 
     document.querySelector<HTMLDivElement>("#connection-down-modal").style.width = "0";
 }
@@ -153,6 +370,18 @@ closeSettingsNavButton.addEventListener("click", (ev) => {
     settingsNav.style.width = "0" 
 })
 
+const createHistoryButton = () => {
+    const historyButton = document.createElement("button")
+    historyButton.innerText = "History"
+    historyButton.addEventListener("click", (ev) => {
+        ev.preventDefault()
+        doSend(JSON.stringify({ type: "History", limit: 50 }))
+        settingsNav.style.width = "0"
+    })
+    historyButton.classList.add("nav-btn")
+    return historyButton
+}
+
 if (context === Context.Host) {
     const copyJoinUrlButton = document.createElement("button")
     copyJoinUrlButton.innerText = "Copy URL"
@@ -178,6 +407,35 @@ if (context === Context.Host) {
     devicesButton.classList.add("nav-btn")
     settingsNavContent.appendChild(devicesButton)
 
+    const librarySources: [string, string][] = [["playlists", "My playlists"], ["saved", "Saved tracks"], ["top", "Top tracks"]]
+    for (const [source, label] of librarySources) {
+        const libraryButton = document.createElement("button")
+        libraryButton.innerText = label
+        libraryButton.addEventListener("click", (ev) => {
+            ev.preventDefault()
+            doSend(JSON.stringify({ type: "Library", source, limit: 50 }))
+            settingsNav.style.width = "0"
+        })
+        libraryButton.classList.add("nav-btn")
+        settingsNavContent.appendChild(libraryButton)
+    }
+
+    // What plays once the queue is done
+    autoplaySelect = document.createElement("select")
+    for (const mode of ["off", "playlist", "recommendations", "history"]) {
+        const option = document.createElement("option")
+        option.value = mode
+        option.innerText = `Autoplay: ${mode}`
+        autoplaySelect.appendChild(option)
+    }
+    autoplaySelect.addEventListener("change", () => {
+        doSend(JSON.stringify({ type: "SetAutoplay", mode: autoplaySelect.value }))
+    })
+    autoplaySelect.classList.add("nav-btn")
+    settingsNavContent.appendChild(autoplaySelect)
+
+    settingsNavContent.appendChild(createHistoryButton())
+
     const endSessionButton = document.createElement("button")
     endSessionButton.innerText = "End session"
     endSessionButton.id = "end-session-btn"
@@ -189,6 +447,8 @@ if (context === Context.Host) {
     settingsNavContent.appendChild(endSessionButton)
 
 } else if (context === Context.Peer) {
+    settingsNavContent.appendChild(createHistoryButton())
+
     const exitSessionButton = document.createElement("button")
     exitSessionButton.innerText = "Exit session"
     exitSessionButton.addEventListener("click", (ev) => {
@@ -209,13 +469,124 @@ const voteTrack = (ev: MouseEvent, trackId: string) => {
     doSend(JSON.stringify(voteRequets))
 }
 
-const createTrackListEntry = (info: TrackInfo, buttonText: string, onClickCb: (ev: MouseEvent, trackId: string) => void) => {
+const formatDuration = (durationMs: number) => {
+    const seconds = Math.round(durationMs / 1000)
+    return `${Math.floor(seconds / 60)}:${String(seconds % 60).padStart(2, "0")}`
+}
+
+const setProgress = (update: Progress) => {
+    progress = update
+    progressReceivedAt = Date.now()
+}
+
+const renderProgress = () => {
+    const progressBar = document.querySelector<HTMLProgressElement>("#track-progress")
+    const progressText = document.querySelector<HTMLSpanElement>("#track-progress-text")
+    if (!progressBar || !progressText) {
+        return
+    }
+
+    if (!progress) {
+        progressBar.removeAttribute("value")
+        progressText.textContent = ""
+        return
+    }
+
+    // Interpolate from the time of receipt rather than the server's clock
+    let position = progress.position_ms
+    if (progress.is_playing) {
+        position = Math.min(position + Date.now() - progressReceivedAt, progress.duration_ms)
+    }
+    progressBar.value = position
+    progressText.textContent = `${formatDuration(position)} / ${formatDuration(progress.duration_ms)}`
+}
+
+setInterval(renderProgress, 1000)
+
+const createPlaybackControls = (stateUpdate: StateUpdate) => {
+    const controls = document.createElement("div")
+    controls.id = "playback-controls"
+
+    const pauseButton = document.createElement("button")
+    pauseButton.innerText = stateUpdate.paused ? "Resume" : "Pause"
+    pauseButton.addEventListener("click", (ev) => {
+        ev.preventDefault()
+        doSend(JSON.stringify({ type: stateUpdate.paused ? "Resume" : "Pause" }))
+        pauseButton.disabled = true
+    })
+    controls.appendChild(pauseButton)
+
+    const volume = document.createElement("input")
+    volume.type = "range"
+    volume.min = "0"
+    volume.max = "100"
+    volume.value = String(stateUpdate.progress?.device?.volume_percent ?? 100)
+    volume.addEventListener("change", () => {
+        doSend(JSON.stringify({ type: "Volume", volume_percent: Number(volume.value) }))
+    })
+    controls.appendChild(volume)
+
+    return controls
+}
+
+// Lets the host seek by clicking on the progress bar
+const seekTo = (ev: MouseEvent) => {
+    const progressBar = ev.currentTarget as HTMLProgressElement
+    const fraction = ev.offsetX / progressBar.clientWidth
+    const positionMs = Math.floor(fraction * progressBar.max)
+    doSend(JSON.stringify({ type: "Seek", position_ms: positionMs }))
+}
+
+const describeTrack = (info: TrackInfo) => {
+    const details = [info.album, formatDuration(info.duration_ms)]
+    if (info.kind === "episode") {
+        details.unshift("Episode")
+    }
+    if (info.explicit) {
+        details.push("Explicit")
+    }
+    return details.join(" · ")
+}
+
+// Picks the smallest cover that is still at least `size` pixels wide
+const createCoverImage = (info: TrackInfo, size: number) => {
+    if (info.images.length === 0) {
+        return null
+    }
+
+    const image = info.images
+        .filter((image) => image.width === null || image.width >= size)
+        .pop() ?? info.images[0]
+
+    const img = document.createElement("img")
+    img.src = image.url
+    img.alt = info.album
+    img.width = size
+    img.height = size
+    return img
+}
+
+const createTrackListEntry = (info: TrackInfo | QueuedTrack, buttonText: string, onClickCb: (ev: MouseEvent, trackId: string) => void) => {
 
     var listEntry = document.createElement("li")
     listEntry.classList.add("track-container")
+
+    const cover = createCoverImage(info, 64)
+    if (cover) {
+        listEntry.appendChild(cover)
+    }
     
     var paragraph = document.createElement("p")
     paragraph.textContent = info.name + " - " + info.artists
+    paragraph.appendChild(document.createElement("br"))
+    let details = describeTrack(info)
+    if ("votes" in info) {
+        details += ` · ${info.votes} votes`
+        if (info.queued_by !== null && info.queued_by === clientId) {
+            details += " · Queued by you"
+        }
+    }
+    paragraph.appendChild(document.createTextNode(details))
     listEntry.appendChild(paragraph)
 
     const callback = (ev: MouseEvent, trackId: string) => {
@@ -245,7 +616,7 @@ const createTrackListEntry = (info: TrackInfo, buttonText: string, onClickCb: (e
     return listEntry
 }
 
-const createTrackList = (tracks: TrackInfo[], buttonText: string, onClickCb: (ev: MouseEvent, trackId: string) => void) => {
+const createTrackList = (tracks: (TrackInfo | QueuedTrack)[], buttonText: string, onClickCb: (ev: MouseEvent, trackId: string) => void) => {
     var container = document.createElement("ul")
     
     for (var track of tracks) {
@@ -255,6 +626,61 @@ const createTrackList = (tracks: TrackInfo[], buttonText: string, onClickCb: (ev
     return container
 }
 
+// Lets the host play from one of their playlists whenever the queue runs dry
+const createFallbackButton = (playlist: { id: string }) => {
+    const isFallback = fallbackPlaylist === playlist.id
+    const button = document.createElement("button")
+    button.innerText = isFallback ? "Stop fallback" : "Use as fallback"
+    button.classList.add("nav-btn")
+    button.addEventListener("click", (ev) => {
+        ev.preventDefault()
+        doSend(JSON.stringify({ type: "SetFallbackPlaylist", uri: isFallback ? null : playlist.id }))
+        closeSearchNavButton.click()
+    })
+    return button
+}
+
+// Albums and playlists are opened to list their tracks
+const createCollectionList = <T extends { id: string, name: string }>(collections: T[], describe: (collection: T) => string, extraButton?: (collection: T) => HTMLButtonElement) => {
+    var container = document.createElement("ul")
+
+    for (const collection of collections) {
+        var listEntry = document.createElement("li")
+        listEntry.classList.add("track-container")
+
+        var paragraph = document.createElement("p")
+        paragraph.textContent = collection.name
+        paragraph.appendChild(document.createElement("br"))
+        paragraph.appendChild(document.createTextNode(describe(collection)))
+        listEntry.appendChild(paragraph)
+
+        var button = document.createElement("button")
+        button.innerText = "Open"
+        button.classList.add("nav-btn")
+        button.addEventListener("click", (ev) => {
+            ev.preventDefault()
+            doSend(JSON.stringify({ type: "Browse", uri: collection.id, limit: 50 }))
+        })
+        listEntry.appendChild(button)
+
+        var queueButton = document.createElement("button")
+        queueButton.innerText = "Queue all"
+        queueButton.classList.add("nav-btn")
+        queueButton.addEventListener("click", (ev) => {
+            queueTrack(ev, collection.id)
+        })
+        listEntry.appendChild(queueButton)
+
+        if (extraButton) {
+            listEntry.appendChild(extraButton(collection))
+        }
+
+        container.appendChild(listEntry)
+    }
+
+    return container
+}
+
 const searchResultsList = document.querySelector<HTMLDivElement>("#search-results")
 const trackQueue = document.querySelector<HTMLDivElement>("#track-queue")
 const searchInput = document.querySelector<HTMLInputElement>("#search-input")
@@ -275,7 +701,7 @@ searchButton.addEventListener("click", (ev) => {
     const input = searchInput.value
     searchInput.value = ""
 
-    const searchRequest = { type: "Search", query: input }
+    const searchRequest = { type: "Search", query: input, types: ["track", "album", "playlist", "episode"] }
     doSend(JSON.stringify(searchRequest))
 })
 
@@ -0,0 +1,10 @@
+-- A client may vote for the same track in different sessions
+ALTER TABLE votes DROP CONSTRAINT votes_pkey;
+ALTER TABLE votes ADD PRIMARY KEY (client_id, session_id, track_uri);
+
+-- The vote counter is derived from the votes table, bring existing rows in line with it
+UPDATE queued_tracks
+SET votes = (
+    SELECT COUNT(*) FROM votes
+    WHERE votes.session_id = queued_tracks.session_id AND votes.track_uri = queued_tracks.track_uri
+);
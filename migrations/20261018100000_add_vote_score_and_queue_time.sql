@@ -0,0 +1,5 @@
+-- Votes carry a signed score so that tracks can be voted down as well as up
+ALTER TABLE votes ADD COLUMN score SMALLINT DEFAULT 1 NOT NULL;
+
+-- Tracks with the same net score are played in the order they were queued
+ALTER TABLE queued_tracks ADD COLUMN queued_at timestamptz DEFAULT now() NOT NULL;
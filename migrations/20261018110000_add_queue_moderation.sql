@@ -0,0 +1,4 @@
+-- Lets the host pin tracks to the top of the queue or move them to a fixed position
+ALTER TABLE queued_tracks ADD COLUMN pinned_at timestamptz;
+ALTER TABLE queued_tracks ADD COLUMN position INTEGER;
+ALTER TABLE queued_tracks ADD COLUMN moved_at timestamptz;
@@ -0,0 +1,6 @@
+CREATE TABLE skip_votes(
+    client_id uuid NOT NULL,
+    session_id uuid NOT NULL REFERENCES sessions (id),
+    track_uri TEXT NOT NULL,
+    PRIMARY KEY (client_id, session_id, track_uri)
+);
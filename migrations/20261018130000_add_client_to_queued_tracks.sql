@@ -0,0 +1,2 @@
+ALTER TABLE queued_tracks ADD COLUMN client_id uuid;
+CREATE INDEX queued_tracks_client_idx ON queued_tracks (session_id, client_id);
@@ -0,0 +1,6 @@
+CREATE TABLE tracks(
+    uri TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    artists TEXT[] NOT NULL,
+    fetched_at timestamptz NOT NULL DEFAULT now()
+);
@@ -0,0 +1,10 @@
+-- Tracks stored so far lack the new details, they are fetched again when needed
+DELETE FROM tracks;
+
+ALTER TABLE tracks
+    ADD COLUMN album TEXT NOT NULL,
+    ADD COLUMN images JSONB NOT NULL,
+    ADD COLUMN duration_ms BIGINT NOT NULL,
+    ADD COLUMN explicit BOOLEAN NOT NULL,
+    ADD COLUMN popularity INTEGER NOT NULL,
+    ADD COLUMN preview_url TEXT;
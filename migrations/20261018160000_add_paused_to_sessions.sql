@@ -0,0 +1 @@
+ALTER TABLE sessions ADD COLUMN paused BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,6 @@
+-- Everything stored so far is a track
+ALTER TABLE queued_tracks ADD COLUMN item_type TEXT NOT NULL DEFAULT 'track';
+ALTER TABLE tracks ADD COLUMN item_type TEXT NOT NULL DEFAULT 'track';
+
+ALTER TABLE sessions ADD COLUMN current_item_type TEXT;
+UPDATE sessions SET current_item_type = 'track' WHERE current_track_uri IS NOT NULL;
@@ -0,0 +1,3 @@
+-- Played from once the queue runs dry, position is the next track to play
+ALTER TABLE sessions ADD COLUMN fallback_playlist_uri TEXT;
+ALTER TABLE sessions ADD COLUMN fallback_position INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,13 @@
+-- Sessions with a fallback playlist keep playing from it, as they did before
+ALTER TABLE sessions ADD COLUMN autoplay_mode TEXT NOT NULL DEFAULT 'playlist';
+ALTER TABLE sessions ADD COLUMN current_autoplayed BOOLEAN NOT NULL DEFAULT false;
+
+CREATE TABLE played_tracks(
+    id BIGSERIAL PRIMARY KEY,
+    session_id uuid NOT NULL REFERENCES sessions (id),
+    track_uri TEXT NOT NULL,
+    item_type TEXT NOT NULL,
+    autoplayed BOOLEAN NOT NULL DEFAULT false,
+    started_at timestamptz NOT NULL DEFAULT now()
+);
+CREATE INDEX played_tracks_session_id_started_at ON played_tracks (session_id, started_at);
@@ -0,0 +1,5 @@
+ALTER TABLE played_tracks ADD COLUMN ended_at timestamptz;
+ALTER TABLE played_tracks ADD COLUMN client_id uuid;
+-- The track's score when it left the queue
+ALTER TABLE played_tracks ADD COLUMN votes INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE played_tracks ADD COLUMN skipped BOOLEAN NOT NULL DEFAULT false;
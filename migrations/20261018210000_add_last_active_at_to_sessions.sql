@@ -0,0 +1,2 @@
+ALTER TABLE sessions ADD COLUMN last_active_at timestamptz NOT NULL DEFAULT now();
+CREATE INDEX sessions_last_active_at_idx ON sessions (last_active_at);
@@ -1,9 +1,15 @@
+use crate::cluster::Cluster;
 use crate::configuration::Settings;
 use crate::controller::Controller;
 use crate::db::Database;
 use crate::middleware::reject_anonymous_users;
-use crate::routes::{callback, create_session, index, join, logout, session_index, ws_connect};
-use crate::session_agent::SessionAgentRequest;
+use crate::provider::MusicProvider;
+use crate::routes::{
+    api_browse, api_devices, api_history, api_kill, api_queue, api_search, api_state, api_transfer,
+    api_vote, callback, create_session, index, join, logout, metrics, session_index, ws_connect,
+};
+use crate::session_agent::{SessionAgentRequest, WorkerMetrics};
+use crate::sweeper::Sweeper;
 use actix::Actor;
 use actix_files as fs;
 use actix_session::storage::RedisSessionStore;
@@ -13,24 +19,35 @@ use actix_web::dev::Server;
 use actix_web::{web, App, HttpServer};
 use actix_web_lab::middleware::from_fn;
 use secrecy::ExposeSecret;
-use tokio::sync::mpsc::UnboundedSender;
+use std::net::TcpListener;
+use tokio::sync::mpsc;
 
 pub struct Application {
+    port: u16,
     server: Server,
 }
 
 // TODO: redirect valid sessions away from non /session paths
 impl Application {
-    pub async fn build(
+    pub async fn build<P: MusicProvider>(
         settings: Settings,
-        agent_tx: UnboundedSender<SessionAgentRequest>,
+        agent_tx: mpsc::Sender<SessionAgentRequest>,
+        provider: P,
+        cluster: Cluster,
+        worker_metrics: WorkerMetrics,
     ) -> Result<Self, anyhow::Error> {
         let hmac_secret = settings.application.hmac_secret;
         let secret_key = Key::from(hmac_secret.expose_secret().as_bytes());
         let redis_store = RedisSessionStore::new(settings.redis_uri.expose_secret()).await?;
         let db = web::Data::new(Database::new(&settings.database, settings.spotify.clone()));
-        let controller = Controller::new(agent_tx).start();
+        let provider = web::Data::new(provider);
+        let worker_metrics = web::Data::new(worker_metrics);
+        let sweeper = Sweeper::new(db.get_ref().clone(), cluster.clone(), &settings.session);
+        let controller = Controller::new(agent_tx, cluster).start();
+        actix::spawn(sweeper.run(controller.clone()));
         let address = format!("0.0.0.0:{}", settings.application.port);
+        let listener = TcpListener::bind(address)?;
+        let port = listener.local_addr()?.port();
 
         let server = HttpServer::new(move || {
             App::new()
@@ -39,9 +56,10 @@ impl Application {
                     secret_key.clone(),
                 ))
                 .route("/", web::get().to(index))
-                .route("/create", web::get().to(create_session))
-                .route("/callback", web::get().to(callback))
+                .route("/create", web::get().to(create_session::<P>))
+                .route("/callback", web::get().to(callback::<P>))
                 .route("/join/{id}", web::get().to(join))
+                .route("/metrics", web::get().to(metrics))
                 .service(
                     web::scope("/session")
                         .wrap(from_fn(reject_anonymous_users))
@@ -49,15 +67,33 @@ impl Application {
                         .route("/ws", web::get().to(ws_connect))
                         .route("/logout", web::get().to(logout)),
                 )
+                .service(
+                    web::scope("/api/sessions/{id}")
+                        .wrap(from_fn(reject_anonymous_users))
+                        .route("/search", web::get().to(api_search))
+                        .route("/browse", web::get().to(api_browse))
+                        .route("/queue", web::post().to(api_queue))
+                        .route("/vote", web::post().to(api_vote))
+                        .route("/state", web::get().to(api_state))
+                        .route("/history", web::get().to(api_history))
+                        .route("/devices", web::get().to(api_devices))
+                        .route("/transfer", web::post().to(api_transfer))
+                        .route("/kill", web::post().to(api_kill)),
+                )
                 .service(fs::Files::new("/static", "."))
                 .app_data(db.clone())
                 .app_data(web::Data::new(controller.clone()))
-                .app_data(web::Data::new(settings.spotify.clone()))
+                .app_data(provider.clone())
+                .app_data(worker_metrics.clone())
         })
-        .bind(address)?
+        .listen(listener)?
         .run();
 
-        Ok(Self { server })
+        Ok(Self { port, server })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
     }
 
     pub async fn run(self) -> Result<(), std::io::Error> {
@@ -0,0 +1,61 @@
+use crate::session_state::Context;
+
+/// Operations a client can ask of the session it is connected to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Hello,
+    Search,
+    Browse,
+    Queue,
+    State,
+    Vote,
+    Unvote,
+    Kill,
+    Devices,
+    Transfer,
+    VotedTracks,
+    RemoveTrack,
+    PinTrack,
+    MoveTrack,
+    VoteSkip,
+    Skip,
+    Pause,
+    Resume,
+    Seek,
+    Volume,
+    Library,
+    Import,
+    SetFallbackPlaylist,
+    SetAutoplay,
+    History,
+    SaveHistory,
+}
+
+/// Operations that only the host of a session may perform.
+pub const HOST_ONLY_OPERATIONS: &[Operation] = &[
+    Operation::Kill,
+    Operation::Transfer,
+    Operation::RemoveTrack,
+    Operation::PinTrack,
+    Operation::MoveTrack,
+    Operation::Skip,
+    Operation::Pause,
+    Operation::Resume,
+    Operation::Seek,
+    Operation::Volume,
+    Operation::Library,
+    Operation::Import,
+    Operation::SetFallbackPlaylist,
+    Operation::SetAutoplay,
+    Operation::SaveHistory,
+];
+
+impl Operation {
+    pub fn is_host_only(&self) -> bool {
+        HOST_ONLY_OPERATIONS.contains(self)
+    }
+}
+
+pub fn is_authorized(context: Context, operation: Operation) -> bool {
+    context == Context::Host || !operation.is_host_only()
+}
@@ -0,0 +1,60 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Where the next track comes from when nothing is left in the queue.
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AutoplayMode {
+    /// Playback stops once the queue is done.
+    Off,
+    /// The host's fallback playlist, if they picked one.
+    Playlist,
+    /// Recommendations seeded by the tracks the session played last.
+    Recommendations,
+    /// Tracks the session played before, the longest ago first.
+    History,
+}
+
+impl AutoplayMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AutoplayMode::Off => "off",
+            AutoplayMode::Playlist => "playlist",
+            AutoplayMode::Recommendations => "recommendations",
+            AutoplayMode::History => "history",
+        }
+    }
+}
+
+impl FromStr for AutoplayMode {
+    type Err = anyhow::Error;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "off" => Ok(AutoplayMode::Off),
+            "playlist" => Ok(AutoplayMode::Playlist),
+            "recommendations" => Ok(AutoplayMode::Recommendations),
+            "history" => Ok(AutoplayMode::History),
+            _ => anyhow::bail!("Unknown autoplay mode {}", mode),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes_round_trip_through_their_names() {
+        for mode in [
+            AutoplayMode::Off,
+            AutoplayMode::Playlist,
+            AutoplayMode::Recommendations,
+            AutoplayMode::History,
+        ] {
+            assert_eq!(AutoplayMode::from_str(mode.as_str()).unwrap(), mode);
+        }
+        assert!(AutoplayMode::from_str("shuffle").is_err());
+    }
+}
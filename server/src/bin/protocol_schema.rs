@@ -0,0 +1,9 @@
+//! Prints the JSON Schema of the session WebSocket protocol to stdout, e.g.
+//! `cargo run --bin protocol_schema > protocol.schema.json`.
+
+use queuetify::protocol::protocol_schema;
+
+fn main() -> Result<(), serde_json::Error> {
+    println!("{}", serde_json::to_string_pretty(&protocol_schema())?);
+    Ok(())
+}
@@ -0,0 +1,257 @@
+use crate::session_agent::{build_artist_string_vec, ImageInfo};
+use rspotify::model::{FullArtist, FullPlaylist, SimplifiedAlbum, SimplifiedPlaylist};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+pub const DEFAULT_PAGE_LIMIT: u32 = 10;
+/// The most results the provider hands out at once.
+pub const MAX_PAGE_LIMIT: u32 = 50;
+
+/// What a search can look for.
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchKind {
+    Track,
+    Album,
+    Artist,
+    Playlist,
+    Episode,
+    Show,
+}
+
+/// Searched for when a search doesn't say.
+pub const DEFAULT_SEARCH_KINDS: &[SearchKind] =
+    &[SearchKind::Track, SearchKind::Episode, SearchKind::Show];
+
+impl FromStr for SearchKind {
+    type Err = anyhow::Error;
+
+    fn from_str(kind: &str) -> Result<Self, Self::Err> {
+        match kind {
+            "track" => Ok(SearchKind::Track),
+            "album" => Ok(SearchKind::Album),
+            "artist" => Ok(SearchKind::Artist),
+            "playlist" => Ok(SearchKind::Playlist),
+            "episode" => Ok(SearchKind::Episode),
+            "show" => Ok(SearchKind::Show),
+            _ => anyhow::bail!("Unknown search type {}", kind),
+        }
+    }
+}
+
+/// The parts of the host's own library that can be listed.
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LibrarySource {
+    Playlists,
+    Saved,
+    Top,
+}
+
+impl FromStr for LibrarySource {
+    type Err = anyhow::Error;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        match source {
+            "playlists" => Ok(LibrarySource::Playlists),
+            "saved" => Ok(LibrarySource::Saved),
+            "top" => Ok(LibrarySource::Top),
+            _ => anyhow::bail!("Unknown library source {}", source),
+        }
+    }
+}
+
+/// Which slice of a longer list of results to return.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PageRequest {
+    pub limit: u32,
+    pub offset: u32,
+}
+
+impl PageRequest {
+    pub fn new(limit: Option<u32>, offset: Option<u32>) -> Result<Self, String> {
+        let limit = limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+        if limit == 0 || limit > MAX_PAGE_LIMIT {
+            return Err(format!(
+                "The limit has to be between 1 and {MAX_PAGE_LIMIT}, not {limit}"
+            ));
+        }
+
+        Ok(Self {
+            limit,
+            offset: offset.unwrap_or(0),
+        })
+    }
+}
+
+impl Default for PageRequest {
+    fn default() -> Self {
+        Self {
+            limit: DEFAULT_PAGE_LIMIT,
+            offset: 0,
+        }
+    }
+}
+
+/// A slice of results, along with how many there are in all.
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub offset: u32,
+    pub limit: u32,
+    pub total: u32,
+}
+
+impl<T> Default for Page<T> {
+    fn default() -> Self {
+        Self {
+            items: Vec::new(),
+            offset: 0,
+            limit: 0,
+            total: 0,
+        }
+    }
+}
+
+impl<T: Clone> Page<T> {
+    /// Cuts the requested page out of the complete list of results.
+    pub fn slice(all: &[T], page: PageRequest) -> Self {
+        Self {
+            items: all
+                .iter()
+                .skip(page.offset as usize)
+                .take(page.limit as usize)
+                .cloned()
+                .collect(),
+            offset: page.offset,
+            limit: page.limit,
+            total: all.len() as u32,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug)]
+pub struct AlbumInfo {
+    pub id: String,
+    pub name: String,
+    pub artists: Vec<String>,
+    pub images: Vec<ImageInfo>,
+    pub release_date: Option<String>,
+}
+
+impl TryFrom<SimplifiedAlbum> for AlbumInfo {
+    type Error = ();
+
+    fn try_from(album: SimplifiedAlbum) -> Result<Self, Self::Error> {
+        let id = album.id.ok_or(())?;
+        Ok(AlbumInfo {
+            id: id.to_string(),
+            name: album.name,
+            artists: build_artist_string_vec(&album.artists),
+            images: album.images.into_iter().map(ImageInfo::from).collect(),
+            release_date: album.release_date,
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug)]
+pub struct ArtistInfo {
+    pub id: String,
+    pub name: String,
+    pub genres: Vec<String>,
+    pub images: Vec<ImageInfo>,
+}
+
+impl From<FullArtist> for ArtistInfo {
+    fn from(artist: FullArtist) -> Self {
+        ArtistInfo {
+            id: artist.id.to_string(),
+            name: artist.name,
+            genres: artist.genres,
+            images: artist.images.into_iter().map(ImageInfo::from).collect(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug)]
+pub struct PlaylistInfo {
+    pub id: String,
+    pub name: String,
+    pub owner: Option<String>,
+    pub images: Vec<ImageInfo>,
+    pub total_tracks: u32,
+}
+
+impl From<SimplifiedPlaylist> for PlaylistInfo {
+    fn from(playlist: SimplifiedPlaylist) -> Self {
+        PlaylistInfo {
+            id: playlist.id.to_string(),
+            name: playlist.name,
+            owner: playlist.owner.display_name,
+            images: playlist.images.into_iter().map(ImageInfo::from).collect(),
+            total_tracks: playlist.tracks.total,
+        }
+    }
+}
+
+impl From<FullPlaylist> for PlaylistInfo {
+    fn from(playlist: FullPlaylist) -> Self {
+        PlaylistInfo {
+            id: playlist.id.to_string(),
+            name: playlist.name,
+            owner: playlist.owner.display_name,
+            images: playlist.images.into_iter().map(ImageInfo::from).collect(),
+            total_tracks: playlist.tracks.total,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_requests_default_and_stay_within_the_limit() {
+        assert_eq!(PageRequest::new(None, None), Ok(PageRequest::default()));
+        assert_eq!(
+            PageRequest::new(Some(MAX_PAGE_LIMIT), Some(20)),
+            Ok(PageRequest {
+                limit: MAX_PAGE_LIMIT,
+                offset: 20
+            })
+        );
+        assert!(PageRequest::new(Some(0), None).is_err());
+        assert!(PageRequest::new(Some(MAX_PAGE_LIMIT + 1), None).is_err());
+    }
+
+    #[test]
+    fn slices_pages_out_of_all_results() {
+        let all: Vec<u32> = (0..25).collect();
+        let page = Page::slice(&all, PageRequest::new(Some(10), Some(20)).unwrap());
+        assert_eq!(page.items, vec![20, 21, 22, 23, 24]);
+        assert_eq!((page.offset, page.limit, page.total), (20, 10, 25));
+
+        let past_the_end = Page::slice(&all, PageRequest::new(None, Some(30)).unwrap());
+        assert!(past_the_end.items.is_empty());
+        assert_eq!(past_the_end.total, 25);
+    }
+
+    #[test]
+    fn parses_search_kinds() {
+        assert_eq!(
+            SearchKind::from_str("playlist").unwrap(),
+            SearchKind::Playlist
+        );
+        assert!(SearchKind::from_str("podcast").is_err());
+    }
+
+    #[test]
+    fn parses_library_sources() {
+        assert_eq!(
+            LibrarySource::from_str("saved").unwrap(),
+            LibrarySource::Saved
+        );
+        assert!(LibrarySource::from_str("albums").is_err());
+    }
+}
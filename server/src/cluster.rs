@@ -0,0 +1,236 @@
+use crate::controller::messages::RemoteBroadcast;
+use crate::controller::Controller;
+use crate::progress::Progress;
+use crate::protocol::StateUpdatePayload;
+use actix::Addr;
+use futures_util::StreamExt;
+use redis::aio::ConnectionManager;
+use redis::{AsyncCommands, Client, Script};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use uuid::Uuid;
+
+const BROADCAST_CHANNEL: &str = "queuetify:broadcasts";
+const RESUBSCRIBE_INTERVAL: Duration = Duration::from_secs(1);
+/// How long an instance's count of the clients in a session is trusted. Instances
+/// renew it with every check of the session's playback, so the count of one that
+/// went away without clearing it stops counting after a while.
+const PRESENCE_TTL: Duration = Duration::from_secs(300);
+
+// Takes the lease if it is free, or renews it if we already hold it
+const ACQUIRE_LEASE_SCRIPT: &str = r#"
+    if redis.call("GET", KEYS[1]) == ARGV[1] then
+        return redis.call("PEXPIRE", KEYS[1], ARGV[2])
+    end
+    if redis.call("SET", KEYS[1], ARGV[1], "NX", "PX", ARGV[2]) then
+        return 1
+    end
+    return 0
+"#;
+
+const RELEASE_LEASE_SCRIPT: &str = r#"
+    if redis.call("GET", KEYS[1]) == ARGV[1] then
+        return redis.call("DEL", KEYS[1])
+    end
+    return 0
+"#;
+
+// Starts a client's wait between two queue requests, unless it is still waiting,
+// in which case the time it has left is returned
+const CLAIM_QUEUE_TURN_SCRIPT: &str = r#"
+    if redis.call("SET", KEYS[1], "1", "NX", "PX", ARGV[1]) then
+        return 0
+    end
+    return redis.call("PTTL", KEYS[1])
+"#;
+
+/// Something every instance with clients in the session has to pass on to them.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Broadcast {
+    StateUpdate {
+        session_id: Uuid,
+        update: Box<StateUpdatePayload>,
+    },
+    Progress {
+        session_id: Uuid,
+        progress: Progress,
+    },
+    Shutdown {
+        session_id: Uuid,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Published {
+    origin: Uuid,
+    broadcast: Broadcast,
+}
+
+/// Coordinates the instances serving sessions through Redis. Broadcasts are
+/// fanned out over pub/sub, and a lease per session elects the one instance
+/// that polls its playback and refreshes its token.
+#[derive(Clone)]
+pub struct Cluster {
+    instance_id: Uuid,
+    client: Client,
+    connection: ConnectionManager,
+}
+
+impl Cluster {
+    pub async fn connect(redis_uri: &str) -> Result<Self, redis::RedisError> {
+        let client = Client::open(redis_uri)?;
+        let connection = ConnectionManager::new(client.clone()).await?;
+        Ok(Self {
+            instance_id: Uuid::new_v4(),
+            client,
+            connection,
+        })
+    }
+
+    pub async fn publish(&self, broadcast: Broadcast) -> Result<(), anyhow::Error> {
+        let published = Published {
+            origin: self.instance_id,
+            broadcast,
+        };
+        let payload = serde_json::to_string(&published)?;
+        let mut connection = self.connection.clone();
+        connection
+            .publish::<_, _, ()>(BROADCAST_CHANNEL, payload)
+            .await?;
+        Ok(())
+    }
+
+    /// Hands broadcasts published by other instances to the local controller
+    /// for as long as the process runs, resubscribing if the connection drops.
+    pub async fn listen(self, controller: Addr<Controller>) {
+        loop {
+            if let Err(err) = self.forward_broadcasts(&controller).await {
+                log::error!("Lost broadcast subscription, {err}");
+            }
+            tokio::time::sleep(RESUBSCRIBE_INTERVAL).await;
+        }
+    }
+
+    async fn forward_broadcasts(&self, controller: &Addr<Controller>) -> Result<(), anyhow::Error> {
+        let mut pubsub = self.client.get_async_connection().await?.into_pubsub();
+        pubsub.subscribe(BROADCAST_CHANNEL).await?;
+        let mut messages = pubsub.on_message();
+
+        while let Some(message) = messages.next().await {
+            let payload: String = message.get_payload()?;
+            let published = match serde_json::from_str::<Published>(&payload) {
+                Ok(published) => published,
+                Err(err) => {
+                    log::error!("Failed to parse broadcast, {err}");
+                    continue;
+                }
+            };
+
+            // Our own broadcasts were already delivered locally
+            if published.origin != self.instance_id {
+                controller.do_send(RemoteBroadcast(published.broadcast));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether this instance owns the session, taking or renewing the lease
+    /// for `ttl`.
+    pub async fn acquire_lease(
+        &self,
+        session_id: Uuid,
+        ttl: Duration,
+    ) -> Result<bool, redis::RedisError> {
+        let mut connection = self.connection.clone();
+        let acquired: i32 = Script::new(ACQUIRE_LEASE_SCRIPT)
+            .key(lease_key(session_id))
+            .arg(self.instance_id.to_string())
+            .arg(ttl.as_millis() as u64)
+            .invoke_async(&mut connection)
+            .await?;
+        Ok(acquired == 1)
+    }
+
+    pub async fn release_lease(&self, session_id: Uuid) -> Result<(), redis::RedisError> {
+        let mut connection = self.connection.clone();
+        Script::new(RELEASE_LEASE_SCRIPT)
+            .key(lease_key(session_id))
+            .arg(self.instance_id.to_string())
+            .invoke_async::<_, i32>(&mut connection)
+            .await?;
+        Ok(())
+    }
+
+    /// Records how many of the session's clients are connected to this instance.
+    pub async fn set_presence(
+        &self,
+        session_id: Uuid,
+        clients: usize,
+    ) -> Result<(), redis::RedisError> {
+        let mut connection = self.connection.clone();
+        let key = presence_key(session_id);
+        let instance_id = self.instance_id.to_string();
+        if clients == 0 {
+            return connection.hdel::<_, _, ()>(key, instance_id).await;
+        }
+
+        redis::pipe()
+            .atomic()
+            .hset(&key, instance_id, clients)
+            .ignore()
+            .pexpire(&key, PRESENCE_TTL.as_millis() as usize)
+            .ignore()
+            .query_async(&mut connection)
+            .await
+    }
+
+    /// The number of clients connected to the session across all instances.
+    pub async fn connected_clients(&self, session_id: Uuid) -> Result<usize, redis::RedisError> {
+        let mut connection = self.connection.clone();
+        let counts: Vec<usize> = connection.hvals(presence_key(session_id)).await?;
+        Ok(counts.into_iter().sum())
+    }
+
+    /// Takes the client's turn to queue, which is its last for `interval`. Returns
+    /// how long the client still has to wait instead if it queued too recently.
+    pub async fn claim_queue_turn(
+        &self,
+        session_id: Uuid,
+        client_id: Uuid,
+        interval: Duration,
+    ) -> Result<Option<Duration>, redis::RedisError> {
+        let mut connection = self.connection.clone();
+        let wait: i64 = Script::new(CLAIM_QUEUE_TURN_SCRIPT)
+            .key(queue_turn_key(session_id, client_id))
+            .arg(interval.as_millis() as u64)
+            .invoke_async(&mut connection)
+            .await?;
+        Ok((wait > 0).then(|| Duration::from_millis(wait as u64)))
+    }
+
+    /// Gives back a turn that didn't end up queueing anything.
+    pub async fn release_queue_turn(
+        &self,
+        session_id: Uuid,
+        client_id: Uuid,
+    ) -> Result<(), redis::RedisError> {
+        let mut connection = self.connection.clone();
+        connection
+            .del::<_, ()>(queue_turn_key(session_id, client_id))
+            .await
+    }
+}
+
+fn lease_key(session_id: Uuid) -> String {
+    format!("queuetify:lease:{session_id}")
+}
+
+fn presence_key(session_id: Uuid) -> String {
+    format!("queuetify:presence:{session_id}")
+}
+
+fn queue_turn_key(session_id: Uuid, client_id: Uuid) -> String {
+    format!("queuetify:queue-turn:{session_id}:{client_id}")
+}
@@ -1,11 +1,15 @@
 use config::Config;
-use secrecy::Secret;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::postgres::{PgConnectOptions, PgSslMode};
+use std::time::Duration;
 
 #[derive(serde:: Deserialize, Clone)]
 pub struct Settings {
     pub application: ApplicationSettings,
     pub database: DatabaseSettings,
     pub spotify: SpotifySettings,
+    pub session: SessionSettings,
+    pub track_cache: TrackCacheSettings,
     pub redis_uri: Secret<String>,
 }
 
@@ -25,6 +29,27 @@ pub struct DatabaseSettings {
     pub require_ssl: bool,
 }
 
+impl DatabaseSettings {
+    pub fn without_db(&self) -> PgConnectOptions {
+        let ssl_mode = if self.require_ssl {
+            PgSslMode::Require
+        } else {
+            // Try an encrypted connection, fallback to unencrypted if it fails
+            PgSslMode::Prefer
+        };
+        PgConnectOptions::new()
+            .host(&self.host)
+            .username(&self.username)
+            .password(self.password.expose_secret())
+            .port(self.port)
+            .ssl_mode(ssl_mode)
+    }
+
+    pub fn with_db(&self) -> PgConnectOptions {
+        self.without_db().database(&self.database_name)
+    }
+}
+
 #[derive(serde:: Deserialize, Clone)]
 pub struct SpotifySettings {
     pub client_id: Secret<String>,
@@ -32,11 +57,67 @@ pub struct SpotifySettings {
     pub redirect_uri: Secret<String>,
 }
 
+#[derive(serde:: Deserialize, Clone)]
+pub struct SessionSettings {
+    /// Queued tracks whose net vote score drops below this are removed from the queue.
+    pub removal_score_threshold: i32,
+    /// Fraction of connected clients that must vote to skip before the current track is skipped.
+    pub skip_vote_fraction: f64,
+    /// Maximum number of tracks waiting in a session's queue.
+    pub max_queue_length: i64,
+    /// Maximum number of tracks a single client may have waiting in the queue.
+    pub max_pending_tracks_per_client: i64,
+    /// Maximum number of tracks queued at once from an album or playlist.
+    pub max_collection_tracks: usize,
+    /// Minimum number of seconds between two queue requests from the same client.
+    pub min_queue_interval_secs: u64,
+    /// Maximum number of requests waiting for the agent to hand them to their session's
+    /// worker before new ones are turned away.
+    pub agent_queue_capacity: usize,
+    /// Maximum number of requests waiting for a session's worker before new ones are turned away.
+    pub worker_queue_capacity: usize,
+    /// Number of seconds a session's worker may spend on a single request.
+    pub request_timeout_secs: u64,
+    /// Number of seconds before the end of a track that the next one is handed to the
+    /// provider. Has to be at least as long as the crossfade set up in the player.
+    pub handoff_lead_secs: u64,
+    /// Longest number of seconds a session's playback goes unchecked while no track is
+    /// due, which bounds how long changes made in the provider's own app go unnoticed.
+    pub playback_check_secs: u64,
+    /// Number of seconds a session may go without clients connected or requests from
+    /// them before it is deleted.
+    pub idle_ttl_secs: u64,
+    /// Number of seconds between two looks for idle sessions.
+    pub sweep_interval_secs: u64,
+}
+
+impl SessionSettings {
+    /// How long an instance owns a session after last checking it. Outlasts the
+    /// longest wait between two checks, so the owner renews it before another
+    /// instance can take over.
+    pub fn lease_ttl(&self) -> Duration {
+        Duration::from_secs(self.playback_check_secs * 3)
+    }
+}
+
+#[derive(serde:: Deserialize, Clone)]
+pub struct TrackCacheSettings {
+    /// Number of seconds track metadata is trusted before it is fetched again.
+    pub ttl_secs: u64,
+    /// Maximum number of tracks kept in memory.
+    pub capacity: usize,
+    /// Whether to also keep track metadata in the database, where it outlives restarts
+    /// and is shared between instances.
+    pub persist: bool,
+}
+
+#[allow(dead_code)]
 enum Environment {
     Local,
     Production,
 }
 
+#[allow(dead_code)]
 impl Environment {
     pub fn as_str(&self) -> &'static str {
         match self {
@@ -1,23 +1,56 @@
-use crate::controller::messages::Response;
+use crate::authorization::{is_authorized, Operation};
+use crate::cluster::{Broadcast, Cluster};
 use crate::controller::messages::{
-    Connect, Devices, DevicesComplete, DevicesPayload, Disconnect, Kill, KillComplete, Queue,
-    Refresh, Search, SearchComplete, State, StateUpdate, Transfer, TransferComplete,
-    TransferResponsePayload, Vote, VotedTracks, VotedTracksComplete, VotedTracksPayload, WsMessage,
+    Browse, BrowseComplete, ClientRequest, Connect, Devices, DevicesComplete, Disconnect,
+    Dispatched, History, HistoryComplete, Import, Kill, KillComplete, Library, LibraryComplete,
+    MoveTrack, Pause, PinTrack, ProgressUpdate, Queue, QueueCollection, Refresh, RemoteBroadcast,
+    RemoveTrack, RequestFailed, Resume, SaveHistory, SaveHistoryComplete, Search, SearchComplete,
+    Seek, SetAutoplay, SetFallbackPlaylist, Skip, State, StateUpdate, Transfer, TransferComplete,
+    Unvote, Volume, Vote, VoteSkip, VotedTracks, VotedTracksComplete, Wakeup, WsMessage,
 };
-use crate::session_agent::SessionAgentRequest;
-use actix::prelude::{Actor, Context, Handler, Recipient};
-use actix::AsyncContext;
+use crate::protocol::{
+    DevicesPayload, ProgressPayload, Response, TransferResponsePayload, VotedTracksPayload,
+};
+use crate::session_agent::{turn_away, RequestBody, RequestHeader, SessionAgentRequest};
+use crate::session_state::Context as SessionContext;
+use actix::prelude::{Actor, Context, Handler, Message, Recipient};
+use actix::{AsyncContext, SpawnHandle};
 use std::collections::{HashMap, HashSet};
 use std::time::Duration;
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::oneshot;
 use uuid::Uuid;
 
 type Socket = Recipient<WsMessage>;
 
+struct Client {
+    socket: Socket,
+    context: SessionContext,
+}
+
+/// A caller outside the WebSocket waiting for the reply to one request.
+struct PendingReply {
+    reply: oneshot::Sender<Response>,
+}
+
+/// Replies are looked up by the connection a request came in on as well as its
+/// id, so a socket can't claim the reply to someone else's request.
+type ReplyKey = (Uuid, String);
+
 pub struct Controller {
-    clients: HashMap<Uuid, Socket>,
+    clients: HashMap<Uuid, Client>,
     sessions: HashMap<Uuid, HashSet<Uuid>>,
-    agent_tx: UnboundedSender<SessionAgentRequest>,
+    pending_replies: HashMap<ReplyKey, PendingReply>,
+    /// The context of the caller of the dispatched request being handled, which
+    /// is authorized by it instead of by a socket.
+    dispatcher: Option<SessionContext>,
+    agent_tx: mpsc::Sender<SessionAgentRequest>,
+    cluster: Cluster,
+    /// The next playback check of each session used through this instance.
+    wakeups: HashMap<Uuid, SpawnHandle>,
+    /// The next token refresh of each session used through this instance.
+    refreshes: HashMap<Uuid, SpawnHandle>,
 }
 
 // TODO: handle all unwraps
@@ -26,37 +59,211 @@ pub struct Controller {
 // TODO: validate session id
 
 impl Controller {
-    pub fn new(agent_tx: UnboundedSender<SessionAgentRequest>) -> Self {
+    pub fn new(agent_tx: mpsc::Sender<SessionAgentRequest>, cluster: Cluster) -> Self {
         Self {
             clients: HashMap::new(),
             sessions: HashMap::new(),
+            pending_replies: HashMap::new(),
+            dispatcher: None,
             agent_tx,
+            cluster,
+            wakeups: HashMap::new(),
+            refreshes: HashMap::new(),
+        }
+    }
+
+    /// Passes a request on to the agent, which hands it to the session's worker.
+    /// Requests the agent has no room for are turned away.
+    fn forward(&self, header: RequestHeader, body: RequestBody) {
+        let session_id = header.session_id;
+        match self.agent_tx.try_send(SessionAgentRequest { header, body }) {
+            Ok(()) => {}
+            Err(TrySendError::Full(request)) => {
+                log::warn!("Agent is full, dropping request for session {session_id}");
+                turn_away(request);
+            }
+            Err(TrySendError::Closed(_)) => {
+                log::error!("Failed to send request for session {session_id} to the agent");
+            }
+        }
+    }
+
+    /// Starts the session's token refreshes and playback checks, unless they run
+    /// already. They keep going whether clients are connected here or only call
+    /// the API, until the session ends or the worker finds it gone.
+    fn keep_alive(&mut self, session_id: Uuid, ctx: &mut Context<Self>) {
+        if !self.refreshes.contains_key(&session_id) {
+            self.schedule_refresh(session_id, Duration::from_secs(1), ctx);
+        }
+        if !self.wakeups.contains_key(&session_id) {
+            self.schedule_poll(session_id, Duration::ZERO, ctx);
+        }
+    }
+
+    /// Checks on the session's playback after `after`, replacing the check that
+    /// was scheduled before.
+    fn schedule_poll(&mut self, session_id: Uuid, after: Duration, ctx: &mut Context<Self>) {
+        if let Some(handle) = self.wakeups.remove(&session_id) {
+            ctx.cancel_future(handle);
+        }
+
+        let handle = ctx.run_later(after, move |actor, ctx| {
+            actor.wakeups.remove(&session_id);
+            // Keeps the count of clients here from expiring while it still holds
+            actor.publish_presence(session_id);
+            actor.forward(
+                RequestHeader::poll(session_id, ctx.address()),
+                RequestBody::PollState,
+            );
+        });
+        self.wakeups.insert(session_id, handle);
+    }
+
+    /// Refreshes the session's token after `after`, replacing the refresh that was
+    /// scheduled before.
+    fn schedule_refresh(&mut self, session_id: Uuid, after: Duration, ctx: &mut Context<Self>) {
+        if let Some(handle) = self.refreshes.remove(&session_id) {
+            ctx.cancel_future(handle);
+        }
+
+        let handle = ctx.run_later(after, move |actor, ctx| {
+            actor.refreshes.remove(&session_id);
+            actor.forward(
+                RequestHeader::token_refresh(session_id, ctx.address()),
+                RequestBody::Refresh,
+            );
+        });
+        self.refreshes.insert(session_id, handle);
+    }
+
+    /// Drops the clients of a session that ended and stops its timers.
+    fn forget_session(&mut self, session_id: &Uuid, ctx: &mut Context<Self>) {
+        self.sessions.remove(session_id);
+        self.publish_presence(*session_id);
+        if let Some(handle) = self.wakeups.remove(session_id) {
+            ctx.cancel_future(handle);
+        }
+        if let Some(handle) = self.refreshes.remove(session_id) {
+            ctx.cancel_future(handle);
+        }
+    }
+
+    /// Tells the session's clients here that it is over, and forgets it.
+    fn shut_down(&mut self, session_id: &Uuid, ctx: &mut Context<Self>) {
+        self.broadcast(Response::Shutdown, session_id);
+        self.forget_session(session_id, ctx);
+    }
+
+    /// Passes a broadcast on to the other instances, which deliver it to the
+    /// clients of the session connected to them.
+    fn publish(&self, broadcast: Broadcast) {
+        let cluster = self.cluster.clone();
+        actix::spawn(async move {
+            if let Err(err) = cluster.publish(broadcast).await {
+                log::error!("Failed to publish broadcast, {err}");
+            }
+        });
+    }
+
+    /// Lets the other instances know how many of the session's clients are
+    /// connected here, so votes are weighed against the whole room.
+    fn publish_presence(&self, session_id: Uuid) {
+        let clients = self
+            .sessions
+            .get(&session_id)
+            .map(|session| session.len())
+            .unwrap_or_default();
+        let cluster = self.cluster.clone();
+        actix::spawn(async move {
+            if let Err(err) = cluster.set_presence(session_id, clients).await {
+                log::error!("Failed to record the clients of {session_id}, {err}");
+            }
+        });
+    }
+
+    fn broadcast(&self, message: Response, session_id: &Uuid) {
+        if let Some(session) = self.sessions.get(session_id) {
+            session.iter().for_each(|client| {
+                self.send_message(message.clone(), client);
+            });
         }
     }
     fn send_message(&self, message: Response, id_to: &Uuid) {
-        if let Some(socket_recipient) = self.clients.get(id_to) {
-            let _ = socket_recipient.do_send(WsMessage(message));
+        if let Some(client) = self.clients.get(id_to) {
+            client.socket.do_send(WsMessage(message));
         } else {
             log::info!("attempting to send message but couldn't find user id.");
         }
     }
+
+    /// Forgets the callers that gave up waiting for their replies.
+    fn prune_pending_replies(&mut self) {
+        self.pending_replies
+            .retain(|_, pending| !pending.reply.is_closed());
+    }
+
+    /// Takes the caller waiting outside the socket for the reply to a request,
+    /// forgetting the callers that gave up waiting along the way.
+    fn take_pending_reply(
+        &mut self,
+        connection_id: Uuid,
+        request_id: Option<&str>,
+    ) -> Option<PendingReply> {
+        self.prune_pending_replies();
+        let request_id = request_id?;
+        self.pending_replies
+            .remove(&(connection_id, request_id.to_string()))
+    }
+
+    /// Sends a response to whoever is waiting for it, which is either a caller
+    /// of a `Dispatched` request or the client's socket.
+    fn reply(&mut self, message: Response, id_to: &Uuid) {
+        if let Some(pending) = self.take_pending_reply(*id_to, message.request_id()) {
+            let _ = pending.reply.send(message);
+            return;
+        }
+
+        self.send_message(message, id_to);
+    }
+
+    /// Checks the operation against the caller's context: the one a dispatched
+    /// request carries, or else the one of the socket it came in on.
+    fn authorize(
+        &mut self,
+        connection_id: &Uuid,
+        operation: Operation,
+        request_id: &Option<String>,
+    ) -> bool {
+        let context = self
+            .dispatcher
+            .or_else(|| self.clients.get(connection_id).map(|client| client.context));
+        let authorized = match context {
+            Some(context) => is_authorized(context, operation),
+            None => false,
+        };
+
+        if !authorized {
+            log::error!("Rejected {:?} from connection {}", operation, connection_id);
+            self.reply(
+                Response::forbidden(operation, request_id.clone()),
+                connection_id,
+            );
+        }
+
+        authorized
+    }
 }
 
 pub const REFRESH_TOKEN_INTERVAL: Duration = Duration::from_secs(3600);
+/// How often a session is checked while it can't be looked after properly,
+/// like when its playback can't be read or another instance owns it.
 pub const POLL_STATE_INTERVAL: Duration = Duration::from_secs(5);
 
 impl Actor for Controller {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        ctx.run_interval(POLL_STATE_INTERVAL, |actor, ctx| {
-            for (session_id, _) in actor.sessions.iter() {
-                let request = SessionAgentRequest::PollState((*session_id, ctx.address()));
-                if let Err(err) = actor.agent_tx.send(request) {
-                    log::error!("Failed to send SessionAgentRequest::PollState, {err}");
-                }
-            }
-        });
+        actix::spawn(self.cluster.clone().listen(ctx.address()));
     }
 }
 
@@ -69,14 +276,56 @@ impl Handler<Disconnect> for Controller {
                 if session.len() > 1 {
                     session.remove(&msg.connection_id);
                 } else {
-                    //only one in the lobby, remove it entirely
+                    // Only one in the lobby, remove it entirely. Its timers keep
+                    // going until the session ends, for callers of the API.
                     self.sessions.remove(&msg.session_id);
                 }
+                self.publish_presence(msg.session_id);
             }
         }
     }
 }
 
+impl Handler<Wakeup> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: Wakeup, ctx: &mut Context<Self>) -> Self::Result {
+        self.schedule_poll(msg.session_id, msg.after, ctx);
+    }
+}
+
+impl<M> ClientRequest for M
+where
+    M: Message<Result = ()> + Send + 'static,
+    Controller: Handler<M, Result = ()>,
+{
+    fn handle_in(self, controller: &mut Controller, ctx: &mut Context<Controller>) {
+        Handler::handle(controller, self, ctx);
+    }
+}
+
+impl<M> Handler<Dispatched<M>> for Controller
+where
+    M: ClientRequest,
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: Dispatched<M>, ctx: &mut Context<Self>) -> Self::Result {
+        // A session driven over HTTP alone still has to advance and stay signed in
+        self.keep_alive(msg.session_id, ctx);
+
+        self.prune_pending_replies();
+        self.pending_replies.insert(
+            (msg.connection_id, msg.request_id),
+            PendingReply { reply: msg.reply },
+        );
+
+        self.dispatcher = Some(msg.context);
+        msg.request.handle_in(self, ctx);
+        self.dispatcher = None;
+    }
+}
+
 impl Handler<Connect> for Controller {
     type Result = ();
 
@@ -84,16 +333,28 @@ impl Handler<Connect> for Controller {
         // create a room if necessary, and then add the id to it
         self.sessions
             .entry(msg.session_id)
-            .or_insert_with(HashSet::new)
+            .or_default()
             .insert(msg.connection_id);
 
         // store the address
-        self.clients.insert(msg.connection_id, msg.client_addr);
-
-        ctx.address().do_send(Refresh {
-            duration: Duration::from_secs(1), /*REFRESH_TOKEN_INTERVAL*/
-            session_id: msg.session_id,
-        });
+        self.clients.insert(
+            msg.connection_id,
+            Client {
+                socket: msg.client_addr,
+                context: msg.context,
+            },
+        );
+
+        self.publish_presence(msg.session_id);
+
+        // The first client here starts the session's token refreshes and playback checks
+        self.keep_alive(msg.session_id, ctx);
+
+        // Joining counts as using the session, even if the client never asks for anything
+        self.forward(
+            RequestHeader::client(msg.session_id, msg.connection_id, None, ctx.address()),
+            RequestBody::Connect,
+        );
     }
 }
 
@@ -101,10 +362,13 @@ impl Handler<Search> for Controller {
     type Result = ();
 
     fn handle(&mut self, msg: Search, ctx: &mut Context<Self>) -> Self::Result {
-        let request = SessionAgentRequest::Search((msg, ctx.address()));
-        if let Err(err) = self.agent_tx.send(request) {
-            log::error!("Failed to send SessionAgentRequest::Search, {err}");
-        }
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::Search(msg));
     }
 }
 
@@ -113,7 +377,30 @@ impl Handler<SearchComplete> for Controller {
 
     fn handle(&mut self, msg: SearchComplete, _ctx: &mut Context<Self>) -> Self::Result {
         let response = Response::SearchResult(msg.result);
-        self.send_message(response, &msg.connection_id);
+        self.reply(response, &msg.connection_id);
+    }
+}
+
+impl Handler<Browse> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: Browse, ctx: &mut Context<Self>) -> Self::Result {
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::Browse(msg));
+    }
+}
+
+impl Handler<BrowseComplete> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: BrowseComplete, _ctx: &mut Context<Self>) -> Self::Result {
+        let response = Response::TrackList(msg.result);
+        self.reply(response, &msg.connection_id);
     }
 }
 
@@ -121,10 +408,36 @@ impl Handler<Queue> for Controller {
     type Result = ();
 
     fn handle(&mut self, msg: Queue, ctx: &mut Context<Self>) -> Self::Result {
-        let request = SessionAgentRequest::Queue((msg, ctx.address()));
-        if let Err(err) = self.agent_tx.send(request) {
-            log::error!("Failed to send SessionAgentRequest::Queue, {err}");
-        }
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::Queue(msg));
+    }
+}
+
+impl Handler<QueueCollection> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: QueueCollection, ctx: &mut Context<Self>) -> Self::Result {
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::QueueCollection(msg));
+    }
+}
+
+impl Handler<RequestFailed> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: RequestFailed, _: &mut Context<Self>) -> Self::Result {
+        let response = Response::error(msg.code, msg.message, msg.request_id);
+        self.reply(response, &msg.connection_id);
     }
 }
 
@@ -132,11 +445,13 @@ impl Handler<State> for Controller {
     type Result = ();
 
     fn handle(&mut self, msg: State, ctx: &mut Context<Self>) -> Self::Result {
-        let request =
-            SessionAgentRequest::GetState((msg.session_id, Some(msg.connection_id), ctx.address()));
-        if let Err(err) = self.agent_tx.send(request) {
-            log::error!("Failed to send SessionAgentRequest::GetState, {err}");
-        }
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::GetState(msg));
     }
 }
 
@@ -144,10 +459,27 @@ impl Handler<Vote> for Controller {
     type Result = ();
 
     fn handle(&mut self, msg: Vote, ctx: &mut Context<Self>) -> Self::Result {
-        let request = SessionAgentRequest::Vote((msg, ctx.address()));
-        if let Err(err) = self.agent_tx.send(request) {
-            log::error!("Failed to send SessionAgentRequest::GetState, {err}");
-        }
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::Vote(msg));
+    }
+}
+
+impl Handler<Unvote> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: Unvote, ctx: &mut Context<Self>) -> Self::Result {
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::Unvote(msg));
     }
 }
 
@@ -155,19 +487,65 @@ impl Handler<StateUpdate> for Controller {
     type Result = ();
 
     fn handle(&mut self, msg: StateUpdate, _: &mut Context<Self>) -> Self::Result {
-        let response = Response::StateUpdate(msg.update);
-        self.sessions
-            .get(&msg.session_id)
-            .unwrap()
+        // A requester waiting outside the socket gets its own copy of the update
+        let mut reply_to = msg.reply_to;
+        let pending = reply_to.as_ref().and_then(|reply_to| {
+            self.take_pending_reply(reply_to.connection_id, reply_to.request_id.as_deref())
+        });
+        if let Some(pending) = pending {
+            let mut update = msg.update.clone();
+            update.request_id = reply_to.take().and_then(|reply_to| reply_to.request_id);
+            let _ = pending.reply.send(Response::StateUpdate(update));
+        }
+
+        if msg.connection_id.is_none() {
+            self.publish(Broadcast::StateUpdate {
+                session_id: msg.session_id,
+                update: Box::new(msg.update.clone()),
+            });
+        }
+
+        let session = match self.sessions.get(&msg.session_id) {
+            Some(session) => session,
+            None => return,
+        };
+
+        session
             .iter()
             .filter(|connection_id| {
                 if let Some(id) = msg.connection_id {
                     return id == *connection_id.to_owned();
                 }
 
-                return true;
+                true
             })
-            .for_each(|client| self.send_message(response.clone(), client)); // TODO: clone needed?
+            .for_each(|client| {
+                let mut update = msg.update.clone();
+                update.request_id = match &reply_to {
+                    Some(reply_to) if reply_to.connection_id == *client => {
+                        reply_to.request_id.clone()
+                    }
+                    _ => None,
+                };
+                self.send_message(Response::StateUpdate(update), client)
+            });
+    }
+}
+
+impl Handler<ProgressUpdate> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: ProgressUpdate, _ctx: &mut Context<Self>) -> Self::Result {
+        self.publish(Broadcast::Progress {
+            session_id: msg.session_id,
+            progress: msg.progress.clone(),
+        });
+        self.broadcast(
+            Response::Progress(ProgressPayload {
+                payload: msg.progress,
+            }),
+            &msg.session_id,
+        );
     }
 }
 
@@ -175,12 +553,7 @@ impl Handler<Refresh> for Controller {
     type Result = ();
 
     fn handle(&mut self, msg: Refresh, ctx: &mut Context<Self>) -> Self::Result {
-        ctx.run_later(msg.duration, move |actor, ctx| {
-            let request = SessionAgentRequest::Refresh((msg.session_id, ctx.address()));
-            if let Err(err) = actor.agent_tx.send(request) {
-                log::error!("Failed to send SessionAgentRequest::Refresh, {err}");
-            }
-        });
+        self.schedule_refresh(msg.session_id, msg.duration, ctx);
     }
 }
 
@@ -188,22 +561,54 @@ impl Handler<Kill> for Controller {
     type Result = ();
 
     fn handle(&mut self, msg: Kill, ctx: &mut Context<Self>) -> Self::Result {
-        let request = SessionAgentRequest::Kill((msg.session_id, ctx.address()));
-        if let Err(err) = self.agent_tx.send(request) {
-            log::error!("Failed to send SessionAgentRequest::Kill, {err}");
+        if !self.authorize(&msg.connection_id, Operation::Kill, &msg.request_id) {
+            return;
         }
+
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::Kill(msg));
     }
 }
 
 impl Handler<KillComplete> for Controller {
     type Result = ();
 
-    fn handle(&mut self, msg: KillComplete, _ctx: &mut Context<Self>) -> Self::Result {
-        if let Some(session) = self.sessions.get(&msg.session_id) {
-            let shutdown = Response::Shutdown;
-            session.iter().for_each(|client| {
-                self.send_message(shutdown.clone(), client);
-            });
+    fn handle(&mut self, msg: KillComplete, ctx: &mut Context<Self>) -> Self::Result {
+        let pending = msg.reply_to.and_then(|reply_to| {
+            self.take_pending_reply(reply_to.connection_id, reply_to.request_id.as_deref())
+        });
+        if let Some(pending) = pending {
+            let _ = pending.reply.send(Response::Shutdown);
+        }
+
+        self.publish(Broadcast::Shutdown {
+            session_id: msg.session_id,
+        });
+        self.shut_down(&msg.session_id, ctx);
+    }
+}
+
+impl Handler<RemoteBroadcast> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: RemoteBroadcast, ctx: &mut Context<Self>) -> Self::Result {
+        match msg.0 {
+            Broadcast::StateUpdate { session_id, update } => {
+                self.broadcast(Response::StateUpdate(*update), &session_id)
+            }
+            Broadcast::Progress {
+                session_id,
+                progress,
+            } => self.broadcast(
+                Response::Progress(ProgressPayload { payload: progress }),
+                &session_id,
+            ),
+            Broadcast::Shutdown { session_id } => self.shut_down(&session_id, ctx),
         }
     }
 }
@@ -212,10 +617,13 @@ impl Handler<Devices> for Controller {
     type Result = ();
 
     fn handle(&mut self, msg: Devices, ctx: &mut Context<Self>) -> Self::Result {
-        let request = SessionAgentRequest::Devices((msg, ctx.address()));
-        if let Err(err) = self.agent_tx.send(request) {
-            log::error!("Failed to send SessionAgentRequest::Devices, {err}");
-        }
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::Devices(msg));
     }
 }
 
@@ -225,8 +633,9 @@ impl Handler<DevicesComplete> for Controller {
     fn handle(&mut self, msg: DevicesComplete, _ctx: &mut Context<Self>) -> Self::Result {
         let response = Response::Devices(DevicesPayload {
             payload: msg.devices,
+            request_id: msg.request_id,
         });
-        self.send_message(response, &msg.connection_id)
+        self.reply(response, &msg.connection_id)
     }
 }
 
@@ -234,10 +643,17 @@ impl Handler<Transfer> for Controller {
     type Result = ();
 
     fn handle(&mut self, msg: Transfer, ctx: &mut Context<Self>) -> Self::Result {
-        let request = SessionAgentRequest::Transfer((msg, ctx.address()));
-        if let Err(err) = self.agent_tx.send(request) {
-            log::error!("Failed to send SessionAgentRequest::Transfer, {err}");
+        if !self.authorize(&msg.connection_id, Operation::Transfer, &msg.request_id) {
+            return;
         }
+
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::Transfer(msg));
     }
 }
 
@@ -246,9 +662,10 @@ impl Handler<TransferComplete> for Controller {
 
     fn handle(&mut self, msg: TransferComplete, _ctx: &mut Context<Self>) -> Self::Result {
         let response = Response::Transfer(TransferResponsePayload {
-            payload: msg.result,
+            payload: msg.device_id,
+            request_id: msg.request_id,
         });
-        self.send_message(response, &msg.connection_id)
+        self.reply(response, &msg.connection_id)
     }
 }
 
@@ -256,10 +673,13 @@ impl Handler<VotedTracks> for Controller {
     type Result = ();
 
     fn handle(&mut self, msg: VotedTracks, ctx: &mut Context<Self>) -> Self::Result {
-        let request = SessionAgentRequest::VotedTracks((msg, ctx.address()));
-        if let Err(err) = self.agent_tx.send(request) {
-            log::error!("Failed to send SessionAgentRequest::VotedTracks, {err}");
-        }
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::VotedTracks(msg));
     }
 }
 
@@ -269,7 +689,305 @@ impl Handler<VotedTracksComplete> for Controller {
     fn handle(&mut self, msg: VotedTracksComplete, _ctx: &mut Context<Self>) -> Self::Result {
         let response = Response::VotedTracks(VotedTracksPayload {
             payload: msg.tracks,
+            request_id: msg.request_id,
         });
-        self.send_message(response, &msg.connection_id)
+        self.reply(response, &msg.connection_id)
+    }
+}
+
+impl Handler<RemoveTrack> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: RemoveTrack, ctx: &mut Context<Self>) -> Self::Result {
+        if !self.authorize(&msg.connection_id, Operation::RemoveTrack, &msg.request_id) {
+            return;
+        }
+
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::RemoveTrack(msg));
+    }
+}
+
+impl Handler<PinTrack> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: PinTrack, ctx: &mut Context<Self>) -> Self::Result {
+        if !self.authorize(&msg.connection_id, Operation::PinTrack, &msg.request_id) {
+            return;
+        }
+
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::PinTrack(msg));
+    }
+}
+
+impl Handler<MoveTrack> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: MoveTrack, ctx: &mut Context<Self>) -> Self::Result {
+        if !self.authorize(&msg.connection_id, Operation::MoveTrack, &msg.request_id) {
+            return;
+        }
+
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::MoveTrack(msg));
+    }
+}
+
+impl Handler<VoteSkip> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: VoteSkip, ctx: &mut Context<Self>) -> Self::Result {
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::VoteSkip(msg));
+    }
+}
+
+impl Handler<Skip> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: Skip, ctx: &mut Context<Self>) -> Self::Result {
+        if !self.authorize(&msg.connection_id, Operation::Skip, &msg.request_id) {
+            return;
+        }
+
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::Skip(msg));
+    }
+}
+
+impl Handler<Pause> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: Pause, ctx: &mut Context<Self>) -> Self::Result {
+        if !self.authorize(&msg.connection_id, Operation::Pause, &msg.request_id) {
+            return;
+        }
+
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::Pause(msg));
+    }
+}
+
+impl Handler<Resume> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: Resume, ctx: &mut Context<Self>) -> Self::Result {
+        if !self.authorize(&msg.connection_id, Operation::Resume, &msg.request_id) {
+            return;
+        }
+
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::Resume(msg));
+    }
+}
+
+impl Handler<Seek> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: Seek, ctx: &mut Context<Self>) -> Self::Result {
+        if !self.authorize(&msg.connection_id, Operation::Seek, &msg.request_id) {
+            return;
+        }
+
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::Seek(msg));
+    }
+}
+
+impl Handler<Volume> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: Volume, ctx: &mut Context<Self>) -> Self::Result {
+        if !self.authorize(&msg.connection_id, Operation::Volume, &msg.request_id) {
+            return;
+        }
+
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::Volume(msg));
+    }
+}
+
+impl Handler<Library> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: Library, ctx: &mut Context<Self>) -> Self::Result {
+        if !self.authorize(&msg.connection_id, Operation::Library, &msg.request_id) {
+            return;
+        }
+
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::Library(msg));
+    }
+}
+
+impl Handler<LibraryComplete> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: LibraryComplete, _ctx: &mut Context<Self>) -> Self::Result {
+        let response = Response::LibraryResult(msg.result);
+        self.reply(response, &msg.connection_id);
+    }
+}
+
+impl Handler<Import> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: Import, ctx: &mut Context<Self>) -> Self::Result {
+        if !self.authorize(&msg.connection_id, Operation::Import, &msg.request_id) {
+            return;
+        }
+
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::Import(msg));
+    }
+}
+
+impl Handler<SetFallbackPlaylist> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetFallbackPlaylist, ctx: &mut Context<Self>) -> Self::Result {
+        if !self.authorize(
+            &msg.connection_id,
+            Operation::SetFallbackPlaylist,
+            &msg.request_id,
+        ) {
+            return;
+        }
+
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::SetFallbackPlaylist(msg));
+    }
+}
+
+impl Handler<SetAutoplay> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetAutoplay, ctx: &mut Context<Self>) -> Self::Result {
+        if !self.authorize(&msg.connection_id, Operation::SetAutoplay, &msg.request_id) {
+            return;
+        }
+
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::SetAutoplay(msg));
+    }
+}
+
+impl Handler<History> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: History, ctx: &mut Context<Self>) -> Self::Result {
+        if !self.authorize(&msg.connection_id, Operation::History, &msg.request_id) {
+            return;
+        }
+
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::History(msg));
+    }
+}
+
+impl Handler<HistoryComplete> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: HistoryComplete, _ctx: &mut Context<Self>) -> Self::Result {
+        let response = Response::History(msg.result);
+        self.reply(response, &msg.connection_id);
+    }
+}
+
+impl Handler<SaveHistory> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: SaveHistory, ctx: &mut Context<Self>) -> Self::Result {
+        if !self.authorize(&msg.connection_id, Operation::SaveHistory, &msg.request_id) {
+            return;
+        }
+
+        let header = RequestHeader::client(
+            msg.session_id,
+            msg.connection_id,
+            msg.request_id.clone(),
+            ctx.address(),
+        );
+        self.forward(header, RequestBody::SaveHistory(msg));
+    }
+}
+
+impl Handler<SaveHistoryComplete> for Controller {
+    type Result = ();
+
+    fn handle(&mut self, msg: SaveHistoryComplete, _ctx: &mut Context<Self>) -> Self::Result {
+        let response = Response::SavedPlaylist(msg.result);
+        self.reply(response, &msg.connection_id);
     }
 }
@@ -1,28 +1,30 @@
-use crate::session_agent::{self, SearchResult};
+use crate::autoplay::AutoplayMode;
+use crate::catalog::{LibrarySource, PageRequest, SearchKind};
+use crate::cluster::Broadcast;
+use crate::controller::Controller;
+use crate::item::{CollectionId, ItemId};
+use crate::progress::Progress;
+use crate::protocol::{
+    ErrorCode, HistoryResultPayload, LibraryResultPayload, Response, SavedPlaylistPayload,
+    SearchResultPayload, StateUpdatePayload, TrackListPayload,
+};
+use crate::session_state::Context;
 use actix::prelude::{Message, Recipient};
 use rspotify::model::device::Device;
 use rspotify::model::enums::types::DeviceType;
-use rspotify::model::TrackId;
+use rspotify::model::PlaylistId;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::time::Duration;
+use tokio::sync::oneshot;
 use uuid::Uuid;
 
-// TODO: make struct generic
-#[derive(Serialize, Deserialize, Clone)]
-pub struct SearchResultPayload {
-    pub payload: SearchResult,
-}
-
-#[derive(Serialize, Deserialize, Clone)]
-pub struct StateUpdatePayload {
-    pub payload: session_agent::State,
-}
-
-#[derive(Deserialize, Serialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug, PartialEq)]
 pub struct DeviceInfo {
-    id: String,
-    name: String,
-    dev_type: String,
+    pub id: String,
+    pub name: String,
+    pub dev_type: String,
+    pub volume_percent: Option<u32>,
 }
 
 impl TryFrom<Device> for DeviceInfo {
@@ -38,6 +40,7 @@ impl TryFrom<Device> for DeviceInfo {
             id,
             name: device.name,
             dev_type: device_type_to_string(device._type),
+            volume_percent: device.volume_percent,
         })
     }
 }
@@ -55,43 +58,39 @@ fn device_type_to_string(dev_type: DeviceType) -> String {
     result.to_string()
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct DevicesPayload {
-    pub payload: Vec<DeviceInfo>,
-}
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct WsMessage(pub Response);
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct TransferResponsePayload {
-    pub payload: String,
+/// A request made outside the WebSocket, such as over HTTP. It carries the
+/// caller's context, as the caller may have no socket here, and the channel
+/// the reply to it goes to.
+pub struct Dispatched<M> {
+    pub session_id: Uuid,
+    pub connection_id: Uuid,
+    pub request_id: String,
+    pub context: Context,
+    pub reply: oneshot::Sender<Response>,
+    pub request: M,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct VotedTracksPayload {
-    pub payload: Vec<String>,
+impl<M: ClientRequest> Message for Dispatched<M> {
+    type Result = ();
 }
 
-// TODO: change name. not everything is a response
-#[derive(Serialize, Deserialize, Clone)]
-#[serde(tag = "type")]
-pub enum Response {
-    SearchResult(SearchResultPayload),
-    Shutdown,
-    StateUpdate(StateUpdatePayload),
-    Devices(DevicesPayload),
-    Transfer(TransferResponsePayload),
-    VotedTracks(VotedTracksPayload),
+/// A client request that can be dispatched from outside the WebSocket.
+pub trait ClientRequest: Message<Result = ()> + Send + 'static {
+    /// Handles the request as if it came in over the socket.
+    fn handle_in(self, controller: &mut Controller, ctx: &mut actix::Context<Controller>);
 }
 
-#[derive(Message)]
-#[rtype(result = "()")]
-pub struct WsMessage(pub Response);
-
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Connect {
     pub client_addr: Recipient<WsMessage>,
     pub session_id: Uuid,
     pub connection_id: Uuid,
+    pub context: Context,
 }
 
 #[derive(Message)]
@@ -105,8 +104,12 @@ pub struct Disconnect {
 #[rtype(result = "()")]
 pub struct Search {
     pub query: String,
+    /// Empty to search for tracks, episodes and shows.
+    pub types: Vec<SearchKind>,
+    pub page: PageRequest,
     pub session_id: Uuid,
     pub connection_id: Uuid,
+    pub request_id: Option<String>,
 }
 
 #[derive(Message)]
@@ -116,11 +119,50 @@ pub struct SearchComplete {
     pub connection_id: Uuid,
 }
 
+/// Lists the tracks of an album or a playlist.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Browse {
+    pub collection: CollectionId,
+    pub page: PageRequest,
+    pub session_id: Uuid,
+    pub connection_id: Uuid,
+    pub request_id: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct BrowseComplete {
+    pub result: TrackListPayload,
+    pub connection_id: Uuid,
+}
+
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Queue {
-    pub track_id: TrackId,
+    pub track_id: ItemId,
     pub session_id: Uuid,
+    pub connection_id: Uuid,
+    pub request_id: Option<String>,
+}
+
+/// Queues the tracks of an album or a playlist, as if the client had queued each.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct QueueCollection {
+    pub collection: CollectionId,
+    pub session_id: Uuid,
+    pub connection_id: Uuid,
+    pub request_id: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RequestFailed {
+    pub connection_id: Uuid,
+    pub code: ErrorCode,
+    pub message: String,
+    pub request_id: Option<String>,
 }
 
 #[derive(Message)]
@@ -128,14 +170,41 @@ pub struct Queue {
 pub struct State {
     pub session_id: Uuid,
     pub connection_id: Uuid,
+    pub request_id: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum VoteKind {
+    Up,
+    Down,
+}
+
+impl VoteKind {
+    pub fn score(&self) -> i16 {
+        match self {
+            VoteKind::Up => 1,
+            VoteKind::Down => -1,
+        }
+    }
 }
 
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Vote {
-    pub track_id: TrackId,
+    pub track_id: ItemId,
     pub session_id: Uuid,
     pub connection_id: Uuid,
+    pub kind: VoteKind,
+    pub request_id: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Unvote {
+    pub track_id: ItemId,
+    pub session_id: Uuid,
+    pub connection_id: Uuid,
+    pub request_id: Option<String>,
 }
 
 #[derive(Message)]
@@ -144,8 +213,38 @@ pub struct StateUpdate {
     pub update: StateUpdatePayload,
     pub session_id: Uuid,
     pub connection_id: Option<Uuid>,
+    pub reply_to: Option<ReplyTo>,
+}
+
+/// The client request a broadcast state update answers. Only that client gets
+/// its request id echoed back.
+pub struct ReplyTo {
+    pub connection_id: Uuid,
+    pub request_id: Option<String>,
+}
+
+impl StateUpdate {
+    pub fn in_reply_to(mut self, connection_id: Uuid, request_id: Option<String>) -> Self {
+        self.reply_to = Some(ReplyTo {
+            connection_id,
+            request_id,
+        });
+        self
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ProgressUpdate {
+    pub session_id: Uuid,
+    pub progress: Progress,
 }
 
+/// A broadcast published by another instance for the clients connected here.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RemoteBroadcast(pub Broadcast);
+
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Refresh {
@@ -153,16 +252,27 @@ pub struct Refresh {
     pub session_id: Uuid,
 }
 
+/// Asks for the session's playback to be checked again after `after`.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Wakeup {
+    pub session_id: Uuid,
+    pub after: Duration,
+}
+
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Kill {
     pub session_id: Uuid,
+    pub connection_id: Uuid,
+    pub request_id: Option<String>,
 }
 
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct KillComplete {
     pub session_id: Uuid,
+    pub reply_to: Option<ReplyTo>,
 }
 
 #[derive(Message)]
@@ -170,6 +280,7 @@ pub struct KillComplete {
 pub struct Devices {
     pub session_id: Uuid,
     pub connection_id: Uuid,
+    pub request_id: Option<String>,
 }
 
 #[derive(Message)]
@@ -177,6 +288,7 @@ pub struct Devices {
 pub struct DevicesComplete {
     pub connection_id: Uuid,
     pub devices: Vec<DeviceInfo>,
+    pub request_id: Option<String>,
 }
 
 #[derive(Message)]
@@ -185,13 +297,15 @@ pub struct Transfer {
     pub session_id: Uuid,
     pub connection_id: Uuid,
     pub device_id: String,
+    pub request_id: Option<String>,
 }
 
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct TransferComplete {
     pub connection_id: Uuid,
-    pub result: String,
+    pub device_id: String,
+    pub request_id: Option<String>,
 }
 
 #[derive(Clone, Message)]
@@ -199,6 +313,7 @@ pub struct TransferComplete {
 pub struct VotedTracks {
     pub session_id: Uuid,
     pub connection_id: Uuid,
+    pub request_id: Option<String>,
 }
 
 #[derive(Message)]
@@ -206,4 +321,163 @@ pub struct VotedTracks {
 pub struct VotedTracksComplete {
     pub connection_id: Uuid,
     pub tracks: Vec<String>,
+    pub request_id: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RemoveTrack {
+    pub track_id: ItemId,
+    pub session_id: Uuid,
+    pub connection_id: Uuid,
+    pub request_id: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PinTrack {
+    pub track_id: ItemId,
+    pub session_id: Uuid,
+    pub connection_id: Uuid,
+    pub request_id: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct MoveTrack {
+    pub track_id: ItemId,
+    pub session_id: Uuid,
+    pub connection_id: Uuid,
+    pub position: usize,
+    pub request_id: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct VoteSkip {
+    pub session_id: Uuid,
+    pub connection_id: Uuid,
+    pub request_id: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Skip {
+    pub session_id: Uuid,
+    pub connection_id: Uuid,
+    pub request_id: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Pause {
+    pub session_id: Uuid,
+    pub connection_id: Uuid,
+    pub request_id: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Resume {
+    pub session_id: Uuid,
+    pub connection_id: Uuid,
+    pub request_id: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Seek {
+    pub session_id: Uuid,
+    pub connection_id: Uuid,
+    pub position: Duration,
+    pub request_id: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Volume {
+    pub session_id: Uuid,
+    pub connection_id: Uuid,
+    pub volume_percent: u8,
+    pub request_id: Option<String>,
+}
+
+/// Lists a page of the host's playlists, saved tracks or top tracks.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Library {
+    pub source: LibrarySource,
+    pub page: PageRequest,
+    pub session_id: Uuid,
+    pub connection_id: Uuid,
+    pub request_id: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct LibraryComplete {
+    pub result: LibraryResultPayload,
+    pub connection_id: Uuid,
+}
+
+/// Queues the tracks the host picked, in the order given.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Import {
+    pub track_ids: Vec<ItemId>,
+    pub session_id: Uuid,
+    pub connection_id: Uuid,
+    pub request_id: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetFallbackPlaylist {
+    pub playlist: Option<PlaylistId>,
+    pub session_id: Uuid,
+    pub connection_id: Uuid,
+    pub request_id: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetAutoplay {
+    pub mode: AutoplayMode,
+    pub session_id: Uuid,
+    pub connection_id: Uuid,
+    pub request_id: Option<String>,
+}
+
+/// Lists what the session played, a page at a time or all of it at once.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct History {
+    pub page: Option<PageRequest>,
+    pub session_id: Uuid,
+    pub connection_id: Uuid,
+    pub request_id: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct HistoryComplete {
+    pub result: HistoryResultPayload,
+    pub connection_id: Uuid,
+}
+
+/// Saves what the session played to a new playlist of the host's.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SaveHistory {
+    pub name: Option<String>,
+    pub session_id: Uuid,
+    pub connection_id: Uuid,
+    pub request_id: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SaveHistoryComplete {
+    pub result: SavedPlaylistPayload,
+    pub connection_id: Uuid,
 }
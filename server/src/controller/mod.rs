@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 pub mod controller;
 pub mod messages;
 pub mod ws_connection;
@@ -1,16 +1,25 @@
+use crate::authorization::is_authorized;
+use crate::catalog::PageRequest;
 use crate::controller::controller::Controller;
 use crate::controller::messages::{
-    Connect, Devices, Disconnect, Kill, Queue, Search, State, Transfer, Vote, VotedTracks,
-    WsMessage,
+    Browse, Connect, Devices, Disconnect, History, Import, Kill, Library, MoveTrack, Pause,
+    PinTrack, Queue, QueueCollection, RemoveTrack, Resume, SaveHistory, Search, Seek, SetAutoplay,
+    SetFallbackPlaylist, Skip, State, Transfer, Unvote, Volume, Vote, VoteKind, VoteSkip,
+    VotedTracks, WsMessage,
 };
+use crate::item::{CollectionId, ItemId};
+use crate::protocol::{
+    negotiate_version, Envelope, ErrorCode, Request, Response, Welcome, WelcomePayload,
+    SUPPORTED_PROTOCOL_VERSIONS,
+};
+use crate::session_state::Context;
 use actix::ActorFutureExt;
 use actix::{fut, ActorContext};
 use actix::{Actor, Addr, ContextFutureSpawner, Running, StreamHandler, WrapFuture};
 use actix::{AsyncContext, Handler};
 use actix_web_actors::ws;
 use actix_web_actors::ws::Message::Text;
-use rspotify::model::TrackId;
-use serde::{Deserialize, Serialize};
+use rspotify::model::PlaylistId;
 use serde_json;
 use std::str::FromStr;
 use std::time::{Duration, Instant};
@@ -24,15 +33,31 @@ pub struct WsConnection {
     controller_addr: Addr<Controller>,
     last_heartbeat_timestamp: Instant,
     connection_id: Uuid, //TODO: change to client_id?
+    context: Context,
+    /// Protocol version agreed on in the `Hello`/`Welcome` exchange.
+    version: Option<u32>,
 }
 
 impl WsConnection {
-    pub fn new(session_id: Uuid, client_id: Uuid, controller_addr: Addr<Controller>) -> Self {
+    pub fn new(
+        session_id: Uuid,
+        client_id: Uuid,
+        context: Context,
+        controller_addr: Addr<Controller>,
+    ) -> Self {
         Self {
             session_id,
             controller_addr,
             last_heartbeat_timestamp: Instant::now(),
             connection_id: client_id,
+            context,
+            version: None,
+        }
+    }
+
+    fn send_response(&self, response: &Response, ctx: &mut ws::WebsocketContext<Self>) {
+        if let Ok(data) = serde_json::to_string(response) {
+            ctx.text(data);
         }
     }
 
@@ -65,6 +90,7 @@ impl Actor for WsConnection {
                 client_addr: addr.recipient(),
                 session_id: self.session_id,
                 connection_id: self.connection_id,
+                context: self.context,
             })
             .into_actor(self)
             .then(|res, _, ctx| {
@@ -86,37 +112,269 @@ impl Actor for WsConnection {
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct SearchPayload {
-    query: String,
+fn parse_track_id(uri: &str, request_id: &Option<String>) -> Result<ItemId, Box<Response>> {
+    ItemId::from_str(uri).map_err(|_| {
+        Box::new(Response::error(
+            ErrorCode::InvalidTrackUri,
+            format!("{uri} is not a valid track or episode uri"),
+            request_id.clone(),
+        ))
+    })
 }
 
-#[derive(Serialize, Deserialize)]
-struct QueuePayload {
-    uri: String,
+fn parse_collection_id(
+    uri: &str,
+    request_id: &Option<String>,
+) -> Result<CollectionId, Box<Response>> {
+    CollectionId::from_str(uri).map_err(|_| {
+        Box::new(Response::error(
+            ErrorCode::InvalidTrackUri,
+            format!("{uri} is not a valid album or playlist uri"),
+            request_id.clone(),
+        ))
+    })
 }
 
-#[derive(Serialize, Deserialize)]
-struct VotePayload {
-    uri: String,
+fn parse_playlist_id(uri: &str, request_id: &Option<String>) -> Result<PlaylistId, Box<Response>> {
+    PlaylistId::from_str(uri).map_err(|_| {
+        Box::new(Response::error(
+            ErrorCode::InvalidTrackUri,
+            format!("{uri} is not a valid playlist uri"),
+            request_id.clone(),
+        ))
+    })
 }
 
-#[derive(Serialize, Deserialize)]
-struct TransferPayload {
-    device_id: String,
+fn parse_page(
+    limit: Option<u32>,
+    offset: Option<u32>,
+    request_id: &Option<String>,
+) -> Result<PageRequest, Box<Response>> {
+    PageRequest::new(limit, offset).map_err(|message| {
+        Box::new(Response::error(
+            ErrorCode::OutOfRange,
+            message,
+            request_id.clone(),
+        ))
+    })
 }
 
-#[derive(Serialize, Deserialize)]
-#[serde(tag = "type")]
-enum Request {
-    Search(SearchPayload),
-    Queue(QueuePayload),
-    State,
-    Vote(VotePayload),
-    Kill,
-    Devices,
-    Transfer(TransferPayload),
-    VotedTracks,
+impl WsConnection {
+    /// Answers the handshake itself and hands everything else over to the
+    /// controller, or returns the error to reply with if it cannot.
+    fn handle_request(
+        &mut self,
+        request: Request,
+        request_id: Option<String>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) -> Result<(), Box<Response>> {
+        if self.version.is_none() && !matches!(request, Request::Hello(_)) {
+            return Err(Box::new(Response::error(
+                ErrorCode::HandshakeRequired,
+                "Send Hello before any other request",
+                request_id,
+            )));
+        }
+
+        let session_id = self.session_id;
+        let connection_id = self.connection_id;
+        match request {
+            Request::Hello(hello) => {
+                let version = negotiate_version(&hello.versions).ok_or_else(|| {
+                    Box::new(Response::error(
+                        ErrorCode::UnsupportedVersion,
+                        format!(
+                            "Supported protocol versions are {:?}",
+                            SUPPORTED_PROTOCOL_VERSIONS
+                        ),
+                        request_id.clone(),
+                    ))
+                })?;
+                self.version = Some(version);
+                let welcome = Response::Welcome(WelcomePayload {
+                    payload: Welcome {
+                        version,
+                        client_id: connection_id,
+                    },
+                    request_id,
+                });
+                self.send_response(&welcome, ctx);
+            }
+            Request::Search(s) => self.controller_addr.do_send(Search {
+                page: parse_page(s.limit, s.offset, &request_id)?,
+                query: s.query,
+                types: s.types,
+                session_id,
+                connection_id,
+                request_id,
+            }),
+            Request::Browse(b) => self.controller_addr.do_send(Browse {
+                collection: parse_collection_id(&b.uri, &request_id)?,
+                page: parse_page(b.limit, b.offset, &request_id)?,
+                session_id,
+                connection_id,
+                request_id,
+            }),
+            Request::Queue(q) => match CollectionId::from_str(&q.uri) {
+                Ok(collection) => self.controller_addr.do_send(QueueCollection {
+                    collection,
+                    session_id,
+                    connection_id,
+                    request_id,
+                }),
+                Err(_) => self.controller_addr.do_send(Queue {
+                    track_id: parse_track_id(&q.uri, &request_id)?,
+                    session_id,
+                    connection_id,
+                    request_id,
+                }),
+            },
+            Request::State => self.controller_addr.do_send(State {
+                session_id,
+                connection_id,
+                request_id,
+            }),
+            Request::Vote(v) => self.controller_addr.do_send(Vote {
+                track_id: parse_track_id(&v.uri, &request_id)?,
+                session_id,
+                connection_id,
+                kind: VoteKind::Up,
+                request_id,
+            }),
+            Request::Downvote(v) => self.controller_addr.do_send(Vote {
+                track_id: parse_track_id(&v.uri, &request_id)?,
+                session_id,
+                connection_id,
+                kind: VoteKind::Down,
+                request_id,
+            }),
+            Request::Unvote(v) => self.controller_addr.do_send(Unvote {
+                track_id: parse_track_id(&v.uri, &request_id)?,
+                session_id,
+                connection_id,
+                request_id,
+            }),
+            Request::Kill => self.controller_addr.do_send(Kill {
+                session_id,
+                connection_id,
+                request_id,
+            }),
+            Request::Devices => self.controller_addr.do_send(Devices {
+                session_id,
+                connection_id,
+                request_id,
+            }),
+            Request::Transfer(t) => self.controller_addr.do_send(Transfer {
+                session_id,
+                connection_id,
+                device_id: t.device_id,
+                request_id,
+            }),
+            Request::VotedTracks => self.controller_addr.do_send(VotedTracks {
+                session_id,
+                connection_id,
+                request_id,
+            }),
+            Request::RemoveTrack(t) => self.controller_addr.do_send(RemoveTrack {
+                track_id: parse_track_id(&t.uri, &request_id)?,
+                session_id,
+                connection_id,
+                request_id,
+            }),
+            Request::PinTrack(t) => self.controller_addr.do_send(PinTrack {
+                track_id: parse_track_id(&t.uri, &request_id)?,
+                session_id,
+                connection_id,
+                request_id,
+            }),
+            Request::MoveTrack(m) => self.controller_addr.do_send(MoveTrack {
+                track_id: parse_track_id(&m.uri, &request_id)?,
+                session_id,
+                connection_id,
+                position: m.position,
+                request_id,
+            }),
+            Request::VoteSkip => self.controller_addr.do_send(VoteSkip {
+                session_id,
+                connection_id,
+                request_id,
+            }),
+            Request::Skip => self.controller_addr.do_send(Skip {
+                session_id,
+                connection_id,
+                request_id,
+            }),
+            Request::Pause => self.controller_addr.do_send(Pause {
+                session_id,
+                connection_id,
+                request_id,
+            }),
+            Request::Resume => self.controller_addr.do_send(Resume {
+                session_id,
+                connection_id,
+                request_id,
+            }),
+            Request::Seek(s) => self.controller_addr.do_send(Seek {
+                session_id,
+                connection_id,
+                position: Duration::from_millis(s.position_ms),
+                request_id,
+            }),
+            Request::Volume(v) => self.controller_addr.do_send(Volume {
+                session_id,
+                connection_id,
+                volume_percent: v.volume_percent,
+                request_id,
+            }),
+            Request::Library(l) => self.controller_addr.do_send(Library {
+                source: l.source,
+                page: parse_page(l.limit, l.offset, &request_id)?,
+                session_id,
+                connection_id,
+                request_id,
+            }),
+            Request::Import(i) => self.controller_addr.do_send(Import {
+                track_ids: i
+                    .uris
+                    .iter()
+                    .map(|uri| parse_track_id(uri, &request_id))
+                    .collect::<Result<_, _>>()?,
+                session_id,
+                connection_id,
+                request_id,
+            }),
+            Request::SetFallbackPlaylist(f) => self.controller_addr.do_send(SetFallbackPlaylist {
+                playlist: f
+                    .uri
+                    .as_deref()
+                    .map(|uri| parse_playlist_id(uri, &request_id))
+                    .transpose()?,
+                session_id,
+                connection_id,
+                request_id,
+            }),
+            Request::SetAutoplay(a) => self.controller_addr.do_send(SetAutoplay {
+                mode: a.mode,
+                session_id,
+                connection_id,
+                request_id,
+            }),
+            Request::History(h) => self.controller_addr.do_send(History {
+                page: Some(parse_page(h.limit, h.offset, &request_id)?),
+                session_id,
+                connection_id,
+                request_id,
+            }),
+            Request::SaveHistory(s) => self.controller_addr.do_send(SaveHistory {
+                name: s.name,
+                session_id,
+                connection_id,
+                request_id,
+            }),
+        }
+
+        Ok(())
+    }
 }
 
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsConnection {
@@ -129,7 +387,14 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsConnection {
             Ok(ws::Message::Pong(_)) => {
                 self.last_heartbeat_timestamp = Instant::now();
             }
-            Ok(ws::Message::Binary(bin)) => ctx.binary(bin),
+            Ok(ws::Message::Binary(_)) => {
+                let response = Response::error(
+                    ErrorCode::MalformedRequest,
+                    "Requests must be sent as text",
+                    None,
+                );
+                self.send_response(&response, ctx);
+            }
             Ok(ws::Message::Close(reason)) => {
                 ctx.close(reason);
                 ctx.stop();
@@ -139,54 +404,50 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsConnection {
             }
             Ok(ws::Message::Nop) => (),
             Ok(Text(s)) => {
-                if let Ok(req) = serde_json::from_str(&s.to_string()) {
-                    match req {
-                        Request::Search(s) => self.controller_addr.do_send(Search {
-                            query: s.query,
-                            session_id: self.session_id,
-                            connection_id: self.connection_id,
-                        }),
-                        Request::Queue(q) => {
-                            if let Ok(track_id) = TrackId::from_str(&q.uri) {
-                                self.controller_addr.do_send(Queue {
-                                    track_id,
-                                    session_id: self.session_id,
-                                })
-                            }
-                        }
-                        Request::State => self.controller_addr.do_send(State {
-                            session_id: self.session_id,
-                            connection_id: self.connection_id,
-                        }),
-                        Request::Vote(v) => {
-                            if let Ok(track_id) = TrackId::from_str(&v.uri) {
-                                self.controller_addr.do_send(Vote {
-                                    track_id,
-                                    session_id: self.session_id,
-                                    connection_id: self.connection_id,
-                                })
-                            }
-                        }
-                        Request::Kill => self.controller_addr.do_send(Kill {
-                            session_id: self.session_id,
-                        }),
-                        Request::Devices => self.controller_addr.do_send(Devices {
-                            session_id: self.session_id,
-                            connection_id: self.connection_id,
-                        }),
-                        Request::Transfer(t) => self.controller_addr.do_send(Transfer {
-                            session_id: self.session_id,
-                            connection_id: self.connection_id,
-                            device_id: t.device_id,
-                        }),
-                        Request::VotedTracks => self.controller_addr.do_send(VotedTracks {
-                            session_id: self.session_id,
-                            connection_id: self.connection_id,
-                        }),
+                let Envelope {
+                    request_id,
+                    request,
+                } = match serde_json::from_str::<Envelope>(&s) {
+                    Ok(envelope) => envelope,
+                    Err(err) => {
+                        log::error!(
+                            "Malformed request from connection {}: {err}",
+                            self.connection_id
+                        );
+                        // Echo the id back if the request got far enough to have one
+                        let request_id = serde_json::from_str::<serde_json::Value>(&s)
+                            .ok()
+                            .and_then(|value| value["request_id"].as_str().map(String::from));
+                        let response = Response::error(
+                            ErrorCode::MalformedRequest,
+                            err.to_string(),
+                            request_id,
+                        );
+                        self.send_response(&response, ctx);
+                        return;
                     }
+                };
+
+                let operation = request.operation();
+                if !is_authorized(self.context, operation) {
+                    log::error!(
+                        "Rejected {:?} from {} connection {}",
+                        operation,
+                        self.context,
+                        self.connection_id
+                    );
+                    self.send_response(&Response::forbidden(operation, request_id), ctx);
+                    return;
+                }
+
+                if let Err(response) = self.handle_request(request, request_id, ctx) {
+                    self.send_response(&response, ctx);
                 }
             }
-            Err(e) => panic!("{}", e),
+            Err(err) => {
+                log::error!("Protocol error on connection {}: {err}", self.connection_id);
+                ctx.stop();
+            }
         }
     }
 }
@@ -195,8 +456,6 @@ impl Handler<WsMessage> for WsConnection {
     type Result = ();
 
     fn handle(&mut self, msg: WsMessage, ctx: &mut Self::Context) {
-        if let Ok(data) = serde_json::to_string(&msg.0) {
-            ctx.text(data);
-        }
+        self.send_response(&msg.0, ctx);
     }
 }
@@ -1,16 +1,20 @@
 use std::str::FromStr;
+use std::time::Duration;
 
+use crate::autoplay::AutoplayMode;
 use crate::configuration::{DatabaseSettings, SpotifySettings};
-use crate::controller::Vote;
+use crate::controller::{Unvote, Vote};
+use crate::item::{ItemId, ItemKind};
+use crate::queue::{order_queue, QueueEntry};
+use crate::session_agent::{ImageInfo, TrackInfo};
 use crate::spotify::{create_token_from_string, get_default_spotify, get_token_string};
-use rspotify::model::TrackId;
 use rspotify::AuthCodeSpotify;
-use secrecy::ExposeSecret;
-use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::postgres::PgPoolOptions;
+use sqlx::types::chrono::{DateTime, Utc};
+use sqlx::types::Json;
 use sqlx::PgPool;
 use sqlx::{Postgres, Transaction};
 use uuid::Uuid;
-use sqlx::postgres::PgSslMode;
 
 #[derive(Clone)]
 pub struct Database {
@@ -24,11 +28,102 @@ pub struct Session {
 }
 
 pub struct State {
-    pub current_track_uri: Option<TrackId>,
-    pub current_queue: Vec<TrackId>,
+    pub current_track_uri: Option<ItemId>,
+    pub current_queue: Vec<QueueEntry>,
+    pub paused: bool,
+    pub fallback_playlist: Option<String>,
+    pub autoplay: AutoplayMode,
+    pub current_autoplayed: bool,
 }
 
-// TODO: take TrackId references instead?
+/// A track becoming the current one, with what the session's history records about it.
+pub struct Play {
+    pub track_id: ItemId,
+    pub queued_by: Option<Uuid>,
+    pub votes: i32,
+    pub autoplayed: bool,
+}
+
+impl Play {
+    pub fn queued(track_id: ItemId, client_id: Uuid) -> Self {
+        Self {
+            track_id,
+            queued_by: Some(client_id),
+            votes: 0,
+            autoplayed: false,
+        }
+    }
+
+    pub fn autoplayed(track_id: ItemId) -> Self {
+        Self {
+            track_id,
+            queued_by: None,
+            votes: 0,
+            autoplayed: true,
+        }
+    }
+}
+
+impl From<QueueEntry> for Play {
+    fn from(entry: QueueEntry) -> Self {
+        Self {
+            track_id: entry.track_id,
+            queued_by: entry.client_id,
+            votes: entry.votes,
+            autoplayed: false,
+        }
+    }
+}
+
+pub struct PlayedTrack {
+    pub track_id: ItemId,
+    pub queued_by: Option<Uuid>,
+    pub votes: i32,
+    pub autoplayed: bool,
+    pub skipped: bool,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: Option<DateTime<Utc>>,
+}
+
+#[derive(sqlx::FromRow)]
+struct PlayedRow {
+    track_uri: String,
+    item_type: String,
+    client_id: Option<Uuid>,
+    votes: i32,
+    autoplayed: bool,
+    skipped: bool,
+    started_at: DateTime<Utc>,
+    ended_at: Option<DateTime<Utc>>,
+}
+
+#[derive(sqlx::FromRow)]
+struct QueueRow {
+    track_uri: String,
+    item_type: String,
+    votes: i32,
+    queued_at: DateTime<Utc>,
+    pinned_at: Option<DateTime<Utc>>,
+    position: Option<i32>,
+    moved_at: Option<DateTime<Utc>>,
+    client_id: Option<Uuid>,
+}
+
+#[derive(sqlx::FromRow)]
+struct TrackRow {
+    uri: String,
+    name: String,
+    artists: Vec<String>,
+    album: String,
+    images: Json<Vec<ImageInfo>>,
+    duration_ms: i64,
+    explicit: bool,
+    popularity: i32,
+    preview_url: Option<String>,
+    item_type: String,
+}
+
+// TODO: take ItemId references instead?
 impl Database {
     pub fn new(settings: &DatabaseSettings, spotify_settings: SpotifySettings) -> Self {
         Self {
@@ -84,6 +179,26 @@ impl Database {
         .execute(&mut transaction)
         .await?;
 
+        sqlx::query!(
+            r#"
+                DELETE FROM skip_votes
+                WHERE session_id = $1
+            "#,
+            id
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        sqlx::query!(
+            r#"
+                DELETE FROM played_tracks
+                WHERE session_id = $1
+            "#,
+            id
+        )
+        .execute(&mut transaction)
+        .await?;
+
         sqlx::query!(
             r#"
                 DELETE FROM sessions 
@@ -115,6 +230,33 @@ impl Database {
         Ok(session)
     }
 
+    /// Records that the session is still in use.
+    pub async fn touch_session(&self, id: Uuid) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+                UPDATE sessions SET last_active_at = now() WHERE id = $1
+            "#,
+            id
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Sessions that nobody used for longer than `ttl`.
+    pub async fn idle_sessions(&self, ttl: Duration) -> Result<Vec<Uuid>, sqlx::Error> {
+        let rows: Vec<(Uuid,)> = sqlx::query_as(
+            r#"
+                SELECT id FROM sessions
+                WHERE last_active_at < now() - make_interval(secs => $1)
+            "#,
+        )
+        .bind(ttl.as_secs_f64())
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(|(id,)| id).collect())
+    }
+
     pub async fn get_session(&self, id: Uuid) -> Result<Session, sqlx::Error> {
         let mut transaction = self.pool.begin().await?;
         let session = self.get_session_impl(&mut transaction, id).await?;
@@ -126,17 +268,19 @@ impl Database {
         &self,
         transaction: &mut Transaction<'static, Postgres>,
         id: Uuid,
-    ) -> Result<Option<TrackId>, sqlx::Error> {
-        let session = self.get_session_impl(transaction, id).await?;
+    ) -> Result<Option<ItemId>, sqlx::Error> {
+        let (uri, item_type): (Option<String>, Option<String>) = sqlx::query_as(
+            r#"
+                SELECT current_track_uri, current_item_type FROM sessions WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_one(transaction)
+        .await?;
 
-        let track_id: Option<TrackId> = match session.current_track_uri {
-            Some(current_track_uri) => {
-                match TrackId::from_str(&current_track_uri) {
-                    Ok(id) => Some(id),
-                    Err(_) => None, //TODO: handle invalid stored uri
-                }
-            }
-            None => None,
+        let track_id = match (uri, item_type) {
+            (Some(uri), Some(item_type)) => parse_item(&item_type, &uri),
+            _ => None,
         };
 
         Ok(track_id)
@@ -145,55 +289,109 @@ impl Database {
     pub async fn get_current_track(
         &self,
         id: Uuid,
-    ) -> Result<(Option<TrackId>, Transaction<'static, Postgres>), sqlx::Error> {
+    ) -> Result<(Option<ItemId>, Transaction<'static, Postgres>), sqlx::Error> {
         let mut transaction = self.pool.begin().await?;
         let track_id = self.get_current_track_impl(&mut transaction, id).await?;
         Ok((track_id, transaction))
     }
 
+    /// Makes the track the current one and adds it to the session's history, ending
+    /// the entry of the track it replaces.
     pub async fn set_current_track(
         &self,
         mut transaction: Transaction<'static, Postgres>,
         id: Uuid,
-        track_id: Option<TrackId>,
+        play: Option<Play>,
     ) -> Result<(), sqlx::Error> {
-        let track_id = match track_id {
-            Some(id) => Some(id.to_string()),
-            None => None,
-        };
+        let track_id = play.as_ref().map(|play| play.track_id.to_string());
+        let item_type = play.as_ref().map(|play| play.track_id.kind().as_str());
         sqlx::query!(
             r#"
-                UPDATE sessions 
-                SET 
-                    current_track_uri = $2
+                UPDATE sessions
+                SET
+                    current_track_uri = $2,
+                    current_item_type = $3,
+                    current_autoplayed = $4,
+                    paused = false
                 WHERE
                     id = $1
             "#,
             id,
-            track_id
+            track_id.as_deref(),
+            item_type,
+            play.as_ref().is_some_and(|play| play.autoplayed)
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        sqlx::query!(
+            r#"
+                UPDATE played_tracks SET ended_at = now()
+                WHERE session_id = $1 AND ended_at IS NULL
+            "#,
+            id
         )
         .execute(&mut transaction)
         .await?;
 
+        if let (Some(play), Some(track_id), Some(item_type)) = (play, track_id, item_type) {
+            sqlx::query!(
+                r#"
+                    INSERT INTO played_tracks
+                        (session_id, track_uri, item_type, autoplayed, client_id, votes)
+                    VALUES ($1, $2, $3, $4, $5, $6)
+                "#,
+                id,
+                track_id,
+                item_type,
+                play.autoplayed,
+                play.queued_by,
+                play.votes
+            )
+            .execute(&mut transaction)
+            .await?;
+        }
+
         transaction.commit().await?;
         Ok(())
     }
 
+    /// Marks the history entry of the track that is playing as skipped.
+    pub async fn mark_skipped(
+        &self,
+        transaction: &mut Transaction<'static, Postgres>,
+        id: Uuid,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+                UPDATE played_tracks SET skipped = true
+                WHERE session_id = $1 AND ended_at IS NULL
+            "#,
+            id
+        )
+        .execute(transaction)
+        .await?;
+        Ok(())
+    }
+
     pub async fn queue_track(
         &self,
         mut transaction: Transaction<'static, Postgres>,
         id: Uuid,
-        track_id: TrackId,
+        track_id: ItemId,
+        client_id: Uuid,
     ) -> Result<(), sqlx::Error> {
         sqlx::query!(
             r#"
                 INSERT INTO queued_tracks
-                    (track_uri, session_id)
-                VALUES ($1, $2)
+                    (track_uri, session_id, client_id, item_type)
+                VALUES ($1, $2, $3, $4)
                 ON CONFLICT (track_uri, session_id) DO NOTHING
             "#,
             track_id.to_string(),
-            id
+            id,
+            client_id,
+            track_id.kind().as_str()
         )
         .execute(&mut transaction)
         .await?;
@@ -202,110 +400,725 @@ impl Database {
         Ok(())
     }
 
-    pub async fn pop_track_from_queue(
+    /// Queues up to `limit` of the given tracks for a client, in the order given and
+    /// leaving out those already waiting. Returns how many were queued. Doesn't commit,
+    /// so the caller can make the rest of its changes in the same transaction.
+    pub async fn queue_tracks(
+        &self,
+        transaction: &mut Transaction<'static, Postgres>,
+        id: Uuid,
+        track_ids: &[ItemId],
+        client_id: Uuid,
+        limit: i64,
+    ) -> Result<u64, sqlx::Error> {
+        let uris: Vec<String> = track_ids.iter().map(|id| id.to_string()).collect();
+        let item_types: Vec<String> = track_ids
+            .iter()
+            .map(|id| id.kind().as_str().to_string())
+            .collect();
+        // Every row of a statement gets the same now(), so the order is kept by
+        // spacing the queue times a microsecond apart
+        let result = sqlx::query!(
+            r#"
+                INSERT INTO queued_tracks
+                    (track_uri, item_type, session_id, client_id, queued_at)
+                SELECT uri, item_type, $3, $4, now() + ord * interval '1 microsecond'
+                FROM unnest($1::text[], $2::text[]) WITH ORDINALITY AS items(uri, item_type, ord)
+                WHERE NOT EXISTS (
+                    SELECT 1 FROM queued_tracks WHERE session_id = $3 and track_uri = items.uri
+                )
+                ORDER BY ord
+                LIMIT $5
+                ON CONFLICT (track_uri, session_id) DO NOTHING
+            "#,
+            &uris,
+            &item_types,
+            id,
+            client_id,
+            limit
+        )
+        .execute(transaction)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Like `get_current_track`, with the session's queue lock taken first so
+    /// neither the track nor the queue change until the transaction ends.
+    pub async fn lock_current_track(
+        &self,
+        id: Uuid,
+    ) -> Result<(Option<ItemId>, Transaction<'static, Postgres>), sqlx::Error> {
+        let mut transaction = self.begin_queue_change(id).await?;
+        let track_id = self.get_current_track_impl(&mut transaction, id).await?;
+        Ok((track_id, transaction))
+    }
+
+    /// Starts a transaction that holds the session's queue lock, see `lock_queue`.
+    pub async fn begin_queue_change(
         &self,
         id: Uuid,
+    ) -> Result<Transaction<'static, Postgres>, sqlx::Error> {
+        let mut transaction = self.pool.begin().await?;
+        self.lock_queue(&mut transaction, id).await?;
+        Ok(transaction)
+    }
+
+    /// Locks the session until the transaction ends, so the queue doesn't change
+    /// between counting it and changing it.
+    async fn lock_queue(
+        &self,
         transaction: &mut Transaction<'static, Postgres>,
-    ) -> Result<Option<TrackId>, sqlx::Error> {
-        let result: Option<(String,)> = sqlx::query_as(
+        id: Uuid,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
             r#"
-                DELETE FROM queued_tracks 
-                WHERE track_uri = any (array(SELECT track_uri FROM queued_tracks WHERE session_id = $1 ORDER BY votes DESC LIMIT 1)) RETURNING track_uri;
-            "#
+                SELECT id FROM sessions WHERE id = $1 FOR UPDATE
+            "#,
         )
         .bind(id)
-        .fetch_optional(transaction)
+        .fetch_one(transaction)
         .await?;
+        Ok(())
+    }
 
-        let track_id: Option<TrackId> = match result {
-            Some((track_uri,)) => {
-                match TrackId::from_str(&track_uri) {
-                    Ok(id) => Some(id),
-                    Err(_) => None, //TODO: handle invalid stored uri
-                }
-            }
-            None => None,
+    pub async fn queue_length(
+        &self,
+        transaction: &mut Transaction<'static, Postgres>,
+        id: Uuid,
+    ) -> Result<i64, sqlx::Error> {
+        let (count,): (i64,) = sqlx::query_as(
+            r#"
+                SELECT COUNT(*) FROM queued_tracks WHERE session_id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_one(transaction)
+        .await?;
+        Ok(count)
+    }
+
+    /// Number of tracks the given client has queued that have not been played yet.
+    pub async fn pending_track_count(
+        &self,
+        transaction: &mut Transaction<'static, Postgres>,
+        id: Uuid,
+        client_id: Uuid,
+    ) -> Result<i64, sqlx::Error> {
+        let (count,): (i64,) = sqlx::query_as(
+            r#"
+                SELECT COUNT(*) FROM queued_tracks WHERE session_id = $1 and client_id = $2
+            "#,
+        )
+        .bind(id)
+        .bind(client_id)
+        .fetch_one(transaction)
+        .await?;
+        Ok(count)
+    }
+
+    pub async fn pop_track_from_queue(
+        &self,
+        id: Uuid,
+        transaction: &mut Transaction<'static, Postgres>,
+    ) -> Result<Option<QueueEntry>, sqlx::Error> {
+        let queue = self.get_queue_entries(transaction, id).await?;
+        let next = match queue.into_iter().next() {
+            Some(entry) => entry,
+            None => return Ok(None),
         };
 
-        Ok(track_id)
+        self.delete_queued_track(transaction, id, &next.track_id, 0)
+            .await?;
+        Ok(Some(next))
     }
 
-    async fn get_queue_impl(
+    async fn get_queue_entries(
         &self,
         transaction: &mut Transaction<'static, Postgres>,
         id: Uuid,
-    ) -> Result<Vec<TrackId>, sqlx::Error> {
-        let uris: Vec<(String,)> = sqlx::query_as(
+    ) -> Result<Vec<QueueEntry>, sqlx::Error> {
+        let rows: Vec<QueueRow> = sqlx::query_as(
             r#"
-                    SELECT track_uri FROM queued_tracks where session_id = $1 ORDER BY votes DESC
+                    SELECT
+                        track_uri, item_type, votes, queued_at, pinned_at, position, moved_at,
+                        client_id
+                    FROM queued_tracks where session_id = $1
                 "#,
         )
         .bind(id)
         .fetch_all(transaction)
         .await?;
 
-        let mut queue = Vec::new();
-        for (uri,) in uris.iter() {
-            match TrackId::from_str(&uri) {
-                Ok(id) => {
-                    queue.push(id);
-                }
-                _ => {}
+        let mut entries = Vec::new();
+        for row in rows.into_iter() {
+            if let Some(track_id) = parse_item(&row.item_type, &row.track_uri) {
+                entries.push(QueueEntry {
+                    track_id,
+                    votes: row.votes,
+                    queued_at: row.queued_at,
+                    pinned_at: row.pinned_at,
+                    position: row.position,
+                    moved_at: row.moved_at,
+                    client_id: row.client_id,
+                });
             }
         }
 
-        Ok(queue)
+        Ok(order_queue(entries))
+    }
+
+    // Removes a track found at `index` of the ordered queue. Tracks the host moved
+    // further down shift up with the rest of the queue, counting from the position
+    // the removed track was moved to if it was.
+    async fn delete_queued_track(
+        &self,
+        transaction: &mut Transaction<'static, Postgres>,
+        id: Uuid,
+        track_id: &ItemId,
+        index: usize,
+    ) -> Result<bool, sqlx::Error> {
+        let deleted = sqlx::query!(
+            r#"
+                DELETE FROM queued_tracks
+                WHERE session_id = $1 and track_uri = $2
+                RETURNING position
+            "#,
+            id,
+            track_id.to_string()
+        )
+        .fetch_optional(&mut *transaction)
+        .await?;
+
+        let position = match deleted {
+            Some(row) => row.position.unwrap_or(index as i32),
+            None => return Ok(false),
+        };
+
+        sqlx::query!(
+            r#"
+                UPDATE queued_tracks
+                SET
+                    position = position - 1
+                WHERE
+                    session_id = $1 and position > $2
+            "#,
+            id,
+            position
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        Ok(true)
+    }
+
+    /// Removes a track and its votes from the queue. Returns false if it wasn't queued.
+    pub async fn remove_queued_track(
+        &self,
+        id: Uuid,
+        track_id: &ItemId,
+    ) -> Result<bool, sqlx::Error> {
+        let mut transaction = self.pool.begin().await?;
+        let queue = self.get_queue_entries(&mut transaction, id).await?;
+        let index = match queue.iter().position(|entry| &entry.track_id == track_id) {
+            Some(index) => index,
+            None => return Ok(false),
+        };
+
+        self.remove_votes(&mut transaction, id, track_id.clone())
+            .await?;
+        self.delete_queued_track(&mut transaction, id, track_id, index)
+            .await?;
+
+        transaction.commit().await?;
+        Ok(true)
+    }
+
+    /// Pins a track to the top of the queue. Returns false if it wasn't queued.
+    pub async fn pin_track(&self, id: Uuid, track_id: &ItemId) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+                UPDATE queued_tracks
+                SET
+                    pinned_at = now(), position = NULL, moved_at = NULL
+                WHERE
+                    session_id = $1 and track_uri = $2
+            "#,
+            id,
+            track_id.to_string()
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Moves a track to a fixed position in the queue. Returns false if it wasn't queued.
+    pub async fn move_track(
+        &self,
+        transaction: &mut Transaction<'static, Postgres>,
+        id: Uuid,
+        track_id: &ItemId,
+        position: i32,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+                UPDATE queued_tracks
+                SET
+                    position = $3, moved_at = now(), pinned_at = NULL
+                WHERE
+                    session_id = $1 and track_uri = $2
+            "#,
+            id,
+            track_id.to_string(),
+            position
+        )
+        .execute(transaction)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Remembers track metadata, replacing what was stored for the same tracks before.
+    pub async fn store_tracks(&self, tracks: &[TrackInfo]) -> Result<(), sqlx::Error> {
+        let mut transaction = self.pool.begin().await?;
+        for track in tracks {
+            sqlx::query!(
+                r#"
+                    INSERT INTO tracks
+                        (uri, name, artists, album, images, duration_ms, explicit, popularity,
+                        preview_url, item_type, fetched_at)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, now())
+                    ON CONFLICT (uri) DO UPDATE
+                    SET
+                        name = $2, artists = $3, album = $4, images = $5, duration_ms = $6,
+                        explicit = $7, popularity = $8, preview_url = $9, item_type = $10,
+                        fetched_at = now()
+                "#,
+                &track.id,
+                &track.name,
+                &track.artists,
+                &track.album,
+                Json(&track.images) as _,
+                track.duration_ms as i64,
+                track.explicit,
+                track.popularity as i32,
+                track.preview_url.as_deref(),
+                track.kind.as_str()
+            )
+            .execute(&mut transaction)
+            .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    /// Stored metadata of the given tracks that was fetched after `fetched_since`.
+    pub async fn get_tracks(
+        &self,
+        uris: &[String],
+        fetched_since: DateTime<Utc>,
+    ) -> Result<Vec<TrackInfo>, sqlx::Error> {
+        let rows: Vec<TrackRow> = sqlx::query_as(
+            r#"
+                SELECT
+                    uri, name, artists, album, images, duration_ms, explicit, popularity,
+                    preview_url, item_type
+                FROM tracks
+                WHERE uri = ANY($1) and fetched_at > $2
+            "#,
+        )
+        .bind(uris)
+        .bind(fetched_since)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| {
+                Some(TrackInfo {
+                    kind: ItemKind::from_str(&row.item_type).ok()?,
+                    name: row.name,
+                    artists: row.artists,
+                    id: row.uri,
+                    album: row.album,
+                    images: row.images.0,
+                    duration_ms: row.duration_ms as u64,
+                    explicit: row.explicit,
+                    popularity: row.popularity as u32,
+                    preview_url: row.preview_url,
+                })
+            })
+            .collect())
     }
 
     pub async fn get_current_state(&self, id: Uuid) -> Result<State, sqlx::Error> {
         let mut transaction = self.pool.begin().await?;
         let current_track_uri = self.get_current_track_impl(&mut transaction, id).await?;
-        let current_queue = self.get_queue_impl(&mut transaction, id).await?;
+        let current_queue = self.get_queue_entries(&mut transaction, id).await?;
+        let paused = self.is_paused(&mut transaction, id).await?;
+        let fallback_playlist = self
+            .get_fallback_playlist(&mut transaction, id)
+            .await?
+            .map(|(uri, _)| uri);
+        let (autoplay, current_autoplayed) = self.get_autoplay(&mut transaction, id).await?;
         transaction.commit().await?;
         Ok(State {
             current_track_uri,
             current_queue,
+            paused,
+            fallback_playlist,
+            autoplay,
+            current_autoplayed,
         })
     }
 
-    pub async fn add_vote(&self, msg: &Vote) -> Result<(), sqlx::Error> {
-        let mut transaction = self.pool.begin().await?;
+    /// Whether the host paused the session, in which case its playback is left alone.
+    pub async fn is_paused(
+        &self,
+        transaction: &mut Transaction<'static, Postgres>,
+        id: Uuid,
+    ) -> Result<bool, sqlx::Error> {
+        let (paused,): (bool,) = sqlx::query_as(
+            r#"
+                SELECT paused FROM sessions WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_one(transaction)
+        .await?;
+        Ok(paused)
+    }
+
+    pub async fn set_paused(&self, id: Uuid, paused: bool) -> Result<(), sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+                UPDATE sessions SET paused = $2 WHERE id = $1
+            "#,
+            id,
+            paused
+        )
+        .execute(&self.pool)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        Ok(())
+    }
+
+    /// The playlist the session falls back on once its queue runs dry, along with
+    /// the position of the next track to play from it.
+    pub async fn get_fallback_playlist(
+        &self,
+        transaction: &mut Transaction<'static, Postgres>,
+        id: Uuid,
+    ) -> Result<Option<(String, i32)>, sqlx::Error> {
+        let (uri, position): (Option<String>, i32) = sqlx::query_as(
+            r#"
+                SELECT fallback_playlist_uri, fallback_position FROM sessions WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_one(transaction)
+        .await?;
+        Ok(uri.map(|uri| (uri, position)))
+    }
+
+    /// Sets or clears the fallback playlist. A new playlist is played from the start.
+    pub async fn set_fallback_playlist(
+        &self,
+        id: Uuid,
+        uri: Option<String>,
+    ) -> Result<(), sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+                UPDATE sessions SET fallback_playlist_uri = $2, fallback_position = 0
+                WHERE id = $1
+            "#,
+            id,
+            uri
+        )
+        .execute(&self.pool)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        Ok(())
+    }
+
+    pub async fn set_fallback_position(
+        &self,
+        transaction: &mut Transaction<'static, Postgres>,
+        id: Uuid,
+        position: i32,
+    ) -> Result<(), sqlx::Error> {
         sqlx::query!(
+            r#"
+                UPDATE sessions SET fallback_position = $2 WHERE id = $1
+            "#,
+            id,
+            position
+        )
+        .execute(transaction)
+        .await?;
+        Ok(())
+    }
+
+    /// The session's autoplay mode, and whether autoplay picked its current track.
+    pub async fn get_autoplay(
+        &self,
+        transaction: &mut Transaction<'static, Postgres>,
+        id: Uuid,
+    ) -> Result<(AutoplayMode, bool), sqlx::Error> {
+        let (mode, autoplayed): (String, bool) = sqlx::query_as(
+            r#"
+                SELECT autoplay_mode, current_autoplayed FROM sessions WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_one(transaction)
+        .await?;
+        let mode = AutoplayMode::from_str(&mode).unwrap_or(AutoplayMode::Off);
+        Ok((mode, autoplayed))
+    }
+
+    pub async fn set_autoplay_mode(&self, id: Uuid, mode: AutoplayMode) -> Result<(), sqlx::Error> {
+        let result = sqlx::query!(
+            r#"
+                UPDATE sessions SET autoplay_mode = $2 WHERE id = $1
+            "#,
+            id,
+            mode.as_str()
+        )
+        .execute(&self.pool)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        Ok(())
+    }
+
+    /// Up to `limit` of the tracks the session played, the most recently played first
+    /// and each only once.
+    pub async fn recently_played(
+        &self,
+        transaction: &mut Transaction<'static, Postgres>,
+        id: Uuid,
+        limit: i64,
+    ) -> Result<Vec<ItemId>, sqlx::Error> {
+        let rows: Vec<(String, String)> = sqlx::query_as(
+            r#"
+                SELECT track_uri, item_type FROM played_tracks
+                WHERE session_id = $1
+                GROUP BY track_uri, item_type
+                ORDER BY max(started_at) DESC
+                LIMIT $2
+            "#,
+        )
+        .bind(id)
+        .bind(limit)
+        .fetch_all(transaction)
+        .await?;
+        Ok(rows
+            .iter()
+            .filter_map(|(uri, item_type)| parse_item(item_type, uri))
+            .collect())
+    }
+
+    /// The track the session played longest ago, leaving out the current one.
+    pub async fn least_recently_played(
+        &self,
+        transaction: &mut Transaction<'static, Postgres>,
+        id: Uuid,
+    ) -> Result<Option<ItemId>, sqlx::Error> {
+        let row: Option<(String, String)> = sqlx::query_as(
+            r#"
+                SELECT track_uri, item_type FROM played_tracks
+                WHERE session_id = $1 AND track_uri IS DISTINCT FROM (
+                    SELECT current_track_uri FROM sessions WHERE id = $1
+                )
+                GROUP BY track_uri, item_type
+                ORDER BY max(started_at) ASC
+                LIMIT 1
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(transaction)
+        .await?;
+        Ok(row.and_then(|(uri, item_type)| parse_item(&item_type, &uri)))
+    }
+
+    /// The tracks the session played in the order they started, `limit` at a time or
+    /// all of them when there is no limit.
+    pub async fn get_played_tracks(
+        &self,
+        id: Uuid,
+        limit: Option<i64>,
+        offset: i64,
+    ) -> Result<Vec<PlayedTrack>, sqlx::Error> {
+        let rows: Vec<PlayedRow> = sqlx::query_as(
+            r#"
+                SELECT
+                    track_uri, item_type, client_id, votes, autoplayed, skipped, started_at,
+                    ended_at
+                FROM played_tracks
+                WHERE session_id = $1
+                ORDER BY started_at, id
+                LIMIT $2 OFFSET $3
+            "#,
+        )
+        .bind(id)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|row| {
+                Some(PlayedTrack {
+                    track_id: parse_item(&row.item_type, &row.track_uri)?,
+                    queued_by: row.client_id,
+                    votes: row.votes,
+                    autoplayed: row.autoplayed,
+                    skipped: row.skipped,
+                    started_at: row.started_at,
+                    ended_at: row.ended_at,
+                })
+            })
+            .collect())
+    }
+
+    pub async fn played_track_count(&self, id: Uuid) -> Result<i64, sqlx::Error> {
+        let (count,): (i64,) = sqlx::query_as(
+            r#"
+                SELECT count(*) FROM played_tracks WHERE session_id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(count)
+    }
+
+    /// Records a vote and returns whether it changed the track's score. Votes for
+    /// tracks that aren't queued and repeated votes from the same client are ignored,
+    /// while voting in the opposite direction replaces the client's earlier vote.
+    pub async fn add_vote(&self, msg: &Vote) -> Result<bool, sqlx::Error> {
+        let mut transaction = self.pool.begin().await?;
+        let result = sqlx::query!(
             r#"
                 INSERT INTO votes
-                    (client_id, session_id, track_uri)
-                VALUES ($1, $2, $3)
+                    (client_id, session_id, track_uri, score)
+                SELECT $1, $2, $3, $4
+                WHERE EXISTS (
+                    SELECT 1 FROM queued_tracks WHERE session_id = $2 and track_uri = $3
+                )
+                ON CONFLICT (client_id, session_id, track_uri) DO UPDATE
+                SET score = EXCLUDED.score
+                WHERE votes.score <> EXCLUDED.score
             "#,
             msg.connection_id,
             msg.session_id,
             msg.track_id.to_string(),
+            msg.kind.score()
         )
         .execute(&mut transaction)
         .await?;
 
-        sqlx::query!(
+        let counted = result.rows_affected() > 0;
+        if counted {
+            self.sync_vote_count(&mut transaction, msg.session_id, &msg.track_id)
+                .await?;
+        }
+
+        transaction.commit().await?;
+        Ok(counted)
+    }
+
+    /// Retracts a vote and returns whether there was one to retract.
+    pub async fn remove_vote(&self, msg: &Unvote) -> Result<bool, sqlx::Error> {
+        let mut transaction = self.pool.begin().await?;
+        let result = sqlx::query!(
             r#"
-                UPDATE queued_tracks 
-                SET 
-                    votes = votes + 1
-                WHERE
-                    track_uri = $1 and session_id = $2
+                DELETE FROM votes
+                WHERE client_id = $1 and session_id = $2 and track_uri = $3
             "#,
-            msg.track_id.to_string(),
+            msg.connection_id,
             msg.session_id,
+            msg.track_id.to_string()
         )
         .execute(&mut transaction)
         .await?;
 
+        let removed = result.rows_affected() > 0;
+        if removed {
+            self.sync_vote_count(&mut transaction, msg.session_id, &msg.track_id)
+                .await?;
+        }
+
         transaction.commit().await?;
+        Ok(removed)
+    }
+
+    // Derive the counter from the votes table rather than incrementing it, so the two can't drift
+    async fn sync_vote_count(
+        &self,
+        transaction: &mut Transaction<'static, Postgres>,
+        id: Uuid,
+        track_id: &ItemId,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+                UPDATE queued_tracks
+                SET
+                    votes = (
+                        SELECT COALESCE(SUM(score), 0) FROM votes
+                        WHERE session_id = $1 and track_uri = $2
+                    )
+                WHERE
+                    session_id = $1 and track_uri = $2
+            "#,
+            id,
+            track_id.to_string()
+        )
+        .execute(transaction)
+        .await?;
         Ok(())
     }
 
+    /// Drops queued tracks whose net score has fallen below `threshold` and
+    /// returns how many were removed. Tracks the host moved shift up like they
+    /// do when a single track is removed.
+    pub async fn remove_tracks_below_score(
+        &self,
+        id: Uuid,
+        threshold: i32,
+    ) -> Result<u64, sqlx::Error> {
+        let mut transaction = self.pool.begin().await?;
+        let queue = self.get_queue_entries(&mut transaction, id).await?;
+        let below: Vec<(usize, QueueEntry)> = queue
+            .into_iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.votes < threshold)
+            .collect();
+
+        // From the bottom up, so the indices of those still to go stay the same
+        for (index, entry) in below.iter().rev() {
+            self.remove_votes(&mut transaction, id, entry.track_id.clone())
+                .await?;
+            self.delete_queued_track(&mut transaction, id, &entry.track_id, *index)
+                .await?;
+        }
+
+        transaction.commit().await?;
+        Ok(below.len() as u64)
+    }
+
     pub async fn remove_votes(
         &self,
         transaction: &mut Transaction<'static, Postgres>,
         id: Uuid,
-        track_id: TrackId,
+        track_id: ItemId,
     ) -> Result<(), sqlx::Error> {
         sqlx::query!(
             r#"
@@ -320,12 +1133,66 @@ impl Database {
         Ok(())
     }
 
+    /// Records a client's vote to skip the given track and returns how many
+    /// clients have voted to skip it so far.
+    pub async fn add_skip_vote(
+        &self,
+        id: Uuid,
+        client_id: Uuid,
+        track_id: &ItemId,
+    ) -> Result<i64, sqlx::Error> {
+        let mut transaction = self.pool.begin().await?;
+        sqlx::query!(
+            r#"
+                INSERT INTO skip_votes
+                    (client_id, session_id, track_uri)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (client_id, session_id, track_uri) DO NOTHING
+            "#,
+            client_id,
+            id,
+            track_id.to_string()
+        )
+        .execute(&mut transaction)
+        .await?;
+
+        let (count,): (i64,) = sqlx::query_as(
+            r#"
+                SELECT COUNT(*) FROM skip_votes WHERE session_id = $1 and track_uri = $2
+            "#,
+        )
+        .bind(id)
+        .bind(track_id.to_string())
+        .fetch_one(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+        Ok(count)
+    }
+
+    pub async fn clear_skip_votes(
+        &self,
+        transaction: &mut Transaction<'static, Postgres>,
+        id: Uuid,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            DELETE FROM skip_votes
+            WHERE session_id = $1
+            "#,
+            id
+        )
+        .execute(transaction)
+        .await?;
+        Ok(())
+    }
+
     pub async fn set_spotify(
         &self,
         id: Uuid,
         spotify: &AuthCodeSpotify,
     ) -> Result<(), anyhow::Error> {
-        let token = get_token_string(&spotify).await?;
+        let token = get_token_string(spotify).await?;
         sqlx::query!(
             r#"
             UPDATE sessions
@@ -350,6 +1217,7 @@ impl Database {
         Ok(spotify)
     }
 
+    /// The tracks the client voted up, leaving out the ones it voted down.
     pub async fn voted_tracks(
         &self,
         id: Uuid,
@@ -357,7 +1225,8 @@ impl Database {
     ) -> Result<Vec<String>, sqlx::Error> {
         let uris: Vec<(String,)> = sqlx::query_as(
             r#"
-                    SELECT track_uri FROM votes where session_id = $1 and client_id = $2
+                    SELECT track_uri FROM votes
+                    WHERE session_id = $1 and client_id = $2 and score > 0
                 "#,
         )
         .bind(id)
@@ -375,21 +1244,13 @@ impl Database {
 }
 
 fn get_connection_pool(settings: &DatabaseSettings) -> PgPool {
-    let ssl_mode = if settings.require_ssl {
-        PgSslMode:: Require
-    } else {
-        // Try an encrypted connection, fallback to unencrypted if it fails
-        PgSslMode:: Prefer
-    };
-    let options = PgConnectOptions::new()
-        .host(&settings.host)
-        .username(&settings.username)
-        .password(&settings.password.expose_secret())
-        .database(&settings.database_name)
-        .port(settings.port)
-        .ssl_mode(ssl_mode);
-
     PgPoolOptions::new()
         .acquire_timeout(std::time::Duration::from_secs(2))
-        .connect_lazy_with(options)
+        .connect_lazy_with(settings.with_db())
+}
+
+// Rows with an unknown type or a uri that doesn't match it are skipped like missing ones.
+fn parse_item(item_type: &str, uri: &str) -> Option<ItemId> {
+    let kind = ItemKind::from_str(item_type).ok()?;
+    ItemId::parse(kind, uri).ok()
 }
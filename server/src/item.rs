@@ -0,0 +1,186 @@
+use rspotify::model::{AlbumId, EpisodeId, PlayableId, PlaylistId, TrackId};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// The kinds of items a session can queue and play.
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ItemKind {
+    Track,
+    Episode,
+}
+
+impl ItemKind {
+    /// How the kind is stored in the database.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ItemKind::Track => "track",
+            ItemKind::Episode => "episode",
+        }
+    }
+}
+
+impl FromStr for ItemKind {
+    type Err = anyhow::Error;
+
+    fn from_str(kind: &str) -> Result<Self, Self::Err> {
+        match kind {
+            "track" => Ok(ItemKind::Track),
+            "episode" => Ok(ItemKind::Episode),
+            _ => anyhow::bail!("Unknown item kind {}", kind),
+        }
+    }
+}
+
+/// A track or a podcast episode, identified by its uri.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ItemId {
+    Track(TrackId),
+    Episode(EpisodeId),
+}
+
+impl ItemId {
+    /// Parses a uri that is stored along with its kind.
+    pub fn parse(kind: ItemKind, uri: &str) -> Result<Self, anyhow::Error> {
+        let id = match kind {
+            ItemKind::Track => ItemId::Track(TrackId::from_str(uri)?),
+            ItemKind::Episode => ItemId::Episode(EpisodeId::from_str(uri)?),
+        };
+        Ok(id)
+    }
+
+    pub fn kind(&self) -> ItemKind {
+        match self {
+            ItemId::Track(_) => ItemKind::Track,
+            ItemId::Episode(_) => ItemKind::Episode,
+        }
+    }
+
+    /// The id in the form the provider's playback calls take.
+    pub fn as_playable(&self) -> &dyn PlayableId {
+        match self {
+            ItemId::Track(id) => id,
+            ItemId::Episode(id) => id,
+        }
+    }
+}
+
+/// Accepts track and episode uris. Bare ids are taken to be tracks.
+impl FromStr for ItemId {
+    type Err = anyhow::Error;
+
+    fn from_str(uri: &str) -> Result<Self, Self::Err> {
+        if let Ok(id) = TrackId::from_str(uri) {
+            return Ok(ItemId::Track(id));
+        }
+        match EpisodeId::from_str(uri) {
+            Ok(id) => Ok(ItemId::Episode(id)),
+            Err(_) => anyhow::bail!("{} is neither a track nor an episode", uri),
+        }
+    }
+}
+
+impl fmt::Display for ItemId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ItemId::Track(id) => write!(f, "{}", id),
+            ItemId::Episode(id) => write!(f, "{}", id),
+        }
+    }
+}
+
+impl From<TrackId> for ItemId {
+    fn from(id: TrackId) -> Self {
+        ItemId::Track(id)
+    }
+}
+
+impl From<EpisodeId> for ItemId {
+    fn from(id: EpisodeId) -> Self {
+        ItemId::Episode(id)
+    }
+}
+
+/// An album or a playlist, whose tracks can be listed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CollectionId {
+    Album(AlbumId),
+    Playlist(PlaylistId),
+}
+
+impl FromStr for CollectionId {
+    type Err = anyhow::Error;
+
+    fn from_str(uri: &str) -> Result<Self, Self::Err> {
+        if let Ok(id) = AlbumId::from_str(uri) {
+            return Ok(CollectionId::Album(id));
+        }
+        match PlaylistId::from_str(uri) {
+            Ok(id) => Ok(CollectionId::Playlist(id)),
+            Err(_) => anyhow::bail!("{} is neither an album nor a playlist", uri),
+        }
+    }
+}
+
+impl fmt::Display for CollectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CollectionId::Album(id) => write!(f, "{}", id),
+            CollectionId::Playlist(id) => write!(f, "{}", id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACK: &str = "spotify:track:4iV5W9uYEdYUVa79Axb7Rh";
+    const EPISODE: &str = "spotify:episode:512ojhOuo1ktJprKbVcKyQ";
+
+    #[test]
+    fn tells_tracks_and_episodes_apart() {
+        let track = ItemId::from_str(TRACK).unwrap();
+        assert_eq!(track.kind(), ItemKind::Track);
+        assert_eq!(track.to_string(), TRACK);
+
+        let episode = ItemId::from_str(EPISODE).unwrap();
+        assert_eq!(episode.kind(), ItemKind::Episode);
+        assert_eq!(episode.to_string(), EPISODE);
+    }
+
+    #[test]
+    fn refuses_other_uris() {
+        assert!(ItemId::from_str("spotify:album:4aawyAB9vmqN3uQ7FjRGTy").is_err());
+        assert!(ItemId::from_str("not a uri").is_err());
+    }
+
+    #[test]
+    fn tells_albums_and_playlists_apart() {
+        let album = "spotify:album:4aawyAB9vmqN3uQ7FjRGTy";
+        let playlist = "spotify:playlist:37i9dQZF1DXcBWIGoYBM5M";
+        assert!(matches!(
+            CollectionId::from_str(album).unwrap(),
+            CollectionId::Album(_)
+        ));
+        assert!(matches!(
+            CollectionId::from_str(playlist).unwrap(),
+            CollectionId::Playlist(_)
+        ));
+        assert_eq!(
+            CollectionId::from_str(playlist).unwrap().to_string(),
+            playlist
+        );
+        assert!(CollectionId::from_str(TRACK).is_err());
+    }
+
+    #[test]
+    fn parses_stored_uris_by_their_kind() {
+        let episode = ItemId::parse(ItemKind::Episode, EPISODE).unwrap();
+        assert_eq!(episode.kind(), ItemKind::Episode);
+        assert!(ItemId::parse(ItemKind::Track, EPISODE).is_err());
+        assert_eq!(ItemKind::from_str("episode").unwrap(), ItemKind::Episode);
+    }
+}
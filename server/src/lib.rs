@@ -1,10 +1,21 @@
 pub mod application;
+pub mod authorization;
+pub mod autoplay;
+pub mod catalog;
+pub mod cluster;
 pub mod configuration;
 pub mod controller;
 pub mod db;
+pub mod item;
 pub mod middleware;
+pub mod playback;
+pub mod progress;
+pub mod protocol;
+pub mod provider;
+pub mod queue;
 pub mod routes;
 pub mod session_agent;
 pub mod session_state;
 pub mod spotify;
+pub mod sweeper;
 pub mod templates;
@@ -1,15 +1,26 @@
 use env_logger::Env;
 use queuetify::application::Application;
+use queuetify::cluster::Cluster;
 use queuetify::configuration::get_configuration;
+use queuetify::db::Database;
+use queuetify::provider::SpotifyProvider;
 use queuetify::session_agent::SessionAgent;
+use secrecy::ExposeSecret;
 
 #[actix_web::main]
 async fn main() -> anyhow::Result<()> {
     env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
 
     let settings = get_configuration().expect("Failed to get configuration");
-    let (agent, agent_tx) = SessionAgent::build(settings.clone());
-    let application = Application::build(settings, agent_tx).await?;
+    let provider = SpotifyProvider::new(
+        Database::new(&settings.database, settings.spotify.clone()),
+        settings.spotify.clone(),
+    );
+    let cluster = Cluster::connect(settings.redis_uri.expose_secret()).await?;
+    let (agent, agent_tx) =
+        SessionAgent::build(settings.clone(), provider.clone(), cluster.clone());
+    let application =
+        Application::build(settings, agent_tx, provider, cluster, agent.metrics()).await?;
     let application_task = tokio::spawn(application.run());
     let agent_task = tokio::spawn(agent.run());
 
@@ -0,0 +1,454 @@
+use crate::controller::POLL_STATE_INTERVAL;
+use std::time::{Duration, Instant};
+
+/// How long after the current track was due to end the next one may take to
+/// show up before it is started by hand.
+pub const HANDOFF_GRACE: Duration = Duration::from_secs(5);
+/// Shortest wait between two checks, so a wakeup that lands a little early
+/// doesn't turn into a busy loop.
+const MIN_WAKEUP_INTERVAL: Duration = Duration::from_millis(100);
+/// First retry after starting a track that didn't play, doubled on every
+/// further attempt up to `POLL_STATE_INTERVAL`.
+const STALL_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+/// A paused track this close to its end has finished rather than been paused.
+const ENDED_THRESHOLD: Duration = Duration::from_secs(1);
+
+/// How the provider picks up the next track when a session advances.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Handoff {
+    /// Queue it behind the track that is about to finish.
+    Enqueue,
+    /// Start it right away, cutting the current track short.
+    Immediate,
+}
+
+/// Playback as reported by the provider on a check.
+#[derive(Clone, Debug)]
+pub struct Observation {
+    /// Uri of the playing track or episode, `None` when the item has no id, like
+    /// a local file.
+    pub track_id: Option<String>,
+    pub position: Duration,
+    pub duration: Duration,
+    pub is_playing: bool,
+}
+
+impl Observation {
+    fn remaining(&self) -> Duration {
+        self.duration.saturating_sub(self.position)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Phase {
+    /// Nothing is expected to play.
+    Idle,
+    /// The expected track is playing and is due to end at `ends_at`.
+    Playing { track_id: String, ends_at: Instant },
+    /// The expected track is loaded but someone paused it.
+    Paused { track_id: String },
+    /// The next track was handed to the provider and `from` is allowed to play
+    /// out until `deadline`.
+    HandingOff {
+        from: String,
+        to: Option<String>,
+        deadline: Instant,
+    },
+    /// The expected track was started but hasn't been seen playing yet.
+    Stalled { track_id: String, attempts: u32 },
+}
+
+/// What the session has to do after a check.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    Wait,
+    /// Start the expected track from the beginning.
+    Start,
+    /// Move on to the next track in the queue.
+    Advance(Handoff),
+}
+
+/// Decides when a session moves on to its next track. It only ever sees what
+/// the provider reports and what the session expects to play, and leaves the
+/// provider calls to its caller, so every transition can be tried out without
+/// either of them.
+#[derive(Clone, Debug)]
+pub struct Playback {
+    phase: Phase,
+    /// How long before the end of a track the next one is queued, which has
+    /// to cover the provider's crossfade.
+    handoff_lead: Duration,
+    /// Longest wait between two checks, for changes made in the provider's
+    /// own app that the session is never told about.
+    check_interval: Duration,
+}
+
+impl Playback {
+    pub fn new(handoff_lead: Duration, check_interval: Duration) -> Self {
+        Self {
+            phase: Phase::Idle,
+            handoff_lead,
+            check_interval,
+        }
+    }
+
+    pub fn phase(&self) -> &Phase {
+        &self.phase
+    }
+
+    /// The track the session was last seen expecting.
+    pub fn track_id(&self) -> Option<&str> {
+        match &self.phase {
+            Phase::Idle => None,
+            Phase::Playing { track_id, .. }
+            | Phase::Paused { track_id }
+            | Phase::Stalled { track_id, .. } => Some(track_id),
+            Phase::HandingOff { to, .. } => to.as_deref(),
+        }
+    }
+
+    /// Moves along with a check of the session. `expected` is the track the
+    /// session has as current, and `observed` what the provider reported.
+    pub fn observe(
+        &mut self,
+        expected: Option<&str>,
+        observed: Option<&Observation>,
+        now: Instant,
+    ) -> Action {
+        // The previous track may still play out after the next one was queued
+        if let Phase::HandingOff { from, deadline, .. } = &self.phase {
+            let still_playing = observed
+                .and_then(|observed| observed.track_id.as_deref())
+                .is_some_and(|track_id| track_id == from);
+            if now < *deadline && (still_playing || expected.is_none()) {
+                return Action::Wait;
+            }
+        }
+
+        let expected = match expected {
+            Some(expected) => expected,
+            None => {
+                self.phase = Phase::Idle;
+                return Action::Wait;
+            }
+        };
+
+        let observed = match observed {
+            Some(observed) => observed,
+            None => return self.start(expected),
+        };
+        let track_id = match observed.track_id.as_deref() {
+            Some(track_id) => track_id,
+            // Leave whatever the host put on alone
+            None => return Action::Wait,
+        };
+        if track_id != expected {
+            return self.start(expected);
+        }
+
+        let remaining = observed.remaining();
+        if !observed.is_playing {
+            self.phase = Phase::Paused {
+                track_id: expected.to_string(),
+            };
+            if remaining < ENDED_THRESHOLD {
+                return Action::Advance(Handoff::Immediate);
+            }
+            return Action::Wait;
+        }
+
+        self.phase = Phase::Playing {
+            track_id: expected.to_string(),
+            ends_at: now + remaining,
+        };
+        if remaining <= self.handoff_lead {
+            return Action::Advance(Handoff::Enqueue);
+        }
+        Action::Wait
+    }
+
+    /// Keeps the session where it is while the host has it paused, so nothing is
+    /// started or advanced behind their back.
+    pub fn hold(&mut self, expected: Option<&str>) {
+        self.phase = match expected {
+            Some(track_id) => Phase::Paused {
+                track_id: track_id.to_string(),
+            },
+            None => Phase::Idle,
+        };
+    }
+
+    /// Records that the session moved on to `to` after an `Advance`.
+    pub fn handed_off(&mut self, to: Option<String>, handoff: Handoff, now: Instant) {
+        let (from, ends_at) = match &self.phase {
+            Phase::Playing { track_id, ends_at } => (track_id.clone(), *ends_at),
+            Phase::Paused { track_id } => (track_id.clone(), now),
+            _ => return,
+        };
+
+        self.phase = match handoff {
+            Handoff::Enqueue => Phase::HandingOff {
+                from,
+                to,
+                deadline: ends_at.max(now) + HANDOFF_GRACE,
+            },
+            // The provider was told to play the new track right away
+            Handoff::Immediate => match to {
+                Some(track_id) => Phase::Stalled {
+                    track_id,
+                    attempts: 1,
+                },
+                None => Phase::Idle,
+            },
+        };
+    }
+
+    /// How long until the session should be checked again, which is when the
+    /// next track has to be handed to the provider. Pauses and seeks made
+    /// through the session have it checked right away instead, so the wait
+    /// only falls back to the check interval for the provider's own app.
+    pub fn next_wakeup(&self, now: Instant) -> Duration {
+        let wakeup = match &self.phase {
+            Phase::Idle | Phase::Paused { .. } => self.check_interval,
+            Phase::Playing { ends_at, .. } => {
+                ends_at.saturating_duration_since(now + self.handoff_lead)
+            }
+            Phase::HandingOff { deadline, .. } => deadline.saturating_duration_since(now),
+            Phase::Stalled { attempts, .. } => {
+                let retry = STALL_RETRY_INTERVAL * 2u32.saturating_pow(attempts.saturating_sub(1));
+                retry.min(POLL_STATE_INTERVAL)
+            }
+        };
+        wakeup.clamp(MIN_WAKEUP_INTERVAL, self.check_interval)
+    }
+
+    fn start(&mut self, expected: &str) -> Action {
+        let attempts = match &self.phase {
+            Phase::Stalled { track_id, attempts } if track_id == expected => attempts + 1,
+            _ => 1,
+        };
+        self.phase = Phase::Stalled {
+            track_id: expected.to_string(),
+            attempts,
+        };
+        Action::Start
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEAD: Duration = Duration::from_secs(10);
+    const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+    const FIRST: &str = "spotify:track:0000000000000000000001";
+    const SECOND: &str = "spotify:track:0000000000000000000002";
+
+    fn playing(track_id: &str, position_secs: u64) -> Observation {
+        Observation {
+            track_id: Some(track_id.to_string()),
+            position: Duration::from_secs(position_secs),
+            duration: Duration::from_secs(180),
+            is_playing: true,
+        }
+    }
+
+    fn paused(track_id: &str, position_secs: u64) -> Observation {
+        Observation {
+            is_playing: false,
+            ..playing(track_id, position_secs)
+        }
+    }
+
+    fn playing_first(now: Instant) -> Playback {
+        let mut playback = Playback::new(LEAD, CHECK_INTERVAL);
+        playback.observe(Some(FIRST), Some(&playing(FIRST, 60)), now);
+        playback
+    }
+
+    #[test]
+    fn stays_idle_without_a_current_track() {
+        let now = Instant::now();
+        let mut playback = Playback::new(LEAD, CHECK_INTERVAL);
+        assert_eq!(playback.observe(None, None, now), Action::Wait);
+        assert_eq!(playback.phase(), &Phase::Idle);
+        assert_eq!(playback.next_wakeup(now), CHECK_INTERVAL);
+    }
+
+    #[test]
+    fn wakes_up_when_the_next_track_has_to_be_queued() {
+        let now = Instant::now();
+        let mut playback = Playback::new(LEAD, CHECK_INTERVAL);
+        let observed = playing(FIRST, 167);
+        assert_eq!(
+            playback.observe(Some(FIRST), Some(&observed), now),
+            Action::Wait
+        );
+        assert!(matches!(playback.phase(), Phase::Playing { .. }));
+        assert_eq!(playback.next_wakeup(now), Duration::from_secs(3));
+
+        let observed = playing(FIRST, 170);
+        let later = now + Duration::from_secs(3);
+        assert_eq!(
+            playback.observe(Some(FIRST), Some(&observed), later),
+            Action::Advance(Handoff::Enqueue)
+        );
+    }
+
+    #[test]
+    fn long_tracks_are_still_checked_regularly() {
+        let now = Instant::now();
+        assert_eq!(playing_first(now).next_wakeup(now), CHECK_INTERVAL);
+
+        let mut playback = playing_first(now);
+        playback.observe(Some(FIRST), Some(&playing(FIRST, 150)), now);
+        assert_eq!(playback.next_wakeup(now), Duration::from_secs(20));
+    }
+
+    #[test]
+    fn seeking_moves_the_wakeup() {
+        let now = Instant::now();
+        let mut playback = playing_first(now);
+        playback.observe(Some(FIRST), Some(&playing(FIRST, 168)), now);
+        assert_eq!(playback.next_wakeup(now), Duration::from_secs(2));
+
+        playback.observe(Some(FIRST), Some(&playing(FIRST, 155)), now);
+        assert_eq!(playback.next_wakeup(now), Duration::from_secs(15));
+    }
+
+    #[test]
+    fn lets_the_previous_track_play_out_after_handing_off() {
+        let now = Instant::now();
+        let mut playback = Playback::new(LEAD, CHECK_INTERVAL);
+        playback.observe(Some(FIRST), Some(&playing(FIRST, 175)), now);
+        playback.handed_off(Some(SECOND.to_string()), Handoff::Enqueue, now);
+        assert!(matches!(playback.phase(), Phase::HandingOff { .. }));
+
+        // Crossfading into the next track
+        let later = now + Duration::from_secs(8);
+        assert_eq!(
+            playback.observe(Some(SECOND), Some(&playing(FIRST, 180)), later),
+            Action::Wait
+        );
+        assert_eq!(playback.next_wakeup(later), Duration::from_secs(2));
+
+        let later = now + Duration::from_secs(9);
+        assert_eq!(
+            playback.observe(Some(SECOND), Some(&playing(SECOND, 1)), later),
+            Action::Wait
+        );
+        assert!(matches!(
+            playback.phase(),
+            Phase::Playing { track_id, .. } if track_id == SECOND
+        ));
+    }
+
+    #[test]
+    fn starts_the_next_track_when_the_handoff_stalls() {
+        let now = Instant::now();
+        let mut playback = Playback::new(LEAD, CHECK_INTERVAL);
+        playback.observe(Some(FIRST), Some(&playing(FIRST, 175)), now);
+        playback.handed_off(Some(SECOND.to_string()), Handoff::Enqueue, now);
+
+        let later = now + Duration::from_secs(5) + HANDOFF_GRACE;
+        assert_eq!(
+            playback.observe(Some(SECOND), Some(&playing(FIRST, 180)), later),
+            Action::Start
+        );
+        assert_eq!(
+            playback.phase(),
+            &Phase::Stalled {
+                track_id: SECOND.to_string(),
+                attempts: 1
+            }
+        );
+    }
+
+    #[test]
+    fn backs_off_while_stalled() {
+        let now = Instant::now();
+        let mut playback = Playback::new(LEAD, CHECK_INTERVAL);
+        assert_eq!(playback.observe(Some(FIRST), None, now), Action::Start);
+        assert_eq!(playback.next_wakeup(now), Duration::from_secs(1));
+        assert_eq!(playback.observe(Some(FIRST), None, now), Action::Start);
+        assert_eq!(playback.next_wakeup(now), Duration::from_secs(2));
+        assert_eq!(playback.observe(Some(FIRST), None, now), Action::Start);
+        assert_eq!(playback.next_wakeup(now), Duration::from_secs(4));
+        assert_eq!(playback.observe(Some(FIRST), None, now), Action::Start);
+        assert_eq!(playback.next_wakeup(now), POLL_STATE_INTERVAL);
+
+        playback.observe(Some(FIRST), Some(&playing(FIRST, 0)), now);
+        assert!(matches!(playback.phase(), Phase::Playing { .. }));
+    }
+
+    #[test]
+    fn does_not_advance_a_paused_track() {
+        let now = Instant::now();
+        let mut playback = playing_first(now);
+        assert_eq!(
+            playback.observe(Some(FIRST), Some(&paused(FIRST, 175)), now),
+            Action::Wait
+        );
+        assert_eq!(
+            playback.phase(),
+            &Phase::Paused {
+                track_id: FIRST.to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn advances_right_away_when_the_track_already_ended() {
+        let now = Instant::now();
+        let mut playback = playing_first(now);
+        assert_eq!(
+            playback.observe(Some(FIRST), Some(&paused(FIRST, 180)), now),
+            Action::Advance(Handoff::Immediate)
+        );
+
+        playback.handed_off(Some(SECOND.to_string()), Handoff::Immediate, now);
+        assert!(matches!(playback.phase(), Phase::Stalled { .. }));
+    }
+
+    #[test]
+    fn goes_idle_once_the_last_track_played_out() {
+        let now = Instant::now();
+        let mut playback = Playback::new(LEAD, CHECK_INTERVAL);
+        playback.observe(Some(FIRST), Some(&playing(FIRST, 175)), now);
+        playback.handed_off(None, Handoff::Enqueue, now);
+
+        assert_eq!(playback.observe(None, None, now), Action::Wait);
+        assert!(matches!(playback.phase(), Phase::HandingOff { .. }));
+
+        let later = now + Duration::from_secs(5) + HANDOFF_GRACE;
+        assert_eq!(playback.observe(None, None, later), Action::Wait);
+        assert_eq!(playback.phase(), &Phase::Idle);
+    }
+
+    #[test]
+    fn holding_keeps_a_session_from_moving_on() {
+        let now = Instant::now();
+        let mut playback = playing_first(now);
+        playback.hold(Some(FIRST));
+        assert_eq!(
+            playback.phase(),
+            &Phase::Paused {
+                track_id: FIRST.to_string()
+            }
+        );
+        assert_eq!(playback.next_wakeup(now), CHECK_INTERVAL);
+
+        playback.hold(None);
+        assert_eq!(playback.phase(), &Phase::Idle);
+    }
+
+    #[test]
+    fn restarts_the_expected_track_when_something_else_plays() {
+        let now = Instant::now();
+        let mut playback = playing_first(now);
+        assert_eq!(
+            playback.observe(Some(FIRST), Some(&playing(SECOND, 10)), now),
+            Action::Start
+        );
+    }
+}
@@ -0,0 +1,124 @@
+use crate::controller::messages::DeviceInfo;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// How far the reported position may stray from where clients expect it to be
+/// before they are sent a correction.
+pub const PROGRESS_DRIFT_TOLERANCE: Duration = Duration::from_secs(2);
+
+/// Playback of the current track as last seen by the server. Clients can move
+/// `position_ms` along on their own while `is_playing` is set.
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct Progress {
+    /// Uri of the track this is about.
+    pub track_id: String,
+    pub position_ms: u64,
+    pub duration_ms: u64,
+    pub is_playing: bool,
+    pub device: Option<DeviceInfo>,
+    /// Server time at `position_ms`, in milliseconds since the Unix epoch.
+    pub timestamp_ms: i64,
+}
+
+impl Progress {
+    /// Where playback is expected to be `elapsed` after this was seen.
+    pub fn advanced_by(&self, elapsed: Duration) -> Self {
+        let elapsed_ms = elapsed.as_millis() as u64;
+        let position_ms = if self.is_playing {
+            (self.position_ms + elapsed_ms).min(self.duration_ms)
+        } else {
+            self.position_ms
+        };
+
+        Progress {
+            position_ms,
+            timestamp_ms: self.timestamp_ms + elapsed_ms as i64,
+            ..self.clone()
+        }
+    }
+
+    /// Whether clients expecting this need to hear about `actual`, which they do
+    /// when playback was paused, skipped, moved or turned up or down.
+    pub fn has_drifted(&self, actual: &Progress) -> bool {
+        let device = |progress: &Progress| {
+            progress
+                .device
+                .as_ref()
+                .map(|d| (d.id.clone(), d.volume_percent))
+        };
+        if self.track_id != actual.track_id
+            || self.is_playing != actual.is_playing
+            || device(self) != device(actual)
+        {
+            return true;
+        }
+
+        let drift = self.position_ms.abs_diff(actual.position_ms);
+        drift > PROGRESS_DRIFT_TOLERANCE.as_millis() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn progress(position_ms: u64, is_playing: bool) -> Progress {
+        Progress {
+            track_id: "spotify:track:0000000000000000000001".to_string(),
+            position_ms,
+            duration_ms: 180_000,
+            is_playing,
+            device: None,
+            timestamp_ms: 1_600_000_000_000,
+        }
+    }
+
+    #[test]
+    fn playing_tracks_move_along_until_they_end() {
+        let advanced = progress(10_000, true).advanced_by(Duration::from_secs(5));
+        assert_eq!(advanced.position_ms, 15_000);
+        assert_eq!(advanced.timestamp_ms, 1_600_000_005_000);
+
+        let advanced = progress(178_000, true).advanced_by(Duration::from_secs(5));
+        assert_eq!(advanced.position_ms, 180_000);
+    }
+
+    #[test]
+    fn paused_tracks_stay_put() {
+        let advanced = progress(10_000, false).advanced_by(Duration::from_secs(5));
+        assert_eq!(advanced.position_ms, 10_000);
+    }
+
+    #[test]
+    fn small_differences_are_not_drift() {
+        let expected = progress(10_000, true);
+        assert!(!expected.has_drifted(&progress(11_500, true)));
+        assert!(!expected.has_drifted(&progress(8_500, true)));
+        assert!(expected.has_drifted(&progress(13_000, true)));
+        assert!(expected.has_drifted(&progress(7_000, true)));
+    }
+
+    #[test]
+    fn pausing_or_switching_tracks_is_drift() {
+        let expected = progress(10_000, true);
+        assert!(expected.has_drifted(&progress(10_000, false)));
+
+        let mut other_track = progress(10_000, true);
+        other_track.track_id = "spotify:track:0000000000000000000002".to_string();
+        assert!(expected.has_drifted(&other_track));
+
+        let mut other_device = progress(10_000, true);
+        other_device.device = Some(DeviceInfo {
+            id: "device-1".to_string(),
+            name: "Living room".to_string(),
+            dev_type: "Speaker".to_string(),
+            volume_percent: Some(50),
+        });
+        assert!(expected.has_drifted(&other_device));
+
+        let mut louder = other_device.clone();
+        louder.device.as_mut().unwrap().volume_percent = Some(80);
+        assert!(other_device.has_drifted(&louder));
+    }
+}
@@ -0,0 +1,437 @@
+//! The messages exchanged with clients over the session WebSocket.
+//!
+//! A client opens the conversation with a `Hello` listing the protocol versions it
+//! understands. The server answers with a `Welcome` carrying the version it picked,
+//! and refuses every other request until that has happened.
+
+use crate::authorization::Operation;
+use crate::autoplay::AutoplayMode;
+use crate::catalog::{LibrarySource, Page, PlaylistInfo, SearchKind};
+use crate::controller::messages::DeviceInfo;
+use crate::progress::Progress;
+use crate::session_agent::{HistoryEntry, LibraryResult, SearchResult, State, TrackList};
+use schemars::gen::SchemaSettings;
+use schemars::schema::RootSchema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// The newest protocol version this server speaks.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// Every protocol version this server still accepts, oldest first.
+pub const SUPPORTED_PROTOCOL_VERSIONS: &[u32] = &[1];
+
+/// Picks the newest version both sides understand.
+pub fn negotiate_version(client_versions: &[u32]) -> Option<u32> {
+    SUPPORTED_PROTOCOL_VERSIONS
+        .iter()
+        .rev()
+        .find(|version| client_versions.contains(version))
+        .copied()
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct HelloPayload {
+    pub versions: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct SearchPayload {
+    pub query: String,
+    /// What to search for, tracks, episodes and shows if left out.
+    #[serde(default)]
+    pub types: Vec<SearchKind>,
+    /// Results per type, up to 50. Defaults to 10.
+    #[serde(default)]
+    pub limit: Option<u32>,
+    #[serde(default)]
+    pub offset: Option<u32>,
+}
+
+/// Asks for the tracks of an album or a playlist, a page at a time.
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct BrowsePayload {
+    pub uri: String,
+    #[serde(default)]
+    pub limit: Option<u32>,
+    #[serde(default)]
+    pub offset: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct QueuePayload {
+    /// A track or an episode, or an album or playlist to queue the tracks of.
+    pub uri: String,
+}
+
+/// Asks for a page of the host's own playlists, saved tracks or top tracks.
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct LibraryPayload {
+    pub source: LibrarySource,
+    #[serde(default)]
+    pub limit: Option<u32>,
+    #[serde(default)]
+    pub offset: Option<u32>,
+}
+
+/// Tracks and episodes the host picked to queue, in the order they should play.
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct ImportPayload {
+    pub uris: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct FallbackPlaylistPayload {
+    /// The playlist to play from when the queue runs dry, or none to stop doing so.
+    #[serde(default)]
+    pub uri: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct AutoplayPayload {
+    pub mode: AutoplayMode,
+}
+
+/// Asks for a page of what the session played, oldest first.
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct HistoryPayload {
+    #[serde(default)]
+    pub limit: Option<u32>,
+    #[serde(default)]
+    pub offset: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct SaveHistoryPayload {
+    /// The name of the new playlist, one with the date of the session if left out.
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct VotePayload {
+    pub uri: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct TransferPayload {
+    pub device_id: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct TrackPayload {
+    pub uri: String,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct MovePayload {
+    pub uri: String,
+    pub position: usize,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct SeekPayload {
+    pub position_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct VolumePayload {
+    /// From 0 to 100.
+    pub volume_percent: u8,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type")]
+pub enum Request {
+    Hello(HelloPayload),
+    Search(SearchPayload),
+    Browse(BrowsePayload),
+    Queue(QueuePayload),
+    State,
+    Vote(VotePayload),
+    Downvote(VotePayload),
+    Unvote(VotePayload),
+    Kill,
+    Devices,
+    Transfer(TransferPayload),
+    VotedTracks,
+    RemoveTrack(TrackPayload),
+    PinTrack(TrackPayload),
+    MoveTrack(MovePayload),
+    VoteSkip,
+    Skip,
+    Pause,
+    Resume,
+    Seek(SeekPayload),
+    Volume(VolumePayload),
+    Library(LibraryPayload),
+    Import(ImportPayload),
+    SetFallbackPlaylist(FallbackPlaylistPayload),
+    SetAutoplay(AutoplayPayload),
+    History(HistoryPayload),
+    SaveHistory(SaveHistoryPayload),
+}
+
+impl Request {
+    pub fn operation(&self) -> Operation {
+        match self {
+            Request::Hello(_) => Operation::Hello,
+            Request::Search(_) => Operation::Search,
+            Request::Browse(_) => Operation::Browse,
+            Request::Queue(_) => Operation::Queue,
+            Request::State => Operation::State,
+            Request::Vote(_) | Request::Downvote(_) => Operation::Vote,
+            Request::Unvote(_) => Operation::Unvote,
+            Request::Kill => Operation::Kill,
+            Request::Devices => Operation::Devices,
+            Request::Transfer(_) => Operation::Transfer,
+            Request::VotedTracks => Operation::VotedTracks,
+            Request::RemoveTrack(_) => Operation::RemoveTrack,
+            Request::PinTrack(_) => Operation::PinTrack,
+            Request::MoveTrack(_) => Operation::MoveTrack,
+            Request::VoteSkip => Operation::VoteSkip,
+            Request::Skip => Operation::Skip,
+            Request::Pause => Operation::Pause,
+            Request::Resume => Operation::Resume,
+            Request::Seek(_) => Operation::Seek,
+            Request::Volume(_) => Operation::Volume,
+            Request::Library(_) => Operation::Library,
+            Request::Import(_) => Operation::Import,
+            Request::SetFallbackPlaylist(_) => Operation::SetFallbackPlaylist,
+            Request::SetAutoplay(_) => Operation::SetAutoplay,
+            Request::History(_) => Operation::History,
+            Request::SaveHistory(_) => Operation::SaveHistory,
+        }
+    }
+}
+
+/// A request as sent by the client. The optional `request_id` is echoed back on
+/// every reply to it.
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct Envelope {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    pub request: Request,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct Welcome {
+    pub version: u32,
+    /// Identifies the client, as in `queued_by` of the tracks it queued.
+    pub client_id: Uuid,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct WelcomePayload {
+    pub payload: Welcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+// TODO: make struct generic
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct SearchResultPayload {
+    pub payload: SearchResult,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct TrackListPayload {
+    pub payload: TrackList,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct LibraryResultPayload {
+    pub payload: LibraryResult,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct HistoryResultPayload {
+    pub payload: Page<HistoryEntry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// The playlist the session history was saved to.
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct SavedPlaylistPayload {
+    pub payload: PlaylistInfo,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct StateUpdatePayload {
+    pub payload: State,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// Sent between state updates when playback is not where clients expect it to be.
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct ProgressPayload {
+    pub payload: Progress,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct DevicesPayload {
+    pub payload: Vec<DeviceInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct TransferResponsePayload {
+    pub payload: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// The tracks the client voted up.
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+pub struct VotedTracksPayload {
+    pub payload: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCode {
+    /// The request could not be parsed.
+    MalformedRequest,
+    /// A request other than `Hello` arrived before the version was agreed on.
+    HandshakeRequired,
+    /// None of the versions offered in `Hello` are supported.
+    UnsupportedVersion,
+    /// The request referred to something that is not a valid uri of the kind it takes.
+    InvalidTrackUri,
+    /// The operation is reserved for the session host.
+    Forbidden,
+    /// The session no longer exists.
+    SessionNotFound,
+    /// The track is not waiting in the queue.
+    TrackNotQueued,
+    /// There is no current track to act on.
+    NothingPlaying,
+    /// A position, volume or page limit lies outside of what is allowed.
+    OutOfRange,
+    QueueFull,
+    TooManyPendingTracks,
+    /// None of the tracks of the album, playlist or import could be queued.
+    NothingToQueue,
+    /// The session has not played anything yet.
+    EmptyHistory,
+    RateLimited,
+    /// The session has too many requests waiting already.
+    Busy,
+    /// The request was not handled in time.
+    Timeout,
+    /// The music provider rejected the request or could not be reached.
+    ProviderError,
+    /// Something went wrong on our side.
+    Internal,
+}
+
+// TODO: change name. not everything is a response
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
+#[serde(tag = "type")]
+pub enum Response {
+    Welcome(WelcomePayload),
+    SearchResult(SearchResultPayload),
+    TrackList(TrackListPayload),
+    LibraryResult(LibraryResultPayload),
+    History(HistoryResultPayload),
+    SavedPlaylist(SavedPlaylistPayload),
+    Shutdown,
+    StateUpdate(StateUpdatePayload),
+    Progress(ProgressPayload),
+    Devices(DevicesPayload),
+    Transfer(TransferResponsePayload),
+    VotedTracks(VotedTracksPayload),
+    Error {
+        code: ErrorCode,
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
+}
+
+impl Response {
+    pub fn error(code: ErrorCode, message: impl Into<String>, request_id: Option<String>) -> Self {
+        Response::Error {
+            code,
+            message: message.into(),
+            request_id,
+        }
+    }
+
+    /// The id of the request this response answers, if any.
+    pub fn request_id(&self) -> Option<&str> {
+        let request_id = match self {
+            Response::Welcome(welcome) => &welcome.request_id,
+            Response::SearchResult(result) => &result.request_id,
+            Response::TrackList(list) => &list.request_id,
+            Response::LibraryResult(result) => &result.request_id,
+            Response::History(history) => &history.request_id,
+            Response::SavedPlaylist(playlist) => &playlist.request_id,
+            Response::Shutdown | Response::Progress(_) => return None,
+            Response::StateUpdate(update) => &update.request_id,
+            Response::Devices(devices) => &devices.request_id,
+            Response::Transfer(transfer) => &transfer.request_id,
+            Response::VotedTracks(tracks) => &tracks.request_id,
+            Response::Error { request_id, .. } => request_id,
+        };
+        request_id.as_deref()
+    }
+
+    pub fn forbidden(operation: Operation, request_id: Option<String>) -> Self {
+        Response::error(
+            ErrorCode::Forbidden,
+            format!("{:?} is only permitted for the session host", operation),
+            request_id,
+        )
+    }
+}
+
+/// JSON Schemas for everything a client may send and receive.
+#[derive(Serialize)]
+pub struct ProtocolSchema {
+    pub version: u32,
+    pub request: RootSchema,
+    pub response: RootSchema,
+}
+
+pub fn protocol_schema() -> ProtocolSchema {
+    let settings = SchemaSettings::draft07();
+    ProtocolSchema {
+        version: PROTOCOL_VERSION,
+        request: settings
+            .clone()
+            .into_generator()
+            .into_root_schema_for::<Envelope>(),
+        response: settings.into_generator().into_root_schema_for::<Response>(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_newest_common_version() {
+        assert_eq!(negotiate_version(&[1]), Some(1));
+        assert_eq!(negotiate_version(&[0, 1, 2]), Some(1));
+    }
+
+    #[test]
+    fn refuses_when_no_version_is_shared() {
+        assert_eq!(negotiate_version(&[]), None);
+        assert_eq!(negotiate_version(&[0, 2]), None);
+    }
+}
@@ -0,0 +1,382 @@
+use crate::catalog::{Page, PageRequest, PlaylistInfo, SearchKind};
+use crate::configuration::TrackCacheSettings;
+use crate::controller::messages::DeviceInfo;
+use crate::db::Database;
+use crate::item::ItemId;
+use crate::provider::{CurrentPlayback, MusicProvider};
+use crate::session_agent::{SearchResult, TrackInfo};
+use async_trait::async_trait;
+use rspotify::model::{AlbumId, PlaylistId};
+use sqlx::types::chrono::{TimeZone, Utc};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+struct CachedTrack {
+    info: TrackInfo,
+    cached_at: Instant,
+}
+
+/// Track metadata kept in memory for a while, keyed by track uri.
+struct TrackCache {
+    entries: HashMap<String, CachedTrack>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+impl TrackCache {
+    fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            ttl,
+            capacity,
+        }
+    }
+
+    fn get(&self, uri: &str) -> Option<&TrackInfo> {
+        self.entries
+            .get(uri)
+            .filter(|entry| entry.cached_at.elapsed() < self.ttl)
+            .map(|entry| &entry.info)
+    }
+
+    /// Makes room for a new track by dropping expired tracks, and the oldest one
+    /// if that isn't enough.
+    fn insert(&mut self, info: TrackInfo) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&info.id) {
+            let ttl = self.ttl;
+            self.entries
+                .retain(|_, entry| entry.cached_at.elapsed() < ttl);
+        }
+
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&info.id) {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.cached_at)
+                .map(|(uri, _)| uri.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.entries.insert(
+            info.id.clone(),
+            CachedTrack {
+                info,
+                cached_at: Instant::now(),
+            },
+        );
+    }
+}
+
+/// Wraps a provider to serve track metadata from memory, then from the database,
+/// before asking the provider. Tracks seen in search results and track lists are
+/// remembered too, so queueing one of them usually doesn't need a lookup at all.
+#[derive(Clone)]
+pub struct CachedProvider<P: MusicProvider> {
+    provider: P,
+    cache: Arc<Mutex<TrackCache>>,
+    db: Database,
+    settings: TrackCacheSettings,
+}
+
+impl<P: MusicProvider> CachedProvider<P> {
+    pub fn new(provider: P, db: Database, settings: TrackCacheSettings) -> Self {
+        let cache = TrackCache::new(Duration::from_secs(settings.ttl_secs), settings.capacity);
+        Self {
+            provider,
+            cache: Arc::new(Mutex::new(cache)),
+            db,
+            settings,
+        }
+    }
+
+    async fn store(&self, tracks: &[TrackInfo]) {
+        self.remember(tracks);
+
+        if self.settings.persist {
+            if let Err(err) = self.db.store_tracks(tracks).await {
+                log::error!("Failed to store track metadata, {err}");
+            }
+        }
+    }
+
+    fn remember(&self, tracks: &[TrackInfo]) {
+        let mut cache = self.cache.lock().unwrap();
+        for track in tracks {
+            cache.insert(track.clone());
+        }
+    }
+
+    fn recall(&self, uris: &[String]) -> HashMap<String, TrackInfo> {
+        let cache = self.cache.lock().unwrap();
+        uris.iter()
+            .filter_map(|uri| cache.get(uri).map(|info| (uri.clone(), info.clone())))
+            .collect()
+    }
+
+    async fn load(&self, uris: &[String]) -> Result<Vec<TrackInfo>, anyhow::Error> {
+        if !self.settings.persist || uris.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let cutoff = Utc::now().timestamp() - self.settings.ttl_secs as i64;
+        let fetched_since = Utc.timestamp_opt(cutoff, 0).unwrap();
+        let tracks = self.db.get_tracks(uris, fetched_since).await?;
+        self.remember(&tracks);
+        Ok(tracks)
+    }
+}
+
+#[async_trait]
+impl<P: MusicProvider> MusicProvider for CachedProvider<P> {
+    fn authorize_url(&self) -> Result<String, anyhow::Error> {
+        self.provider.authorize_url()
+    }
+
+    async fn request_token(&self, code: &str) -> Result<String, anyhow::Error> {
+        self.provider.request_token(code).await
+    }
+
+    async fn search(
+        &self,
+        session_id: Uuid,
+        query: &str,
+        types: &[SearchKind],
+        page: PageRequest,
+    ) -> Result<SearchResult, anyhow::Error> {
+        let result = self.provider.search(session_id, query, types, page).await?;
+        self.store(&result.tracks.items).await;
+        self.store(&result.episodes.items).await;
+        Ok(result)
+    }
+
+    async fn album_tracks(
+        &self,
+        session_id: Uuid,
+        id: &AlbumId,
+        page: PageRequest,
+    ) -> Result<Page<TrackInfo>, anyhow::Error> {
+        let tracks = self.provider.album_tracks(session_id, id, page).await?;
+        self.store(&tracks.items).await;
+        Ok(tracks)
+    }
+
+    async fn playlist_tracks(
+        &self,
+        session_id: Uuid,
+        id: &PlaylistId,
+        page: PageRequest,
+    ) -> Result<Page<TrackInfo>, anyhow::Error> {
+        let tracks = self.provider.playlist_tracks(session_id, id, page).await?;
+        self.store(&tracks.items).await;
+        Ok(tracks)
+    }
+
+    async fn user_playlists(
+        &self,
+        session_id: Uuid,
+        page: PageRequest,
+    ) -> Result<Page<PlaylistInfo>, anyhow::Error> {
+        self.provider.user_playlists(session_id, page).await
+    }
+
+    async fn saved_tracks(
+        &self,
+        session_id: Uuid,
+        page: PageRequest,
+    ) -> Result<Page<TrackInfo>, anyhow::Error> {
+        let tracks = self.provider.saved_tracks(session_id, page).await?;
+        self.store(&tracks.items).await;
+        Ok(tracks)
+    }
+
+    async fn top_tracks(
+        &self,
+        session_id: Uuid,
+        page: PageRequest,
+    ) -> Result<Page<TrackInfo>, anyhow::Error> {
+        let tracks = self.provider.top_tracks(session_id, page).await?;
+        self.store(&tracks.items).await;
+        Ok(tracks)
+    }
+
+    async fn recommendations(
+        &self,
+        session_id: Uuid,
+        seeds: &[ItemId],
+        limit: u32,
+    ) -> Result<Vec<TrackInfo>, anyhow::Error> {
+        let tracks = self
+            .provider
+            .recommendations(session_id, seeds, limit)
+            .await?;
+        self.store(&tracks).await;
+        Ok(tracks)
+    }
+
+    async fn create_playlist(
+        &self,
+        session_id: Uuid,
+        name: &str,
+        items: &[ItemId],
+    ) -> Result<PlaylistInfo, anyhow::Error> {
+        self.provider.create_playlist(session_id, name, items).await
+    }
+
+    async fn track(&self, session_id: Uuid, id: &ItemId) -> Result<TrackInfo, anyhow::Error> {
+        self.tracks(session_id, std::slice::from_ref(id))
+            .await?
+            .pop()
+            .ok_or_else(|| anyhow::anyhow!("Unknown item {}", id))
+    }
+
+    async fn tracks(
+        &self,
+        session_id: Uuid,
+        ids: &[ItemId],
+    ) -> Result<Vec<TrackInfo>, anyhow::Error> {
+        let uris: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+        let mut found = self.recall(&uris);
+
+        let missing: Vec<String> = uris
+            .iter()
+            .filter(|uri| !found.contains_key(*uri))
+            .cloned()
+            .collect();
+        for track in self.load(&missing).await? {
+            found.insert(track.id.clone(), track);
+        }
+
+        let missing: Vec<ItemId> = ids
+            .iter()
+            .filter(|id| !found.contains_key(&id.to_string()))
+            .cloned()
+            .collect();
+        if !missing.is_empty() {
+            let tracks = self.provider.tracks(session_id, &missing).await?;
+            self.store(&tracks).await;
+            for track in tracks {
+                found.insert(track.id.clone(), track);
+            }
+        }
+
+        Ok(uris
+            .iter()
+            .filter_map(|uri| found.get(uri).cloned())
+            .collect())
+    }
+
+    async fn start_playback(&self, session_id: Uuid, id: &ItemId) -> Result<(), anyhow::Error> {
+        self.provider.start_playback(session_id, id).await
+    }
+
+    async fn resume_playback(&self, session_id: Uuid) -> Result<(), anyhow::Error> {
+        self.provider.resume_playback(session_id).await
+    }
+
+    async fn pause_playback(&self, session_id: Uuid) -> Result<(), anyhow::Error> {
+        self.provider.pause_playback(session_id).await
+    }
+
+    async fn seek(&self, session_id: Uuid, position: Duration) -> Result<(), anyhow::Error> {
+        self.provider.seek(session_id, position).await
+    }
+
+    async fn set_volume(&self, session_id: Uuid, volume_percent: u8) -> Result<(), anyhow::Error> {
+        self.provider.set_volume(session_id, volume_percent).await
+    }
+
+    async fn add_to_queue(&self, session_id: Uuid, id: &ItemId) -> Result<(), anyhow::Error> {
+        self.provider.add_to_queue(session_id, id).await
+    }
+
+    async fn current_playback(
+        &self,
+        session_id: Uuid,
+    ) -> Result<Option<CurrentPlayback>, anyhow::Error> {
+        self.provider.current_playback(session_id).await
+    }
+
+    async fn devices(&self, session_id: Uuid) -> Result<Vec<DeviceInfo>, anyhow::Error> {
+        self.provider.devices(session_id).await
+    }
+
+    async fn transfer_playback(
+        &self,
+        session_id: Uuid,
+        device_id: &str,
+    ) -> Result<(), anyhow::Error> {
+        self.provider.transfer_playback(session_id, device_id).await
+    }
+
+    async fn refresh_token(&self, session_id: Uuid) -> Result<(), anyhow::Error> {
+        self.provider.refresh_token(session_id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::item::ItemKind;
+
+    fn info(n: u8) -> TrackInfo {
+        TrackInfo {
+            name: format!("Song {n}"),
+            artists: vec!["Artist".to_string()],
+            id: format!("spotify:track:{:0>22}", n),
+            album: "Album".to_string(),
+            images: Vec::new(),
+            duration_ms: 180_000,
+            explicit: false,
+            popularity: 0,
+            preview_url: None,
+            kind: ItemKind::Track,
+        }
+    }
+
+    #[test]
+    fn returns_tracks_until_they_expire() {
+        let mut cache = TrackCache::new(Duration::from_secs(60), 10);
+        cache.insert(info(1));
+        assert_eq!(
+            cache.get(&info(1).id).map(|t| t.name.clone()),
+            Some(info(1).name)
+        );
+        assert!(cache.get(&info(2).id).is_none());
+
+        let mut expired = TrackCache::new(Duration::ZERO, 10);
+        expired.insert(info(1));
+        assert!(expired.get(&info(1).id).is_none());
+    }
+
+    #[test]
+    fn evicts_the_oldest_track_when_full() {
+        let mut cache = TrackCache::new(Duration::from_secs(60), 2);
+        cache.insert(info(1));
+        std::thread::sleep(Duration::from_millis(5));
+        cache.insert(info(2));
+        cache.insert(info(3));
+
+        assert!(cache.get(&info(1).id).is_none());
+        assert!(cache.get(&info(2).id).is_some());
+        assert!(cache.get(&info(3).id).is_some());
+    }
+
+    #[test]
+    fn refreshing_a_track_does_not_evict_others() {
+        let mut cache = TrackCache::new(Duration::from_secs(60), 2);
+        cache.insert(info(1));
+        cache.insert(info(2));
+        cache.insert(info(2));
+
+        assert!(cache.get(&info(1).id).is_some());
+        assert!(cache.get(&info(2).id).is_some());
+    }
+}
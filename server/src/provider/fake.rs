@@ -0,0 +1,645 @@
+use crate::catalog::{
+    AlbumInfo, ArtistInfo, Page, PageRequest, PlaylistInfo, SearchKind, MAX_PAGE_LIMIT,
+};
+use crate::controller::messages::DeviceInfo;
+use crate::item::{ItemId, ItemKind};
+use crate::provider::{CurrentPlayback, MusicProvider, PlayingItem};
+use crate::session_agent::{SearchResult, ShowInfo, TrackInfo};
+use async_trait::async_trait;
+use rspotify::model::{AlbumId, PlaylistId};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use uuid::Uuid;
+
+pub const FAKE_AUTHORIZATION_CODE: &str = "fake-code";
+const FAKE_TOKEN: &str = "fake-token";
+
+struct FakeTrack {
+    info: TrackInfo,
+    duration: Duration,
+}
+
+#[derive(Default)]
+struct FakePlayer {
+    current: Option<ItemId>,
+    position: Duration,
+    is_playing: bool,
+    queue: VecDeque<ItemId>,
+    active_device: Option<String>,
+    volume_percent: Option<u32>,
+}
+
+/// An album or a playlist and the items on it.
+struct FakeCollection<T> {
+    info: T,
+    items: Vec<ItemId>,
+}
+
+#[derive(Default)]
+struct FakeState {
+    catalog: Vec<FakeTrack>,
+    shows: Vec<ShowInfo>,
+    artists: Vec<ArtistInfo>,
+    albums: Vec<FakeCollection<AlbumInfo>>,
+    playlists: Vec<FakeCollection<PlaylistInfo>>,
+    saved: Vec<ItemId>,
+    top: Vec<ItemId>,
+    devices: Vec<DeviceInfo>,
+    players: HashMap<Uuid, FakePlayer>,
+    search_delays: HashMap<Uuid, Duration>,
+    track_lookups: usize,
+}
+
+impl FakeState {
+    fn track(&self, id: &ItemId) -> Result<&FakeTrack, anyhow::Error> {
+        let id = id.to_string();
+        self.catalog
+            .iter()
+            .find(|track| track.info.id == id)
+            .ok_or_else(|| anyhow::anyhow!("Unknown item {}", id))
+    }
+
+    fn infos(&self, ids: &[ItemId]) -> Result<Vec<TrackInfo>, anyhow::Error> {
+        ids.iter()
+            .map(|id| self.track(id).map(|track| track.info.clone()))
+            .collect()
+    }
+}
+
+/// An in-memory stand-in for a music service. Playback is simulated per session
+/// on a clock that only moves when `advance` is called, so tests stay deterministic.
+#[derive(Clone, Default)]
+pub struct FakeProvider {
+    state: Arc<Mutex<FakeState>>,
+}
+
+impl FakeProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_track(&self, id: &ItemId, name: &str, artists: &[&str], duration: Duration) {
+        let track = FakeTrack {
+            info: TrackInfo {
+                kind: ItemKind::Track,
+                name: name.to_string(),
+                artists: artists.iter().map(|artist| artist.to_string()).collect(),
+                id: id.to_string(),
+                album: format!("{name} - Single"),
+                images: Vec::new(),
+                duration_ms: duration.as_millis() as u64,
+                explicit: false,
+                popularity: 50,
+                preview_url: None,
+            },
+            duration,
+        };
+        self.state.lock().unwrap().catalog.push(track);
+    }
+
+    pub fn add_episode(&self, id: &ItemId, name: &str, show: &str, duration: Duration) {
+        let episode = FakeTrack {
+            info: TrackInfo {
+                kind: ItemKind::Episode,
+                name: name.to_string(),
+                artists: vec![format!("{show} Publishing")],
+                id: id.to_string(),
+                album: show.to_string(),
+                images: Vec::new(),
+                duration_ms: duration.as_millis() as u64,
+                explicit: false,
+                popularity: 0,
+                preview_url: None,
+            },
+            duration,
+        };
+        self.state.lock().unwrap().catalog.push(episode);
+    }
+
+    pub fn add_show(&self, id: &str, name: &str) {
+        let show = ShowInfo {
+            id: id.to_string(),
+            name: name.to_string(),
+            publisher: format!("{name} Publishing"),
+            images: Vec::new(),
+        };
+        self.state.lock().unwrap().shows.push(show);
+    }
+
+    pub fn add_artist(&self, id: &str, name: &str) {
+        let artist = ArtistInfo {
+            id: id.to_string(),
+            name: name.to_string(),
+            genres: Vec::new(),
+            images: Vec::new(),
+        };
+        self.state.lock().unwrap().artists.push(artist);
+    }
+
+    pub fn add_album(&self, id: &str, name: &str, artists: &[&str], tracks: &[ItemId]) {
+        let album = FakeCollection {
+            info: AlbumInfo {
+                id: id.to_string(),
+                name: name.to_string(),
+                artists: artists.iter().map(|artist| artist.to_string()).collect(),
+                images: Vec::new(),
+                release_date: None,
+            },
+            items: tracks.to_vec(),
+        };
+        self.state.lock().unwrap().albums.push(album);
+    }
+
+    pub fn add_playlist(&self, id: &str, name: &str, owner: &str, items: &[ItemId]) {
+        let playlist = FakeCollection {
+            info: PlaylistInfo {
+                id: id.to_string(),
+                name: name.to_string(),
+                owner: Some(owner.to_string()),
+                images: Vec::new(),
+                total_tracks: items.len() as u32,
+            },
+            items: items.to_vec(),
+        };
+        self.state.lock().unwrap().playlists.push(playlist);
+    }
+
+    /// The items on a playlist, or none if there is no such playlist.
+    pub fn playlist_items(&self, id: &str) -> Option<Vec<ItemId>> {
+        let state = self.state.lock().unwrap();
+        state
+            .playlists
+            .iter()
+            .find(|playlist| playlist.info.id == id)
+            .map(|playlist| playlist.items.clone())
+    }
+
+    /// Adds the item to the host's saved tracks, ahead of those saved earlier.
+    pub fn save_track(&self, id: &ItemId) {
+        self.state.lock().unwrap().saved.insert(0, id.clone());
+    }
+
+    /// Ranks the item below the host's other top tracks.
+    pub fn add_top_track(&self, id: &ItemId) {
+        self.state.lock().unwrap().top.push(id.clone());
+    }
+
+    pub fn add_device(&self, id: &str, name: &str, dev_type: &str) {
+        let device = DeviceInfo {
+            id: id.to_string(),
+            name: name.to_string(),
+            dev_type: dev_type.to_string(),
+            volume_percent: None,
+        };
+        self.state.lock().unwrap().devices.push(device);
+    }
+
+    pub fn now_playing(&self, session_id: Uuid) -> Option<ItemId> {
+        let state = self.state.lock().unwrap();
+        state
+            .players
+            .get(&session_id)
+            .and_then(|player| player.current.clone())
+    }
+
+    pub fn position(&self, session_id: Uuid) -> Option<Duration> {
+        let state = self.state.lock().unwrap();
+        state
+            .players
+            .get(&session_id)
+            .filter(|player| player.current.is_some())
+            .map(|player| player.position)
+    }
+
+    pub fn volume(&self, session_id: Uuid) -> Option<u32> {
+        let state = self.state.lock().unwrap();
+        state
+            .players
+            .get(&session_id)
+            .and_then(|player| player.volume_percent)
+    }
+
+    pub fn is_playing(&self, session_id: Uuid) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .players
+            .get(&session_id)
+            .map(|player| player.is_playing)
+            .unwrap_or(false)
+    }
+
+    pub fn active_device(&self, session_id: Uuid) -> Option<String> {
+        let state = self.state.lock().unwrap();
+        state
+            .players
+            .get(&session_id)
+            .and_then(|player| player.active_device.clone())
+    }
+
+    /// How many times track metadata was asked for, in any session.
+    pub fn track_lookups(&self) -> usize {
+        self.state.lock().unwrap().track_lookups
+    }
+
+    /// Makes every search in the session take this long, like a slow provider would.
+    pub fn delay_search(&self, session_id: Uuid, by: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.search_delays.insert(session_id, by);
+    }
+
+    pub fn pause(&self, session_id: Uuid) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(player) = state.players.get_mut(&session_id) {
+            player.is_playing = false;
+        }
+    }
+
+    /// Moves the session's playback clock forward, rolling over into the
+    /// provider side queue when the current track ends.
+    pub fn advance(&self, session_id: Uuid, by: Duration) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let player = match state.players.get_mut(&session_id) {
+            Some(player) => player,
+            None => return,
+        };
+
+        if !player.is_playing {
+            return;
+        }
+
+        let mut remaining = by;
+        while let Some(current) = player.current.clone() {
+            let duration = match state
+                .catalog
+                .iter()
+                .find(|t| t.info.id == current.to_string())
+            {
+                Some(track) => track.duration,
+                None => break,
+            };
+
+            let left = duration.saturating_sub(player.position);
+            if remaining < left {
+                player.position += remaining;
+                break;
+            }
+
+            remaining -= left;
+            match player.queue.pop_front() {
+                Some(next) => {
+                    player.current = Some(next);
+                    player.position = Duration::ZERO;
+                }
+                None => {
+                    player.position = duration;
+                    player.is_playing = false;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl MusicProvider for FakeProvider {
+    fn authorize_url(&self) -> Result<String, anyhow::Error> {
+        Ok(format!(
+            "/callback?code={}&state=fake",
+            FAKE_AUTHORIZATION_CODE
+        ))
+    }
+
+    async fn request_token(&self, code: &str) -> Result<String, anyhow::Error> {
+        if code != FAKE_AUTHORIZATION_CODE {
+            anyhow::bail!("Invalid authorization code {}", code);
+        }
+        Ok(FAKE_TOKEN.to_string())
+    }
+
+    async fn search(
+        &self,
+        session_id: Uuid,
+        query: &str,
+        types: &[SearchKind],
+        page: PageRequest,
+    ) -> Result<SearchResult, anyhow::Error> {
+        let delay = self
+            .state
+            .lock()
+            .unwrap()
+            .search_delays
+            .get(&session_id)
+            .copied();
+        if let Some(delay) = delay {
+            tokio::time::sleep(delay).await;
+        }
+
+        let query = query.to_lowercase();
+        let state = self.state.lock().unwrap();
+        let found = |name: &str| name.to_lowercase().contains(&query);
+        let items = |kind: ItemKind| -> Vec<TrackInfo> {
+            state
+                .catalog
+                .iter()
+                .filter(|track| track.info.kind == kind)
+                .filter(|track| {
+                    found(track.info.name.as_str())
+                        || track
+                            .info
+                            .artists
+                            .iter()
+                            .any(|artist| found(artist.as_str()))
+                })
+                .map(|track| track.info.clone())
+                .collect()
+        };
+
+        let mut result = SearchResult::default();
+        for kind in types {
+            match kind {
+                SearchKind::Track => result.tracks = Page::slice(&items(ItemKind::Track), page),
+                SearchKind::Episode => {
+                    result.episodes = Page::slice(&items(ItemKind::Episode), page)
+                }
+                SearchKind::Album => {
+                    let albums: Vec<AlbumInfo> = state
+                        .albums
+                        .iter()
+                        .map(|album| album.info.clone())
+                        .filter(|album| found(album.name.as_str()))
+                        .collect();
+                    result.albums = Page::slice(&albums, page);
+                }
+                SearchKind::Artist => {
+                    let artists: Vec<ArtistInfo> = state
+                        .artists
+                        .iter()
+                        .filter(|artist| found(artist.name.as_str()))
+                        .cloned()
+                        .collect();
+                    result.artists = Page::slice(&artists, page);
+                }
+                SearchKind::Playlist => {
+                    let playlists: Vec<PlaylistInfo> = state
+                        .playlists
+                        .iter()
+                        .map(|playlist| playlist.info.clone())
+                        .filter(|playlist| found(playlist.name.as_str()))
+                        .collect();
+                    result.playlists = Page::slice(&playlists, page);
+                }
+                SearchKind::Show => {
+                    let shows: Vec<ShowInfo> = state
+                        .shows
+                        .iter()
+                        .filter(|show| found(show.name.as_str()))
+                        .cloned()
+                        .collect();
+                    result.shows = Page::slice(&shows, page);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    async fn album_tracks(
+        &self,
+        _session_id: Uuid,
+        id: &AlbumId,
+        page: PageRequest,
+    ) -> Result<Page<TrackInfo>, anyhow::Error> {
+        let state = self.state.lock().unwrap();
+        let id = id.to_string();
+        let album = state
+            .albums
+            .iter()
+            .find(|album| album.info.id == id)
+            .ok_or_else(|| anyhow::anyhow!("Unknown album {}", id))?;
+        Ok(Page::slice(&state.infos(&album.items)?, page))
+    }
+
+    async fn playlist_tracks(
+        &self,
+        _session_id: Uuid,
+        id: &PlaylistId,
+        page: PageRequest,
+    ) -> Result<Page<TrackInfo>, anyhow::Error> {
+        let state = self.state.lock().unwrap();
+        let id = id.to_string();
+        let playlist = state
+            .playlists
+            .iter()
+            .find(|playlist| playlist.info.id == id)
+            .ok_or_else(|| anyhow::anyhow!("Unknown playlist {}", id))?;
+        Ok(Page::slice(&state.infos(&playlist.items)?, page))
+    }
+
+    /// Every playlist in the catalog counts as one of the host's.
+    async fn user_playlists(
+        &self,
+        _session_id: Uuid,
+        page: PageRequest,
+    ) -> Result<Page<PlaylistInfo>, anyhow::Error> {
+        let state = self.state.lock().unwrap();
+        let playlists: Vec<PlaylistInfo> = state
+            .playlists
+            .iter()
+            .map(|playlist| playlist.info.clone())
+            .collect();
+        Ok(Page::slice(&playlists, page))
+    }
+
+    async fn saved_tracks(
+        &self,
+        _session_id: Uuid,
+        page: PageRequest,
+    ) -> Result<Page<TrackInfo>, anyhow::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(Page::slice(&state.infos(&state.saved)?, page))
+    }
+
+    async fn top_tracks(
+        &self,
+        _session_id: Uuid,
+        page: PageRequest,
+    ) -> Result<Page<TrackInfo>, anyhow::Error> {
+        let state = self.state.lock().unwrap();
+        Ok(Page::slice(&state.infos(&state.top)?, page))
+    }
+
+    /// Recommends the tracks of the catalog that aren't among the seeds, in the
+    /// order they were added.
+    async fn recommendations(
+        &self,
+        _session_id: Uuid,
+        seeds: &[ItemId],
+        limit: u32,
+    ) -> Result<Vec<TrackInfo>, anyhow::Error> {
+        let state = self.state.lock().unwrap();
+        let seeds: Vec<String> = seeds.iter().map(|id| id.to_string()).collect();
+        if seeds.is_empty() {
+            return Ok(Vec::new());
+        }
+        Ok(state
+            .catalog
+            .iter()
+            .filter(|track| track.info.kind == ItemKind::Track)
+            .filter(|track| !seeds.contains(&track.info.id))
+            .take(limit as usize)
+            .map(|track| track.info.clone())
+            .collect())
+    }
+
+    /// The new playlist joins the catalog, owned by the host.
+    async fn create_playlist(
+        &self,
+        _session_id: Uuid,
+        name: &str,
+        items: &[ItemId],
+    ) -> Result<PlaylistInfo, anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        let info = PlaylistInfo {
+            id: format!("spotify:playlist:{:0>22}", 900 + state.playlists.len()),
+            name: name.to_string(),
+            owner: Some("Host".to_string()),
+            images: Vec::new(),
+            total_tracks: items.len() as u32,
+        };
+        state.playlists.push(FakeCollection {
+            info: info.clone(),
+            items: items.to_vec(),
+        });
+        Ok(info)
+    }
+
+    async fn track(&self, _session_id: Uuid, id: &ItemId) -> Result<TrackInfo, anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        state.track_lookups += 1;
+        Ok(state.track(id)?.info.clone())
+    }
+
+    async fn tracks(
+        &self,
+        _session_id: Uuid,
+        ids: &[ItemId],
+    ) -> Result<Vec<TrackInfo>, anyhow::Error> {
+        // Like Spotify, only so many ids are looked up at once
+        if ids.len() > MAX_PAGE_LIMIT as usize {
+            anyhow::bail!("Can't look up more than {MAX_PAGE_LIMIT} items at once");
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.track_lookups += 1;
+        let mut tracks = Vec::new();
+        for id in ids {
+            tracks.push(state.track(id)?.info.clone());
+        }
+        Ok(tracks)
+    }
+
+    async fn start_playback(&self, session_id: Uuid, id: &ItemId) -> Result<(), anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        state.track(id)?;
+        let player = state.players.entry(session_id).or_default();
+        player.current = Some(id.clone());
+        player.position = Duration::ZERO;
+        player.is_playing = true;
+        Ok(())
+    }
+
+    async fn resume_playback(&self, session_id: Uuid) -> Result<(), anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        let player = state.players.entry(session_id).or_default();
+        if player.current.is_none() {
+            anyhow::bail!("Nothing to resume");
+        }
+        player.is_playing = true;
+        Ok(())
+    }
+
+    async fn pause_playback(&self, session_id: Uuid) -> Result<(), anyhow::Error> {
+        self.pause(session_id);
+        Ok(())
+    }
+
+    async fn seek(&self, session_id: Uuid, position: Duration) -> Result<(), anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        let player = state.players.entry(session_id).or_default();
+        if player.current.is_none() {
+            anyhow::bail!("Nothing to seek in");
+        }
+        player.position = position;
+        Ok(())
+    }
+
+    async fn set_volume(&self, session_id: Uuid, volume_percent: u8) -> Result<(), anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        let player = state.players.entry(session_id).or_default();
+        player.volume_percent = Some(volume_percent as u32);
+        Ok(())
+    }
+
+    async fn add_to_queue(&self, session_id: Uuid, id: &ItemId) -> Result<(), anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        state.track(id)?;
+        let player = state.players.entry(session_id).or_default();
+        player.queue.push_back(id.clone());
+        Ok(())
+    }
+
+    async fn current_playback(
+        &self,
+        session_id: Uuid,
+    ) -> Result<Option<CurrentPlayback>, anyhow::Error> {
+        let state = self.state.lock().unwrap();
+        let player = match state.players.get(&session_id) {
+            Some(player) => player,
+            None => return Ok(None),
+        };
+        let current = match &player.current {
+            Some(current) => current,
+            None => return Ok(None),
+        };
+
+        Ok(Some(CurrentPlayback {
+            item: Some(PlayingItem {
+                id: Some(current.clone()),
+                duration: state.track(current)?.duration,
+            }),
+            progress: Some(player.position),
+            is_playing: player.is_playing,
+            device: state
+                .devices
+                .iter()
+                .find(|device| Some(&device.id) == player.active_device.as_ref())
+                .map(|device| DeviceInfo {
+                    volume_percent: player.volume_percent,
+                    ..device.clone()
+                }),
+        }))
+    }
+
+    async fn devices(&self, _session_id: Uuid) -> Result<Vec<DeviceInfo>, anyhow::Error> {
+        Ok(self.state.lock().unwrap().devices.clone())
+    }
+
+    async fn transfer_playback(
+        &self,
+        session_id: Uuid,
+        device_id: &str,
+    ) -> Result<(), anyhow::Error> {
+        let mut state = self.state.lock().unwrap();
+        if !state.devices.iter().any(|device| device.id == device_id) {
+            anyhow::bail!("Unknown device {}", device_id);
+        }
+        let player = state.players.entry(session_id).or_default();
+        player.active_device = Some(device_id.to_string());
+        Ok(())
+    }
+
+    async fn refresh_token(&self, _session_id: Uuid) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+pub mod cached;
+pub mod fake;
+pub mod spotify;
+
+pub use cached::*;
+pub use fake::*;
+pub use spotify::*;
+
+use crate::catalog::{Page, PageRequest, PlaylistInfo, SearchKind};
+use crate::controller::messages::DeviceInfo;
+use crate::item::ItemId;
+use crate::session_agent::{SearchResult, TrackInfo};
+use async_trait::async_trait;
+use rspotify::model::{AlbumId, PlaylistId};
+use std::time::Duration;
+use uuid::Uuid;
+
+/// The track or episode a device is playing. Local files have no id.
+pub struct PlayingItem {
+    pub id: Option<ItemId>,
+    pub duration: Duration,
+}
+
+pub struct CurrentPlayback {
+    pub item: Option<PlayingItem>,
+    pub progress: Option<Duration>,
+    pub is_playing: bool,
+    pub device: Option<DeviceInfo>,
+}
+
+/// A music service that a session's queue is played through. Every call is
+/// made on behalf of a session, whose credentials the provider looks up itself.
+#[async_trait]
+pub trait MusicProvider: Send + Sync + 'static {
+    fn authorize_url(&self) -> Result<String, anyhow::Error>;
+
+    /// Exchanges the code handed to `/callback` for the token stored with a new session.
+    async fn request_token(&self, code: &str) -> Result<String, anyhow::Error>;
+
+    /// Finds a page of results matching the query for each of the given types.
+    async fn search(
+        &self,
+        session_id: Uuid,
+        query: &str,
+        types: &[SearchKind],
+        page: PageRequest,
+    ) -> Result<SearchResult, anyhow::Error>;
+
+    async fn album_tracks(
+        &self,
+        session_id: Uuid,
+        id: &AlbumId,
+        page: PageRequest,
+    ) -> Result<Page<TrackInfo>, anyhow::Error>;
+
+    /// Playlists may hold episodes as well as tracks.
+    async fn playlist_tracks(
+        &self,
+        session_id: Uuid,
+        id: &PlaylistId,
+        page: PageRequest,
+    ) -> Result<Page<TrackInfo>, anyhow::Error>;
+
+    /// Playlists the session host made or follows.
+    async fn user_playlists(
+        &self,
+        session_id: Uuid,
+        page: PageRequest,
+    ) -> Result<Page<PlaylistInfo>, anyhow::Error>;
+
+    /// Tracks the session host saved to their library, most recently saved first.
+    async fn saved_tracks(
+        &self,
+        session_id: Uuid,
+        page: PageRequest,
+    ) -> Result<Page<TrackInfo>, anyhow::Error>;
+
+    /// The tracks the session host listened to the most lately.
+    async fn top_tracks(
+        &self,
+        session_id: Uuid,
+        page: PageRequest,
+    ) -> Result<Page<TrackInfo>, anyhow::Error>;
+
+    /// Up to `limit` tracks to play after the given ones, which may include
+    /// episodes but only tracks are taken into account.
+    async fn recommendations(
+        &self,
+        session_id: Uuid,
+        seeds: &[ItemId],
+        limit: u32,
+    ) -> Result<Vec<TrackInfo>, anyhow::Error>;
+
+    /// Saves the items, in order, to a new private playlist of the session host.
+    async fn create_playlist(
+        &self,
+        session_id: Uuid,
+        name: &str,
+        items: &[ItemId],
+    ) -> Result<PlaylistInfo, anyhow::Error>;
+
+    /// Looks up a track or an episode.
+    async fn track(&self, session_id: Uuid, id: &ItemId) -> Result<TrackInfo, anyhow::Error>;
+
+    async fn tracks(
+        &self,
+        session_id: Uuid,
+        ids: &[ItemId],
+    ) -> Result<Vec<TrackInfo>, anyhow::Error>;
+
+    async fn start_playback(&self, session_id: Uuid, id: &ItemId) -> Result<(), anyhow::Error>;
+
+    async fn resume_playback(&self, session_id: Uuid) -> Result<(), anyhow::Error>;
+
+    async fn pause_playback(&self, session_id: Uuid) -> Result<(), anyhow::Error>;
+
+    /// Moves playback of the current track to `position`.
+    async fn seek(&self, session_id: Uuid, position: Duration) -> Result<(), anyhow::Error>;
+
+    async fn set_volume(&self, session_id: Uuid, volume_percent: u8) -> Result<(), anyhow::Error>;
+
+    async fn add_to_queue(&self, session_id: Uuid, id: &ItemId) -> Result<(), anyhow::Error>;
+
+    async fn current_playback(
+        &self,
+        session_id: Uuid,
+    ) -> Result<Option<CurrentPlayback>, anyhow::Error>;
+
+    async fn devices(&self, session_id: Uuid) -> Result<Vec<DeviceInfo>, anyhow::Error>;
+
+    async fn transfer_playback(
+        &self,
+        session_id: Uuid,
+        device_id: &str,
+    ) -> Result<(), anyhow::Error>;
+
+    async fn refresh_token(&self, session_id: Uuid) -> Result<(), anyhow::Error>;
+}
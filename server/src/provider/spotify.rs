@@ -0,0 +1,469 @@
+use crate::catalog::{
+    AlbumInfo, ArtistInfo, Page, PageRequest, PlaylistInfo, SearchKind, MAX_PAGE_LIMIT,
+};
+use crate::configuration::SpotifySettings;
+use crate::controller::messages::DeviceInfo;
+use crate::db::Database;
+use crate::item::ItemId;
+use crate::provider::{CurrentPlayback, MusicProvider, PlayingItem};
+use crate::session_agent::{SearchResult, ShowInfo, TrackInfo};
+use crate::spotify::{get_default_spotify, get_token_string};
+use async_trait::async_trait;
+use rspotify::clients::{BaseClient, OAuthClient};
+use rspotify::model::enums::misc::Market;
+use rspotify::model::SearchResult::{Albums, Artists, Episodes, Playlists, Shows, Tracks};
+use rspotify::model::{AdditionalType, AlbumId, ArtistId, EpisodeId, PlayableItem, PlaylistId};
+use rspotify::model::{SearchType, TrackId};
+use std::time::Duration;
+use uuid::Uuid;
+
+#[derive(Clone)]
+pub struct SpotifyProvider {
+    db: Database,
+    settings: SpotifySettings,
+}
+
+impl SpotifyProvider {
+    pub fn new(db: Database, settings: SpotifySettings) -> Self {
+        Self { db, settings }
+    }
+}
+
+#[async_trait]
+impl MusicProvider for SpotifyProvider {
+    fn authorize_url(&self) -> Result<String, anyhow::Error> {
+        let spotify = get_default_spotify(&self.settings);
+        Ok(spotify.get_authorize_url(false)?)
+    }
+
+    async fn request_token(&self, code: &str) -> Result<String, anyhow::Error> {
+        let mut spotify = get_default_spotify(&self.settings);
+        spotify.request_token(code).await?;
+        Ok(get_token_string(&spotify).await?)
+    }
+
+    async fn search(
+        &self,
+        session_id: Uuid,
+        query: &str,
+        types: &[SearchKind],
+        page: PageRequest,
+    ) -> Result<SearchResult, anyhow::Error> {
+        let spotify = self.db.get_spotify(session_id).await?;
+        let mut result = SearchResult::default();
+
+        for kind in types {
+            let found = spotify
+                .search(
+                    query,
+                    &search_type(*kind),
+                    Some(&Market::FromToken),
+                    None,
+                    Some(page.limit),
+                    Some(page.offset),
+                )
+                .await?;
+
+            match found {
+                Tracks(tracks) => {
+                    result.tracks = page_of(tracks, |track| TrackInfo::try_from(track).ok())
+                }
+                Albums(albums) => {
+                    result.albums = page_of(albums, |album| AlbumInfo::try_from(album).ok())
+                }
+                Artists(artists) => {
+                    result.artists = page_of(artists, |artist| Some(ArtistInfo::from(artist)))
+                }
+                Playlists(playlists) => {
+                    result.playlists =
+                        page_of(playlists, |playlist| Some(PlaylistInfo::from(playlist)))
+                }
+                Shows(shows) => result.shows = page_of(shows, |show| Some(ShowInfo::from(show))),
+                // Episodes found by search don't name their show, so they are looked up again
+                Episodes(episodes) => {
+                    let ids: Vec<EpisodeId> = episodes
+                        .items
+                        .iter()
+                        .map(|episode| episode.id.clone())
+                        .collect();
+                    let full_episodes = if ids.is_empty() {
+                        Vec::new()
+                    } else {
+                        spotify
+                            .get_several_episodes(ids.iter(), Some(&Market::FromToken))
+                            .await?
+                    };
+                    result.episodes = Page {
+                        items: full_episodes.into_iter().map(TrackInfo::from).collect(),
+                        offset: episodes.offset,
+                        limit: episodes.limit,
+                        total: episodes.total,
+                    };
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    async fn album_tracks(
+        &self,
+        session_id: Uuid,
+        id: &AlbumId,
+        page: PageRequest,
+    ) -> Result<Page<TrackInfo>, anyhow::Error> {
+        let spotify = self.db.get_spotify(session_id).await?;
+        let album_tracks = spotify
+            .album_track_manual(id, Some(page.limit), Some(page.offset))
+            .await?;
+
+        // Album tracks come without the album, so the full tracks are looked up
+        let ids: Vec<TrackId> = album_tracks
+            .items
+            .iter()
+            .filter_map(|track| track.id.clone())
+            .collect();
+        let tracks = if ids.is_empty() {
+            Vec::new()
+        } else {
+            spotify.tracks(ids.iter(), None).await?
+        };
+
+        Ok(Page {
+            items: tracks
+                .into_iter()
+                .filter_map(|track| TrackInfo::try_from(track).ok())
+                .collect(),
+            offset: album_tracks.offset,
+            limit: album_tracks.limit,
+            total: album_tracks.total,
+        })
+    }
+
+    async fn playlist_tracks(
+        &self,
+        session_id: Uuid,
+        id: &PlaylistId,
+        page: PageRequest,
+    ) -> Result<Page<TrackInfo>, anyhow::Error> {
+        let spotify = self.db.get_spotify(session_id).await?;
+        let items = spotify
+            .playlist_items_manual(
+                id,
+                None,
+                Some(&Market::FromToken),
+                Some(page.limit),
+                Some(page.offset),
+            )
+            .await?;
+
+        Ok(page_of(items, |item| match item.track {
+            Some(PlayableItem::Track(track)) => TrackInfo::try_from(track).ok(),
+            Some(PlayableItem::Episode(episode)) => Some(TrackInfo::from(episode)),
+            None => None,
+        }))
+    }
+
+    async fn user_playlists(
+        &self,
+        session_id: Uuid,
+        page: PageRequest,
+    ) -> Result<Page<PlaylistInfo>, anyhow::Error> {
+        let spotify = self.db.get_spotify(session_id).await?;
+        let playlists = spotify
+            .current_user_playlists_manual(Some(page.limit), Some(page.offset))
+            .await?;
+        Ok(page_of(playlists, |playlist| {
+            Some(PlaylistInfo::from(playlist))
+        }))
+    }
+
+    async fn saved_tracks(
+        &self,
+        session_id: Uuid,
+        page: PageRequest,
+    ) -> Result<Page<TrackInfo>, anyhow::Error> {
+        let spotify = self.db.get_spotify(session_id).await?;
+        let saved = spotify
+            .current_user_saved_tracks_manual(
+                Some(&Market::FromToken),
+                Some(page.limit),
+                Some(page.offset),
+            )
+            .await?;
+        Ok(page_of(saved, |saved| {
+            TrackInfo::try_from(saved.track).ok()
+        }))
+    }
+
+    async fn top_tracks(
+        &self,
+        session_id: Uuid,
+        page: PageRequest,
+    ) -> Result<Page<TrackInfo>, anyhow::Error> {
+        let spotify = self.db.get_spotify(session_id).await?;
+        let tracks = spotify
+            .current_user_top_tracks_manual(None, Some(page.limit), Some(page.offset))
+            .await?;
+        Ok(page_of(tracks, |track| TrackInfo::try_from(track).ok()))
+    }
+
+    async fn recommendations(
+        &self,
+        session_id: Uuid,
+        seeds: &[ItemId],
+        limit: u32,
+    ) -> Result<Vec<TrackInfo>, anyhow::Error> {
+        // Spotify takes at most five seeds, and only tracks among them
+        let seed_tracks: Vec<&TrackId> = seeds
+            .iter()
+            .filter_map(|id| match id {
+                ItemId::Track(track_id) => Some(track_id),
+                ItemId::Episode(_) => None,
+            })
+            .take(5)
+            .collect();
+        if seed_tracks.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let spotify = self.db.get_spotify(session_id).await?;
+        let recommendations = spotify
+            .recommendations(
+                std::iter::empty(),
+                None::<Vec<&ArtistId>>,
+                None::<Vec<&str>>,
+                Some(seed_tracks),
+                Some(&Market::FromToken),
+                Some(limit),
+            )
+            .await?;
+
+        // Recommended tracks come without their album, so the full tracks are looked up
+        let ids: Vec<TrackId> = recommendations
+            .tracks
+            .iter()
+            .filter_map(|track| track.id.clone())
+            .collect();
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        Ok(spotify
+            .tracks(ids.iter(), None)
+            .await?
+            .into_iter()
+            .filter_map(|track| TrackInfo::try_from(track).ok())
+            .collect())
+    }
+
+    async fn create_playlist(
+        &self,
+        session_id: Uuid,
+        name: &str,
+        items: &[ItemId],
+    ) -> Result<PlaylistInfo, anyhow::Error> {
+        let spotify = self.db.get_spotify(session_id).await?;
+        let user = spotify.current_user().await?;
+        let mut playlist = spotify
+            .user_playlist_create(&user.id, name, Some(false), None, None)
+            .await?;
+        // Spotify adds at most a hundred items per request
+        for chunk in items.chunks(100) {
+            spotify
+                .playlist_add_items(&playlist.id, chunk.iter().map(|id| id.as_playable()), None)
+                .await?;
+        }
+
+        playlist.tracks.total = items.len() as u32;
+        Ok(PlaylistInfo::from(playlist))
+    }
+
+    async fn track(&self, session_id: Uuid, id: &ItemId) -> Result<TrackInfo, anyhow::Error> {
+        let spotify = self.db.get_spotify(session_id).await?;
+        match id {
+            ItemId::Track(track_id) => {
+                let track = spotify.track(track_id).await?;
+                TrackInfo::try_from(track).map_err(|_| anyhow::anyhow!("Track {} has no id", id))
+            }
+            ItemId::Episode(episode_id) => {
+                let episode = spotify
+                    .get_an_episode(episode_id, Some(&Market::FromToken))
+                    .await?;
+                Ok(TrackInfo::from(episode))
+            }
+        }
+    }
+
+    async fn tracks(
+        &self,
+        session_id: Uuid,
+        ids: &[ItemId],
+    ) -> Result<Vec<TrackInfo>, anyhow::Error> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let spotify = self.db.get_spotify(session_id).await?;
+        let mut track_ids: Vec<TrackId> = Vec::new();
+        let mut episode_ids: Vec<EpisodeId> = Vec::new();
+        for id in ids {
+            match id {
+                ItemId::Track(track_id) => track_ids.push(track_id.clone()),
+                ItemId::Episode(episode_id) => episode_ids.push(episode_id.clone()),
+            }
+        }
+
+        // Spotify looks up at most a page worth of ids at a time
+        let mut infos = Vec::new();
+        for chunk in track_ids.chunks(MAX_PAGE_LIMIT as usize) {
+            for track in spotify.tracks(chunk.iter(), None).await? {
+                if let Ok(info) = TrackInfo::try_from(track) {
+                    infos.push(info);
+                }
+            }
+        }
+        for chunk in episode_ids.chunks(MAX_PAGE_LIMIT as usize) {
+            let episodes = spotify
+                .get_several_episodes(chunk.iter(), Some(&Market::FromToken))
+                .await?;
+            infos.extend(episodes.into_iter().map(TrackInfo::from));
+        }
+
+        Ok(infos)
+    }
+
+    async fn start_playback(&self, session_id: Uuid, id: &ItemId) -> Result<(), anyhow::Error> {
+        let spotify = self.db.get_spotify(session_id).await?;
+        spotify
+            .start_uris_playback(Some(id.as_playable()), None, None, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn resume_playback(&self, session_id: Uuid) -> Result<(), anyhow::Error> {
+        let spotify = self.db.get_spotify(session_id).await?;
+        spotify.resume_playback(None, None).await?;
+        Ok(())
+    }
+
+    async fn pause_playback(&self, session_id: Uuid) -> Result<(), anyhow::Error> {
+        let spotify = self.db.get_spotify(session_id).await?;
+        spotify.pause_playback(None).await?;
+        Ok(())
+    }
+
+    async fn seek(&self, session_id: Uuid, position: Duration) -> Result<(), anyhow::Error> {
+        let spotify = self.db.get_spotify(session_id).await?;
+        spotify
+            .seek_track(position.as_millis() as u32, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn set_volume(&self, session_id: Uuid, volume_percent: u8) -> Result<(), anyhow::Error> {
+        let spotify = self.db.get_spotify(session_id).await?;
+        spotify.volume(volume_percent, None).await?;
+        Ok(())
+    }
+
+    async fn add_to_queue(&self, session_id: Uuid, id: &ItemId) -> Result<(), anyhow::Error> {
+        let spotify = self.db.get_spotify(session_id).await?;
+        match id {
+            ItemId::Track(id) => spotify.add_item_to_queue(id, None).await?,
+            ItemId::Episode(id) => spotify.add_item_to_queue(id, None).await?,
+        }
+        Ok(())
+    }
+
+    async fn current_playback(
+        &self,
+        session_id: Uuid,
+    ) -> Result<Option<CurrentPlayback>, anyhow::Error> {
+        let spotify = self.db.get_spotify(session_id).await?;
+        let context = match spotify
+            .current_playback(
+                None,
+                Some(&[AdditionalType::Track, AdditionalType::Episode]),
+            )
+            .await?
+        {
+            Some(context) => context,
+            None => return Ok(None),
+        };
+
+        let item = match context.item {
+            Some(PlayableItem::Track(track)) => Some(PlayingItem {
+                id: track.id.map(ItemId::from),
+                duration: track.duration,
+            }),
+            Some(PlayableItem::Episode(episode)) => Some(PlayingItem {
+                id: Some(ItemId::from(episode.id)),
+                duration: episode.duration,
+            }),
+            None => None,
+        };
+
+        Ok(Some(CurrentPlayback {
+            item,
+            progress: context.progress,
+            is_playing: context.is_playing,
+            device: DeviceInfo::try_from(context.device).ok(),
+        }))
+    }
+
+    async fn devices(&self, session_id: Uuid) -> Result<Vec<DeviceInfo>, anyhow::Error> {
+        let spotify = self.db.get_spotify(session_id).await?;
+        let devices = spotify.device().await?;
+
+        let mut device_infos = Vec::new();
+
+        for device in devices.into_iter() {
+            let dev_info = match DeviceInfo::try_from(device) {
+                Ok(info) => info,
+                Err(_) => continue,
+            };
+
+            device_infos.push(dev_info);
+        }
+
+        Ok(device_infos)
+    }
+
+    async fn transfer_playback(
+        &self,
+        session_id: Uuid,
+        device_id: &str,
+    ) -> Result<(), anyhow::Error> {
+        let spotify = self.db.get_spotify(session_id).await?;
+        spotify.transfer_playback(device_id, Some(false)).await?;
+        Ok(())
+    }
+
+    async fn refresh_token(&self, session_id: Uuid) -> Result<(), anyhow::Error> {
+        let spotify = self.db.get_spotify(session_id).await?;
+        spotify.refresh_token().await?;
+        self.db.set_spotify(session_id, &spotify).await?;
+        Ok(())
+    }
+}
+
+fn search_type(kind: SearchKind) -> SearchType {
+    match kind {
+        SearchKind::Track => SearchType::Track,
+        SearchKind::Album => SearchType::Album,
+        SearchKind::Artist => SearchType::Artist,
+        SearchKind::Playlist => SearchType::Playlist,
+        SearchKind::Episode => SearchType::Episode,
+        SearchKind::Show => SearchType::Show,
+    }
+}
+
+// Items the conversion gives up on, like local files without an id, are left out.
+fn page_of<T, U>(page: rspotify::model::Page<T>, convert: impl Fn(T) -> Option<U>) -> Page<U> {
+    Page {
+        items: page.items.into_iter().filter_map(convert).collect(),
+        offset: page.offset,
+        limit: page.limit,
+        total: page.total,
+    }
+}
@@ -0,0 +1,140 @@
+use crate::item::ItemId;
+use sqlx::types::chrono::{DateTime, Utc};
+use std::cmp::Reverse;
+use uuid::Uuid;
+
+/// A row of `queued_tracks` with everything that decides its place in the queue,
+/// and the client that queued it.
+#[derive(Clone, Debug)]
+pub struct QueueEntry {
+    pub track_id: ItemId,
+    pub votes: i32,
+    pub queued_at: DateTime<Utc>,
+    pub pinned_at: Option<DateTime<Utc>>,
+    pub position: Option<i32>,
+    pub moved_at: Option<DateTime<Utc>>,
+    pub client_id: Option<Uuid>,
+}
+
+/// Orders a session's queue. Pinned tracks come first, most recently pinned on
+/// top. Tracks the host moved are placed at their position among the rest, and
+/// everything else follows net score with ties played in the order they were queued.
+pub fn order_queue(entries: Vec<QueueEntry>) -> Vec<QueueEntry> {
+    let (mut pinned, rest): (Vec<_>, Vec<_>) =
+        entries.into_iter().partition(|e| e.pinned_at.is_some());
+    let (mut positioned, mut voted): (Vec<_>, Vec<_>) =
+        rest.into_iter().partition(|e| e.position.is_some());
+
+    pinned.sort_by_key(|e| Reverse(e.pinned_at));
+    voted.sort_by(|a, b| b.votes.cmp(&a.votes).then(a.queued_at.cmp(&b.queued_at)));
+    // Of two tracks moved to the same position, the last one moved ends up there
+    positioned.sort_by(|a, b| {
+        a.position
+            .cmp(&b.position)
+            .then(a.moved_at.cmp(&b.moved_at))
+    });
+
+    let pinned_count = pinned.len();
+    let mut queue = pinned;
+    queue.append(&mut voted);
+
+    for entry in positioned {
+        let position = entry.position.unwrap_or_default().max(0) as usize;
+        let index = position.max(pinned_count).min(queue.len());
+        queue.insert(index, entry);
+    }
+
+    queue
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::types::chrono::TimeZone;
+    use std::str::FromStr;
+
+    fn entry(n: u8, votes: i32, queued: i64) -> QueueEntry {
+        QueueEntry {
+            track_id: ItemId::from_str(&format!("spotify:track:{:0>22}", n)).unwrap(),
+            votes,
+            queued_at: at(queued),
+            pinned_at: None,
+            position: None,
+            moved_at: None,
+            client_id: None,
+        }
+    }
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_600_000_000 + seconds, 0).unwrap()
+    }
+
+    fn order(entries: Vec<QueueEntry>) -> Vec<String> {
+        order_queue(entries)
+            .into_iter()
+            .map(|e| e.track_id.to_string())
+            .collect()
+    }
+
+    fn uris(ns: &[u8]) -> Vec<String> {
+        ns.iter()
+            .map(|n| format!("spotify:track:{:0>22}", n))
+            .collect()
+    }
+
+    #[test]
+    fn orders_by_votes_then_queue_time() {
+        let entries = vec![
+            entry(1, 0, 0),
+            entry(2, 2, 1),
+            entry(3, 0, -1),
+            entry(4, -1, -2),
+        ];
+        assert_eq!(order(entries), uris(&[2, 3, 1, 4]));
+    }
+
+    #[test]
+    fn pinned_tracks_come_first_regardless_of_votes() {
+        let mut first = entry(1, -2, 0);
+        first.pinned_at = Some(at(10));
+        let mut second = entry(2, 0, 1);
+        second.pinned_at = Some(at(20));
+        let entries = vec![first, second, entry(3, 5, 2)];
+        assert_eq!(order(entries), uris(&[2, 1, 3]));
+    }
+
+    #[test]
+    fn moved_tracks_keep_their_position() {
+        let mut moved = entry(1, 0, 0);
+        moved.position = Some(2);
+        moved.moved_at = Some(at(5));
+        let entries = vec![moved, entry(2, 3, 1), entry(3, 2, 2), entry(4, 1, 3)];
+        assert_eq!(order(entries), uris(&[2, 3, 1, 4]));
+    }
+
+    #[test]
+    fn last_moved_track_wins_a_contested_position() {
+        let mut earlier = entry(1, 0, 0);
+        earlier.position = Some(0);
+        earlier.moved_at = Some(at(5));
+        let mut later = entry(2, 0, 1);
+        later.position = Some(0);
+        later.moved_at = Some(at(6));
+        let entries = vec![earlier, later, entry(3, 1, 2)];
+        assert_eq!(order(entries), uris(&[2, 1, 3]));
+    }
+
+    #[test]
+    fn moved_tracks_stay_below_pinned_tracks() {
+        let mut pinned = entry(1, 0, 0);
+        pinned.pinned_at = Some(at(1));
+        let mut moved = entry(2, 0, 1);
+        moved.position = Some(0);
+        moved.moved_at = Some(at(2));
+        let mut far = entry(3, 0, 2);
+        far.position = Some(10);
+        far.moved_at = Some(at(3));
+        let entries = vec![pinned, moved, far, entry(4, 0, 3)];
+        assert_eq!(order(entries), uris(&[1, 2, 4, 3]));
+    }
+}
@@ -0,0 +1,47 @@
+use super::dispatch::{dispatch, parse_collection_id, parse_page, Caller};
+use crate::authorization::Operation;
+use crate::controller::messages::Browse;
+use crate::controller::Controller;
+use crate::session_state::TypedSession;
+use actix::Addr;
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct BrowseQuery {
+    uri: String,
+    limit: Option<u32>,
+    offset: Option<u32>,
+}
+
+pub async fn api_browse(
+    path: web::Path<Uuid>,
+    query: web::Query<BrowseQuery>,
+    session: TypedSession,
+    controller: web::Data<Addr<Controller>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let caller = match Caller::for_session(&session, path.into_inner()) {
+        Ok(caller) => caller,
+        Err(response) => return Ok(response),
+    };
+    let collection = match parse_collection_id(&query.uri) {
+        Ok(collection) => collection,
+        Err(response) => return Ok(response),
+    };
+    let page = match parse_page(query.limit, query.offset) {
+        Ok(page) => page,
+        Err(response) => return Ok(response),
+    };
+
+    dispatch(&controller, &caller, Operation::Browse, |request_id| {
+        Browse {
+            collection,
+            page,
+            session_id: caller.session_id,
+            connection_id: caller.client_id,
+            request_id,
+        }
+    })
+    .await
+}
@@ -0,0 +1,28 @@
+use super::dispatch::{dispatch, Caller};
+use crate::authorization::Operation;
+use crate::controller::messages::Devices;
+use crate::controller::Controller;
+use crate::session_state::TypedSession;
+use actix::Addr;
+use actix_web::{web, HttpResponse};
+use uuid::Uuid;
+
+pub async fn api_devices(
+    path: web::Path<Uuid>,
+    session: TypedSession,
+    controller: web::Data<Addr<Controller>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let caller = match Caller::for_session(&session, path.into_inner()) {
+        Ok(caller) => caller,
+        Err(response) => return Ok(response),
+    };
+
+    dispatch(&controller, &caller, Operation::Devices, |request_id| {
+        Devices {
+            session_id: caller.session_id,
+            connection_id: caller.client_id,
+            request_id,
+        }
+    })
+    .await
+}
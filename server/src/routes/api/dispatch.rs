@@ -0,0 +1,168 @@
+use crate::authorization::{is_authorized, Operation};
+use crate::catalog::PageRequest;
+use crate::controller::messages::{ClientRequest, Dispatched};
+use crate::controller::Controller;
+use crate::item::{CollectionId, ItemId};
+use crate::protocol::{ErrorCode, Response};
+use crate::routes::utils::e500;
+use crate::session_state::{Context, TypedSession};
+use actix::Addr;
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The client behind an API request, as recorded in its session cookie.
+pub struct Caller {
+    pub session_id: Uuid,
+    pub client_id: Uuid,
+    pub context: Context,
+}
+
+impl Caller {
+    /// Checks that the cookie belongs to the session named in the path.
+    pub fn for_session(session: &TypedSession, session_id: Uuid) -> Result<Self, HttpResponse> {
+        let (id, client_id, context) = match (
+            session.get_id(),
+            session.get_client_id(),
+            session.get_context(),
+        ) {
+            (Ok(Some(id)), Ok(Some(client_id)), Ok(Some(context))) => (id, client_id, context),
+            _ => {
+                return Err(into_http_response(Response::error(
+                    ErrorCode::Internal,
+                    "Failed to read the session cookie",
+                    None,
+                )))
+            }
+        };
+
+        if id != session_id {
+            return Err(into_http_response(Response::error(
+                ErrorCode::Forbidden,
+                "The session cookie belongs to another session",
+                None,
+            )));
+        }
+
+        Ok(Self {
+            session_id,
+            client_id,
+            context,
+        })
+    }
+}
+
+pub fn parse_track_id(uri: &str) -> Result<ItemId, HttpResponse> {
+    ItemId::from_str(uri).map_err(|_| {
+        into_http_response(Response::error(
+            ErrorCode::InvalidTrackUri,
+            format!("{uri} is not a valid track or episode uri"),
+            None,
+        ))
+    })
+}
+
+pub fn parse_collection_id(uri: &str) -> Result<CollectionId, HttpResponse> {
+    CollectionId::from_str(uri).map_err(|_| {
+        into_http_response(Response::error(
+            ErrorCode::InvalidTrackUri,
+            format!("{uri} is not a valid album or playlist uri"),
+            None,
+        ))
+    })
+}
+
+pub fn parse_page(limit: Option<u32>, offset: Option<u32>) -> Result<PageRequest, HttpResponse> {
+    PageRequest::new(limit, offset).map_err(|message| {
+        into_http_response(Response::error(ErrorCode::OutOfRange, message, None))
+    })
+}
+
+/// Sends a request through the controller like a socket would and waits for
+/// the reply to it. `build` is given the request id to put in the message.
+pub async fn dispatch<M, F>(
+    controller: &Addr<Controller>,
+    caller: &Caller,
+    operation: Operation,
+    build: F,
+) -> Result<HttpResponse, actix_web::Error>
+where
+    M: ClientRequest,
+    F: FnOnce(Option<String>) -> M,
+{
+    dispatch_with(controller, caller, operation, build, into_http_response).await
+}
+
+/// Like `dispatch`, with the reply turned into an HTTP response by `render`.
+pub async fn dispatch_with<M, F, R>(
+    controller: &Addr<Controller>,
+    caller: &Caller,
+    operation: Operation,
+    build: F,
+    render: R,
+) -> Result<HttpResponse, actix_web::Error>
+where
+    M: ClientRequest,
+    F: FnOnce(Option<String>) -> M,
+    R: FnOnce(Response) -> HttpResponse,
+{
+    if !is_authorized(caller.context, operation) {
+        return Ok(into_http_response(Response::forbidden(operation, None)));
+    }
+
+    let request_id = Uuid::new_v4().to_string();
+    let (reply_tx, reply_rx) = oneshot::channel();
+    controller.do_send(Dispatched {
+        session_id: caller.session_id,
+        connection_id: caller.client_id,
+        request_id: request_id.clone(),
+        context: caller.context,
+        reply: reply_tx,
+        request: build(Some(request_id)),
+    });
+
+    match tokio::time::timeout(REPLY_TIMEOUT, reply_rx).await {
+        Ok(Ok(response)) => Ok(render(response)),
+        Ok(Err(err)) => Err(e500(err)),
+        Err(_) => Ok(HttpResponse::GatewayTimeout().json(Response::error(
+            ErrorCode::Internal,
+            "Timed out waiting for the session",
+            None,
+        ))),
+    }
+}
+
+pub fn into_http_response(response: Response) -> HttpResponse {
+    let status = match &response {
+        Response::Error { code, .. } => status_for(*code),
+        _ => StatusCode::OK,
+    };
+    HttpResponse::build(status).json(response)
+}
+
+fn status_for(code: ErrorCode) -> StatusCode {
+    match code {
+        ErrorCode::MalformedRequest
+        | ErrorCode::HandshakeRequired
+        | ErrorCode::UnsupportedVersion
+        | ErrorCode::InvalidTrackUri
+        | ErrorCode::OutOfRange => StatusCode::BAD_REQUEST,
+        ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+        ErrorCode::SessionNotFound | ErrorCode::TrackNotQueued => StatusCode::NOT_FOUND,
+        ErrorCode::NothingPlaying
+        | ErrorCode::QueueFull
+        | ErrorCode::TooManyPendingTracks
+        | ErrorCode::NothingToQueue
+        | ErrorCode::EmptyHistory => StatusCode::CONFLICT,
+        ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+        ErrorCode::ProviderError => StatusCode::BAD_GATEWAY,
+        ErrorCode::Busy => StatusCode::SERVICE_UNAVAILABLE,
+        ErrorCode::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
@@ -0,0 +1,121 @@
+use super::dispatch::{dispatch_with, into_http_response, Caller};
+use crate::authorization::Operation;
+use crate::controller::messages::History;
+use crate::controller::Controller;
+use crate::protocol::{ErrorCode, Response};
+use crate::session_agent::HistoryEntry;
+use crate::session_state::TypedSession;
+use actix::Addr;
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use uuid::Uuid;
+
+const CSV_HEADER: &str =
+    "started_at,ended_at,uri,kind,name,artists,album,queued_by,votes,autoplayed,skipped";
+
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    format: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+enum ExportFormat {
+    Json,
+    Csv,
+}
+
+/// Exports everything the session played, oldest first, as JSON or CSV.
+pub async fn api_history(
+    path: web::Path<Uuid>,
+    query: web::Query<HistoryQuery>,
+    session: TypedSession,
+    controller: web::Data<Addr<Controller>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let caller = match Caller::for_session(&session, path.into_inner()) {
+        Ok(caller) => caller,
+        Err(response) => return Ok(response),
+    };
+    let format = match query.format.as_deref() {
+        None | Some("json") => ExportFormat::Json,
+        Some("csv") => ExportFormat::Csv,
+        Some(other) => {
+            return Ok(into_http_response(Response::error(
+                ErrorCode::MalformedRequest,
+                format!("History can be exported as json or csv, not {other}"),
+                None,
+            )))
+        }
+    };
+
+    dispatch_with(
+        &controller,
+        &caller,
+        Operation::History,
+        |request_id| History {
+            page: None,
+            session_id: caller.session_id,
+            connection_id: caller.client_id,
+            request_id,
+        },
+        |response| match response {
+            Response::History(history) => export(&history.payload.items, format),
+            response => into_http_response(response),
+        },
+    )
+    .await
+}
+
+fn export(entries: &[HistoryEntry], format: ExportFormat) -> HttpResponse {
+    match format {
+        ExportFormat::Json => HttpResponse::Ok()
+            .insert_header(attachment("history.json"))
+            .json(entries),
+        ExportFormat::Csv => HttpResponse::Ok()
+            .content_type("text/csv; charset=utf-8")
+            .insert_header(attachment("history.csv"))
+            .body(to_csv(entries)),
+    }
+}
+
+fn attachment(filename: &str) -> (&'static str, String) {
+    (
+        "Content-Disposition",
+        format!("attachment; filename=\"{filename}\""),
+    )
+}
+
+fn to_csv(entries: &[HistoryEntry]) -> String {
+    let mut csv = String::from(CSV_HEADER);
+    csv.push_str("\r\n");
+    for entry in entries {
+        let fields = [
+            entry.started_at.clone(),
+            entry.ended_at.clone().unwrap_or_default(),
+            entry.track.id.clone(),
+            entry.track.kind.as_str().to_string(),
+            entry.track.name.clone(),
+            entry.track.artists.join("; "),
+            entry.track.album.clone(),
+            entry
+                .queued_by
+                .map(|client_id| client_id.to_string())
+                .unwrap_or_default(),
+            entry.votes.to_string(),
+            entry.autoplayed.to_string(),
+            entry.skipped.to_string(),
+        ];
+        let row: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        csv.push_str(&row.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+// Quotes fields that would otherwise break the row apart
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
@@ -0,0 +1,20 @@
+pub mod browse;
+pub mod devices;
+pub mod dispatch;
+pub mod history;
+pub mod kill;
+pub mod queue;
+pub mod search;
+pub mod state;
+pub mod transfer;
+pub mod vote;
+
+pub use browse::*;
+pub use devices::*;
+pub use history::*;
+pub use kill::*;
+pub use queue::*;
+pub use search::*;
+pub use state::*;
+pub use transfer::*;
+pub use vote::*;
@@ -0,0 +1,52 @@
+use super::dispatch::{dispatch, parse_track_id, Caller};
+use crate::authorization::Operation;
+use crate::controller::messages::{Queue, QueueCollection};
+use crate::controller::Controller;
+use crate::item::CollectionId;
+use crate::session_state::TypedSession;
+use actix::Addr;
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use std::str::FromStr;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct QueueBody {
+    uri: String,
+}
+
+pub async fn api_queue(
+    path: web::Path<Uuid>,
+    body: web::Json<QueueBody>,
+    session: TypedSession,
+    controller: web::Data<Addr<Controller>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let caller = match Caller::for_session(&session, path.into_inner()) {
+        Ok(caller) => caller,
+        Err(response) => return Ok(response),
+    };
+    if let Ok(collection) = CollectionId::from_str(&body.uri) {
+        return dispatch(&controller, &caller, Operation::Queue, |request_id| {
+            QueueCollection {
+                collection,
+                session_id: caller.session_id,
+                connection_id: caller.client_id,
+                request_id,
+            }
+        })
+        .await;
+    }
+
+    let track_id = match parse_track_id(&body.uri) {
+        Ok(track_id) => track_id,
+        Err(response) => return Ok(response),
+    };
+
+    dispatch(&controller, &caller, Operation::Queue, |request_id| Queue {
+        track_id,
+        session_id: caller.session_id,
+        connection_id: caller.client_id,
+        request_id,
+    })
+    .await
+}
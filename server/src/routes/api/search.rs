@@ -0,0 +1,74 @@
+use super::dispatch::{dispatch, into_http_response, parse_page, Caller};
+use crate::authorization::Operation;
+use crate::catalog::SearchKind;
+use crate::controller::messages::Search;
+use crate::controller::Controller;
+use crate::protocol::{ErrorCode, Response};
+use crate::session_state::TypedSession;
+use actix::Addr;
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use std::str::FromStr;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    query: String,
+    /// Comma separated, as in `track,album`.
+    types: Option<String>,
+    limit: Option<u32>,
+    offset: Option<u32>,
+}
+
+fn parse_types(types: Option<&str>) -> Result<Vec<SearchKind>, HttpResponse> {
+    let types = match types {
+        Some(types) => types,
+        None => return Ok(Vec::new()),
+    };
+
+    types
+        .split(',')
+        .map(|kind| {
+            SearchKind::from_str(kind.trim()).map_err(|err| {
+                into_http_response(Response::error(
+                    ErrorCode::MalformedRequest,
+                    err.to_string(),
+                    None,
+                ))
+            })
+        })
+        .collect()
+}
+
+pub async fn api_search(
+    path: web::Path<Uuid>,
+    query: web::Query<SearchQuery>,
+    session: TypedSession,
+    controller: web::Data<Addr<Controller>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let caller = match Caller::for_session(&session, path.into_inner()) {
+        Ok(caller) => caller,
+        Err(response) => return Ok(response),
+    };
+    let query = query.into_inner();
+    let types = match parse_types(query.types.as_deref()) {
+        Ok(types) => types,
+        Err(response) => return Ok(response),
+    };
+    let page = match parse_page(query.limit, query.offset) {
+        Ok(page) => page,
+        Err(response) => return Ok(response),
+    };
+
+    dispatch(&controller, &caller, Operation::Search, |request_id| {
+        Search {
+            query: query.query,
+            types,
+            page,
+            session_id: caller.session_id,
+            connection_id: caller.client_id,
+            request_id,
+        }
+    })
+    .await
+}
@@ -0,0 +1,26 @@
+use super::dispatch::{dispatch, Caller};
+use crate::authorization::Operation;
+use crate::controller::messages::State;
+use crate::controller::Controller;
+use crate::session_state::TypedSession;
+use actix::Addr;
+use actix_web::{web, HttpResponse};
+use uuid::Uuid;
+
+pub async fn api_state(
+    path: web::Path<Uuid>,
+    session: TypedSession,
+    controller: web::Data<Addr<Controller>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let caller = match Caller::for_session(&session, path.into_inner()) {
+        Ok(caller) => caller,
+        Err(response) => return Ok(response),
+    };
+
+    dispatch(&controller, &caller, Operation::State, |request_id| State {
+        session_id: caller.session_id,
+        connection_id: caller.client_id,
+        request_id,
+    })
+    .await
+}
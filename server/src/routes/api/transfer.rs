@@ -0,0 +1,36 @@
+use super::dispatch::{dispatch, Caller};
+use crate::authorization::Operation;
+use crate::controller::messages::Transfer;
+use crate::controller::Controller;
+use crate::session_state::TypedSession;
+use actix::Addr;
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct TransferBody {
+    device_id: String,
+}
+
+pub async fn api_transfer(
+    path: web::Path<Uuid>,
+    body: web::Json<TransferBody>,
+    session: TypedSession,
+    controller: web::Data<Addr<Controller>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let caller = match Caller::for_session(&session, path.into_inner()) {
+        Ok(caller) => caller,
+        Err(response) => return Ok(response),
+    };
+
+    dispatch(&controller, &caller, Operation::Transfer, |request_id| {
+        Transfer {
+            session_id: caller.session_id,
+            connection_id: caller.client_id,
+            device_id: body.into_inner().device_id,
+            request_id,
+        }
+    })
+    .await
+}
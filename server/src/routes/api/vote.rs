@@ -0,0 +1,41 @@
+use super::dispatch::{dispatch, parse_track_id, Caller};
+use crate::authorization::Operation;
+use crate::controller::messages::{Vote, VoteKind};
+use crate::controller::Controller;
+use crate::session_state::TypedSession;
+use actix::Addr;
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct VoteBody {
+    uri: String,
+    /// Defaults to an upvote.
+    kind: Option<VoteKind>,
+}
+
+pub async fn api_vote(
+    path: web::Path<Uuid>,
+    body: web::Json<VoteBody>,
+    session: TypedSession,
+    controller: web::Data<Addr<Controller>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let caller = match Caller::for_session(&session, path.into_inner()) {
+        Ok(caller) => caller,
+        Err(response) => return Ok(response),
+    };
+    let track_id = match parse_track_id(&body.uri) {
+        Ok(track_id) => track_id,
+        Err(response) => return Ok(response),
+    };
+
+    dispatch(&controller, &caller, Operation::Vote, |request_id| Vote {
+        track_id,
+        session_id: caller.session_id,
+        connection_id: caller.client_id,
+        kind: body.kind.unwrap_or(VoteKind::Up),
+        request_id,
+    })
+    .await
+}
@@ -1,11 +1,9 @@
-use crate::configuration::SpotifySettings;
 use crate::db::Database;
+use crate::provider::MusicProvider;
 use crate::routes::utils::e500;
 use crate::routes::utils::see_other;
 use crate::session_state::{Context::Host, TypedSession};
-use crate::spotify::{get_default_spotify, get_token_string};
 use actix_web::{web, HttpResponse};
-use rspotify::clients::OAuthClient;
 use serde::Deserialize;
 use uuid::Uuid;
 
@@ -15,26 +13,27 @@ pub struct CallbackQuery {
     state: String,
 }
 
-pub async fn callback(
+pub async fn callback<P: MusicProvider>(
     query: web::Query<CallbackQuery>,
     session: TypedSession,
     db: web::Data<Database>,
-    settings: web::Data<SpotifySettings>,
+    provider: web::Data<P>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let CallbackQuery {
         code,
         state: _state,
     } = query.into_inner();
-    let mut spotify = get_default_spotify(&settings);
 
-    if let Err(err) = spotify.request_token(&code).await {
-        log::error!("Failed to get user token {:?}", err);
-        return Ok(see_other("/"));
-    }
+    // TODO: hash token?
+    let token = match provider.request_token(&code).await {
+        Ok(token) => token,
+        Err(err) => {
+            log::error!("Failed to get user token {:?}", err);
+            return Ok(see_other("/"));
+        }
+    };
 
     let session_id = Uuid::new_v4();
-    // TODO: hash token?
-    let token = get_token_string(&spotify).await?;
 
     db.new_session(session_id, &token).await.map_err(e500)?;
 
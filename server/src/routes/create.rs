@@ -1,16 +1,13 @@
-use crate::configuration::SpotifySettings;
-use crate::spotify::get_default_spotify;
+use crate::provider::MusicProvider;
 
 use actix_web::http::header::ContentType;
 use actix_web::web;
 use actix_web::HttpResponse;
 
-pub async fn create_session(
-    settings: web::Data<SpotifySettings>,
+pub async fn create_session<P: MusicProvider>(
+    provider: web::Data<P>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    let spotify = get_default_spotify(&settings);
-
-    if let Ok(url) = spotify.get_authorize_url(false) {
+    if let Ok(url) = provider.authorize_url() {
         return Ok(HttpResponse::Ok()
             .content_type(ContentType::plaintext())
             .body(url));
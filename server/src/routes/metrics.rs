@@ -0,0 +1,60 @@
+use crate::session_agent::WorkerMetrics;
+use actix_web::{web, HttpResponse};
+use std::fmt::Write;
+
+/// Reports the load on the session workers in the Prometheus text format.
+pub async fn metrics(worker_metrics: web::Data<WorkerMetrics>) -> HttpResponse {
+    let queue_depths = worker_metrics.queue_depths();
+    let mut body = String::new();
+
+    let _ = writeln!(
+        body,
+        "# HELP queuetify_session_workers Session workers running."
+    );
+    let _ = writeln!(body, "# TYPE queuetify_session_workers gauge");
+    let _ = writeln!(body, "queuetify_session_workers {}", queue_depths.len());
+
+    let _ = writeln!(
+        body,
+        "# HELP queuetify_session_worker_queue_depth Requests waiting for a session worker."
+    );
+    let _ = writeln!(body, "# TYPE queuetify_session_worker_queue_depth gauge");
+    for (worker, depth) in queue_depths {
+        let _ = writeln!(
+            body,
+            "queuetify_session_worker_queue_depth{{worker=\"{worker}\"}} {depth}"
+        );
+    }
+
+    let _ = writeln!(
+        body,
+        "# HELP queuetify_session_requests_rejected_total Requests turned away by a full worker."
+    );
+    let _ = writeln!(
+        body,
+        "# TYPE queuetify_session_requests_rejected_total counter"
+    );
+    let _ = writeln!(
+        body,
+        "queuetify_session_requests_rejected_total {}",
+        worker_metrics.rejected()
+    );
+
+    let _ = writeln!(
+        body,
+        "# HELP queuetify_session_requests_timed_out_total Requests that took too long to handle."
+    );
+    let _ = writeln!(
+        body,
+        "# TYPE queuetify_session_requests_timed_out_total counter"
+    );
+    let _ = writeln!(
+        body,
+        "queuetify_session_requests_timed_out_total {}",
+        worker_metrics.timed_out()
+    );
+
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body)
+}
@@ -1,13 +1,16 @@
+pub mod api;
 pub mod callback;
 pub mod create;
 pub mod index;
 pub mod join;
+pub mod metrics;
 pub mod session;
 pub mod utils;
 
+pub use api::*;
 pub use callback::*;
 pub use create::*;
 pub use index::*;
 pub use join::*;
+pub use metrics::*;
 pub use session::*;
-pub use ws::*;
@@ -13,7 +13,8 @@ pub async fn ws_connect(
     // TODO: Ok to assume id exists here because of protected route?
     let session_id = session.get_id().unwrap().unwrap();
     let client_id = session.get_client_id().unwrap().unwrap();
-    let ws = WsConnection::new(session_id, client_id, controller.get_ref().clone());
+    let context = session.get_context().unwrap().unwrap();
+    let ws = WsConnection::new(session_id, client_id, context, controller.get_ref().clone());
 
     let resp = ws::start(ws, &req, stream)?;
     Ok(resp)
@@ -1,55 +1,352 @@
-use crate::configuration::Settings;
+use crate::autoplay::AutoplayMode;
+use crate::catalog::{
+    AlbumInfo, ArtistInfo, LibrarySource, Page, PageRequest, PlaylistInfo, DEFAULT_SEARCH_KINDS,
+    MAX_PAGE_LIMIT,
+};
+use crate::cluster::Cluster;
+use crate::configuration::{SessionSettings, Settings};
 use crate::controller;
 use crate::controller::messages::{
-    DeviceInfo, DevicesComplete, KillComplete, SearchComplete, SearchResultPayload, StateUpdate,
-    StateUpdatePayload, TransferComplete, VotedTracksComplete,
+    BrowseComplete, DevicesComplete, HistoryComplete, KillComplete, LibraryComplete,
+    ProgressUpdate, ReplyTo, RequestFailed, SaveHistoryComplete, SearchComplete, StateUpdate,
+    TransferComplete, VotedTracksComplete, Wakeup,
 };
 use crate::controller::{Controller, POLL_STATE_INTERVAL, REFRESH_TOKEN_INTERVAL};
-use crate::db::Database;
+use crate::db::{Database, Play};
+use crate::item::{CollectionId, ItemId, ItemKind};
+use crate::playback::{Action, Handoff, Observation, Playback};
+use crate::progress::Progress;
+use crate::protocol::{
+    ErrorCode, HistoryResultPayload, LibraryResultPayload, SavedPlaylistPayload,
+    SearchResultPayload, StateUpdatePayload, TrackListPayload,
+};
+use crate::provider::{CachedProvider, CurrentPlayback, MusicProvider, PlayingItem};
 use actix::Addr;
-use rspotify::clients::{BaseClient, OAuthClient};
-use rspotify::model::enums::misc::Market;
-use rspotify::model::{AdditionalType, PlayableId, PlayableItem, SimplifiedArtist};
-use rspotify::model::{FullTrack, TrackId};
-use rspotify::model::{SearchResult::Tracks, SearchType};
-use rspotify::AuthCodeSpotify;
-use rspotify::ClientError;
+use rspotify::model::{
+    FullEpisode, FullTrack, Image, PlaylistId, SimplifiedArtist, SimplifiedShow,
+};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use sqlx::types::chrono::Utc;
+use sqlx::{Postgres, Transaction};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::mpsc::error::TrySendError;
 use uuid::Uuid;
 
 // TODO: kill session if db operations fail?
 
-pub enum SessionAgentRequest {
-    Search((controller::Search, Addr<Controller>)),
-    Queue((controller::Queue, Addr<Controller>)),
-    GetState((Uuid, Option<Uuid>, Addr<Controller>)),
-    PollState((Uuid, Addr<Controller>)),
-    Vote((controller::Vote, Addr<Controller>)),
-    Refresh((Uuid, Addr<Controller>)),
-    Kill((Uuid, Addr<Controller>)),
-    Devices((controller::Devices, Addr<Controller>)),
-    Transfer((controller::Transfer, Addr<Controller>)),
-    VotedTracks((controller::VotedTracks, Addr<Controller>)),
-}
-
-pub struct SessionAgent {
-    rx: UnboundedReceiver<SessionAgentRequest>,
+/// A request for a session's worker, along with who made it.
+pub struct SessionAgentRequest {
+    pub header: RequestHeader,
+    pub body: RequestBody,
+}
+
+/// What every request carries whatever it asks for: the session it is about and
+/// where the answer goes.
+#[derive(Clone)]
+pub struct RequestHeader {
+    pub session_id: Uuid,
+    pub addr: Addr<Controller>,
+    requester: Requester,
+}
+
+impl RequestHeader {
+    pub fn client(
+        session_id: Uuid,
+        connection_id: Uuid,
+        request_id: Option<String>,
+        addr: Addr<Controller>,
+    ) -> Self {
+        Self {
+            session_id,
+            addr,
+            requester: Requester::Client {
+                connection_id,
+                request_id,
+            },
+        }
+    }
+
+    pub fn poll(session_id: Uuid, addr: Addr<Controller>) -> Self {
+        Self {
+            session_id,
+            addr,
+            requester: Requester::Poll,
+        }
+    }
+
+    pub fn token_refresh(session_id: Uuid, addr: Addr<Controller>) -> Self {
+        Self {
+            session_id,
+            addr,
+            requester: Requester::TokenRefresh,
+        }
+    }
+}
+
+pub enum RequestBody {
+    /// A client connected. There's nothing to do but note the session is in use.
+    Connect,
+    Search(controller::Search),
+    Browse(controller::Browse),
+    Queue(controller::Queue),
+    QueueCollection(controller::QueueCollection),
+    GetState(controller::State),
+    PollState,
+    Vote(controller::Vote),
+    Unvote(controller::Unvote),
+    Refresh,
+    Kill(controller::Kill),
+    Devices(controller::Devices),
+    Transfer(controller::Transfer),
+    VotedTracks(controller::VotedTracks),
+    RemoveTrack(controller::RemoveTrack),
+    PinTrack(controller::PinTrack),
+    MoveTrack(controller::MoveTrack),
+    VoteSkip(controller::VoteSkip),
+    Skip(controller::Skip),
+    Pause(controller::Pause),
+    Resume(controller::Resume),
+    Seek(controller::Seek),
+    Volume(controller::Volume),
+    SetAutoplay(controller::SetAutoplay),
+    Library(controller::Library),
+    Import(controller::Import),
+    SetFallbackPlaylist(controller::SetFallbackPlaylist),
+    History(controller::History),
+    SaveHistory(controller::SaveHistory),
+}
+
+/// A request the agent turned down for a reason the client should be told about,
+/// as opposed to a failure on our side.
+#[derive(Debug)]
+pub struct Refusal {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl Refusal {
+    fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for Refusal {}
+
+fn not_queued(track_id: &ItemId) -> anyhow::Error {
+    Refusal::new(
+        ErrorCode::TrackNotQueued,
+        format!("{track_id} is not in the queue"),
+    )
+    .into()
+}
+
+fn nothing_playing() -> anyhow::Error {
+    Refusal::new(ErrorCode::NothingPlaying, "Nothing is playing right now").into()
+}
+
+/// Who has to be told when a request is dropped instead of handled.
+#[derive(Clone)]
+enum Requester {
+    Client {
+        connection_id: Uuid,
+        request_id: Option<String>,
+    },
+    TokenRefresh,
+    Poll,
+}
+
+/// Lets the sender of a dropped request know. Clients get an error, and a token
+/// refresh is tried again later so the session keeps its credentials.
+fn abandon(header: RequestHeader, code: ErrorCode, message: &str) {
+    let RequestHeader {
+        session_id,
+        addr,
+        requester,
+    } = header;
+    match requester {
+        Requester::Client {
+            connection_id,
+            request_id,
+        } => addr.do_send(RequestFailed {
+            connection_id,
+            code,
+            message: message.to_string(),
+            request_id,
+        }),
+        Requester::TokenRefresh => addr.do_send(controller::Refresh {
+            duration: REFRESH_RETRY_INTERVAL,
+            session_id,
+        }),
+        // Keep the session's checks going
+        Requester::Poll => addr.do_send(Wakeup {
+            session_id,
+            after: POLL_STATE_INTERVAL,
+        }),
+    }
+}
+
+/// Turns away a request there is no room for, telling its sender the session is busy.
+pub fn turn_away(request: SessionAgentRequest) {
+    abandon(
+        request.header,
+        ErrorCode::Busy,
+        "The session is busy, try again shortly",
+    );
+}
+
+const REFRESH_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+/// How long the provider gets to pick up a track a client started before the
+/// session is checked again.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Workers of sessions that go quiet for this long are stopped. Sessions still in
+/// use are checked at least every `playback_check_secs`, which should stay below
+/// this so their workers keep what they know about playback.
+const WORKER_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+struct WorkerHandle {
+    tx: mpsc::Sender<SessionAgentRequest>,
+    worker: u64,
+}
+
+/// Queue depths of the running session workers and how many requests they had
+/// to drop, shared with the metrics endpoint.
+#[derive(Clone)]
+pub struct WorkerMetrics {
+    capacity: usize,
+    workers: Arc<Mutex<HashMap<Uuid, WorkerHandle>>>,
+    started: Arc<AtomicU64>,
+    rejected: Arc<AtomicU64>,
+    timed_out: Arc<AtomicU64>,
+}
+
+impl WorkerMetrics {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            workers: Arc::new(Mutex::new(HashMap::new())),
+            started: Arc::new(AtomicU64::new(0)),
+            rejected: Arc::new(AtomicU64::new(0)),
+            timed_out: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// The number of requests waiting for each running worker, keyed by worker
+    /// number rather than session id since the latter lets anyone join.
+    pub fn queue_depths(&self) -> Vec<(u64, usize)> {
+        let workers = self.workers.lock().unwrap();
+        workers
+            .values()
+            .map(|handle| (handle.worker, self.capacity - handle.tx.capacity()))
+            .collect()
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    pub fn timed_out(&self) -> u64 {
+        self.timed_out.load(Ordering::Relaxed)
+    }
+
+    /// Hands a request to the session's worker, or gives it back as closed when
+    /// there is none. The map stays locked while handing it over, so a worker
+    /// can't retire with the request left unread.
+    fn hand_over(
+        &self,
+        request: SessionAgentRequest,
+    ) -> Result<(), Box<TrySendError<SessionAgentRequest>>> {
+        let workers = self.workers.lock().unwrap();
+        match workers.get(&request.header.session_id) {
+            Some(handle) => handle.tx.try_send(request).map_err(Box::new),
+            None => Err(Box::new(TrySendError::Closed(request))),
+        }
+    }
+
+    fn add(&self, session_id: Uuid, tx: mpsc::Sender<SessionAgentRequest>) -> u64 {
+        let worker = self.started.fetch_add(1, Ordering::Relaxed);
+        let mut workers = self.workers.lock().unwrap();
+        workers.insert(session_id, WorkerHandle { tx, worker });
+        worker
+    }
+
+    /// Takes an idle worker out of the map, unless a request came in after all,
+    /// which it gets back to handle instead.
+    fn retire(
+        &self,
+        session_id: Uuid,
+        worker: u64,
+        rx: &mut mpsc::Receiver<SessionAgentRequest>,
+    ) -> Option<SessionAgentRequest> {
+        let mut workers = self.workers.lock().unwrap();
+        if let Ok(request) = rx.try_recv() {
+            return Some(request);
+        }
+        if workers.get(&session_id).map(|handle| handle.worker) == Some(worker) {
+            workers.remove(&session_id);
+        }
+        None
+    }
+
+    fn remove(&self, session_id: Uuid, worker: u64) {
+        let mut workers = self.workers.lock().unwrap();
+        // A replacement may already have taken the stopped worker's place
+        if workers.get(&session_id).map(|handle| handle.worker) == Some(worker) {
+            workers.remove(&session_id);
+        }
+    }
+}
+
+/// Hands each session's requests to a worker of its own, so a slow provider call
+/// for one party doesn't hold up polling and voting for the others.
+pub struct SessionAgent<P: MusicProvider> {
+    rx: mpsc::Receiver<SessionAgentRequest>,
     db: Database,
+    provider: CachedProvider<P>,
+    settings: SessionSettings,
+    cluster: Cluster,
+    metrics: WorkerMetrics,
 }
 
-impl SessionAgent {
-    pub fn build(settings: Settings) -> (Self, UnboundedSender<SessionAgentRequest>) {
-        let (tx, rx) = mpsc::unbounded_channel();
+impl<P: MusicProvider + Clone> SessionAgent<P> {
+    pub fn build(
+        settings: Settings,
+        provider: P,
+        cluster: Cluster,
+    ) -> (Self, mpsc::Sender<SessionAgentRequest>) {
+        let (tx, rx) = mpsc::channel(settings.session.agent_queue_capacity);
+        let db = Database::new(&settings.database, settings.spotify);
         let agent = Self {
             rx,
-            db: Database::new(&settings.database, settings.spotify),
+            provider: CachedProvider::new(provider, db.clone(), settings.track_cache),
+            db,
+            metrics: WorkerMetrics::new(settings.session.worker_queue_capacity),
+            settings: settings.session,
+            cluster,
         };
         (agent, tx)
     }
 
+    pub fn metrics(&self) -> WorkerMetrics {
+        self.metrics.clone()
+    }
+
     pub async fn run(mut self) -> Result<(), std::io::Error> {
         loop {
             let request = match self.rx.recv().await {
@@ -57,120 +354,555 @@ impl SessionAgent {
                 None => return Ok(()),
             };
 
-            match request {
-                SessionAgentRequest::Search((msg, addr)) => {
-                    if let Ok(search_result) = on_search(&msg, &self.db).await {
-                        // TODO: have on search return complete SearchComplete strutc
-                        addr.do_send(SearchComplete {
-                            result: SearchResultPayload {
-                                payload: search_result,
-                            },
-                            connection_id: msg.connection_id,
-                        });
+            self.dispatch(request);
+        }
+    }
+
+    fn dispatch(&mut self, request: SessionAgentRequest) {
+        let session_id = request.header.session_id;
+        let err = match self.metrics.hand_over(request) {
+            Ok(()) => return,
+            Err(err) => err,
+        };
+        let request = match *err {
+            TrySendError::Full(request) => {
+                log::warn!("Worker for session {session_id} is full, dropping request");
+                self.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                turn_away(request);
+                return;
+            }
+            // No worker yet, or the last one went idle and stopped
+            TrySendError::Closed(request) => request,
+        };
+
+        let tx = self.start_worker(session_id);
+        if tx.try_send(request).is_err() {
+            log::error!("Failed to hand request to new worker for session {session_id}");
+        }
+    }
+
+    fn start_worker(&mut self, session_id: Uuid) -> mpsc::Sender<SessionAgentRequest> {
+        let (tx, rx) = mpsc::channel(self.settings.worker_queue_capacity);
+        let worker = SessionWorker {
+            session_id,
+            worker: self.metrics.add(session_id, tx.clone()),
+            rx,
+            db: self.db.clone(),
+            provider: self.provider.clone(),
+            settings: self.settings.clone(),
+            cluster: self.cluster.clone(),
+            metrics: self.metrics.clone(),
+            progress: None,
+            playback: Playback::new(
+                Duration::from_secs(self.settings.handoff_lead_secs),
+                Duration::from_secs(self.settings.playback_check_secs),
+            ),
+        };
+        tokio::spawn(worker.run());
+        tx
+    }
+}
+
+/// Handles the requests of a single session one at a time.
+struct SessionWorker<P: MusicProvider> {
+    session_id: Uuid,
+    worker: u64,
+    rx: mpsc::Receiver<SessionAgentRequest>,
+    db: Database,
+    provider: P,
+    settings: SessionSettings,
+    cluster: Cluster,
+    metrics: WorkerMetrics,
+    /// Playback as last seen by a poll, and when.
+    progress: Option<(Progress, Instant)>,
+    playback: Playback,
+}
+
+impl<P: MusicProvider + Clone> SessionWorker<P> {
+    async fn run(mut self) {
+        loop {
+            match tokio::time::timeout(WORKER_IDLE_TIMEOUT, self.rx.recv()).await {
+                Ok(Some(request)) => self.process(request).await,
+                Ok(None) => break,
+                Err(_) => match self
+                    .metrics
+                    .retire(self.session_id, self.worker, &mut self.rx)
+                {
+                    Some(request) => self.process(request).await,
+                    // Requests that come in from now on start a new worker
+                    None => return,
+                },
+            }
+        }
+
+        self.metrics.remove(self.session_id, self.worker);
+    }
+
+    async fn process(&mut self, request: SessionAgentRequest) {
+        let header = request.header.clone();
+        // What clients ask for keeps the session from being swept, and so do polls
+        // while clients are connected, as they may just be listening. Token
+        // refreshes carry on whether anyone is listening or not
+        let active = match header.requester {
+            Requester::Client { .. } => true,
+            Requester::Poll => self.has_listeners().await,
+            Requester::TokenRefresh => false,
+        };
+        if active {
+            if let Err(err) = self.db.touch_session(self.session_id).await {
+                log::error!(
+                    "Failed to record activity in session {}, {err}",
+                    self.session_id
+                );
+            }
+        }
+        let timeout = Duration::from_secs(self.settings.request_timeout_secs);
+        if tokio::time::timeout(timeout, self.handle(request))
+            .await
+            .is_err()
+        {
+            log::error!("Request for session {} timed out", self.session_id);
+            self.metrics.timed_out.fetch_add(1, Ordering::Relaxed);
+            abandon(
+                header,
+                ErrorCode::Timeout,
+                "The request took too long to handle",
+            );
+        }
+    }
+
+    async fn handle(&mut self, request: SessionAgentRequest) {
+        let addr = request.header.addr;
+        match request.body {
+            RequestBody::Connect => {}
+            RequestBody::Search(msg) => {
+                match on_search(&msg, &self.provider).await {
+                    // TODO: have on search return complete SearchComplete strutc
+                    Ok(search_result) => addr.do_send(SearchComplete {
+                        result: SearchResultPayload {
+                            payload: search_result,
+                            request_id: msg.request_id,
+                        },
+                        connection_id: msg.connection_id,
+                    }),
+                    Err(err) => {
+                        report_failure(&addr, msg.connection_id, msg.request_id, "search", err)
                     }
                 }
-                SessionAgentRequest::Queue((msg, addr)) => {
-                    let result = on_queue(msg, &self.db).await;
-                    match result {
-                        Ok(update) => {
-                            addr.do_send(update);
-                        }
-                        Err(err) => {
-                            log::error!("Error on queue {err}");
-                        }
+            }
+            RequestBody::Browse(msg) => match on_browse(&msg, &self.provider).await {
+                Ok(track_list) => addr.do_send(BrowseComplete {
+                    result: TrackListPayload {
+                        payload: track_list,
+                        request_id: msg.request_id,
+                    },
+                    connection_id: msg.connection_id,
+                }),
+                Err(err) => report_failure(&addr, msg.connection_id, msg.request_id, "browse", err),
+            },
+            RequestBody::Queue(msg) => {
+                let (connection_id, request_id) = (msg.connection_id, msg.request_id.clone());
+                let result = self
+                    .in_queue_turn(
+                        connection_id,
+                        on_queue(msg, &self.db, &self.provider, &self.settings),
+                    )
+                    .await;
+                match result {
+                    Ok(update) => {
+                        self.recheck_if_moved(&addr, &update);
+                        self.send_state(&addr, update.in_reply_to(connection_id, request_id));
                     }
+                    Err(err) => report_failure(&addr, connection_id, request_id, "queue", err),
                 }
-                SessionAgentRequest::GetState((id, connection_id, addr)) => {
-                    let spotify = match self.db.get_spotify(id).await {
-                        Ok(spotify) => spotify,
-                        Err(_) => continue,
-                    };
-                    match get_current_state(id, connection_id, &spotify, &self.db).await {
-                        Ok(update) => {
-                            addr.do_send(update);
-                        }
-                        Err(err) => {
-                            log::error!("Failed to get current state {err}");
-                        }
+            }
+            RequestBody::QueueCollection(msg) => {
+                let (connection_id, request_id) = (msg.connection_id, msg.request_id.clone());
+                let result = self
+                    .in_queue_turn(
+                        connection_id,
+                        on_queue_collection(msg, &self.db, &self.provider, &self.settings),
+                    )
+                    .await;
+                match result {
+                    Ok(update) => {
+                        self.recheck_if_moved(&addr, &update);
+                        self.send_state(&addr, update.in_reply_to(connection_id, request_id));
                     }
-                }
-                SessionAgentRequest::PollState((id, addr)) => {
-                    match on_poll_state(id, &self.db).await {
-                        Ok(update) => {
-                            if let Some(update) = update {
-                                addr.do_send(update);
-                            }
-                        }
-                        Err(err) => {
-                            log::error!("Error on poll state {err}");
-                        }
+                    Err(err) => {
+                        report_failure(&addr, connection_id, request_id, "queue collection", err)
                     }
                 }
-                SessionAgentRequest::Vote((msg, addr)) => match on_vote(msg, &self.db).await {
+            }
+            RequestBody::GetState(msg) => {
+                let result = get_current_state(
+                    msg.session_id,
+                    Some(msg.connection_id),
+                    &self.db,
+                    &self.provider,
+                )
+                .await;
+                match result {
                     Ok(update) => {
-                        if let Some(update) = update {
-                            addr.do_send(update);
+                        self.send_state(
+                            &addr,
+                            update.in_reply_to(msg.connection_id, msg.request_id),
+                        );
+                    }
+                    Err(err) => {
+                        report_failure(&addr, msg.connection_id, msg.request_id, "get state", err)
+                    }
+                }
+            }
+            RequestBody::PollState => {
+                let id = self.session_id;
+                // Every instance the session is used through polls it, only the owner acts
+                if !self.owns_session(id).await {
+                    addr.do_send(Wakeup {
+                        session_id: id,
+                        after: POLL_STATE_INTERVAL,
+                    });
+                    return;
+                }
+
+                let result = on_poll_state(id, &self.db, &self.provider, &mut self.playback).await;
+                if result.is_err() && self.session_gone(id).await {
+                    log::info!("Session {id} no longer exists, no longer polling it");
+                    return;
+                }
+                let after = match &result {
+                    Ok(_) => self.playback.next_wakeup(Instant::now()),
+                    Err(_) => POLL_STATE_INTERVAL,
+                };
+                addr.do_send(Wakeup {
+                    session_id: id,
+                    after,
+                });
+
+                match result {
+                    Ok(poll) => {
+                        let expected = self.expected_progress();
+                        self.progress = poll
+                            .progress
+                            .clone()
+                            .map(|progress| (progress, Instant::now()));
+
+                        match (poll.update, poll.progress) {
+                            (Some(update), _) => self.send_state(&addr, update),
+                            (None, Some(progress)) => {
+                                let drifted = expected
+                                    .map(|expected| expected.has_drifted(&progress))
+                                    .unwrap_or(true);
+                                if drifted {
+                                    addr.do_send(ProgressUpdate {
+                                        session_id: id,
+                                        progress,
+                                    });
+                                }
+                            }
+                            (None, None) => {}
                         }
                     }
                     Err(err) => {
-                        log::error!("Error on vote {err}");
+                        log::error!("Error on poll state {err}");
                     }
-                },
-                SessionAgentRequest::Refresh((id, addr)) => {
-                    match on_refresh(id, &self.db).await {
-                        Ok(()) => addr.do_send(controller::Refresh {
-                            duration: REFRESH_TOKEN_INTERVAL,
+                }
+            }
+            RequestBody::Vote(msg) => {
+                let (session_id, connection_id, request_id) =
+                    (msg.session_id, msg.connection_id, msg.request_id.clone());
+                match on_vote(msg, &self.db, &self.provider, &self.settings).await {
+                    Ok(Some(update)) => {
+                        self.send_state(&addr, update.in_reply_to(connection_id, request_id));
+                    }
+                    Ok(None) => {
+                        self.acknowledge(session_id, connection_id, request_id, &addr)
+                            .await
+                    }
+                    Err(err) => report_failure(&addr, connection_id, request_id, "vote", err),
+                }
+            }
+            RequestBody::Unvote(msg) => {
+                let (session_id, connection_id, request_id) =
+                    (msg.session_id, msg.connection_id, msg.request_id.clone());
+                match on_unvote(msg, &self.db, &self.provider).await {
+                    Ok(Some(update)) => {
+                        self.send_state(&addr, update.in_reply_to(connection_id, request_id));
+                    }
+                    Ok(None) => {
+                        self.acknowledge(session_id, connection_id, request_id, &addr)
+                            .await
+                    }
+                    Err(err) => report_failure(&addr, connection_id, request_id, "unvote", err),
+                }
+            }
+            RequestBody::Refresh => {
+                let id = self.session_id;
+                if !self.owns_session(id).await {
+                    // Check back in case the owner goes away
+                    addr.do_send(controller::Refresh {
+                        duration: self.settings.lease_ttl(),
+                        session_id: id,
+                    });
+                    return;
+                }
+
+                match self.provider.refresh_token(id).await {
+                    Ok(()) => addr.do_send(controller::Refresh {
+                        duration: REFRESH_TOKEN_INTERVAL,
+                        session_id: id,
+                    }),
+                    Err(_) => {
+                        if self.session_gone(id).await {
+                            log::info!(
+                                "Session {id} no longer exists, no longer refreshing its token"
+                            );
+                            return;
+                        }
+                        addr.do_send(controller::Refresh {
+                            duration: REFRESH_RETRY_INTERVAL, //TODO: exponential backoff wait? kill session after some number of tries?
                             session_id: id,
+                        })
+                    }
+                }
+            }
+            RequestBody::Kill(msg) => match self.db.delete_session(msg.session_id).await {
+                Ok(()) => {
+                    if let Err(err) = self.cluster.release_lease(msg.session_id).await {
+                        log::error!("Failed to release lease on {}, {err}", msg.session_id);
+                    }
+                    addr.do_send(KillComplete {
+                        session_id: msg.session_id,
+                        reply_to: Some(ReplyTo {
+                            connection_id: msg.connection_id,
+                            request_id: msg.request_id,
                         }),
-                        Err(_) => {
-                            addr.do_send(controller::Refresh {
-                                duration: Duration::from_secs(10), //TODO: exponential backoff wait? kill session after some number of tries?
-                                session_id: id,
-                            })
-                        }
+                    })
+                }
+                Err(err) => {
+                    report_failure(&addr, msg.connection_id, msg.request_id, "kill", err.into())
+                }
+            },
+            RequestBody::Devices(msg) => match self.provider.devices(msg.session_id).await {
+                Ok(devices) => addr.do_send(DevicesComplete {
+                    connection_id: msg.connection_id,
+                    devices,
+                    request_id: msg.request_id,
+                }),
+                Err(err) => {
+                    report_failure(&addr, msg.connection_id, msg.request_id, "devices", err)
+                }
+            },
+            RequestBody::Transfer(msg) => {
+                match self
+                    .provider
+                    .transfer_playback(msg.session_id, &msg.device_id)
+                    .await
+                {
+                    Ok(()) => addr.do_send(TransferComplete {
+                        connection_id: msg.connection_id,
+                        device_id: msg.device_id,
+                        request_id: msg.request_id,
+                    }),
+                    Err(err) => {
+                        report_failure(&addr, msg.connection_id, msg.request_id, "transfer", err)
                     }
                 }
-                SessionAgentRequest::Kill((id, addr)) => match self.db.delete_session(id).await {
-                    Ok(()) => addr.do_send(KillComplete { session_id: id }),
+            }
+            RequestBody::VotedTracks(msg) => match on_voted_tracks(msg.clone(), &self.db).await {
+                Ok(tracks) => addr.do_send(VotedTracksComplete {
+                    connection_id: msg.connection_id,
+                    tracks,
+                    request_id: msg.request_id,
+                }),
+                Err(err) => report_failure(
+                    &addr,
+                    msg.connection_id,
+                    msg.request_id,
+                    "voted tracks",
+                    err,
+                ),
+            },
+            RequestBody::RemoveTrack(msg) => {
+                let (connection_id, request_id) = (msg.connection_id, msg.request_id.clone());
+                match on_remove_track(msg, &self.db, &self.provider).await {
+                    Ok(update) => {
+                        self.send_state(&addr, update.in_reply_to(connection_id, request_id))
+                    }
                     Err(err) => {
-                        log::error!("Error on kill {err}");
+                        report_failure(&addr, connection_id, request_id, "remove track", err)
                     }
-                },
-                // TODO: make endpoint of this instead
-                SessionAgentRequest::Devices((msg, addr)) => {
-                    let connection_id = msg.connection_id;
-                    match on_devices(msg, &self.db).await {
-                        Ok(devices) => addr.do_send(DevicesComplete {
-                            connection_id,
-                            devices,
-                        }),
-                        Err(err) => {
-                            log::error!("Error on devices {err}")
-                        }
+                }
+            }
+            RequestBody::PinTrack(msg) => {
+                let (connection_id, request_id) = (msg.connection_id, msg.request_id.clone());
+                match on_pin_track(msg, &self.db, &self.provider).await {
+                    Ok(update) => {
+                        self.send_state(&addr, update.in_reply_to(connection_id, request_id))
                     }
+                    Err(err) => report_failure(&addr, connection_id, request_id, "pin track", err),
                 }
-                SessionAgentRequest::Transfer((msg, addr)) => {
-                    let connection_id = msg.connection_id;
-                    match on_transfer(msg, &self.db).await {
-                        Ok(()) => addr.do_send(TransferComplete {
-                            connection_id,
-                            result: "OK".to_string(),
-                        }),
-                        Err(_) => addr.do_send(TransferComplete {
-                            connection_id,
-                            result: "Err".to_string(),
-                        }),
+            }
+            RequestBody::MoveTrack(msg) => {
+                let (connection_id, request_id) = (msg.connection_id, msg.request_id.clone());
+                match on_move_track(msg, &self.db, &self.provider).await {
+                    Ok(update) => {
+                        self.send_state(&addr, update.in_reply_to(connection_id, request_id))
                     }
+                    Err(err) => report_failure(&addr, connection_id, request_id, "move track", err),
                 }
-                SessionAgentRequest::VotedTracks((msg, addr)) => {
-                    match on_voted_tracks(msg.clone(), &self.db).await {
-                        Ok(tracks) => addr.do_send(VotedTracksComplete {
-                            connection_id: msg.connection_id,
-                            tracks,
-                        }),
-                        Err(err) => {
-                            log::error!("Error on devices {err}");
-                        }
+            }
+            RequestBody::VoteSkip(msg) => {
+                let (session_id, connection_id, request_id) =
+                    (msg.session_id, msg.connection_id, msg.request_id.clone());
+                match on_vote_skip(msg, &self.db, &self.provider, &self.cluster, &self.settings)
+                    .await
+                {
+                    Ok(Some(update)) => {
+                        self.recheck_if_moved(&addr, &update);
+                        self.send_state(&addr, update.in_reply_to(connection_id, request_id));
+                    }
+                    Ok(None) => {
+                        self.acknowledge(session_id, connection_id, request_id, &addr)
+                            .await
+                    }
+                    Err(err) => report_failure(&addr, connection_id, request_id, "vote skip", err),
+                }
+            }
+            RequestBody::Skip(msg) => {
+                let (connection_id, request_id) = (msg.connection_id, msg.request_id.clone());
+                match on_skip(msg, &self.db, &self.provider).await {
+                    Ok(update) => {
+                        self.recheck_if_moved(&addr, &update);
+                        self.send_state(&addr, update.in_reply_to(connection_id, request_id))
+                    }
+                    Err(err) => report_failure(&addr, connection_id, request_id, "skip", err),
+                }
+            }
+            RequestBody::Pause(msg) => {
+                let (connection_id, request_id) = (msg.connection_id, msg.request_id.clone());
+                match on_pause(msg, &self.db, &self.provider).await {
+                    Ok(update) => {
+                        self.adjust_progress(|progress| progress.is_playing = false);
+                        self.recheck(&addr);
+                        self.send_state(&addr, update.in_reply_to(connection_id, request_id))
+                    }
+                    Err(err) => report_failure(&addr, connection_id, request_id, "pause", err),
+                }
+            }
+            RequestBody::Resume(msg) => {
+                let (connection_id, request_id) = (msg.connection_id, msg.request_id.clone());
+                match on_resume(msg, &self.db, &self.provider).await {
+                    Ok(update) => {
+                        self.adjust_progress(|progress| progress.is_playing = true);
+                        self.recheck(&addr);
+                        self.send_state(&addr, update.in_reply_to(connection_id, request_id))
+                    }
+                    Err(err) => report_failure(&addr, connection_id, request_id, "resume", err),
+                }
+            }
+            RequestBody::Seek(msg) => {
+                let (connection_id, request_id) = (msg.connection_id, msg.request_id.clone());
+                let position = msg.position;
+                match on_seek(msg, &self.db, &self.provider).await {
+                    Ok(update) => {
+                        self.adjust_progress(|progress| {
+                            progress.position_ms = position.as_millis() as u64
+                        });
+                        // The track now ends at another time
+                        self.recheck(&addr);
+                        self.send_state(&addr, update.in_reply_to(connection_id, request_id))
+                    }
+                    Err(err) => report_failure(&addr, connection_id, request_id, "seek", err),
+                }
+            }
+            RequestBody::Volume(msg) => {
+                let (connection_id, request_id) = (msg.connection_id, msg.request_id.clone());
+                let volume_percent = msg.volume_percent;
+                match on_volume(msg, &self.db, &self.provider).await {
+                    Ok(update) => {
+                        self.adjust_progress(|progress| {
+                            if let Some(device) = progress.device.as_mut() {
+                                device.volume_percent = Some(volume_percent as u32);
+                            }
+                        });
+                        self.send_state(&addr, update.in_reply_to(connection_id, request_id))
+                    }
+                    Err(err) => report_failure(&addr, connection_id, request_id, "volume", err),
+                }
+            }
+            RequestBody::Library(msg) => match on_library(&msg, &self.provider).await {
+                Ok(library) => addr.do_send(LibraryComplete {
+                    result: LibraryResultPayload {
+                        payload: library,
+                        request_id: msg.request_id,
+                    },
+                    connection_id: msg.connection_id,
+                }),
+                Err(err) => {
+                    report_failure(&addr, msg.connection_id, msg.request_id, "library", err)
+                }
+            },
+            RequestBody::Import(msg) => {
+                let (connection_id, request_id) = (msg.connection_id, msg.request_id.clone());
+                match on_import(msg, &self.db, &self.provider, &self.settings).await {
+                    Ok(update) => {
+                        self.recheck_if_moved(&addr, &update);
+                        self.send_state(&addr, update.in_reply_to(connection_id, request_id));
+                    }
+                    Err(err) => report_failure(&addr, connection_id, request_id, "import", err),
+                }
+            }
+            RequestBody::SetFallbackPlaylist(msg) => {
+                let (connection_id, request_id) = (msg.connection_id, msg.request_id.clone());
+                match on_set_fallback_playlist(msg, &self.db, &self.provider).await {
+                    Ok(update) => {
+                        self.send_state(&addr, update.in_reply_to(connection_id, request_id))
+                    }
+                    Err(err) => report_failure(
+                        &addr,
+                        connection_id,
+                        request_id,
+                        "set fallback playlist",
+                        err,
+                    ),
+                }
+            }
+            RequestBody::SetAutoplay(msg) => {
+                let (connection_id, request_id) = (msg.connection_id, msg.request_id.clone());
+                match on_set_autoplay(msg, &self.db, &self.provider).await {
+                    Ok(update) => {
+                        self.send_state(&addr, update.in_reply_to(connection_id, request_id))
+                    }
+                    Err(err) => {
+                        report_failure(&addr, connection_id, request_id, "set autoplay", err)
+                    }
+                }
+            }
+            RequestBody::History(msg) => match on_history(&msg, &self.db, &self.provider).await {
+                Ok(history) => addr.do_send(HistoryComplete {
+                    result: HistoryResultPayload {
+                        payload: history,
+                        request_id: msg.request_id,
+                    },
+                    connection_id: msg.connection_id,
+                }),
+                Err(err) => {
+                    report_failure(&addr, msg.connection_id, msg.request_id, "history", err)
+                }
+            },
+            RequestBody::SaveHistory(msg) => {
+                let (connection_id, request_id) = (msg.connection_id, msg.request_id.clone());
+                match on_save_history(msg, &self.db, &self.provider).await {
+                    Ok(playlist) => addr.do_send(SaveHistoryComplete {
+                        result: SavedPlaylistPayload {
+                            payload: playlist,
+                            request_id,
+                        },
+                        connection_id,
+                    }),
+                    Err(err) => {
+                        report_failure(&addr, connection_id, request_id, "save history", err)
                     }
                 }
             }
@@ -178,11 +910,234 @@ impl SessionAgent {
     }
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
-struct TrackInfo {
-    name: String,
-    artists: Vec<String>,
-    id: String,
+impl<P: MusicProvider + Clone> SessionWorker<P> {
+    /// Whether this instance polls the session and refreshes its token. When the
+    /// lease can't be checked we stay out, since two owners would advance the
+    /// queue twice.
+    async fn owns_session(&self, session_id: Uuid) -> bool {
+        match self
+            .cluster
+            .acquire_lease(session_id, self.settings.lease_ttl())
+            .await
+        {
+            Ok(owner) => owner,
+            Err(err) => {
+                log::error!("Failed to acquire lease on {session_id}, {err}");
+                false
+            }
+        }
+    }
+
+    /// Whether any instance has clients of the session connected. When they can't
+    /// be counted the session is taken to be in use, so it isn't swept by mistake.
+    async fn has_listeners(&self) -> bool {
+        match self.cluster.connected_clients(self.session_id).await {
+            Ok(clients) => clients > 0,
+            Err(err) => {
+                log::error!("Failed to count the clients of {}, {err}", self.session_id);
+                true
+            }
+        }
+    }
+
+    /// Queues something on the client's turn, turning away clients that queued too
+    /// recently. The turn is kept in Redis, so it holds across workers and instances.
+    /// When the turn can't be checked the client is let through.
+    async fn in_queue_turn<T>(
+        &self,
+        client_id: Uuid,
+        queue: impl Future<Output = Result<T, anyhow::Error>>,
+    ) -> Result<T, anyhow::Error> {
+        let min_interval = Duration::from_secs(self.settings.min_queue_interval_secs);
+        if min_interval.is_zero() {
+            return queue.await;
+        }
+
+        match self
+            .cluster
+            .claim_queue_turn(self.session_id, client_id, min_interval)
+            .await
+        {
+            Ok(None) => {}
+            Ok(Some(wait)) => {
+                return Err(Refusal::new(
+                    ErrorCode::RateLimited,
+                    format!(
+                        "Wait {} more seconds before queueing again",
+                        wait.as_secs() + 1
+                    ),
+                )
+                .into())
+            }
+            Err(err) => {
+                log::error!("Failed to check when {client_id} last queued, {err}");
+                return queue.await;
+            }
+        }
+
+        let result = queue.await;
+        // Nothing was queued, so the client may try again right away
+        if result.is_err() {
+            if let Err(err) = self
+                .cluster
+                .release_queue_turn(self.session_id, client_id)
+                .await
+            {
+                log::error!("Failed to release the queue turn of {client_id}, {err}");
+            }
+        }
+        result
+    }
+
+    /// Whether the session was deleted, in which case its timers should stop.
+    async fn session_gone(&self, session_id: Uuid) -> bool {
+        matches!(self.db.session_exists(session_id).await, Ok(false))
+    }
+
+    /// Where clients should expect playback to be by now, going by the last poll.
+    fn expected_progress(&self) -> Option<Progress> {
+        self.progress
+            .as_ref()
+            .map(|(progress, seen_at)| progress.advanced_by(seen_at.elapsed()))
+    }
+
+    /// Has the session checked again shortly, rather than at the wakeup planned
+    /// before a request changed its playback.
+    fn recheck(&self, addr: &Addr<Controller>) {
+        addr.do_send(Wakeup {
+            session_id: self.session_id,
+            after: RECHECK_INTERVAL,
+        });
+    }
+
+    fn recheck_if_moved(&self, addr: &Addr<Controller>, update: &StateUpdate) {
+        let current_track = update.update.payload.track.as_ref();
+        if current_track.map(|track| track.id.as_str()) != self.playback.track_id() {
+            self.recheck(addr);
+        }
+    }
+
+    /// Applies a change made on the host's behalf to the progress clients are
+    /// told about, instead of waiting for the next poll to see it.
+    fn adjust_progress(&mut self, change: impl FnOnce(&mut Progress)) {
+        if let Some(mut progress) = self.expected_progress() {
+            change(&mut progress);
+            self.progress = Some((progress, Instant::now()));
+        }
+    }
+
+    /// Sends a state update along with the progress of its current track.
+    fn send_state(&self, addr: &Addr<Controller>, mut update: StateUpdate) {
+        let state = &mut update.update.payload;
+        let current_track = state.track.as_ref().map(|track| track.id.clone());
+        state.progress = self
+            .expected_progress()
+            .filter(|progress| Some(&progress.track_id) == current_track.as_ref());
+        addr.do_send(update);
+    }
+
+    /// Answers a request that did not change the session. Only clients that asked
+    /// for a reply by sending a request id get one, in the form of the current state.
+    async fn acknowledge(
+        &self,
+        session_id: Uuid,
+        connection_id: Uuid,
+        request_id: Option<String>,
+        addr: &Addr<Controller>,
+    ) {
+        if request_id.is_none() {
+            return;
+        }
+
+        match get_current_state(session_id, Some(connection_id), &self.db, &self.provider).await {
+            Ok(update) => self.send_state(addr, update.in_reply_to(connection_id, request_id)),
+            Err(err) => report_failure(addr, connection_id, request_id, "get state", err),
+        }
+    }
+}
+
+/// Logs a failed request and tells the client that sent it what went wrong.
+fn report_failure(
+    addr: &Addr<Controller>,
+    connection_id: Uuid,
+    request_id: Option<String>,
+    operation: &str,
+    err: anyhow::Error,
+) {
+    log::error!("Error on {operation} {err}");
+    let (code, message) = describe_error(&err);
+    addr.do_send(RequestFailed {
+        connection_id,
+        code,
+        message,
+        request_id,
+    });
+}
+
+/// Maps an error to what the client is told about it. Refusals carry their own
+/// code, everything else is our failure and only described in broad terms.
+fn describe_error(err: &anyhow::Error) -> (ErrorCode, String) {
+    if let Some(refusal) = err.downcast_ref::<Refusal>() {
+        return (refusal.code, refusal.message.clone());
+    }
+
+    if err.downcast_ref::<redis::RedisError>().is_some() {
+        return (
+            ErrorCode::Internal,
+            "Failed to update the session".to_string(),
+        );
+    }
+
+    match err.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::RowNotFound) => (
+            ErrorCode::SessionNotFound,
+            "The session does not exist anymore".to_string(),
+        ),
+        Some(_) => (
+            ErrorCode::Internal,
+            "Failed to update the session".to_string(),
+        ),
+        None => (
+            ErrorCode::ProviderError,
+            "The music provider could not handle the request".to_string(),
+        ),
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug)]
+pub struct ImageInfo {
+    pub url: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+impl From<Image> for ImageInfo {
+    fn from(image: Image) -> Self {
+        ImageInfo {
+            url: image.url,
+            width: image.width,
+            height: image.height,
+        }
+    }
+}
+
+/// A track, or a podcast episode described in the same terms: the publisher
+/// stands in for the artist and the show for the album.
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug)]
+pub struct TrackInfo {
+    pub kind: ItemKind,
+    pub name: String,
+    pub artists: Vec<String>,
+    pub id: String,
+    pub album: String,
+    /// Cover art of the album in the sizes the provider has, largest first.
+    pub images: Vec<ImageInfo>,
+    pub duration_ms: u64,
+    pub explicit: bool,
+    /// How popular the track is, between 0 and 100.
+    pub popularity: u32,
+    /// A short clip of the track, if the provider has one.
+    pub preview_url: Option<String>,
 }
 
 impl TryFrom<FullTrack> for TrackInfo {
@@ -195,285 +1150,1069 @@ impl TryFrom<FullTrack> for TrackInfo {
         };
 
         Ok(TrackInfo {
+            kind: ItemKind::Track,
             name: track.name,
             artists: build_artist_string_vec(&track.artists),
             id: track_id.to_string(),
+            album: track.album.name,
+            images: track
+                .album
+                .images
+                .into_iter()
+                .map(ImageInfo::from)
+                .collect(),
+            duration_ms: track.duration.as_millis() as u64,
+            explicit: track.explicit,
+            popularity: track.popularity,
+            preview_url: track.preview_url,
         })
     }
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
-pub struct SearchResult {
-    tracks: Vec<TrackInfo>,
-}
+impl From<FullEpisode> for TrackInfo {
+    fn from(episode: FullEpisode) -> Self {
+        TrackInfo {
+            kind: ItemKind::Episode,
+            name: episode.name,
+            artists: vec![episode.show.publisher],
+            id: ItemId::from(episode.id).to_string(),
+            album: episode.show.name,
+            images: episode.images.into_iter().map(ImageInfo::from).collect(),
+            duration_ms: episode.duration.as_millis() as u64,
+            explicit: episode.explicit,
+            popularity: 0,
+            preview_url: episode.audio_preview_url,
+        }
+    }
+}
+
+/// A podcast, whose episodes can be queued.
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug)]
+pub struct ShowInfo {
+    pub id: String,
+    pub name: String,
+    pub publisher: String,
+    pub images: Vec<ImageInfo>,
+}
+
+impl From<SimplifiedShow> for ShowInfo {
+    fn from(show: SimplifiedShow) -> Self {
+        ShowInfo {
+            id: show.id.to_string(),
+            name: show.name,
+            publisher: show.publisher,
+            images: show.images.into_iter().map(ImageInfo::from).collect(),
+        }
+    }
+}
+
+/// A page of results for each type searched for. Types that weren't searched
+/// for come back empty.
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug, Default)]
+pub struct SearchResult {
+    pub tracks: Page<TrackInfo>,
+    pub albums: Page<AlbumInfo>,
+    pub artists: Page<ArtistInfo>,
+    pub playlists: Page<PlaylistInfo>,
+    pub episodes: Page<TrackInfo>,
+    pub shows: Page<ShowInfo>,
+}
+
+/// The tracks of an album or a playlist.
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug)]
+pub struct TrackList {
+    pub id: String,
+    pub tracks: Page<TrackInfo>,
+}
+
+/// A page of the host's library. Only the page of the source asked for is filled in.
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug)]
+pub struct LibraryResult {
+    pub source: LibrarySource,
+    pub playlists: Page<PlaylistInfo>,
+    pub tracks: Page<TrackInfo>,
+}
+
+/// A track waiting in the queue, with the client that queued it and its vote score.
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug)]
+pub struct QueuedTrack {
+    #[serde(flatten)]
+    pub track: TrackInfo,
+    pub queued_by: Option<Uuid>,
+    pub votes: i32,
+}
+
+/// A track the session played. Times are in RFC 3339, and the entry of the
+/// current track has no end yet.
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug)]
+pub struct HistoryEntry {
+    #[serde(flatten)]
+    pub track: TrackInfo,
+    pub queued_by: Option<Uuid>,
+    /// The score the track had when it left the queue.
+    pub votes: i32,
+    pub autoplayed: bool,
+    pub skipped: bool,
+    pub started_at: String,
+    pub ended_at: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Debug)]
+pub struct State {
+    track: Option<TrackInfo>,
+    queue: Vec<QueuedTrack>,
+    /// Unknown until the instance polling the session has seen the track play.
+    progress: Option<Progress>,
+    /// Set while the host has paused the session.
+    paused: bool,
+    /// Set when autoplay picked the current track rather than the queue.
+    autoplayed: bool,
+    autoplay: AutoplayMode,
+    /// Played from when the queue runs dry, if the host picked one.
+    fallback_playlist: Option<String>,
+}
+
+pub(crate) fn build_artist_string_vec(artists: &[SimplifiedArtist]) -> Vec<String> {
+    let mut artist_string_vec = Vec::new();
+
+    for artist in artists.iter() {
+        artist_string_vec.push(artist.name.clone());
+    }
+
+    artist_string_vec
+}
+
+async fn on_search<P: MusicProvider>(
+    msg: &controller::Search,
+    provider: &P,
+) -> Result<SearchResult, anyhow::Error> {
+    let types = if msg.types.is_empty() {
+        DEFAULT_SEARCH_KINDS
+    } else {
+        msg.types.as_slice()
+    };
+    provider
+        .search(msg.session_id, &msg.query, types, msg.page)
+        .await
+}
+
+async fn on_browse<P: MusicProvider>(
+    msg: &controller::Browse,
+    provider: &P,
+) -> Result<TrackList, anyhow::Error> {
+    let tracks = match &msg.collection {
+        CollectionId::Album(id) => provider.album_tracks(msg.session_id, id, msg.page).await?,
+        CollectionId::Playlist(id) => {
+            provider
+                .playlist_tracks(msg.session_id, id, msg.page)
+                .await?
+        }
+    };
+    Ok(TrackList {
+        id: msg.collection.to_string(),
+        tracks,
+    })
+}
+
+async fn on_library<P: MusicProvider>(
+    msg: &controller::Library,
+    provider: &P,
+) -> Result<LibraryResult, anyhow::Error> {
+    let mut result = LibraryResult {
+        source: msg.source,
+        playlists: Page::default(),
+        tracks: Page::default(),
+    };
+    match msg.source {
+        LibrarySource::Playlists => {
+            result.playlists = provider.user_playlists(msg.session_id, msg.page).await?
+        }
+        LibrarySource::Saved => {
+            result.tracks = provider.saved_tracks(msg.session_id, msg.page).await?
+        }
+        LibrarySource::Top => result.tracks = provider.top_tracks(msg.session_id, msg.page).await?,
+    }
+    Ok(result)
+}
+
+async fn on_history<P: MusicProvider>(
+    msg: &controller::History,
+    db: &Database,
+    provider: &P,
+) -> Result<Page<HistoryEntry>, anyhow::Error> {
+    let total = db.played_track_count(msg.session_id).await?;
+    let played = match msg.page {
+        Some(page) => {
+            db.get_played_tracks(msg.session_id, Some(page.limit as i64), page.offset as i64)
+                .await?
+        }
+        None => db.get_played_tracks(msg.session_id, None, 0).await?,
+    };
+    let ids: Vec<ItemId> = played.iter().map(|play| play.track_id.clone()).collect();
+    let infos = lookup_tracks(msg.session_id, &ids, provider).await?;
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
-pub struct State {
-    track: Option<TrackInfo>,
-    queue: Vec<TrackInfo>,
+    let items: Vec<HistoryEntry> = played
+        .into_iter()
+        .filter_map(|play| {
+            let track = infos.get(&play.track_id.to_string())?.clone();
+            Some(HistoryEntry {
+                track,
+                queued_by: play.queued_by,
+                votes: play.votes,
+                autoplayed: play.autoplayed,
+                skipped: play.skipped,
+                started_at: play.started_at.to_rfc3339(),
+                ended_at: play.ended_at.map(|ended_at| ended_at.to_rfc3339()),
+            })
+        })
+        .collect();
+    let page = msg.page.unwrap_or(PageRequest {
+        limit: total as u32,
+        offset: 0,
+    });
+    Ok(Page {
+        items,
+        offset: page.offset,
+        limit: page.limit,
+        total: total as u32,
+    })
 }
 
-fn build_artist_string_vec(artists: &Vec<SimplifiedArtist>) -> Vec<String> {
-    let mut artist_string_vec = Vec::new();
-
-    for artist in artists.iter() {
-        artist_string_vec.push(artist.name.clone());
+async fn on_save_history<P: MusicProvider>(
+    msg: controller::SaveHistory,
+    db: &Database,
+    provider: &P,
+) -> Result<PlaylistInfo, anyhow::Error> {
+    let played: Vec<ItemId> = db
+        .get_played_tracks(msg.session_id, None, 0)
+        .await?
+        .into_iter()
+        .map(|play| play.track_id)
+        .collect();
+    if played.is_empty() {
+        return Err(Refusal::new(
+            ErrorCode::EmptyHistory,
+            "Nothing has been played in this session yet",
+        )
+        .into());
     }
 
-    artist_string_vec
+    let name = match msg.name {
+        Some(name) if !name.trim().is_empty() => name,
+        _ => format!("Queuetify session {}", Utc::now().format("%Y-%m-%d")),
+    };
+    provider
+        .create_playlist(msg.session_id, &name, &played)
+        .await
 }
 
-async fn get_search_results(
-    spotify: &AuthCodeSpotify,
-    input: &str,
-) -> Result<SearchResult, ClientError> {
-    let mut search_result = SearchResult { tracks: Vec::new() };
-
-    if let Tracks(track_pages) = spotify
-        .search(
-            &input,
-            &SearchType::Track,
-            Some(&Market::FromToken),
-            None,
-            Some(10),
-            None,
-        )
-        .await?
-    {
-        for item in track_pages.items {
-            let track_info = match TrackInfo::try_from(item) {
-                Ok(info) => info,
-                Err(_) => continue,
-            };
-            search_result.tracks.push(track_info);
+/// Looks up every item once, however often it occurs, in batches the provider
+/// accepts. The result is keyed by uri.
+async fn lookup_tracks<P: MusicProvider>(
+    session_id: Uuid,
+    ids: &[ItemId],
+    provider: &P,
+) -> Result<HashMap<String, TrackInfo>, anyhow::Error> {
+    let mut unique: Vec<ItemId> = Vec::new();
+    for id in ids {
+        if !unique.contains(id) {
+            unique.push(id.clone());
         }
     }
 
-    Ok(search_result)
-}
-
-async fn on_search(msg: &controller::Search, db: &Database) -> Result<SearchResult, ()> {
-    if let Ok(spotify) = db.get_spotify(msg.session_id).await {
-        if let Ok(search_result) = get_search_results(&spotify, &msg.query).await {
-            return Ok(search_result);
+    let mut infos = HashMap::new();
+    for chunk in unique.chunks(MAX_PAGE_LIMIT as usize) {
+        for info in provider.tracks(session_id, chunk).await? {
+            infos.insert(info.id.clone(), info);
         }
     }
-
-    Err(())
-}
-
-async fn start_playback(spotify: &AuthCodeSpotify, id: TrackId) -> Result<(), anyhow::Error> {
-    let uri: Box<dyn PlayableId> = Box::new(id);
-    spotify
-        .start_uris_playback(Some(uri.as_ref()), None, None, None)
-        .await?;
-    Ok(())
+    Ok(infos)
 }
 
-async fn get_current_state(
+async fn get_current_state<P: MusicProvider>(
     id: Uuid,
     connection_id: Option<Uuid>,
-    spotify: &AuthCodeSpotify,
     db: &Database,
+    provider: &P,
 ) -> Result<StateUpdate, anyhow::Error> {
     let state = db.get_current_state(id).await?;
     let current_track = match state.current_track_uri {
-        Some(current_track_id) => {
-            let current_track = spotify.track(&current_track_id).await?;
-            match TrackInfo::try_from(current_track) {
-                Ok(info) => Some(info),
-                Err(_) => None,
-            }
-        }
+        Some(current_track_id) => Some(provider.track(id, &current_track_id).await?),
         None => None,
     };
 
-    let mut current_queue = Vec::new();
-
-    if !state.current_queue.is_empty() {
-        let tracks = state.current_queue.iter().collect::<Vec<_>>();
-        let queue = spotify.tracks(tracks, None).await?;
-
-        for track in queue.iter() {
-            match TrackInfo::try_from(track.clone()) {
-                Ok(info) => {
-                    current_queue.push(info);
-                }
-                Err(_) => {}
-            }
-        }
-    }
+    let queued_ids: Vec<ItemId> = state
+        .current_queue
+        .iter()
+        .map(|entry| entry.track_id.clone())
+        .collect();
+    let mut infos = lookup_tracks(id, &queued_ids, provider).await?;
+    let current_queue = state
+        .current_queue
+        .into_iter()
+        .filter_map(|entry| {
+            infos
+                .remove(&entry.track_id.to_string())
+                .map(|track| QueuedTrack {
+                    track,
+                    queued_by: entry.client_id,
+                    votes: entry.votes,
+                })
+        })
+        .collect();
 
     let payload = State {
         track: current_track,
         queue: current_queue,
+        progress: None,
+        paused: state.paused,
+        autoplayed: state.current_autoplayed,
+        autoplay: state.autoplay,
+        fallback_playlist: state.fallback_playlist,
     };
     Ok(StateUpdate {
-        update: StateUpdatePayload { payload },
+        update: StateUpdatePayload {
+            payload,
+            request_id: None,
+        },
         session_id: id,
         connection_id,
+        reply_to: None,
     })
 }
 
-async fn on_queue(msg: controller::Queue, db: &Database) -> Result<StateUpdate, anyhow::Error> {
-    let spotify = db.get_spotify(msg.session_id).await?;
+/// How many more tracks the client may queue, refusing if that's none. The
+/// transaction has to hold the queue lock.
+async fn queue_room(
+    transaction: &mut Transaction<'static, Postgres>,
+    id: Uuid,
+    connection_id: Uuid,
+    db: &Database,
+    settings: &SessionSettings,
+) -> Result<i64, anyhow::Error> {
+    let queue_length = db.queue_length(transaction, id).await?;
+    if queue_length >= settings.max_queue_length {
+        return Err(Refusal::new(ErrorCode::QueueFull, "The queue is full").into());
+    }
+
+    let pending = db
+        .pending_track_count(transaction, id, connection_id)
+        .await?;
+    if pending >= settings.max_pending_tracks_per_client {
+        return Err(Refusal::new(
+            ErrorCode::TooManyPendingTracks,
+            format!("You already have {pending} tracks waiting in the queue"),
+        )
+        .into());
+    }
+
+    Ok((settings.max_queue_length - queue_length)
+        .min(settings.max_pending_tracks_per_client - pending))
+}
 
-    let (track, transaction) = db.get_current_track(msg.session_id).await?;
+async fn on_queue<P: MusicProvider>(
+    msg: controller::Queue,
+    db: &Database,
+    provider: &P,
+    settings: &SessionSettings,
+) -> Result<StateUpdate, anyhow::Error> {
+    let (track, mut transaction) = db.lock_current_track(msg.session_id).await?;
     match track {
         Some(_) => {
-            db.queue_track(transaction, msg.session_id, msg.track_id)
+            queue_room(
+                &mut transaction,
+                msg.session_id,
+                msg.connection_id,
+                db,
+                settings,
+            )
+            .await?;
+            db.queue_track(transaction, msg.session_id, msg.track_id, msg.connection_id)
                 .await?;
         }
         None => {
-            let _ = start_playback(&spotify, msg.track_id.clone()).await?;
-            let _ = db
-                .set_current_track(transaction, msg.session_id, Some(msg.track_id))
+            provider
+                .start_playback(msg.session_id, &msg.track_id)
                 .await?;
+            db.set_current_track(
+                transaction,
+                msg.session_id,
+                Some(Play::queued(msg.track_id, msg.connection_id)),
+            )
+            .await?;
         }
     }
-
-    let state = get_current_state(msg.session_id, None, &spotify, &db).await?;
+    let state = get_current_state(msg.session_id, None, db, provider).await?;
     Ok(state)
 }
 
-async fn on_poll_state(id: Uuid, db: &Database) -> Result<Option<StateUpdate>, anyhow::Error> {
-    let spotify = db.get_spotify(id).await?;
-    let (track, mut transaction) = db.get_current_track(id).await?;
-    match track {
-        Some(expected_playing_id) => {
-            match spotify
-                .current_playing(None, None::<Vec<&AdditionalType>>)
-                .await?
-            {
-                Some(current_playing_context) => match current_playing_context.item {
-                    Some(PlayableItem::Track(track)) => {
-                        if let Some(actual_playing_id) = track.id {
-                            if actual_playing_id != expected_playing_id {
-                                let _ = start_playback(&spotify, expected_playing_id).await?;
-                            } else {
-                                if current_playing_context.is_playing {
-                                    match current_playing_context.progress {
-                                        Some(duration) => {
-                                            if (track.duration - duration) < POLL_STATE_INTERVAL {
-                                                match db
-                                                    .pop_track_from_queue(id, &mut transaction)
-                                                    .await?
-                                                {
-                                                    Some(new_track) => {
-                                                        db.remove_votes(
-                                                            &mut transaction,
-                                                            id,
-                                                            new_track.clone(),
-                                                        )
-                                                        .await?;
-                                                        db.set_current_track(
-                                                            transaction,
-                                                            id,
-                                                            Some(new_track.clone()),
-                                                        )
-                                                        .await?;
-                                                        spotify
-                                                            .add_item_to_queue(&new_track, None)
-                                                            .await?
-                                                    }
-                                                    None => {
-                                                        db.set_current_track(transaction, id, None)
-                                                            .await?;
-                                                    }
-                                                }
-
-                                                let state =
-                                                    get_current_state(id, None, &spotify, &db)
-                                                        .await?;
-                                                return Ok(Some(state));
-                                            }
-                                        }
-                                        None => {
-                                            log::error!(
-                                                "Progress missing for current playing context!"
-                                            );
-                                            // TODO
-                                        }
-                                    }
-                                } else {
-                                    spotify.resume_playback(None, None).await?;
-                                }
-                            }
-                        } else {
-                            log::error!("Track id missing for actual currently playing track!");
-                            // TODO
-                        }
-                    }
-                    Some(PlayableItem::Episode(_)) => {
-                        log::error!("Actual current playing is episode");
-                        // TODO
-                    }
-                    None => {
-                        log::error!("Actual current playing item is none");
-                        // TODO
-                        let _ = start_playback(&spotify, expected_playing_id).await?;
-                    }
-                },
-                None => {
-                    start_playback(&spotify, expected_playing_id).await?;
+/// The first `cap` tracks of an album or playlist, without repeats.
+async fn collection_items<P: MusicProvider>(
+    id: Uuid,
+    collection: &CollectionId,
+    cap: usize,
+    provider: &P,
+) -> Result<Vec<ItemId>, anyhow::Error> {
+    let mut items: Vec<ItemId> = Vec::new();
+    let mut offset = 0;
+    while items.len() < cap {
+        let page = PageRequest {
+            limit: MAX_PAGE_LIMIT,
+            offset,
+        };
+        let tracks = match collection {
+            CollectionId::Album(album_id) => provider.album_tracks(id, album_id, page).await?,
+            CollectionId::Playlist(playlist_id) => {
+                provider.playlist_tracks(id, playlist_id, page).await?
+            }
+        };
+
+        for track in tracks.items.iter() {
+            if let Ok(track_id) = ItemId::from_str(&track.id) {
+                if !items.contains(&track_id) {
+                    items.push(track_id);
                 }
             }
         }
+
+        offset += MAX_PAGE_LIMIT;
+        if offset >= tracks.total {
+            break;
+        }
+    }
+
+    items.truncate(cap);
+    Ok(items)
+}
+
+/// Queues as many of the collection's tracks as the queue and the client's limits
+/// have room for, all in one transaction. When nothing is playing the first of
+/// them starts right away.
+async fn on_queue_collection<P: MusicProvider>(
+    msg: controller::QueueCollection,
+    db: &Database,
+    provider: &P,
+    settings: &SessionSettings,
+) -> Result<StateUpdate, anyhow::Error> {
+    let mut items = collection_items(
+        msg.session_id,
+        &msg.collection,
+        settings.max_collection_tracks,
+        provider,
+    )
+    .await?;
+    if items.is_empty() {
+        return Err(Refusal::new(
+            ErrorCode::NothingToQueue,
+            format!("{} has no tracks to queue", msg.collection),
+        )
+        .into());
+    }
+
+    let (track, mut transaction) = db.lock_current_track(msg.session_id).await?;
+    let first = match track {
+        Some(_) => None,
+        None => Some(items.remove(0)),
+    };
+
+    let mut queued = 0;
+    if !items.is_empty() {
+        let room = queue_room(
+            &mut transaction,
+            msg.session_id,
+            msg.connection_id,
+            db,
+            settings,
+        )
+        .await?;
+        queued = db
+            .queue_tracks(
+                &mut transaction,
+                msg.session_id,
+                &items,
+                msg.connection_id,
+                room,
+            )
+            .await?;
+    }
+
+    match first {
+        Some(first) => {
+            provider.start_playback(msg.session_id, &first).await?;
+            db.set_current_track(
+                transaction,
+                msg.session_id,
+                Some(Play::queued(first, msg.connection_id)),
+            )
+            .await?;
+        }
+        None if queued == 0 => {
+            return Err(Refusal::new(
+                ErrorCode::NothingToQueue,
+                format!("Every track of {} is in the queue already", msg.collection),
+            )
+            .into());
+        }
+        None => transaction.commit().await?,
+    }
+    get_current_state(msg.session_id, None, db, provider).await
+}
+
+/// Queues the tracks the host picked, in the order given and as far as the queue
+/// has room. Being the host's own pick, the limits on clients don't apply. When
+/// nothing is playing the first of them starts right away.
+async fn on_import<P: MusicProvider>(
+    msg: controller::Import,
+    db: &Database,
+    provider: &P,
+    settings: &SessionSettings,
+) -> Result<StateUpdate, anyhow::Error> {
+    let mut items: Vec<ItemId> = Vec::new();
+    for track_id in msg.track_ids {
+        if !items.contains(&track_id) {
+            items.push(track_id);
+        }
+    }
+    if items.is_empty() {
+        return Err(Refusal::new(ErrorCode::NothingToQueue, "There is nothing to import").into());
+    }
+
+    let (track, mut transaction) = db.lock_current_track(msg.session_id).await?;
+    let first = match track {
+        Some(_) => None,
+        None => Some(items.remove(0)),
+    };
+
+    let mut queued = 0;
+    if !items.is_empty() {
+        let queue_length = db.queue_length(&mut transaction, msg.session_id).await?;
+        if queue_length >= settings.max_queue_length && first.is_none() {
+            return Err(Refusal::new(ErrorCode::QueueFull, "The queue is full").into());
+        }
+        queued = db
+            .queue_tracks(
+                &mut transaction,
+                msg.session_id,
+                &items,
+                msg.connection_id,
+                (settings.max_queue_length - queue_length).max(0),
+            )
+            .await?;
+    }
+
+    match first {
+        Some(first) => {
+            provider.start_playback(msg.session_id, &first).await?;
+            db.set_current_track(
+                transaction,
+                msg.session_id,
+                Some(Play::queued(first, msg.connection_id)),
+            )
+            .await?;
+        }
+        None if queued == 0 => {
+            return Err(Refusal::new(
+                ErrorCode::NothingToQueue,
+                "Every imported track is in the queue already",
+            )
+            .into());
+        }
+        None => transaction.commit().await?,
+    }
+
+    get_current_state(msg.session_id, None, db, provider).await
+}
+
+async fn on_set_fallback_playlist<P: MusicProvider>(
+    msg: controller::SetFallbackPlaylist,
+    db: &Database,
+    provider: &P,
+) -> Result<StateUpdate, anyhow::Error> {
+    // Make sure the playlist can be played from before relying on it
+    if let Some(playlist) = &msg.playlist {
+        let tracks = provider
+            .playlist_tracks(msg.session_id, playlist, PageRequest::default())
+            .await?;
+        if tracks.total == 0 {
+            return Err(Refusal::new(
+                ErrorCode::NothingToQueue,
+                format!("{playlist} has no tracks to fall back on"),
+            )
+            .into());
+        }
+    }
+
+    db.set_fallback_playlist(
+        msg.session_id,
+        msg.playlist.map(|playlist| playlist.to_string()),
+    )
+    .await?;
+    get_current_state(msg.session_id, None, db, provider).await
+}
+
+async fn on_set_autoplay<P: MusicProvider>(
+    msg: controller::SetAutoplay,
+    db: &Database,
+    provider: &P,
+) -> Result<StateUpdate, anyhow::Error> {
+    db.set_autoplay_mode(msg.session_id, msg.mode).await?;
+    get_current_state(msg.session_id, None, db, provider).await
+}
+
+/// How many of the tracks played last seed recommendations.
+const RECOMMENDATION_SEEDS: usize = 5;
+/// Tracks played this recently aren't recommended again.
+const RECENTLY_PLAYED_WINDOW: i64 = 50;
+
+/// Picks the track to play once the queue is done, the way the session's
+/// autoplay mode has it.
+async fn next_autoplay_item<P: MusicProvider>(
+    id: Uuid,
+    transaction: &mut Transaction<'static, Postgres>,
+    db: &Database,
+    provider: &P,
+) -> Result<Option<ItemId>, anyhow::Error> {
+    let (mode, _) = db.get_autoplay(transaction, id).await?;
+    match mode {
+        AutoplayMode::Off => Ok(None),
+        AutoplayMode::Playlist => next_fallback_item(id, transaction, db, provider).await,
+        AutoplayMode::Recommendations => recommended_item(id, transaction, db, provider).await,
+        AutoplayMode::History => Ok(db.least_recently_played(transaction, id).await?),
+    }
+}
+
+/// A track recommended after the ones the session played last, which it hasn't
+/// played lately itself.
+async fn recommended_item<P: MusicProvider>(
+    id: Uuid,
+    transaction: &mut Transaction<'static, Postgres>,
+    db: &Database,
+    provider: &P,
+) -> Result<Option<ItemId>, anyhow::Error> {
+    let recent = db
+        .recently_played(transaction, id, RECENTLY_PLAYED_WINDOW)
+        .await?;
+    let seeds: Vec<ItemId> = recent
+        .iter()
+        .filter(|item| item.kind() == ItemKind::Track)
+        .take(RECOMMENDATION_SEEDS)
+        .cloned()
+        .collect();
+    if seeds.is_empty() {
+        return Ok(None);
+    }
+
+    let recommended = provider.recommendations(id, &seeds, MAX_PAGE_LIMIT).await?;
+    Ok(recommended
+        .iter()
+        .filter_map(|track| ItemId::from_str(&track.id).ok())
+        .find(|item| !recent.contains(item)))
+}
+
+/// The next track of the session's fallback playlist, if it has one. Tracks that
+/// can't be played are passed over, and the playlist starts over once it ends.
+async fn next_fallback_item<P: MusicProvider>(
+    id: Uuid,
+    transaction: &mut Transaction<'static, Postgres>,
+    db: &Database,
+    provider: &P,
+) -> Result<Option<ItemId>, anyhow::Error> {
+    let (uri, position) = match db.get_fallback_playlist(transaction, id).await? {
+        Some(fallback) => fallback,
+        None => return Ok(None),
+    };
+    let playlist = PlaylistId::from_str(&uri)?;
+
+    let mut offset = position.max(0) as u32;
+    let mut started_over = false;
+    loop {
+        let page = PageRequest { limit: 1, offset };
+        let tracks = provider.playlist_tracks(id, &playlist, page).await?;
+        if offset >= tracks.total {
+            if started_over || offset == 0 {
+                return Ok(None);
+            }
+            offset = 0;
+            started_over = true;
+            continue;
+        }
+
+        offset += 1;
+        let item = tracks
+            .items
+            .first()
+            .and_then(|track| ItemId::from_str(&track.id).ok());
+        if let Some(item) = item {
+            db.set_fallback_position(transaction, id, offset as i32)
+                .await?;
+            return Ok(Some(item));
+        }
+    }
+}
+
+async fn advance_track<P: MusicProvider>(
+    id: Uuid,
+    mut transaction: Transaction<'static, Postgres>,
+    db: &Database,
+    provider: &P,
+    handoff: Handoff,
+) -> Result<StateUpdate, anyhow::Error> {
+    db.clear_skip_votes(&mut transaction, id).await?;
+    let next = match db.pop_track_from_queue(id, &mut transaction).await? {
+        Some(entry) => {
+            db.remove_votes(&mut transaction, id, entry.track_id.clone())
+                .await?;
+            Some(Play::from(entry))
+        }
+        None => next_autoplay_item(id, &mut transaction, db, provider)
+            .await
+            .unwrap_or_else(|err| {
+                log::error!("Failed to autoplay in session {id}, {err}");
+                None
+            })
+            .map(Play::autoplayed),
+    };
+
+    match next {
+        Some(play) => {
+            let new_track = play.track_id.clone();
+            db.set_current_track(transaction, id, Some(play)).await?;
+            match handoff {
+                Handoff::Enqueue => provider.add_to_queue(id, &new_track).await?,
+                Handoff::Immediate => provider.start_playback(id, &new_track).await?,
+            }
+        }
         None => {
-            log::info!("No current track"); // TODO: check queue?
+            db.set_current_track(transaction, id, None).await?;
+            if handoff == Handoff::Immediate {
+                provider.pause_playback(id).await?;
+            }
+        }
+    }
+
+    get_current_state(id, None, db, provider).await
+}
+
+/// What polling a session's playback turned up.
+struct Poll {
+    update: Option<StateUpdate>,
+    progress: Option<Progress>,
+}
+
+fn observed_progress(playback: &CurrentPlayback) -> Option<Progress> {
+    match &playback.item {
+        Some(PlayingItem {
+            id: Some(track_id),
+            duration,
+        }) => Some(Progress {
+            track_id: track_id.to_string(),
+            position_ms: playback.progress.unwrap_or_default().as_millis() as u64,
+            duration_ms: duration.as_millis() as u64,
+            is_playing: playback.is_playing,
+            device: playback.device.clone(),
+            timestamp_ms: Utc::now().timestamp_millis(),
+        }),
+        _ => None,
+    }
+}
+
+fn observation(playback: &CurrentPlayback) -> Observation {
+    let (track_id, duration) = match &playback.item {
+        Some(PlayingItem { id, duration }) => (id.as_ref().map(|id| id.to_string()), *duration),
+        None => (None, Duration::ZERO),
+    };
+    Observation {
+        track_id,
+        position: playback.progress.unwrap_or_default(),
+        duration,
+        is_playing: playback.is_playing,
+    }
+}
+
+async fn on_poll_state<P: MusicProvider>(
+    id: Uuid,
+    db: &Database,
+    provider: &P,
+    playback: &mut Playback,
+) -> Result<Poll, anyhow::Error> {
+    let (track, mut transaction) = db.get_current_track(id).await?;
+    let paused = db.is_paused(&mut transaction, id).await?;
+    let current_playback = match &track {
+        Some(_) => provider.current_playback(id).await?,
+        None => None,
+    };
+    let progress = current_playback.as_ref().and_then(observed_progress);
+
+    // Nothing playing at all is told apart from playing something else
+    let observed = current_playback
+        .as_ref()
+        .filter(|current_playback| current_playback.item.is_some())
+        .map(observation);
+    let expected = track.as_ref().map(|track| track.to_string());
+    let now = Instant::now();
+
+    let action = if paused {
+        playback.hold(expected.as_deref());
+        Action::Wait
+    } else {
+        playback.observe(expected.as_deref(), observed.as_ref(), now)
+    };
+
+    let mut update = None;
+    match action {
+        Action::Wait => {}
+        Action::Start => {
+            if let Some(track) = &track {
+                provider.start_playback(id, track).await?;
+            }
+        }
+        Action::Advance(handoff) => {
+            let state = advance_track(id, transaction, db, provider, handoff).await?;
+            let next = state
+                .update
+                .payload
+                .track
+                .as_ref()
+                .map(|track| track.id.clone());
+            playback.handed_off(next, handoff, now);
+            update = Some(state);
         }
     }
-    Ok(None)
+
+    Ok(Poll { update, progress })
 }
 
-async fn on_vote(
+async fn on_vote<P: MusicProvider>(
     msg: controller::Vote,
     db: &Database,
+    provider: &P,
+    settings: &SessionSettings,
 ) -> Result<Option<StateUpdate>, anyhow::Error> {
-    match db.add_vote(&msg).await {
-        Ok(()) => {
-            let spotify = db.get_spotify(msg.session_id).await?;
-            let state = get_current_state(msg.session_id, None, &spotify, &db).await?;
-            Ok(Some(state))
-        }
-        Err(_) => Ok(None),
+    if !db.add_vote(&msg).await? {
+        return Ok(None);
+    }
+
+    let removed = db
+        .remove_tracks_below_score(msg.session_id, settings.removal_score_threshold)
+        .await?;
+    if removed > 0 {
+        log::info!("Removed {removed} tracks voted below the threshold");
+    }
+
+    let state = get_current_state(msg.session_id, None, db, provider).await?;
+    Ok(Some(state))
+}
+
+async fn on_unvote<P: MusicProvider>(
+    msg: controller::Unvote,
+    db: &Database,
+    provider: &P,
+) -> Result<Option<StateUpdate>, anyhow::Error> {
+    if !db.remove_vote(&msg).await? {
+        return Ok(None);
+    }
+
+    let state = get_current_state(msg.session_id, None, db, provider).await?;
+    Ok(Some(state))
+}
+
+async fn on_remove_track<P: MusicProvider>(
+    msg: controller::RemoveTrack,
+    db: &Database,
+    provider: &P,
+) -> Result<StateUpdate, anyhow::Error> {
+    if !db
+        .remove_queued_track(msg.session_id, &msg.track_id)
+        .await?
+    {
+        return Err(not_queued(&msg.track_id));
     }
+
+    let state = get_current_state(msg.session_id, None, db, provider).await?;
+    Ok(state)
 }
 
-async fn on_refresh(id: Uuid, db: &Database) -> Result<(), anyhow::Error> {
-    let spotify = db.get_spotify(id).await?;
-    spotify.refresh_token().await?;
-    db.set_spotify(id, &spotify).await?;
-    Ok(())
+async fn on_pin_track<P: MusicProvider>(
+    msg: controller::PinTrack,
+    db: &Database,
+    provider: &P,
+) -> Result<StateUpdate, anyhow::Error> {
+    if !db.pin_track(msg.session_id, &msg.track_id).await? {
+        return Err(not_queued(&msg.track_id));
+    }
+
+    let state = get_current_state(msg.session_id, None, db, provider).await?;
+    Ok(state)
 }
 
-async fn on_devices(
-    msg: controller::Devices,
+async fn on_move_track<P: MusicProvider>(
+    msg: controller::MoveTrack,
     db: &Database,
-) -> Result<Vec<DeviceInfo>, anyhow::Error> {
-    let spotify = db.get_spotify(msg.session_id).await?;
-    let devices = spotify.device().await?;
+    provider: &P,
+) -> Result<StateUpdate, anyhow::Error> {
+    let mut transaction = db.begin_queue_change(msg.session_id).await?;
+    let queue_length = db.queue_length(&mut transaction, msg.session_id).await?;
+    let position = i32::try_from(msg.position)
+        .ok()
+        .filter(|position| i64::from(*position) < queue_length)
+        .ok_or_else(|| {
+            Refusal::new(
+                ErrorCode::OutOfRange,
+                format!("The queue only has {queue_length} tracks"),
+            )
+        })?;
 
-    let mut device_infos = Vec::new();
+    if !db
+        .move_track(&mut transaction, msg.session_id, &msg.track_id, position)
+        .await?
+    {
+        return Err(not_queued(&msg.track_id));
+    }
+    transaction.commit().await?;
 
-    for device in devices.iter() {
-        let dev_info = match DeviceInfo::try_from(device.clone()) {
-            Ok(info) => info,
-            Err(_) => continue,
-        };
+    let state = get_current_state(msg.session_id, None, db, provider).await?;
+    Ok(state)
+}
+
+fn required_skip_votes(connected_clients: usize, fraction: f64) -> i64 {
+    ((connected_clients as f64) * fraction).ceil().max(1.0) as i64
+}
+
+async fn on_vote_skip<P: MusicProvider>(
+    msg: controller::VoteSkip,
+    db: &Database,
+    provider: &P,
+    cluster: &Cluster,
+    settings: &SessionSettings,
+) -> Result<Option<StateUpdate>, anyhow::Error> {
+    let (track, mut transaction) = db.get_current_track(msg.session_id).await?;
+    let track = match track {
+        Some(track) => track,
+        None => return Err(nothing_playing()),
+    };
+
+    let skip_votes = db
+        .add_skip_vote(msg.session_id, msg.connection_id, &track)
+        .await?;
+    // Clients on other instances count too
+    let connected_clients = cluster.connected_clients(msg.session_id).await?;
+    if skip_votes < required_skip_votes(connected_clients, settings.skip_vote_fraction) {
+        return Ok(None);
+    }
+
+    log::info!("Skipping {track} after {skip_votes} skip votes");
+    db.mark_skipped(&mut transaction, msg.session_id).await?;
+    let state = advance_track(
+        msg.session_id,
+        transaction,
+        db,
+        provider,
+        Handoff::Immediate,
+    )
+    .await?;
+    Ok(Some(state))
+}
+
+async fn on_skip<P: MusicProvider>(
+    msg: controller::Skip,
+    db: &Database,
+    provider: &P,
+) -> Result<StateUpdate, anyhow::Error> {
+    let (track, mut transaction) = db.get_current_track(msg.session_id).await?;
+    if track.is_none() {
+        return Err(nothing_playing());
+    }
 
-        device_infos.push(dev_info);
+    db.mark_skipped(&mut transaction, msg.session_id).await?;
+    let state = advance_track(
+        msg.session_id,
+        transaction,
+        db,
+        provider,
+        Handoff::Immediate,
+    )
+    .await?;
+    Ok(state)
+}
+
+async fn on_pause<P: MusicProvider>(
+    msg: controller::Pause,
+    db: &Database,
+    provider: &P,
+) -> Result<StateUpdate, anyhow::Error> {
+    let (track, _) = db.get_current_track(msg.session_id).await?;
+    if track.is_none() {
+        return Err(nothing_playing());
+    }
+
+    // Paused first, so a poll in between doesn't start playback again
+    db.set_paused(msg.session_id, true).await?;
+    provider.pause_playback(msg.session_id).await?;
+
+    let state = get_current_state(msg.session_id, None, db, provider).await?;
+    Ok(state)
+}
+
+async fn on_resume<P: MusicProvider>(
+    msg: controller::Resume,
+    db: &Database,
+    provider: &P,
+) -> Result<StateUpdate, anyhow::Error> {
+    let (track, _) = db.get_current_track(msg.session_id).await?;
+    if track.is_none() {
+        return Err(nothing_playing());
+    }
+
+    db.set_paused(msg.session_id, false).await?;
+    provider.resume_playback(msg.session_id).await?;
+
+    let state = get_current_state(msg.session_id, None, db, provider).await?;
+    Ok(state)
+}
+
+async fn on_seek<P: MusicProvider>(
+    msg: controller::Seek,
+    db: &Database,
+    provider: &P,
+) -> Result<StateUpdate, anyhow::Error> {
+    let (track, _) = db.get_current_track(msg.session_id).await?;
+    let track = match track {
+        Some(track) => provider.track(msg.session_id, &track).await?,
+        None => return Err(nothing_playing()),
+    };
+    if msg.position.as_millis() >= track.duration_ms as u128 {
+        return Err(Refusal::new(
+            ErrorCode::OutOfRange,
+            format!("{} is only {} ms long", track.id, track.duration_ms),
+        )
+        .into());
     }
 
-    Ok(device_infos)
+    provider.seek(msg.session_id, msg.position).await?;
+
+    let state = get_current_state(msg.session_id, None, db, provider).await?;
+    Ok(state)
 }
 
-async fn on_transfer(msg: controller::Transfer, db: &Database) -> Result<(), anyhow::Error> {
-    let spotify = db.get_spotify(msg.session_id).await?;
-    spotify
-        .transfer_playback(&msg.device_id, Some(false))
+async fn on_volume<P: MusicProvider>(
+    msg: controller::Volume,
+    db: &Database,
+    provider: &P,
+) -> Result<StateUpdate, anyhow::Error> {
+    if msg.volume_percent > 100 {
+        return Err(Refusal::new(
+            ErrorCode::OutOfRange,
+            "The volume goes from 0 to 100 percent",
+        )
+        .into());
+    }
+
+    provider
+        .set_volume(msg.session_id, msg.volume_percent)
         .await?;
-    Ok(())
+
+    let state = get_current_state(msg.session_id, None, db, provider).await?;
+    Ok(state)
 }
 
 async fn on_voted_tracks(
@@ -7,7 +7,7 @@ use std::future::{ready, Ready};
 use uuid::Uuid;
 pub struct TypedSession(Session);
 
-#[derive(serde::Serialize, serde::Deserialize, PartialEq)]
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq)]
 pub enum Context {
     Host,
     Peer,
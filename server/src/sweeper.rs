@@ -0,0 +1,76 @@
+//! Ends sessions that nobody has used for a while.
+//!
+//! A session counts as in use while its clients send it requests. Just being
+//! connected doesn't count, and neither do the session's own checks of its
+//! playback. Every instance sweeps, and only the one holding a session's lease
+//! ends it.
+
+use crate::cluster::Cluster;
+use crate::configuration::SessionSettings;
+use crate::controller::messages::KillComplete;
+use crate::controller::Controller;
+use crate::db::Database;
+use actix::Addr;
+use std::time::Duration;
+
+pub struct Sweeper {
+    db: Database,
+    cluster: Cluster,
+    idle_ttl: Duration,
+    interval: Duration,
+    lease_ttl: Duration,
+}
+
+impl Sweeper {
+    pub fn new(db: Database, cluster: Cluster, settings: &SessionSettings) -> Self {
+        Self {
+            db,
+            cluster,
+            idle_ttl: Duration::from_secs(settings.idle_ttl_secs),
+            interval: Duration::from_secs(settings.sweep_interval_secs),
+            lease_ttl: settings.lease_ttl(),
+        }
+    }
+
+    /// Looks for idle sessions every interval for as long as the process runs.
+    pub async fn run(self, controller: Addr<Controller>) {
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+            if let Err(err) = self.sweep(&controller).await {
+                log::error!("Failed to sweep idle sessions, {err}");
+            }
+        }
+    }
+
+    async fn sweep(&self, controller: &Addr<Controller>) -> Result<(), anyhow::Error> {
+        for session_id in self.db.idle_sessions(self.idle_ttl).await? {
+            match self.cluster.acquire_lease(session_id, self.lease_ttl).await {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(err) => {
+                    log::error!("Failed to acquire lease on {session_id}, {err}");
+                    continue;
+                }
+            }
+
+            log::info!(
+                "Ending session {session_id}, idle for over {:?}",
+                self.idle_ttl
+            );
+            let deleted = self.db.delete_session(session_id).await;
+            if let Err(err) = self.cluster.release_lease(session_id).await {
+                log::error!("Failed to release lease on {session_id}, {err}");
+            }
+            if let Err(err) = deleted {
+                log::error!("Failed to end idle session {session_id}, {err}");
+                continue;
+            }
+            controller.do_send(KillComplete {
+                session_id,
+                reply_to: None,
+            });
+        }
+        Ok(())
+    }
+}
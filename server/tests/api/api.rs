@@ -0,0 +1,214 @@
+use crate::helpers::{album_uri, spawn_app, track_id, track_uri, TRACK_DURATION};
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+#[actix_web::test]
+async fn queueing_over_http_updates_connected_clients() {
+    let app = spawn_app().await;
+    let (session_id, host) = app.create_session_cookie().await;
+    let mut peer = app.join_session(session_id).await;
+
+    let (status, response) = app
+        .api_post(
+            &host,
+            &format!("{session_id}/queue"),
+            json!({ "uri": track_uri(1) }),
+        )
+        .await;
+    assert_eq!(status, 200);
+    assert_eq!(response["type"], "StateUpdate");
+    assert_eq!(response["payload"]["track"]["id"], track_uri(1));
+
+    peer.receive_state(Some(&track_uri(1)), &[]).await;
+    assert_eq!(app.provider.now_playing(session_id), Some(track_id(1)));
+}
+
+#[actix_web::test]
+async fn session_can_be_driven_over_http() {
+    let app = spawn_app().await;
+    let (session_id, host) = app.create_session_cookie().await;
+
+    let (status, response) = app
+        .api_get(&host, &format!("{session_id}/search?query=beta"))
+        .await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        response["payload"]["tracks"]["items"]
+            .as_array()
+            .unwrap()
+            .len(),
+        2
+    );
+
+    let (status, response) = app
+        .api_get(
+            &host,
+            &format!("{session_id}/search?query=alpha&types=album,track&limit=1"),
+        )
+        .await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        response["payload"]["albums"]["items"][0]["id"],
+        album_uri(1)
+    );
+    assert_eq!(response["payload"]["tracks"]["total"], 2);
+
+    let (status, response) = app
+        .api_get(&host, &format!("{session_id}/browse?uri={}", album_uri(1)))
+        .await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        response["payload"]["tracks"]["items"][1]["id"],
+        track_uri(3)
+    );
+
+    for n in 1..=3 {
+        let (status, _) = app
+            .api_post(
+                &host,
+                &format!("{session_id}/queue"),
+                json!({ "uri": track_uri(n) }),
+            )
+            .await;
+        assert_eq!(status, 200);
+    }
+
+    let (status, response) = app
+        .api_post(
+            &host,
+            &format!("{session_id}/vote"),
+            json!({ "uri": track_uri(3) }),
+        )
+        .await;
+    assert_eq!(status, 200);
+    assert_eq!(response["payload"]["queue"][0]["id"], track_uri(3));
+    assert_eq!(app.vote_count(session_id, &track_uri(3)).await, 1);
+
+    let (status, response) = app.api_get(&host, &format!("{session_id}/state")).await;
+    assert_eq!(status, 200);
+    assert_eq!(response["payload"]["track"]["id"], track_uri(1));
+
+    let (status, response) = app.api_get(&host, &format!("{session_id}/devices")).await;
+    assert_eq!(status, 200);
+    assert_eq!(response["payload"][0]["id"], "device-1");
+
+    let (status, response) = app
+        .api_post(
+            &host,
+            &format!("{session_id}/transfer"),
+            json!({ "device_id": "device-1" }),
+        )
+        .await;
+    assert_eq!(status, 200);
+    assert_eq!(response["payload"], "device-1");
+
+    let (status, response) = app
+        .api_post(&host, &format!("{session_id}/kill"), json!({}))
+        .await;
+    assert_eq!(status, 200);
+    assert_eq!(response["type"], "Shutdown");
+}
+
+#[actix_web::test]
+async fn sessions_driven_only_over_http_advance_tracks() {
+    let app = spawn_app().await;
+    let (session_id, host) = app.create_session_cookie().await;
+
+    for n in 1..=2 {
+        let (status, _) = app
+            .api_post(
+                &host,
+                &format!("{session_id}/queue"),
+                json!({ "uri": track_uri(n) }),
+            )
+            .await;
+        assert_eq!(status, 200);
+    }
+
+    app.provider
+        .advance(session_id, TRACK_DURATION - Duration::from_secs(1));
+    let deadline = Instant::now() + Duration::from_secs(15);
+    loop {
+        let (status, response) = app.api_get(&host, &format!("{session_id}/state")).await;
+        assert_eq!(status, 200);
+        if response["payload"]["track"]["id"] == track_uri(2) {
+            assert_eq!(response["payload"]["queue"], json!([]));
+            break;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "Timed out waiting for the next track"
+        );
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+
+    app.provider.advance(session_id, Duration::from_secs(2));
+    assert_eq!(app.provider.now_playing(session_id), Some(track_id(2)));
+}
+
+#[actix_web::test]
+async fn http_callers_get_the_same_errors_as_sockets() {
+    let app = spawn_app().await;
+    let (session_id, _) = app.create_session_cookie().await;
+    let peer = app.join_session_cookie(session_id).await;
+
+    let (status, response) = app
+        .api_post(&peer, &format!("{session_id}/kill"), json!({}))
+        .await;
+    assert_eq!(status, 403);
+    assert_eq!(response["code"], "Forbidden");
+
+    let (status, response) = app
+        .api_post(
+            &peer,
+            &format!("{session_id}/queue"),
+            json!({ "uri": "not-a-uri" }),
+        )
+        .await;
+    assert_eq!(status, 400);
+    assert_eq!(response["code"], "InvalidTrackUri");
+
+    let (other_session, _) = app.create_session_cookie().await;
+    let (status, response) = app.api_get(&peer, &format!("{other_session}/state")).await;
+    assert_eq!(status, 403);
+    assert_eq!(response["code"], "Forbidden");
+}
+
+#[actix_web::test]
+async fn history_is_exported_as_json_and_csv() {
+    let app = spawn_app().await;
+    let (session_id, host) = app.create_session_cookie().await;
+    let (status, _) = app
+        .api_post(
+            &host,
+            &format!("{session_id}/queue"),
+            json!({ "uri": track_uri(3) }),
+        )
+        .await;
+    assert_eq!(status, 200);
+
+    let (status, response) = app.api_get(&host, &format!("{session_id}/history")).await;
+    assert_eq!(status, 200);
+    assert_eq!(response.as_array().unwrap().len(), 1);
+    assert_eq!(response[0]["id"], track_uri(3));
+    assert_eq!(response[0]["autoplayed"], false);
+
+    let (status, csv) = app
+        .api_get_text(&host, &format!("{session_id}/history?format=csv"))
+        .await;
+    assert_eq!(status, 200);
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("started_at,ended_at,uri,"));
+    assert!(lines[1].contains(&format!(
+        ",,{},track,Third Song,Alpha; Beta,Third Song - Single,",
+        track_uri(3)
+    )));
+    assert!(lines[1].ends_with(",0,false,false"));
+
+    let (status, response) = app
+        .api_get(&host, &format!("{session_id}/history?format=xml"))
+        .await;
+    assert_eq!(status, 400);
+    assert_eq!(response["code"], "MalformedRequest");
+}
@@ -0,0 +1,97 @@
+use crate::helpers::{spawn_app, spawn_app_with, track_uri, TRACK_DURATION};
+use queuetify::controller::POLL_STATE_INTERVAL;
+use serde_json::json;
+use std::time::Duration;
+
+#[actix_web::test]
+async fn broadcasts_reach_clients_on_other_instances() {
+    let app = spawn_app().await;
+    let replica = app.spawn_replica().await;
+    let (session_id, mut host) = app.create_session().await;
+    let mut peer = replica.join_session(session_id).await;
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    peer.receive_state(Some(&track_uri(1)), &[]).await;
+
+    host.send(json!({ "type": "Kill" })).await;
+    host.receive("Shutdown").await;
+    peer.receive("Shutdown").await;
+}
+
+#[actix_web::test]
+async fn only_one_instance_advances_a_session() {
+    let app = spawn_app().await;
+    let replica = app.spawn_replica().await;
+    let (session_id, mut host) = app.create_session().await;
+    let mut peer = replica.join_session(session_id).await;
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    host.queue(&track_uri(2)).await;
+    host.queue(&track_uri(3)).await;
+    host.receive_state(Some(&track_uri(1)), &[&track_uri(2), &track_uri(3)])
+        .await;
+
+    app.provider
+        .advance(session_id, TRACK_DURATION - Duration::from_secs(1));
+    peer.receive_state(Some(&track_uri(2)), &[&track_uri(3)])
+        .await;
+
+    // A second poller would see the same track about to end and pop the queue again
+    tokio::time::sleep(POLL_STATE_INTERVAL * 2).await;
+    host.send(json!({ "type": "State" })).await;
+    host.receive_state(Some(&track_uri(2)), &[&track_uri(3)])
+        .await;
+}
+
+#[actix_web::test]
+async fn queue_rate_limit_holds_across_instances() {
+    let app = spawn_app_with(|settings| settings.session.min_queue_interval_secs = 60).await;
+    let replica = app.spawn_replica().await;
+    let (session_id, host) = app.create_session_cookie().await;
+
+    let (status, _) = app
+        .api_post(
+            &host,
+            &format!("{session_id}/queue"),
+            json!({ "uri": track_uri(1) }),
+        )
+        .await;
+    assert_eq!(status, 200);
+
+    let (status, response) = replica
+        .api_post(
+            &host,
+            &format!("{session_id}/queue"),
+            json!({ "uri": track_uri(2) }),
+        )
+        .await;
+    assert_eq!(status, 429);
+    assert_eq!(response["code"], "RateLimited");
+}
+
+#[actix_web::test]
+async fn skip_votes_count_clients_on_every_instance() {
+    let app = spawn_app().await;
+    let replica = app.spawn_replica().await;
+    let (session_id, mut host) = app.create_session().await;
+    let mut first_peer = replica.join_session(session_id).await;
+    let mut second_peer = replica.join_session(session_id).await;
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    host.queue(&track_uri(2)).await;
+    host.receive_state(Some(&track_uri(1)), &[&track_uri(2)])
+        .await;
+
+    // The host counts towards the three clients although it is connected elsewhere
+    first_peer.send(json!({ "type": "VoteSkip" })).await;
+    first_peer.send(json!({ "type": "State" })).await;
+    first_peer
+        .receive_state(Some(&track_uri(1)), &[&track_uri(2)])
+        .await;
+
+    second_peer.send(json!({ "type": "VoteSkip" })).await;
+    host.receive_state(Some(&track_uri(2)), &[]).await;
+}
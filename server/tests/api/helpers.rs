@@ -0,0 +1,432 @@
+use actix_codec::Framed;
+use awc::cookie::Cookie;
+use awc::ws::{Codec, Frame, Message};
+use awc::BoxedSocket;
+use futures_util::{SinkExt, StreamExt};
+use queuetify::application::Application;
+use queuetify::cluster::Cluster;
+use queuetify::configuration::{get_configuration, DatabaseSettings, Settings};
+use queuetify::item::ItemId;
+use queuetify::protocol::PROTOCOL_VERSION;
+use queuetify::provider::FakeProvider;
+use queuetify::session_agent::SessionAgent;
+use secrecy::ExposeSecret;
+use serde_json::{json, Value};
+use sqlx::{Connection, Executor, PgConnection, PgPool};
+use std::str::FromStr;
+use std::time::Duration;
+use uuid::Uuid;
+
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(15);
+
+pub const TRACK_DURATION: Duration = Duration::from_secs(180);
+/// The fake provider's clock only moves when a test moves it, so sessions are
+/// checked often enough for a test to see them catch up.
+pub const PLAYBACK_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+pub fn track_id(n: u8) -> ItemId {
+    ItemId::from_str(&format!("spotify:track:{:0>22}", n)).expect("Invalid track id")
+}
+
+pub fn track_uri(n: u8) -> String {
+    track_id(n).to_string()
+}
+
+pub fn episode_id(n: u8) -> ItemId {
+    ItemId::from_str(&format!("spotify:episode:{:0>22}", n)).expect("Invalid episode id")
+}
+
+pub fn episode_uri(n: u8) -> String {
+    episode_id(n).to_string()
+}
+
+pub fn album_uri(n: u8) -> String {
+    format!("spotify:album:{:0>22}", n)
+}
+
+pub fn playlist_uri(n: u8) -> String {
+    format!("spotify:playlist:{:0>22}", n)
+}
+
+pub struct TestApp {
+    pub address: String,
+    pub db_pool: PgPool,
+    pub provider: FakeProvider,
+    settings: Settings,
+    http: awc::Client,
+}
+
+pub struct TestClient {
+    socket: Framed<BoxedSocket, Codec>,
+}
+
+pub async fn spawn_app() -> TestApp {
+    spawn_app_with(|_| {}).await
+}
+
+/// Like `spawn_app`, but lets the test adjust the settings before the app starts.
+pub async fn spawn_app_with<F>(configure: F) -> TestApp
+where
+    F: FnOnce(&mut Settings),
+{
+    // The fake provider never talks to Spotify, but the settings still have to parse
+    for key in [
+        "QUEUETIFY_APP_SPOTIFY__CLIENT_ID",
+        "QUEUETIFY_APP_SPOTIFY__CLIENT_SECRET",
+        "QUEUETIFY_APP_SPOTIFY__REDIRECT_URI",
+    ] {
+        if std::env::var(key).is_err() {
+            std::env::set_var(key, "test");
+        }
+    }
+
+    let settings = {
+        let mut settings = get_configuration().expect("Failed to read configuration");
+        settings.database.database_name = Uuid::new_v4().to_string();
+        settings.application.port = 0;
+        settings.session.removal_score_threshold = -1;
+        settings.session.min_queue_interval_secs = 0;
+        settings.session.playback_check_secs = PLAYBACK_CHECK_INTERVAL.as_secs();
+        configure(&mut settings);
+        settings
+    };
+
+    let db_pool = configure_database(&settings.database).await;
+
+    let provider = FakeProvider::new();
+    provider.add_track(&track_id(1), "First Song", &["Alpha"], TRACK_DURATION);
+    provider.add_track(&track_id(2), "Second Song", &["Beta"], TRACK_DURATION);
+    provider.add_track(
+        &track_id(3),
+        "Third Song",
+        &["Alpha", "Beta"],
+        TRACK_DURATION,
+    );
+    provider.add_episode(&episode_id(1), "Pilot", "Night Talks", TRACK_DURATION);
+    provider.add_show("spotify:show:0000000000000000000001", "Night Talks");
+    provider.add_artist("spotify:artist:0000000000000000000001", "Alpha");
+    provider.add_album(
+        &album_uri(1),
+        "Alpha Album",
+        &["Alpha"],
+        &[track_id(1), track_id(3)],
+    );
+    provider.add_playlist(
+        &playlist_uri(1),
+        "Alpha Mix",
+        "Host",
+        &[track_id(2), episode_id(1), track_id(1)],
+    );
+    provider.save_track(&track_id(3));
+    provider.save_track(&track_id(1));
+    provider.add_top_track(&track_id(2));
+    provider.add_device("device-1", "Living room", "Speaker");
+
+    let address = start_instance(&settings, &provider).await;
+
+    TestApp {
+        address,
+        db_pool,
+        provider,
+        settings,
+        http: awc::Client::builder().disable_redirects().finish(),
+    }
+}
+
+/// Starts an application and its session agent, returning the address it serves on.
+async fn start_instance(settings: &Settings, provider: &FakeProvider) -> String {
+    let cluster = Cluster::connect(settings.redis_uri.expose_secret())
+        .await
+        .expect("Failed to connect to Redis");
+    let (agent, agent_tx) =
+        SessionAgent::build(settings.clone(), provider.clone(), cluster.clone());
+    let application = Application::build(
+        settings.clone(),
+        agent_tx,
+        provider.clone(),
+        cluster,
+        agent.metrics(),
+    )
+    .await
+    .expect("Failed to build application");
+    let address = format!("http://127.0.0.1:{}", application.port());
+    actix_web::rt::spawn(application.run());
+    actix_web::rt::spawn(agent.run());
+    address
+}
+
+async fn configure_database(settings: &DatabaseSettings) -> PgPool {
+    let mut connection = PgConnection::connect_with(&settings.without_db())
+        .await
+        .expect("Failed to connect to Postgres");
+    connection
+        .execute(format!(r#"CREATE DATABASE "{}";"#, settings.database_name).as_str())
+        .await
+        .expect("Failed to create database");
+
+    let db_pool = PgPool::connect_with(settings.with_db())
+        .await
+        .expect("Failed to connect to Postgres");
+    sqlx::migrate!("../migrations")
+        .run(&db_pool)
+        .await
+        .expect("Failed to migrate the database");
+
+    db_pool
+}
+
+impl TestApp {
+    /// Starts another instance of the app behind the same database, Redis and
+    /// provider, as a second replica behind a load balancer would be.
+    pub async fn spawn_replica(&self) -> TestApp {
+        TestApp {
+            address: start_instance(&self.settings, &self.provider).await,
+            db_pool: self.db_pool.clone(),
+            provider: self.provider.clone(),
+            settings: self.settings.clone(),
+            http: awc::Client::builder().disable_redirects().finish(),
+        }
+    }
+
+    /// Goes through `/create` and `/callback` like a host would and returns the
+    /// new session id together with the host's socket.
+    pub async fn create_session(&self) -> (Uuid, TestClient) {
+        let (session_id, cookie) = self.create_session_cookie().await;
+        (session_id, self.connect(cookie).await)
+    }
+
+    /// Creates a session and returns the host's session cookie instead of a socket.
+    pub async fn create_session_cookie(&self) -> (Uuid, Cookie<'static>) {
+        let mut response = self
+            .http
+            .get(format!("{}/create", self.address))
+            .send()
+            .await
+            .expect("Failed to execute request");
+        let authorize_url = response.body().await.expect("Failed to read body");
+        let authorize_url = String::from_utf8(authorize_url.to_vec()).unwrap();
+
+        let response = self
+            .http
+            .get(format!("{}{}", self.address, authorize_url))
+            .send()
+            .await
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 303);
+        let cookie = response.cookie("id").expect("Missing session cookie");
+
+        let (session_id,): (Uuid,) =
+            sqlx::query_as("SELECT id FROM sessions ORDER BY created_at DESC LIMIT 1")
+                .fetch_one(&self.db_pool)
+                .await
+                .expect("Failed to fetch created session");
+
+        (session_id, cookie)
+    }
+
+    pub async fn join_session(&self, session_id: Uuid) -> TestClient {
+        let cookie = self.join_session_cookie(session_id).await;
+        self.connect(cookie).await
+    }
+
+    /// Joins like `join_session`, but leaves the protocol handshake to the test.
+    pub async fn join_session_without_handshake(&self, session_id: Uuid) -> TestClient {
+        let cookie = self.join_session_cookie(session_id).await;
+        self.open_socket(cookie).await
+    }
+
+    pub async fn join_session_cookie(&self, session_id: Uuid) -> Cookie<'static> {
+        let response = self
+            .http
+            .get(format!("{}/join/{}", self.address, session_id))
+            .send()
+            .await
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 303);
+        response.cookie("id").expect("Missing session cookie")
+    }
+
+    /// Calls the REST API of a session and returns the status code and json body.
+    pub async fn api_get(&self, cookie: &Cookie<'static>, path: &str) -> (u16, Value) {
+        let mut response = self
+            .http
+            .get(format!("{}/api/sessions/{}", self.address, path))
+            .cookie(cookie.clone())
+            .send()
+            .await
+            .expect("Failed to execute request");
+        let body = response.json().await.expect("Response is not json");
+        (response.status().as_u16(), body)
+    }
+
+    /// Like `api_get`, for responses that aren't json.
+    pub async fn api_get_text(&self, cookie: &Cookie<'static>, path: &str) -> (u16, String) {
+        let mut response = self
+            .http
+            .get(format!("{}/api/sessions/{}", self.address, path))
+            .cookie(cookie.clone())
+            .send()
+            .await
+            .expect("Failed to execute request");
+        let body = response.body().await.expect("Failed to read body");
+        (
+            response.status().as_u16(),
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
+
+    pub async fn api_post(
+        &self,
+        cookie: &Cookie<'static>,
+        path: &str,
+        body: Value,
+    ) -> (u16, Value) {
+        let mut response = self
+            .http
+            .post(format!("{}/api/sessions/{}", self.address, path))
+            .cookie(cookie.clone())
+            .send_json(&body)
+            .await
+            .expect("Failed to execute request");
+        let body = response.json().await.expect("Response is not json");
+        (response.status().as_u16(), body)
+    }
+
+    pub async fn metrics(&self) -> String {
+        let mut response = self
+            .http
+            .get(format!("{}/metrics", self.address))
+            .send()
+            .await
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 200);
+        let body = response.body().await.expect("Failed to read body");
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    pub async fn vote_count(&self, session_id: Uuid, uri: &str) -> i32 {
+        let (votes,): (i32,) = sqlx::query_as(
+            "SELECT votes FROM queued_tracks WHERE session_id = $1 and track_uri = $2",
+        )
+        .bind(session_id)
+        .bind(uri)
+        .fetch_one(&self.db_pool)
+        .await
+        .expect("Failed to fetch vote count");
+        votes
+    }
+
+    pub async fn session_exists(&self, session_id: Uuid) -> bool {
+        let (exists,): (bool,) =
+            sqlx::query_as("SELECT EXISTS(SELECT 1 FROM sessions WHERE id = $1)")
+                .bind(session_id)
+                .fetch_one(&self.db_pool)
+                .await
+                .expect("Failed to check for session");
+        exists
+    }
+
+    async fn connect(&self, cookie: Cookie<'static>) -> TestClient {
+        let mut client = self.open_socket(cookie).await;
+        client
+            .send(json!({ "type": "Hello", "versions": [PROTOCOL_VERSION] }))
+            .await;
+        client.receive("Welcome").await;
+        client
+    }
+
+    async fn open_socket(&self, cookie: Cookie<'static>) -> TestClient {
+        let (_, socket) = self
+            .http
+            .ws(format!("{}/session/ws", self.address))
+            .cookie(cookie)
+            .connect()
+            .await
+            .expect("Failed to open websocket");
+        TestClient { socket }
+    }
+}
+
+impl TestClient {
+    pub async fn send(&mut self, request: Value) {
+        self.send_text(&request.to_string()).await;
+    }
+
+    pub async fn send_text(&mut self, text: &str) {
+        self.socket
+            .send(Message::Text(text.to_string().into()))
+            .await
+            .expect("Failed to send request");
+    }
+
+    /// Waits for the next response of the given type, skipping any others.
+    pub async fn receive(&mut self, response_type: &str) -> Value {
+        self.receive_where(response_type, |_| true).await
+    }
+
+    /// Waits for a response of the given type that also satisfies `predicate`.
+    pub async fn receive_where<F>(&mut self, response_type: &str, predicate: F) -> Value
+    where
+        F: Fn(&Value) -> bool,
+    {
+        let socket = &mut self.socket;
+        let receive = async move {
+            loop {
+                let frame = socket
+                    .next()
+                    .await
+                    .expect("Socket closed")
+                    .expect("Failed to read frame");
+                match frame {
+                    Frame::Text(text) => {
+                        let response: Value =
+                            serde_json::from_slice(&text).expect("Response is not json");
+                        if response["type"] == response_type && predicate(&response) {
+                            return response;
+                        }
+                    }
+                    Frame::Ping(bytes) => {
+                        socket
+                            .send(Message::Pong(bytes))
+                            .await
+                            .expect("Failed to send pong");
+                    }
+                    _ => {}
+                }
+            }
+        };
+
+        tokio::time::timeout(RESPONSE_TIMEOUT, receive)
+            .await
+            .unwrap_or_else(|_| panic!("Timed out waiting for {}", response_type))
+    }
+
+    pub async fn queue(&mut self, uri: &str) {
+        self.send(json!({ "type": "Queue", "uri": uri })).await;
+    }
+
+    pub async fn vote(&mut self, uri: &str) {
+        self.send(json!({ "type": "Vote", "uri": uri })).await;
+    }
+
+    /// Waits for a state update whose current track and queue match the given uris.
+    pub async fn receive_state(&mut self, track: Option<&str>, queue: &[&str]) -> Value {
+        let expected_track = track.map(|uri| uri.to_string());
+        let expected_queue: Vec<String> = queue.iter().map(|uri| uri.to_string()).collect();
+        self.receive_where("StateUpdate", move |response| {
+            let state = &response["payload"];
+            let actual_track = state["track"]["id"].as_str().map(|id| id.to_string());
+            let actual_queue: Vec<String> = state["queue"]
+                .as_array()
+                .map(|queue| {
+                    queue
+                        .iter()
+                        .filter_map(|track| track["id"].as_str().map(|id| id.to_string()))
+                        .collect()
+                })
+                .unwrap_or_default();
+            actual_track == expected_track && actual_queue == expected_queue
+        })
+        .await
+    }
+}
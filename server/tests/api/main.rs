@@ -0,0 +1,7 @@
+mod api;
+mod cluster;
+mod helpers;
+mod protocol;
+mod session;
+mod tracks;
+mod workers;
@@ -0,0 +1,193 @@
+use crate::helpers::{spawn_app, track_uri};
+use queuetify::protocol::{protocol_schema, PROTOCOL_VERSION};
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap};
+
+/// Collects every `type` tag the schema allows, wherever it is nested.
+fn message_types(schema: &Value, types: &mut Vec<String>) {
+    match schema {
+        Value::Object(map) => {
+            if let Some(tags) = map
+                .get("properties")
+                .and_then(|properties| properties.get("type"))
+                .and_then(|tag| tag.get("enum"))
+                .and_then(|tags| tags.as_array())
+            {
+                types.extend(tags.iter().filter_map(|t| t.as_str().map(String::from)));
+            }
+            map.values().for_each(|value| message_types(value, types));
+        }
+        Value::Array(values) => values.iter().for_each(|value| message_types(value, types)),
+        _ => {}
+    }
+}
+
+#[test]
+fn schema_covers_every_request_and_response() {
+    let schema = serde_json::to_value(protocol_schema()).expect("Failed to serialize schema");
+    assert_eq!(schema["version"], PROTOCOL_VERSION);
+
+    let mut requests = Vec::new();
+    message_types(&schema["request"], &mut requests);
+    for request in [
+        "Hello",
+        "Search",
+        "Browse",
+        "Queue",
+        "State",
+        "Vote",
+        "Downvote",
+        "Unvote",
+        "Kill",
+        "Devices",
+        "Transfer",
+        "VotedTracks",
+        "RemoveTrack",
+        "PinTrack",
+        "MoveTrack",
+        "VoteSkip",
+        "Skip",
+        "Pause",
+        "Resume",
+        "Seek",
+        "Volume",
+        "Library",
+        "Import",
+        "SetFallbackPlaylist",
+        "SetAutoplay",
+        "History",
+        "SaveHistory",
+    ] {
+        assert!(requests.contains(&request.to_string()), "{request} missing");
+    }
+
+    let mut responses = Vec::new();
+    message_types(&schema["response"], &mut responses);
+    for response in [
+        "Welcome",
+        "SearchResult",
+        "TrackList",
+        "LibraryResult",
+        "History",
+        "SavedPlaylist",
+        "Shutdown",
+        "StateUpdate",
+        "Devices",
+        "Transfer",
+        "VotedTracks",
+        "Error",
+    ] {
+        assert!(
+            responses.contains(&response.to_string()),
+            "{response} missing"
+        );
+    }
+}
+
+/// The fields of every interface declared in the client, including the ones it
+/// inherits.
+fn client_interfaces(source: &str) -> HashMap<String, BTreeSet<String>> {
+    let mut declared: Vec<(String, Option<String>, BTreeSet<String>)> = Vec::new();
+    let mut inside = false;
+    for line in source.lines() {
+        let line = line.trim();
+        if let Some(declaration) = line.strip_prefix("interface ") {
+            let declaration = declaration.trim_end_matches('{').trim();
+            let (name, base) = match declaration.split_once(" extends ") {
+                Some((name, base)) => (name, Some(base.trim().to_string())),
+                None => (declaration, None),
+            };
+            let name = name.split('<').next().unwrap_or(name).trim();
+            declared.push((name.to_string(), base, BTreeSet::new()));
+            inside = true;
+        } else if line == "}" {
+            inside = false;
+        } else if let (true, Some((field, _)), Some((_, _, fields))) =
+            (inside, line.split_once(':'), declared.last_mut())
+        {
+            fields.insert(field.trim_end_matches('?').to_string());
+        }
+    }
+
+    let mut interfaces = HashMap::new();
+    for (name, base, mut fields) in declared.iter().cloned() {
+        let mut base = base;
+        while let Some(parent) = base {
+            let (_, grandparent, inherited) = declared
+                .iter()
+                .find(|(name, _, _)| *name == parent)
+                .unwrap_or_else(|| panic!("{parent} is not declared"));
+            fields.extend(inherited.iter().cloned());
+            base = grandparent.clone();
+        }
+        interfaces.insert(name, fields);
+    }
+    interfaces
+}
+
+#[test]
+fn client_types_match_the_schema() {
+    let schema = serde_json::to_value(protocol_schema()).expect("Failed to serialize schema");
+    let definitions = schema["response"]["definitions"]
+        .as_object()
+        .expect("Schema has no definitions");
+    let source = std::fs::read_to_string(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../client/src/session.ts"
+    ))
+    .expect("Failed to read the client");
+    let interfaces = client_interfaces(&source);
+
+    // Where the client names a type differently than the server
+    let renamed = HashMap::from([
+        ("SearchResults", "SearchResult"),
+        ("StateUpdate", "State"),
+        ("Page", "Page_for_TrackInfo"),
+    ]);
+    let mut compared = 0;
+    for (name, fields) in &interfaces {
+        let definition = renamed.get(name.as_str()).copied().unwrap_or(name);
+        let properties: BTreeSet<String> = match definitions
+            .get(definition)
+            .and_then(|definition| definition["properties"].as_object())
+        {
+            Some(properties) => properties.keys().cloned().collect(),
+            None => continue,
+        };
+        assert_eq!(fields, &properties, "{name} differs from {definition}");
+        compared += 1;
+    }
+    assert!(
+        compared >= renamed.len(),
+        "Too few client types were checked"
+    );
+}
+
+#[actix_web::test]
+async fn requests_are_refused_until_a_version_is_agreed_on() {
+    let app = spawn_app().await;
+    let (session_id, _host) = app.create_session().await;
+    let mut peer = app.join_session_without_handshake(session_id).await;
+
+    peer.queue(&track_uri(1)).await;
+    let response = peer.receive("Error").await;
+    assert_eq!(response["code"], "HandshakeRequired");
+
+    peer.send(json!({ "type": "Hello", "versions": [PROTOCOL_VERSION + 1] }))
+        .await;
+    let response = peer.receive("Error").await;
+    assert_eq!(response["code"], "UnsupportedVersion");
+
+    peer.send(json!({
+        "type": "Hello",
+        "versions": [PROTOCOL_VERSION, PROTOCOL_VERSION + 1],
+        "request_id": "hello-1"
+    }))
+    .await;
+    let response = peer.receive("Welcome").await;
+    assert_eq!(response["payload"]["version"], PROTOCOL_VERSION);
+    assert_eq!(response["request_id"], "hello-1");
+
+    peer.send(json!({ "type": "State" })).await;
+    peer.receive_state(None, &[]).await;
+}
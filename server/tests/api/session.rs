@@ -0,0 +1,1090 @@
+use crate::helpers::{
+    album_uri, episode_id, episode_uri, playlist_uri, spawn_app, spawn_app_with, track_id,
+    track_uri, PLAYBACK_CHECK_INTERVAL, TRACK_DURATION,
+};
+use queuetify::protocol::PROTOCOL_VERSION;
+use serde_json::json;
+use std::time::Duration;
+
+#[actix_web::test]
+async fn host_and_peer_receive_the_same_state() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+    let mut peer = app.join_session(session_id).await;
+
+    host.send(json!({ "type": "State" })).await;
+    peer.send(json!({ "type": "State" })).await;
+
+    host.receive_state(None, &[]).await;
+    peer.receive_state(None, &[]).await;
+}
+
+#[actix_web::test]
+async fn search_returns_matching_tracks() {
+    let app = spawn_app().await;
+    let (_, mut host) = app.create_session().await;
+
+    host.send(json!({ "type": "Search", "query": "alpha" }))
+        .await;
+    let response = host.receive("SearchResult").await;
+
+    let names: Vec<&str> = response["payload"]["tracks"]["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|track| track["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, vec!["First Song", "Third Song"]);
+}
+
+#[actix_web::test]
+async fn search_returns_matching_episodes_and_shows() {
+    let app = spawn_app().await;
+    let (_, mut host) = app.create_session().await;
+
+    host.send(json!({ "type": "Search", "query": "night" }))
+        .await;
+    let response = host.receive("SearchResult").await;
+
+    let payload = &response["payload"];
+    assert_eq!(payload["tracks"]["total"], 0);
+    let episode = &payload["episodes"]["items"][0];
+    assert_eq!(episode["id"], episode_uri(1));
+    assert_eq!(episode["kind"], "episode");
+    assert_eq!(episode["album"], "Night Talks");
+    assert_eq!(payload["shows"]["items"][0]["name"], "Night Talks");
+}
+
+#[actix_web::test]
+async fn search_filters_by_type_and_pages_through_results() {
+    let app = spawn_app().await;
+    let (_, mut host) = app.create_session().await;
+
+    host.send(json!({
+        "type": "Search",
+        "query": "alpha",
+        "types": ["album", "artist", "playlist"]
+    }))
+    .await;
+    let response = host.receive("SearchResult").await;
+    let payload = &response["payload"];
+    assert_eq!(payload["albums"]["items"][0]["id"], album_uri(1));
+    assert_eq!(payload["artists"]["items"][0]["name"], "Alpha");
+    assert_eq!(payload["playlists"]["items"][0]["id"], playlist_uri(1));
+    assert_eq!(payload["playlists"]["items"][0]["total_tracks"], 3);
+    assert_eq!(payload["tracks"]["total"], 0);
+
+    host.send(json!({
+        "type": "Search",
+        "query": "song",
+        "types": ["track"],
+        "limit": 2,
+        "offset": 2
+    }))
+    .await;
+    let response = host.receive("SearchResult").await;
+    let tracks = &response["payload"]["tracks"];
+    assert_eq!(tracks["total"], 3);
+    assert_eq!(tracks["offset"], 2);
+    assert_eq!(tracks["items"].as_array().unwrap().len(), 1);
+    assert_eq!(tracks["items"][0]["id"], track_uri(3));
+}
+
+#[actix_web::test]
+async fn album_and_playlist_tracks_can_be_listed_and_queued() {
+    let app = spawn_app().await;
+    let (_, mut host) = app.create_session().await;
+
+    host.send(json!({ "type": "Browse", "uri": album_uri(1), "request_id": "album-1" }))
+        .await;
+    let response = host.receive("TrackList").await;
+    assert_eq!(response["request_id"], "album-1");
+    assert_eq!(response["payload"]["id"], album_uri(1));
+    let ids: Vec<&str> = response["payload"]["tracks"]["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|track| track["id"].as_str().unwrap())
+        .collect();
+    assert_eq!(ids, vec![track_uri(1), track_uri(3)]);
+
+    host.send(json!({ "type": "Browse", "uri": playlist_uri(1), "limit": 1, "offset": 1 }))
+        .await;
+    let response = host.receive("TrackList").await;
+    let tracks = &response["payload"]["tracks"];
+    assert_eq!(tracks["total"], 3);
+    assert_eq!(tracks["items"][0]["id"], episode_uri(1));
+
+    host.queue(tracks["items"][0]["id"].as_str().unwrap()).await;
+    host.receive_state(Some(&episode_uri(1)), &[]).await;
+}
+
+#[actix_web::test]
+async fn bad_pages_and_collections_are_refused() {
+    let app = spawn_app().await;
+    let (_, mut host) = app.create_session().await;
+
+    host.send(json!({ "type": "Search", "query": "song", "limit": 0 }))
+        .await;
+    let response = host.receive("Error").await;
+    assert_eq!(response["code"], "OutOfRange");
+
+    host.send(json!({ "type": "Browse", "uri": track_uri(1) }))
+        .await;
+    let response = host.receive("Error").await;
+    assert_eq!(response["code"], "InvalidTrackUri");
+
+    host.send(json!({ "type": "Search", "query": "song", "types": ["podcast"] }))
+        .await;
+    let response = host.receive("Error").await;
+    assert_eq!(response["code"], "MalformedRequest");
+}
+
+#[actix_web::test]
+async fn episodes_are_queued_and_played_like_tracks() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    host.queue(&episode_uri(1)).await;
+    let state = host
+        .receive_state(Some(&track_uri(1)), &[&episode_uri(1)])
+        .await;
+    assert_eq!(state["payload"]["track"]["kind"], "track");
+    assert_eq!(state["payload"]["queue"][0]["kind"], "episode");
+    assert_eq!(state["payload"]["queue"][0]["name"], "Pilot");
+
+    app.provider
+        .advance(session_id, TRACK_DURATION - Duration::from_secs(1));
+    host.receive_state(Some(&episode_uri(1)), &[]).await;
+
+    app.provider.advance(session_id, Duration::from_secs(2));
+    assert_eq!(app.provider.now_playing(session_id), Some(episode_id(1)));
+}
+
+#[actix_web::test]
+async fn first_queued_track_starts_playing_and_the_rest_are_queued() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+    let mut peer = app.join_session(session_id).await;
+
+    peer.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    assert_eq!(app.provider.now_playing(session_id), Some(track_id(1)));
+    assert!(app.provider.is_playing(session_id));
+
+    peer.queue(&track_uri(2)).await;
+    host.receive_state(Some(&track_uri(1)), &[&track_uri(2)])
+        .await;
+}
+
+#[actix_web::test]
+async fn votes_reorder_the_queue() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+    let mut peer = app.join_session(session_id).await;
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    host.queue(&track_uri(2)).await;
+    host.receive_state(Some(&track_uri(1)), &[&track_uri(2)])
+        .await;
+    host.queue(&track_uri(3)).await;
+    host.receive_state(Some(&track_uri(1)), &[&track_uri(2), &track_uri(3)])
+        .await;
+
+    peer.vote(&track_uri(3)).await;
+    peer.receive_state(Some(&track_uri(1)), &[&track_uri(3), &track_uri(2)])
+        .await;
+
+    peer.send(json!({ "type": "VotedTracks" })).await;
+    let response = peer.receive("VotedTracks").await;
+    assert_eq!(response["payload"], json!([track_uri(3)]));
+}
+
+#[actix_web::test]
+async fn votes_are_counted_once_and_can_be_retracted() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+    let mut peer = app.join_session(session_id).await;
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    host.queue(&track_uri(2)).await;
+    host.receive_state(Some(&track_uri(1)), &[&track_uri(2)])
+        .await;
+
+    peer.vote(&track_uri(2)).await;
+    peer.vote(&track_uri(2)).await;
+    peer.send(json!({ "type": "VotedTracks" })).await;
+    let response = peer.receive("VotedTracks").await;
+    assert_eq!(response["payload"], json!([track_uri(2)]));
+    assert_eq!(app.vote_count(session_id, &track_uri(2)).await, 1);
+
+    peer.send(json!({ "type": "Unvote", "uri": track_uri(2) }))
+        .await;
+    peer.send(json!({ "type": "VotedTracks" })).await;
+    let response = peer.receive("VotedTracks").await;
+    assert_eq!(response["payload"], json!([]));
+    assert_eq!(app.vote_count(session_id, &track_uri(2)).await, 0);
+}
+
+#[actix_web::test]
+async fn downvoted_tracks_sink_and_are_removed_below_the_threshold() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+    let mut peer = app.join_session(session_id).await;
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    host.queue(&track_uri(2)).await;
+    host.receive_state(Some(&track_uri(1)), &[&track_uri(2)])
+        .await;
+    host.queue(&track_uri(3)).await;
+    host.receive_state(Some(&track_uri(1)), &[&track_uri(2), &track_uri(3)])
+        .await;
+
+    peer.send(json!({ "type": "Downvote", "uri": track_uri(2) }))
+        .await;
+    peer.receive_state(Some(&track_uri(1)), &[&track_uri(3), &track_uri(2)])
+        .await;
+
+    // Only upvoted tracks are listed, the client can still vote for the others
+    peer.send(json!({ "type": "VotedTracks" })).await;
+    let response = peer.receive("VotedTracks").await;
+    assert_eq!(response["payload"], json!([]));
+
+    host.send(json!({ "type": "Downvote", "uri": track_uri(2) }))
+        .await;
+    host.receive_state(Some(&track_uri(1)), &[&track_uri(3)])
+        .await;
+}
+
+#[actix_web::test]
+async fn host_can_pin_move_and_remove_queued_tracks() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+    let mut peer = app.join_session(session_id).await;
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    host.queue(&track_uri(2)).await;
+    host.receive_state(Some(&track_uri(1)), &[&track_uri(2)])
+        .await;
+    host.queue(&track_uri(3)).await;
+    host.receive_state(Some(&track_uri(1)), &[&track_uri(2), &track_uri(3)])
+        .await;
+
+    peer.send(json!({ "type": "PinTrack", "uri": track_uri(3) }))
+        .await;
+    let response = peer.receive("Error").await;
+    assert_eq!(response["code"], "Forbidden");
+
+    host.send(json!({ "type": "PinTrack", "uri": track_uri(3) }))
+        .await;
+    peer.receive_state(Some(&track_uri(1)), &[&track_uri(3), &track_uri(2)])
+        .await;
+
+    host.send(json!({ "type": "MoveTrack", "uri": track_uri(3), "position": 1 }))
+        .await;
+    peer.receive_state(Some(&track_uri(1)), &[&track_uri(2), &track_uri(3)])
+        .await;
+
+    host.send(json!({ "type": "RemoveTrack", "uri": track_uri(2) }))
+        .await;
+    peer.receive_state(Some(&track_uri(1)), &[&track_uri(3)])
+        .await;
+}
+
+#[actix_web::test]
+async fn moved_tracks_shift_up_when_tracks_above_are_voted_out() {
+    let app = spawn_app().await;
+    app.provider
+        .add_track(&track_id(4), "Fourth Song", &["Gamma"], TRACK_DURATION);
+    app.provider
+        .add_track(&track_id(5), "Fifth Song", &["Gamma"], TRACK_DURATION);
+    let (session_id, mut host) = app.create_session().await;
+    let mut peer = app.join_session(session_id).await;
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    for n in 2..=4 {
+        host.queue(&track_uri(n)).await;
+    }
+    host.receive_state(
+        Some(&track_uri(1)),
+        &[&track_uri(2), &track_uri(3), &track_uri(4)],
+    )
+    .await;
+
+    host.send(json!({ "type": "MoveTrack", "uri": track_uri(4), "position": 2 }))
+        .await;
+    peer.send(json!({ "type": "Downvote", "uri": track_uri(3) }))
+        .await;
+    host.send(json!({ "type": "Downvote", "uri": track_uri(3) }))
+        .await;
+    host.receive_state(Some(&track_uri(1)), &[&track_uri(2), &track_uri(4)])
+        .await;
+
+    // The moved track took the place of the one voted out, so new tracks go below it
+    host.queue(&track_uri(5)).await;
+    host.receive_state(
+        Some(&track_uri(1)),
+        &[&track_uri(2), &track_uri(4), &track_uri(5)],
+    )
+    .await;
+}
+
+#[actix_web::test]
+async fn tracks_cannot_be_moved_past_the_end_of_the_queue() {
+    let app = spawn_app().await;
+    let (_, mut host) = app.create_session().await;
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    host.queue(&track_uri(2)).await;
+    host.receive_state(Some(&track_uri(1)), &[&track_uri(2)])
+        .await;
+
+    host.send(json!({ "type": "MoveTrack", "uri": track_uri(2), "position": 1 }))
+        .await;
+    let response = host.receive("Error").await;
+    assert_eq!(response["code"], "OutOfRange");
+}
+
+#[actix_web::test]
+async fn finishing_track_advances_to_the_most_voted_track() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    host.queue(&track_uri(2)).await;
+    host.receive_state(Some(&track_uri(1)), &[&track_uri(2)])
+        .await;
+
+    app.provider
+        .advance(session_id, TRACK_DURATION - Duration::from_secs(1));
+    host.receive_state(Some(&track_uri(2)), &[]).await;
+
+    app.provider.advance(session_id, Duration::from_secs(2));
+    assert_eq!(app.provider.now_playing(session_id), Some(track_id(2)));
+}
+
+#[actix_web::test]
+async fn finishing_track_plays_out_after_the_handoff() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    host.queue(&track_uri(2)).await;
+    host.receive_state(Some(&track_uri(1)), &[&track_uri(2)])
+        .await;
+
+    app.provider
+        .advance(session_id, TRACK_DURATION - Duration::from_secs(8));
+    host.receive_state(Some(&track_uri(2)), &[]).await;
+
+    // Checks during the last seconds must neither cut the track short nor queue the next one twice
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    assert_eq!(app.provider.now_playing(session_id), Some(track_id(1)));
+
+    app.provider.advance(session_id, Duration::from_secs(8));
+    assert_eq!(app.provider.now_playing(session_id), Some(track_id(2)));
+    app.provider.advance(session_id, TRACK_DURATION);
+    assert!(!app.provider.is_playing(session_id));
+}
+
+#[actix_web::test]
+async fn paused_playback_is_left_paused() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    app.provider.pause(session_id);
+
+    tokio::time::sleep(PLAYBACK_CHECK_INTERVAL + Duration::from_secs(1)).await;
+    assert!(!app.provider.is_playing(session_id));
+    assert_eq!(app.provider.now_playing(session_id), Some(track_id(1)));
+}
+
+#[actix_web::test]
+async fn only_the_host_can_transfer_playback() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+    let mut peer = app.join_session(session_id).await;
+
+    peer.send(json!({ "type": "Transfer", "device_id": "device-1" }))
+        .await;
+    let response = peer.receive("Error").await;
+    assert_eq!(response["code"], "Forbidden");
+    assert_eq!(app.provider.active_device(session_id), None);
+
+    host.send(json!({ "type": "Devices" })).await;
+    let response = host.receive("Devices").await;
+    assert_eq!(response["payload"][0]["id"], "device-1");
+
+    host.send(json!({ "type": "Transfer", "device_id": "device-1" }))
+        .await;
+    let response = host.receive("Transfer").await;
+    assert_eq!(response["payload"], "device-1");
+    assert_eq!(
+        app.provider.active_device(session_id),
+        Some("device-1".to_string())
+    );
+}
+
+#[actix_web::test]
+async fn only_the_host_can_kill_the_session() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+    let mut peer = app.join_session(session_id).await;
+
+    peer.send(json!({ "type": "Kill" })).await;
+    let response = peer.receive("Error").await;
+    assert_eq!(response["code"], "Forbidden");
+
+    host.send(json!({ "type": "Kill" })).await;
+    host.receive("Shutdown").await;
+    peer.receive("Shutdown").await;
+
+    let (exists,): (bool,) = sqlx::query_as("SELECT EXISTS(SELECT 1 FROM sessions WHERE id = $1)")
+        .bind(session_id)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(!exists);
+}
+
+#[actix_web::test]
+async fn enough_skip_votes_advance_to_the_next_track() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+    let mut first_peer = app.join_session(session_id).await;
+    let mut second_peer = app.join_session(session_id).await;
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    host.queue(&track_uri(2)).await;
+    host.receive_state(Some(&track_uri(1)), &[&track_uri(2)])
+        .await;
+
+    // Three clients at the default fraction of one half need two skip votes
+    first_peer.send(json!({ "type": "VoteSkip" })).await;
+    first_peer.send(json!({ "type": "VoteSkip" })).await;
+    first_peer.send(json!({ "type": "State" })).await;
+    first_peer
+        .receive_state(Some(&track_uri(1)), &[&track_uri(2)])
+        .await;
+
+    second_peer.send(json!({ "type": "VoteSkip" })).await;
+    host.receive_state(Some(&track_uri(2)), &[]).await;
+    assert_eq!(app.provider.now_playing(session_id), Some(track_id(2)));
+}
+
+#[actix_web::test]
+async fn only_the_host_can_skip_without_a_vote() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+    let mut peer = app.join_session(session_id).await;
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    host.queue(&track_uri(2)).await;
+    host.receive_state(Some(&track_uri(1)), &[&track_uri(2)])
+        .await;
+
+    peer.send(json!({ "type": "Skip" })).await;
+    let response = peer.receive("Error").await;
+    assert_eq!(response["code"], "Forbidden");
+
+    host.send(json!({ "type": "Skip" })).await;
+    host.receive_state(Some(&track_uri(2)), &[]).await;
+    assert_eq!(app.provider.now_playing(session_id), Some(track_id(2)));
+}
+
+#[actix_web::test]
+async fn clients_cannot_exceed_their_pending_track_limit() {
+    let app = spawn_app_with(|settings| settings.session.max_pending_tracks_per_client = 1).await;
+    let (session_id, mut host) = app.create_session().await;
+    let mut peer = app.join_session(session_id).await;
+
+    peer.queue(&track_uri(1)).await;
+    peer.receive_state(Some(&track_uri(1)), &[]).await;
+    peer.queue(&track_uri(2)).await;
+    peer.receive_state(Some(&track_uri(1)), &[&track_uri(2)])
+        .await;
+
+    peer.queue(&track_uri(3)).await;
+    let response = peer.receive("Error").await;
+    assert_eq!(response["code"], "TooManyPendingTracks");
+
+    // The limit is per client, so the host can still queue
+    host.queue(&track_uri(3)).await;
+    host.receive_state(Some(&track_uri(1)), &[&track_uri(2), &track_uri(3)])
+        .await;
+}
+
+#[actix_web::test]
+async fn queueing_is_refused_when_the_queue_is_full() {
+    let app = spawn_app_with(|settings| settings.session.max_queue_length = 1).await;
+    let (_, mut host) = app.create_session().await;
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    host.queue(&track_uri(2)).await;
+    host.receive_state(Some(&track_uri(1)), &[&track_uri(2)])
+        .await;
+
+    host.queue(&track_uri(3)).await;
+    let response = host.receive("Error").await;
+    assert_eq!(response["code"], "QueueFull");
+}
+
+#[actix_web::test]
+async fn albums_and_playlists_are_queued_as_their_tracks() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+    let mut peer = app.join_session_without_handshake(session_id).await;
+    peer.send(json!({ "type": "Hello", "versions": [PROTOCOL_VERSION] }))
+        .await;
+    let welcome = peer.receive("Welcome").await;
+    let peer_id = welcome["payload"]["client_id"].clone();
+
+    peer.queue(&album_uri(1)).await;
+    let state = host
+        .receive_state(Some(&track_uri(1)), &[&track_uri(3)])
+        .await;
+    assert_eq!(state["payload"]["queue"][0]["queued_by"], peer_id);
+    assert_eq!(app.provider.now_playing(session_id), Some(track_id(1)));
+
+    // Tracks already waiting keep their place
+    host.queue(&playlist_uri(1)).await;
+    host.receive_state(
+        Some(&track_uri(1)),
+        &[&track_uri(3), &track_uri(2), &episode_uri(1), &track_uri(1)],
+    )
+    .await;
+
+    host.queue(&playlist_uri(1)).await;
+    let response = host.receive("Error").await;
+    assert_eq!(response["code"], "NothingToQueue");
+}
+
+#[actix_web::test]
+async fn queued_collections_are_cut_to_fit_the_limits() {
+    let app = spawn_app_with(|settings| {
+        settings.session.max_collection_tracks = 3;
+        settings.session.max_pending_tracks_per_client = 1;
+    })
+    .await;
+    let (session_id, mut host) = app.create_session().await;
+    let mut peer = app.join_session(session_id).await;
+
+    host.queue(&playlist_uri(1)).await;
+    host.receive_state(Some(&track_uri(2)), &[&episode_uri(1)])
+        .await;
+
+    host.queue(&track_uri(3)).await;
+    let response = host.receive("Error").await;
+    assert_eq!(response["code"], "TooManyPendingTracks");
+
+    peer.queue(&album_uri(1)).await;
+    peer.receive_state(Some(&track_uri(2)), &[&episode_uri(1), &track_uri(1)])
+        .await;
+}
+
+#[actix_web::test]
+async fn queueing_too_often_is_rate_limited() {
+    let app = spawn_app_with(|settings| settings.session.min_queue_interval_secs = 60).await;
+    let (_, mut host) = app.create_session().await;
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+
+    host.queue(&track_uri(2)).await;
+    let response = host.receive("Error").await;
+    assert_eq!(response["code"], "RateLimited");
+}
+
+#[actix_web::test]
+async fn replies_echo_the_request_id() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+    let mut peer = app.join_session(session_id).await;
+
+    host.send(json!({ "type": "Search", "query": "alpha", "request_id": "search-1" }))
+        .await;
+    let response = host.receive("SearchResult").await;
+    assert_eq!(response["request_id"], "search-1");
+
+    host.send(json!({ "type": "Queue", "uri": track_uri(1), "request_id": "queue-1" }))
+        .await;
+    let response = host.receive_state(Some(&track_uri(1)), &[]).await;
+    assert_eq!(response["request_id"], "queue-1");
+
+    // Everyone else gets the same update, but it was not their request
+    let response = peer.receive_state(Some(&track_uri(1)), &[]).await;
+    assert!(response.get("request_id").is_none());
+}
+
+#[actix_web::test]
+async fn failed_requests_are_answered_with_an_error_code() {
+    let app = spawn_app().await;
+    let (_, mut host) = app.create_session().await;
+
+    host.send_text("{ not json").await;
+    let response = host.receive("Error").await;
+    assert_eq!(response["code"], "MalformedRequest");
+
+    host.send(json!({ "type": "Queue", "uri": "not-a-uri", "request_id": "queue-1" }))
+        .await;
+    let response = host.receive("Error").await;
+    assert_eq!(response["code"], "InvalidTrackUri");
+    assert_eq!(response["request_id"], "queue-1");
+
+    host.send(json!({ "type": "PinTrack", "uri": track_uri(2), "request_id": "pin-1" }))
+        .await;
+    let response = host.receive("Error").await;
+    assert_eq!(response["code"], "TrackNotQueued");
+    assert_eq!(response["request_id"], "pin-1");
+
+    host.send(json!({ "type": "Skip" })).await;
+    let response = host.receive("Error").await;
+    assert_eq!(response["code"], "NothingPlaying");
+
+    host.send(json!({ "type": "Transfer", "device_id": "missing", "request_id": "transfer-1" }))
+        .await;
+    let response = host.receive("Error").await;
+    assert_eq!(response["code"], "ProviderError");
+    assert_eq!(response["request_id"], "transfer-1");
+}
+
+#[actix_web::test]
+async fn state_describes_tracks_and_who_queued_them() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+    let mut peer = app.join_session_without_handshake(session_id).await;
+    peer.send(json!({ "type": "Hello", "versions": [PROTOCOL_VERSION] }))
+        .await;
+    let welcome = peer.receive("Welcome").await;
+    let peer_id = welcome["payload"]["client_id"].clone();
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    peer.queue(&track_uri(2)).await;
+    host.receive_state(Some(&track_uri(1)), &[&track_uri(2)])
+        .await;
+    host.vote(&track_uri(2)).await;
+    let state = host
+        .receive_where("StateUpdate", |response| {
+            response["payload"]["queue"][0]["votes"] == 1
+        })
+        .await;
+
+    let track = &state["payload"]["track"];
+    assert_eq!(track["album"], "First Song - Single");
+    assert_eq!(track["duration_ms"], TRACK_DURATION.as_millis() as u64);
+    assert_eq!(track["explicit"], false);
+
+    let queued = &state["payload"]["queue"][0];
+    assert_eq!(queued["id"], track_uri(2));
+    assert_eq!(queued["name"], "Second Song");
+    assert_eq!(queued["queued_by"], peer_id);
+}
+
+#[actix_web::test]
+async fn clients_hear_about_playback_progress() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+    let mut peer = app.join_session(session_id).await;
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    app.provider.advance(session_id, Duration::from_secs(30));
+
+    let progress = peer
+        .receive_where("Progress", |response| {
+            response["payload"]["position_ms"].as_u64() >= Some(30_000)
+        })
+        .await;
+    assert_eq!(progress["payload"]["track_id"], track_uri(1));
+    assert_eq!(progress["payload"]["is_playing"], true);
+    assert_eq!(
+        progress["payload"]["duration_ms"],
+        TRACK_DURATION.as_millis() as u64
+    );
+
+    peer.send(json!({ "type": "State" })).await;
+    let state = peer
+        .receive_where("StateUpdate", |response| {
+            !response["payload"]["progress"].is_null()
+        })
+        .await;
+    assert_eq!(state["payload"]["progress"]["track_id"], track_uri(1));
+    assert!(state["payload"]["progress"]["position_ms"].as_u64() >= Some(30_000));
+}
+
+#[actix_web::test]
+async fn host_controls_playback_for_everyone() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+    let mut peer = app.join_session(session_id).await;
+
+    host.queue(&track_uri(1)).await;
+    peer.receive_state(Some(&track_uri(1)), &[]).await;
+
+    host.send(json!({ "type": "Pause" })).await;
+    peer.receive_where("StateUpdate", |response| {
+        response["payload"]["paused"] == true
+    })
+    .await;
+    assert!(!app.provider.is_playing(session_id));
+
+    // The poller leaves a paused session alone
+    tokio::time::sleep(PLAYBACK_CHECK_INTERVAL + Duration::from_secs(1)).await;
+    assert!(!app.provider.is_playing(session_id));
+
+    host.send(json!({ "type": "Resume" })).await;
+    peer.receive_where("StateUpdate", |response| {
+        response["payload"]["paused"] == false
+    })
+    .await;
+    assert!(app.provider.is_playing(session_id));
+
+    host.send(json!({ "type": "Seek", "position_ms": 90_000, "request_id": "seek" }))
+        .await;
+    host.receive_where("StateUpdate", |response| response["request_id"] == "seek")
+        .await;
+    assert!(app.provider.position(session_id) >= Some(Duration::from_secs(90)));
+
+    host.send(json!({ "type": "Volume", "volume_percent": 40, "request_id": "volume" }))
+        .await;
+    host.receive_where("StateUpdate", |response| response["request_id"] == "volume")
+        .await;
+    assert_eq!(app.provider.volume(session_id), Some(40));
+}
+
+#[actix_web::test]
+async fn skipping_a_paused_track_resumes_playback() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    host.queue(&track_uri(2)).await;
+    host.queue(&track_uri(3)).await;
+    host.receive_state(Some(&track_uri(1)), &[&track_uri(2), &track_uri(3)])
+        .await;
+
+    host.send(json!({ "type": "Pause" })).await;
+    host.receive_where("StateUpdate", |response| {
+        response["payload"]["paused"] == true
+    })
+    .await;
+
+    host.send(json!({ "type": "Skip" })).await;
+    let state = host
+        .receive_state(Some(&track_uri(2)), &[&track_uri(3)])
+        .await;
+    assert_eq!(state["payload"]["paused"], false);
+    assert!(app.provider.is_playing(session_id));
+
+    // The poller picks the session back up once the new track ends
+    app.provider.advance(session_id, TRACK_DURATION);
+    let state = host.receive_state(Some(&track_uri(3)), &[]).await;
+    assert_eq!(state["payload"]["paused"], false);
+    assert_eq!(app.provider.now_playing(session_id), Some(track_id(3)));
+}
+
+#[actix_web::test]
+async fn only_the_host_controls_playback() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+    let mut peer = app.join_session(session_id).await;
+
+    host.queue(&track_uri(1)).await;
+    peer.receive_state(Some(&track_uri(1)), &[]).await;
+
+    peer.send(json!({ "type": "Pause" })).await;
+    let response = peer.receive("Error").await;
+    assert_eq!(response["code"], "Forbidden");
+    assert!(app.provider.is_playing(session_id));
+}
+
+#[actix_web::test]
+async fn seeking_past_the_end_of_the_track_is_refused() {
+    let app = spawn_app().await;
+    let (_, mut host) = app.create_session().await;
+
+    host.send(json!({ "type": "Seek", "position_ms": 1_000 }))
+        .await;
+    let response = host.receive("Error").await;
+    assert_eq!(response["code"], "NothingPlaying");
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    let position_ms = TRACK_DURATION.as_millis() as u64;
+    host.send(json!({ "type": "Seek", "position_ms": position_ms }))
+        .await;
+    let response = host.receive("Error").await;
+    assert_eq!(response["code"], "OutOfRange");
+
+    host.send(json!({ "type": "Volume", "volume_percent": 101 }))
+        .await;
+    let response = host.receive("Error").await;
+    assert_eq!(response["code"], "OutOfRange");
+}
+
+#[actix_web::test]
+async fn host_lists_and_imports_their_library() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+    let mut peer = app.join_session(session_id).await;
+
+    peer.send(json!({ "type": "Library", "source": "saved" }))
+        .await;
+    let response = peer.receive("Error").await;
+    assert_eq!(response["code"], "Forbidden");
+
+    host.send(json!({ "type": "Library", "source": "playlists" }))
+        .await;
+    let response = host.receive("LibraryResult").await;
+    assert_eq!(response["payload"]["source"], "playlists");
+    assert_eq!(
+        response["payload"]["playlists"]["items"][0]["id"],
+        playlist_uri(1)
+    );
+
+    host.send(json!({ "type": "Library", "source": "saved", "limit": 1, "offset": 1 }))
+        .await;
+    let response = host.receive("LibraryResult").await;
+    assert_eq!(
+        response["payload"]["tracks"]["items"][0]["id"],
+        track_uri(3)
+    );
+    assert_eq!(response["payload"]["tracks"]["total"], 2);
+
+    host.send(json!({ "type": "Library", "source": "top" }))
+        .await;
+    let response = host.receive("LibraryResult").await;
+    assert_eq!(
+        response["payload"]["tracks"]["items"][0]["id"],
+        track_uri(2)
+    );
+
+    // The host's pick becomes the initial queue, in the order given
+    let uris = [track_uri(1), track_uri(3), track_uri(2), track_uri(3)];
+    host.send(json!({ "type": "Import", "uris": uris })).await;
+    peer.receive_state(Some(&track_uri(1)), &[&track_uri(3), &track_uri(2)])
+        .await;
+    assert_eq!(app.provider.now_playing(session_id), Some(track_id(1)));
+
+    host.send(json!({ "type": "Import", "uris": [track_uri(2)] }))
+        .await;
+    let response = host.receive("Error").await;
+    assert_eq!(response["code"], "NothingToQueue");
+}
+
+#[actix_web::test]
+async fn empty_queue_falls_back_on_the_host_playlist() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+    let mut peer = app.join_session(session_id).await;
+
+    peer.send(json!({ "type": "SetFallbackPlaylist", "uri": playlist_uri(1) }))
+        .await;
+    let response = peer.receive("Error").await;
+    assert_eq!(response["code"], "Forbidden");
+
+    host.send(json!({ "type": "SetFallbackPlaylist", "uri": album_uri(1) }))
+        .await;
+    let response = host.receive("Error").await;
+    assert_eq!(response["code"], "InvalidTrackUri");
+
+    host.send(json!({ "type": "SetFallbackPlaylist", "uri": playlist_uri(1) }))
+        .await;
+    peer.receive_where("StateUpdate", |response| {
+        response["payload"]["fallback_playlist"] == playlist_uri(1)
+    })
+    .await;
+
+    host.queue(&track_uri(3)).await;
+    host.receive_state(Some(&track_uri(3)), &[]).await;
+
+    app.provider
+        .advance(session_id, TRACK_DURATION - Duration::from_secs(1));
+    host.receive_state(Some(&track_uri(2)), &[]).await;
+    app.provider.advance(session_id, Duration::from_secs(2));
+    assert_eq!(app.provider.now_playing(session_id), Some(track_id(2)));
+
+    app.provider.advance(session_id, TRACK_DURATION);
+    host.receive_state(Some(&episode_uri(1)), &[]).await;
+
+    // Without a fallback the session stops once the queue is done
+    host.send(json!({ "type": "SetFallbackPlaylist", "uri": null }))
+        .await;
+    host.receive_where("StateUpdate", |response| {
+        response["payload"]["fallback_playlist"].is_null()
+    })
+    .await;
+    app.provider.advance(session_id, TRACK_DURATION);
+    host.receive_state(None, &[]).await;
+}
+
+#[actix_web::test]
+async fn autoplay_recommends_tracks_after_those_played() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+    let mut peer = app.join_session(session_id).await;
+
+    peer.send(json!({ "type": "SetAutoplay", "mode": "recommendations" }))
+        .await;
+    let response = peer.receive("Error").await;
+    assert_eq!(response["code"], "Forbidden");
+
+    host.send(json!({ "type": "SetAutoplay", "mode": "recommendations" }))
+        .await;
+    host.receive_where("StateUpdate", |response| {
+        response["payload"]["autoplay"] == "recommendations"
+    })
+    .await;
+
+    host.queue(&track_uri(1)).await;
+    let state = host.receive_state(Some(&track_uri(1)), &[]).await;
+    assert_eq!(state["payload"]["autoplayed"], false);
+
+    app.provider
+        .advance(session_id, TRACK_DURATION - Duration::from_secs(1));
+    let state = peer.receive_state(Some(&track_uri(2)), &[]).await;
+    assert_eq!(state["payload"]["autoplayed"], true);
+    app.provider.advance(session_id, Duration::from_secs(2));
+    assert_eq!(app.provider.now_playing(session_id), Some(track_id(2)));
+}
+
+#[actix_web::test]
+async fn history_autoplay_replays_what_the_session_played() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+
+    host.send(json!({ "type": "SetAutoplay", "mode": "history" }))
+        .await;
+    host.receive_where("StateUpdate", |response| {
+        response["payload"]["autoplay"] == "history"
+    })
+    .await;
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    host.queue(&track_uri(2)).await;
+    host.receive_state(Some(&track_uri(1)), &[&track_uri(2)])
+        .await;
+
+    app.provider
+        .advance(session_id, TRACK_DURATION - Duration::from_secs(1));
+    host.receive_state(Some(&track_uri(2)), &[]).await;
+    app.provider.advance(session_id, Duration::from_secs(2));
+
+    app.provider.advance(session_id, TRACK_DURATION);
+    let state = host.receive_state(Some(&track_uri(1)), &[]).await;
+    assert_eq!(state["payload"]["autoplayed"], true);
+    assert_eq!(app.provider.now_playing(session_id), Some(track_id(1)));
+
+    host.send(json!({ "type": "SetAutoplay", "mode": "off" }))
+        .await;
+    host.receive_where("StateUpdate", |response| {
+        response["payload"]["autoplay"] == "off"
+    })
+    .await;
+    app.provider.advance(session_id, TRACK_DURATION);
+    host.receive_state(None, &[]).await;
+}
+
+#[actix_web::test]
+async fn history_records_plays_and_is_saved_to_a_playlist() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+    let mut peer = app.join_session_without_handshake(session_id).await;
+    peer.send(json!({ "type": "Hello", "versions": [PROTOCOL_VERSION] }))
+        .await;
+    let welcome = peer.receive("Welcome").await;
+    let peer_id = welcome["payload"]["client_id"].clone();
+
+    host.send(json!({ "type": "SaveHistory" })).await;
+    let response = host.receive("Error").await;
+    assert_eq!(response["code"], "EmptyHistory");
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    peer.queue(&track_uri(2)).await;
+    host.receive_state(Some(&track_uri(1)), &[&track_uri(2)])
+        .await;
+    host.vote(&track_uri(2)).await;
+    host.receive_where("StateUpdate", |response| {
+        response["payload"]["queue"][0]["votes"] == 1
+    })
+    .await;
+    host.send(json!({ "type": "Skip" })).await;
+    host.receive_state(Some(&track_uri(2)), &[]).await;
+
+    host.send(json!({ "type": "History", "limit": 10 })).await;
+    let history = host.receive("History").await;
+    let history = &history["payload"];
+    assert_eq!(history["total"], 2);
+    let first = &history["items"][0];
+    assert_eq!(first["id"], track_uri(1));
+    assert_eq!(first["skipped"], true);
+    assert!(first["ended_at"].is_string());
+    assert!(first["queued_by"].is_string());
+    let second = &history["items"][1];
+    assert_eq!(second["id"], track_uri(2));
+    assert_eq!(second["queued_by"], peer_id);
+    assert_eq!(second["votes"], 1);
+    assert_eq!(second["skipped"], false);
+    assert!(second["ended_at"].is_null());
+
+    peer.send(json!({ "type": "SaveHistory" })).await;
+    let response = peer.receive("Error").await;
+    assert_eq!(response["code"], "Forbidden");
+
+    host.send(json!({ "type": "SaveHistory", "name": "Friday" }))
+        .await;
+    let saved = host.receive("SavedPlaylist").await;
+    assert_eq!(saved["payload"]["name"], "Friday");
+    assert_eq!(saved["payload"]["total_tracks"], 2);
+    let playlist = saved["payload"]["id"].as_str().unwrap();
+    assert_eq!(
+        app.provider.playlist_items(playlist),
+        Some(vec![track_id(1), track_id(2)])
+    );
+}
+
+#[actix_web::test]
+async fn idle_sessions_are_ended_once_their_clients_leave() {
+    let app = spawn_app_with(|settings| {
+        settings.session.idle_ttl_secs = 6;
+        settings.session.sweep_interval_secs = 1;
+    })
+    .await;
+    let (session_id, host) = app.create_session().await;
+    let peer = app.join_session(session_id).await;
+    drop(host);
+
+    // A client that stays connected without asking for anything may be listening
+    tokio::time::sleep(Duration::from_secs(9)).await;
+    assert!(app.session_exists(session_id).await);
+
+    drop(peer);
+    let mut waited = Duration::ZERO;
+    while app.session_exists(session_id).await {
+        assert!(
+            waited < Duration::from_secs(15),
+            "Idle session was not ended"
+        );
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        waited += Duration::from_millis(500);
+    }
+}
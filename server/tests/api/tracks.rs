@@ -0,0 +1,86 @@
+use crate::helpers::{
+    playlist_uri, spawn_app, spawn_app_with, track_id, track_uri, TRACK_DURATION,
+};
+use serde_json::json;
+
+#[actix_web::test]
+async fn searched_tracks_are_not_looked_up_again() {
+    let app = spawn_app().await;
+    let (_, mut host) = app.create_session().await;
+
+    host.send(json!({ "type": "Search", "query": "song" }))
+        .await;
+    host.receive("SearchResult").await;
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    host.queue(&track_uri(2)).await;
+    host.receive_state(Some(&track_uri(1)), &[&track_uri(2)])
+        .await;
+    host.send(json!({ "type": "State" })).await;
+    host.receive_state(Some(&track_uri(1)), &[&track_uri(2)])
+        .await;
+
+    assert_eq!(app.provider.track_lookups(), 0);
+}
+
+#[actix_web::test]
+async fn track_metadata_is_looked_up_once_and_stored() {
+    let app = spawn_app().await;
+    let (_, mut host) = app.create_session().await;
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    host.send(json!({ "type": "State" })).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    assert_eq!(app.provider.track_lookups(), 1);
+
+    let (name,): (String,) = sqlx::query_as("SELECT name FROM tracks WHERE uri = $1")
+        .bind(track_uri(1))
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Track metadata was not stored");
+    assert_eq!(name, "First Song");
+}
+
+#[actix_web::test]
+async fn other_instances_serve_stored_metadata_from_the_database() {
+    let app = spawn_app().await;
+    let (session_id, mut host) = app.create_session().await;
+
+    host.queue(&track_uri(1)).await;
+    host.receive_state(Some(&track_uri(1)), &[]).await;
+    let lookups = app.provider.track_lookups();
+
+    let replica = app.spawn_replica().await;
+    let mut peer = replica.join_session(session_id).await;
+    peer.send(json!({ "type": "State" })).await;
+    peer.receive_state(Some(&track_uri(1)), &[]).await;
+
+    assert_eq!(app.provider.track_lookups(), lookups);
+}
+
+#[actix_web::test]
+async fn long_queues_are_looked_up_in_batches() {
+    let app = spawn_app_with(|settings| {
+        settings.track_cache.capacity = 0;
+        settings.track_cache.persist = false;
+        settings.session.max_queue_length = 100;
+        settings.session.max_pending_tracks_per_client = 100;
+        settings.session.max_collection_tracks = 100;
+    })
+    .await;
+    let tracks: Vec<_> = (10..70).map(track_id).collect();
+    for id in &tracks {
+        app.provider
+            .add_track(id, "Filler", &["Gamma"], TRACK_DURATION);
+    }
+    app.provider
+        .add_playlist(&playlist_uri(2), "Long Mix", "Host", &tracks);
+    let (_, mut host) = app.create_session().await;
+
+    host.queue(&playlist_uri(2)).await;
+    let uris: Vec<String> = (11..70).map(track_uri).collect();
+    let queue: Vec<&str> = uris.iter().map(String::as_str).collect();
+    host.receive_state(Some(&track_uri(10)), &queue).await;
+}
@@ -0,0 +1,59 @@
+use crate::helpers::{spawn_app, spawn_app_with, track_uri};
+use serde_json::json;
+use std::time::Duration;
+
+#[actix_web::test]
+async fn a_slow_session_does_not_hold_up_the_others() {
+    let app = spawn_app().await;
+    let (slow_session, mut slow_host) = app.create_session().await;
+    let (_, mut host) = app.create_session().await;
+    app.provider
+        .delay_search(slow_session, Duration::from_secs(10));
+
+    slow_host
+        .send(json!({ "type": "Search", "query": "song" }))
+        .await;
+    host.queue(&track_uri(1)).await;
+
+    tokio::time::timeout(
+        Duration::from_secs(2),
+        host.receive_state(Some(&track_uri(1)), &[]),
+    )
+    .await
+    .expect("Queueing waited for another session's search");
+}
+
+#[actix_web::test]
+async fn slow_requests_time_out() {
+    let app = spawn_app_with(|settings| settings.session.request_timeout_secs = 1).await;
+    let (session_id, mut host) = app.create_session().await;
+    app.provider
+        .delay_search(session_id, Duration::from_secs(5));
+
+    host.send(json!({ "type": "Search", "query": "song", "request_id": "search-1" }))
+        .await;
+    let response = host.receive("Error").await;
+    assert_eq!(response["code"], "Timeout");
+    assert_eq!(response["request_id"], "search-1");
+
+    let metrics = app.metrics().await;
+    assert!(metrics.contains("queuetify_session_requests_timed_out_total 1"));
+}
+
+#[actix_web::test]
+async fn full_workers_turn_requests_away() {
+    let app = spawn_app_with(|settings| settings.session.worker_queue_capacity = 1).await;
+    let (session_id, mut host) = app.create_session().await;
+    app.provider
+        .delay_search(session_id, Duration::from_secs(3));
+
+    for _ in 0..3 {
+        host.send(json!({ "type": "Search", "query": "song" }))
+            .await;
+    }
+    let response = host.receive("Error").await;
+    assert_eq!(response["code"], "Busy");
+
+    let metrics = app.metrics().await;
+    assert!(metrics.contains("queuetify_session_workers 1"));
+}